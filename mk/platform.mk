@@ -12,11 +12,20 @@
 # Create variables HOST_<triple> containing the host part
 # of each target triple.  For example, the triple i686-darwin-macos
 # would create a variable HOST_i686-darwin-macos with the value
-# i386.
+# i386. Little endian mips64 (mips64el) shares the mips64 port, and
+# powerpc64le the powerpc64 one, since neither register layout depends on
+# the byte order.
 define DEF_HOST_VAR
-  HOST_$(1) = $(subst i686,i386,$(word 1,$(subst -, ,$(1))))
+  HOST_$(1) = $(subst powerpc64le,powerpc64,$(subst mips64el,mips64,$(subst i686,i386,$(word 1,$(subst -, ,$(1))))))
 endef
 $(foreach t,$(CFG_TARGET),$(eval $(call DEF_HOST_VAR,$(t))))
+# Architectures without a runtime port of their own use rt/arch/generic,
+# which switches contexts with the slow ucontext fallback in
+# rt/rust_ucontext.c
+$(foreach t,$(CFG_TARGET),$(if $(wildcard $(CFG_SRC_DIR)src/rt/arch/$(HOST_$(t))),,$(eval HOST_$(t) = generic)))
+# libgreen has to be told to use the fallback too, and this is the only place
+# that decides which architectures do
+$(foreach t,$(CFG_TARGET),$(eval LIBGREEN_CFG_$(t) = $(if $(filter generic,$(HOST_$(t))),--cfg green_ucontext)))
 $(foreach t,$(CFG_TARGET),$(info cfg: host for $(t) is $(HOST_$(t))))
 
 # Ditto for OSTYPE
@@ -63,6 +63,12 @@ define DEF_RUNTIME_TARGETS
 RUNTIME_CFLAGS_$(1)_$(2) = -D_RUST_STAGE$(2)
 RUNTIME_CXXFLAGS_$(1)_$(2) = -D_RUST_STAGE$(2)
 
+# libgreen built with --cfg preserve_xmm expects the x86_64 register swaps to
+# save every XMM register
+ifneq ($(findstring --cfg preserve_xmm,$(CFG_RUSTC_FLAGS)),)
+RUNTIME_SFLAGS_$(1)_$(2) = -DRUST_PRESERVE_XMM
+endif
+
 # XXX: Like with --cfg stage0, pass the defines for stage1 to the stage0
 # build of non-build-triple host compilers
 ifeq ($(2),0)
@@ -72,11 +78,17 @@ RUNTIME_CXXFLAGS_$(1)_$(2) = -D_RUST_STAGE1
 endif
 endif
 
+# rt/rust_ucontext.c is only the context switch on rt/arch/generic
+ifeq ($(HOST_$(1)),generic)
+RUNTIME_CFLAGS_$(1)_$(2) += -DRUST_UCONTEXT_FALLBACK
+endif
+
 RUNTIME_CS_$(1)_$(2) := \
               rt/rust_builtin.c \
               rt/miniz.c \
               rt/rust_android_dummy.c \
-              rt/rust_test_helpers.c
+              rt/rust_test_helpers.c \
+              rt/rust_ucontext.c
 
 RUNTIME_LL_$(1)_$(2) := \
 			rt/rust_try.ll
@@ -111,7 +123,7 @@ $$(RT_BUILD_DIR_$(1)_$(2))/%.o: rt/%.c $$(MKFILE_DEPS)
 $$(RT_BUILD_DIR_$(1)_$(2))/%.o: rt/%.S  $$(MKFILE_DEPS) \
                      $$(LLVM_CONFIG_$$(CFG_BUILD))
 	@$$(call E, compile: $$@)
-	$$(Q)$$(call CFG_ASSEMBLE_$(1),$$@,$$<)
+	$$(Q)$$(call CFG_ASSEMBLE_$(1),$$@,$$(RUNTIME_SFLAGS_$(1)_$(2)) $$<)
 
 $$(RT_BUILD_DIR_$(1)_$(2))/%.o: rt/%.ll  $$(MKFILE_DEPS) \
                      $$(LLVM_CONFIG_$$(CFG_BUILD))
@@ -379,7 +379,7 @@ $(3)/stage$(1)/test/greentest-$(2)$$(X_$(2)):			\
 		$$(LIBGREEN_CRATE) $$(LIBGREEN_INPUTS)	\
 		$$(STDTESTDEP_$(1)_$(2)_$(3))
 	@$$(call E, compile_and_link: $$@)
-	$$(STAGE$(1)_T_$(2)_H_$(3)) -o $$@ $$< --test
+	$$(STAGE$(1)_T_$(2)_H_$(3)) -o $$@ $$< --test $$(LIBGREEN_CFG_$(2))
 
 $(3)/stage$(1)/test/syntaxtest-$(2)$$(X_$(2)):			\
 		$$(LIBSYNTAX_CRATE) $$(LIBSYNTAX_INPUTS)	\
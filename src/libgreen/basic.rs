@@ -236,6 +236,8 @@ mod test {
         SchedPool::new(PoolConfig {
             threads: 1,
             event_loop_factory: Some(basic::event_loop),
+            overflow_handler: false,
+            stack_allocator: None,
         })
     }
 
@@ -266,6 +268,8 @@ mod test {
         let mut pool = SchedPool::new(PoolConfig {
             threads: 2,
             event_loop_factory: Some(basic::event_loop),
+            overflow_handler: false,
+            stack_allocator: None,
         });
 
         for _ in range(0, 20) {
@@ -8,295 +8,8131 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::libc::c_void;
+use std::any::{Any, AnyOwnExt};
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::fmt;
+use std::libc;
+use std::libc::{c_int, c_void, size_t};
+#[cfg(test)] use std::local_data;
+use std::mem;
+use std::os;
+use std::ptr;
+use std::rt::env;
 use std::uint;
-use std::cast::{transmute, transmute_mut_unsafe,
+use std::vec;
+use std::cast::{forget, transmute, transmute_mut_unsafe,
                 transmute_region, transmute_mut_region};
+use std::rt::local::Local;
+use std::rt::task::Task;
+use std::rt::unwind;
+use std::rt::unwind::Unwinder;
+use std::sync::atomics::{AtomicUint, INIT_ATOMIC_UINT, SeqCst, Relaxed};
 use std::unstable::stack;
 
-use stack::StackSegment;
+#[cfg(green_ucontext)]
+pub use context::ucontext::{SAVED_REGISTER_COUNT, STACK_ALIGNMENT,
+                            ARG_POSITIONS, RED_ZONE_SIZE};
+#[cfg(green_ucontext)]
+use context::ucontext::{SNAPSHOT_NEW_REGISTERS, SNAPSHOT_ARCH, Registers,
+                        new_regs, saved_ip, set_saved_ip, saved_sp, saved_fp,
+                        clear_fp_regs, redirect_call, INITIAL_FRAME_BYTES,
+                        initialize_call_frame, ASM_REGISTER_SLOTS,
+                        LAST_FRAME_SLOT};
+use context_registry;
+use signal_stack::{AltStack, SignalStack};
+pub use signal_stack::MIN_SIGNAL_STACK;
+use stack::{StackArena, StackSegment, over_stack_budget};
+use stack_registry;
+use time;
 
 // FIXME #7761: Registers is boxed so that it is 16-byte aligned, for storing
 // SSE regs.  It would be marginally better not to do this. In C++ we
-// use an attribute on a struct.
+// use an attribute on a struct. There's no such attribute yet, so the
+// alignment is down to the allocator, and `Frame::new` checks it.
 // FIXME #7761: It would be nice to define regs as `~Option<Registers>` since
 // the registers are sometimes empty, but the discriminant would
 // then misalign the regs again.
 pub struct Context {
-    /// The context entry point, saved here for later destruction
-    priv start: Option<~proc()>,
-    /// Hold the registers while the task or scheduler is suspended
-    priv regs: ~Registers,
-    /// Lower bound and upper bound for the stack
+    /// Hold the registers while the task or scheduler is suspended, along
+    /// with the context entry point, saved there for later destruction
+    priv frame: ~Frame,
+    /// Lower bound and upper bound for the stack segment that is currently
+    /// active, which is what gets recorded for morestack on a swap
     priv stack_bounds: Option<(uint, uint)>,
+    /// Every stack segment this context may run on, in the order they were
+    /// registered. The first entry is the segment the context was created on.
+    priv segments: ~[(uint, uint)],
+    /// Where `STACK_CANARY` was written above the initial frame, null if this
+    /// context has no stack of its own, see `check_canary`
+    priv canary: *uint,
+    /// Stack protector cookie to install while this context is running, if it
+    /// should differ from the one the thread already has
+    priv stack_guard: Option<uint>,
+    /// FS and GS base addresses to install while this context is running, if
+    /// they should differ from the ones the thread already has, see
+    /// `set_segment_bases`
+    priv segment_bases: Option<(uint, uint)>,
+    /// Task-local data for `current_task_local`, see `set_local`
+    priv local: *mut c_void,
+    /// How long this context has spent running and suspended, only kept up to
+    /// date when built with `--cfg context_timing`
+    priv timing: SwitchTiming,
+    /// Whether this context has ever been swapped into. Contexts from `empty`
+    /// are considered to have started already, they represent whatever code
+    /// is currently running.
+    priv started: Cell<bool>,
+    /// Whether dropping this context while it's suspended should unwind its
+    /// stack first, see `set_unwind_on_drop`
+    priv unwind_on_drop: bool,
+    /// The OS thread this context was last swapped in on, 0 if there hasn't
+    /// been one (or it isn't being tracked)
+    priv last_thread: Cell<ThreadId>,
+    /// What to do if this context is found to have overflowed its stack, if
+    /// it should differ from the global `set_overflow_policy`
+    priv overflow_policy: Option<OverflowPolicy>,
+    /// The lowest stack pointer this context has been swapped out with, only
+    /// kept when built with `--cfg stack_sampling`
+    priv min_sp_seen: uint,
+    /// Whether the `LowStackHook` has already been called for this context's
+    /// current task
+    priv low_stack_reported: bool,
+    /// This context's own alternate signal stack, see `set_signal_stack`
+    priv signal_stack: Option<SignalStack>,
+    /// The alternate signal stack which `signal_stack` replaced when this
+    /// context was last swapped in
+    priv prev_signal_stack: Cell<Option<AltStack>>,
+    /// Identifies this context in the `stack_registry`, 0 for contexts from
+    /// `empty`
+    priv id: uint,
+    /// Which task this context has been set up for, see `generation`
+    priv generation: u64,
+    /// Whether this context is the one currently running on some thread.
+    /// Contexts from `empty` start out running, as they stand for whatever
+    /// code created them.
+    priv running: Cell<bool>,
+    /// The tracing span this context's code is running in, see `set_span`
+    priv span: Option<SpanToken>,
+    /// Whether this context may have changed the floating point registers
+    /// since they were last saved, see `mark_fp_dirty`
+    priv fp_dirty: Cell<bool>,
+    /// Whether this context's floating point registers have been saved, and
+    /// so must be restored when it's swapped back in
+    priv fp_saved: Cell<bool>,
+    /// Why this context was last swapped out, see `swap_with_reason`
+    priv last_switch_reason: Option<SwitchReason>,
+    /// AddressSanitizer's fake stack for this context while it's suspended,
+    /// only used when built with `--cfg asan`
+    priv asan_fake_stack: *c_void,
+    /// The stack AddressSanitizer reported this context as running on, for
+    /// contexts without `stack_bounds` of their own
+    priv asan_stack: Cell<(uint, uint)>,
+    /// The context which last swapped into this one
+    priv asan_from: Cell<*Context>,
+    /// For contexts without stack bounds, the stack base, limit and
+    /// deallocation stack which the windows TIB held when this context was
+    /// last swapped out. Contexts on a stack which is committed as it grows
+    /// (see `StackSegment::reservation`) keep them too, since the OS moves the
+    /// limit down as it commits more of the stack.
+    priv native_tib: Cell<Option<(uint, uint, uint)>>,
+    /// Where the thread this context is running on keeps the stack bounds
+    /// that a swap records, see `bounds_slots`. Only a running context has
+    /// them, and only once something has swapped into it.
+    priv bounds_slots: Cell<Option<(*mut uint, *mut uint)>>,
+    /// For contexts without stack bounds, the bounds of the stack they really
+    /// run on, if they're known, see `set_native_bounds`
+    priv native_bounds: Option<(uint, uint)>,
+    /// A name for debug output, see `set_name`
+    priv name: Option<~str>,
+    /// Where this context's task was created from, see `spawn_backtrace`
+    priv spawn_backtrace: ~[*c_void],
+}
+
+/// Why a context was swapped out, for scheduler diagnostics
+#[deriving(Eq)]
+pub enum SwitchReason {
+    /// The task yielded voluntarily
+    Yielded,
+    /// The task is waiting for I/O or some other event
+    Blocked,
+    /// The task was preempted by its scheduler
+    Preempted,
+    /// The task has finished
+    Finished,
+}
+
+/// An OS thread identifier, as given by `pthread_self` (or `GetCurrentThreadId`
+/// on windows)
+pub type ThreadId = uint;
+
+// The registers and entry point share one allocation, so that creating a
+// context allocates nothing more than this and whatever its entry point owns
+// (the environment of a procedure, or state too large for `new_inline`).
+// Being boxed also keeps the entry point at the same address, which the task
+// is handed as its argument, however the context itself is moved around.
+struct Frame {
+    /// First, so that the box's alignment applies to it
+    regs: Registers,
+    start: Option<TaskStart>,
+}
+
+// What `task_start_wrapper` is handed as its argument
+struct TaskStart {
+    /// The task's entry procedure
+    main: Main,
+    /// The context to switch back to once a forced unwind has reached the
+    /// bottom of the task's stack, null unless one is in progress
+    unwind_to: *Context,
+    /// The context which last swapped into this one, which is switched back
+    /// to when `main` returns
+    resumer: Cell<*Context>,
+    /// What a `Returning` entry procedure returned, until `take_result`
+    result: Option<~Any>,
+    /// What the entry procedure failed with, until `take_failure`
+    failure: Option<~Any>,
+    /// Run once the entry procedure is done with, see `set_on_finish`
+    on_finish: Option<proc()>,
+    /// A copy of the context's canary, which `task_start_wrapper` has no
+    /// other way of finding
+    canary: *uint,
+}
+
+enum Main {
+    Plain(proc()),
+    /// From `new_with_result`. The procedure really returns some `T`, which
+    /// only the paired `call_returning::<T>` knows. Keeping it like this
+    /// rather than wrapping it in a procedure which boxes up its result saves
+    /// allocating that procedure's environment on every spawn.
+    Returning(proc(), fn(&proc()) -> ~Any),
+    /// From `new_with_arg`. The procedure really takes some `T`, which is kept
+    /// boxed up beside it until `call_with_arg::<T>` hands it over, and is
+    /// dropped along with the procedure if the task never runs.
+    WithArg(proc(), Option<~Any>, fn(&proc(), ~Any)),
+    /// From `new_inline`
+    Inline(InlineMain),
+}
+
+/// How many words of state `Context::new_inline` keeps in a context's frame.
+/// Anything larger, or aligned to more than a word, is boxed instead.
+pub static INLINE_ENTRY_WORDS: uint = 8;
+
+// The state and function of an `Inline` entry. The state is some `T`, which
+// only the paired `call_inline::<T>` knows, kept in `buf` if it fits there
+// and otherwise boxed, with the box in `buf[0]`.
+struct InlineMain {
+    buf: [uint, ..INLINE_ENTRY_WORDS],
+    /// The `fn(T)` the state is handed to, as an address
+    f: uint,
+    /// Moves the state out of `buf` and hands it to `f`, or only drops it
+    call: fn(&mut InlineMain, bool),
+    /// Whether `buf` still holds the state
+    live: bool,
+}
+
+impl Drop for InlineMain {
+    // The state of a task which never ran goes with its context
+    fn drop(&mut self) {
+        if self.live {
+            let call = self.call;
+            call(self, false);
+        }
+    }
+}
+
+// Whether a `T` is kept in an `InlineMain`'s buffer rather than boxed
+fn fits_inline<T>() -> bool {
+    mem::size_of::<T>() <= INLINE_ENTRY_WORDS * mem::size_of::<uint>() &&
+        mem::min_align_of::<T>() <= mem::min_align_of::<uint>()
+}
+
+// Takes the `T` out of an `Inline` entry, and calls its function with it if
+// `run` is true
+fn call_inline<T: Send>(main: &mut InlineMain, run: bool) {
+    main.live = false;
+    let data: T = unsafe {
+        if fits_inline::<T>() {
+            ptr::read_ptr(&main.buf[0] as *uint as *T)
+        } else {
+            let data: ~T = transmute(main.buf[0]);
+            *data
+        }
+    };
+    if run {
+        let f: fn(T) = unsafe { transmute(main.f) };
+        f(data)
+    }
+}
+
+// The procedure of a `Returning` entry, for `call_returning::<T>` to call.
+// Procedures are dropped the same way whatever they return.
+fn returning<T: Send>(start: proc() -> T) -> proc() {
+    unsafe { transmute(start) }
+}
+
+// Calls the procedure of a `Returning` entry, which returns a `T`
+fn call_returning<T: Send>(main: &proc()) -> ~Any {
+    unsafe {
+        let f: &|| -> T = transmute(main);
+        ~(*f)() as ~Any
+    }
+}
+
+// Calls the procedure of a `WithArg` entry, which takes a `T`
+fn call_with_arg<T: Send>(main: &proc(), arg: ~Any) {
+    let arg = match arg.move::<T>() {
+        Ok(arg) => arg,
+        Err(..) => unreachable!(),
+    };
+    unsafe {
+        let f: &|T| = transmute(main);
+        (*f)(*arg)
+    }
+}
+
+impl TaskStart {
+    fn new(main: Main) -> TaskStart {
+        TaskStart {
+            main: main,
+            unwind_to: 0 as *Context,
+            resumer: Cell::new(0 as *Context),
+            result: None,
+            failure: None,
+            on_finish: None,
+            canary: 0 as *uint,
+        }
+    }
+}
+
+// How `Registers` are aligned wherever they're kept. Only x86_64 and powerpc64
+// rely on it: the x86_64 swap saves the XMM registers with `movapd`, which
+// faults on anything less than 16 byte aligned, and the powerpc64 one saves
+// the vector registers with `stvx`/`lvx`, which silently round the address
+// down to a multiple of 16.
+static REGISTER_ALIGNMENT: uint = 16;
+
+impl Frame {
+    // Boxes up a frame for a context. Nothing but the allocator lines the
+    // registers up as `REGISTER_ALIGNMENT` asks (see the FIXME on `Context`),
+    // so debug builds make sure a misaligned box is caught here rather than as
+    // a fault in the middle of a swap.
+    fn new(start: Option<TaskStart>) -> ~Frame {
+        let frame = ~Frame { regs: new_regs(), start: start };
+        if cfg!(not(ndebug)) &&
+           (cfg!(target_arch = "x86_64") || cfg!(target_arch = "powerpc64")) {
+            let regs = &frame.regs as *Registers as uint;
+            if regs & (REGISTER_ALIGNMENT - 1) != 0 {
+                rtabort!("the allocator placed a context's registers at {:#x}, \
+                          which isn't {}-byte aligned", regs, REGISTER_ALIGNMENT);
+            }
+        }
+        frame
+    }
+
+    // Hands the canary just written above the initial frame to the task
+    fn set_canary(&mut self, canary: *uint) {
+        match self.start {
+            Some(ref mut start) => start.canary = canary,
+            None => {}
+        }
+    }
 }
 
 impl Context {
     pub fn empty() -> Context {
         Context {
-            start: None,
-            regs: new_regs(),
+            frame: Frame::new(None),
             stack_bounds: None,
+            segments: ~[],
+            canary: 0 as *uint,
+            stack_guard: None,
+            segment_bases: None,
+            local: 0 as *mut c_void,
+            timing: SwitchTiming::new(),
+            started: Cell::new(true),
+            unwind_on_drop: false,
+            last_thread: Cell::new(0),
+            overflow_policy: None,
+            min_sp_seen: uint::max_value,
+            low_stack_reported: false,
+            signal_stack: None,
+            prev_signal_stack: Cell::new(None),
+            id: 0,
+            generation: 0,
+            running: Cell::new(true),
+            span: None,
+            fp_dirty: Cell::new(true),
+            fp_saved: Cell::new(false),
+            last_switch_reason: None,
+            asan_fake_stack: 0 as *c_void,
+            asan_stack: Cell::new((0, 0)),
+            asan_from: Cell::new(0 as *Context),
+            native_tib: Cell::new(None),
+            bounds_slots: Cell::new(None),
+            native_bounds: None,
+            name: None,
+            spawn_backtrace: ~[],
         }
     }
 
     /// Create a new context that will resume execution by running proc()
+    ///
+    /// This fails if the stack can't be used for a context, see `try_new`.
     pub fn new(start: proc(), stack: &mut StackSegment) -> Context {
-        // The C-ABI function that is the task entry point
-        //
-        // Note that this function is a little sketchy. We're taking a
-        // procedure, transmuting it to a stack-closure, and then calling to
-        // closure. This leverages the fact that the representation of these two
-        // types is the same.
-        //
-        // The reason that we're doing this is that this procedure is expected
-        // to never return. The codegen which frees the environment of the
-        // procedure occurs *after* the procedure has completed, and this means
-        // that we'll never actually free the procedure.
-        //
-        // To solve this, we use this transmute (to not trigger the procedure
-        // deallocation here), and then store a copy of the procedure in the
-        // `Context` structure returned. When the `Context` is deallocated, then
-        // the entire procedure box will be deallocated as well.
-        extern fn task_start_wrapper(f: &proc()) {
-            unsafe {
-                let f: &|| = transmute(f);
-                (*f)()
+        Context::new_with_fp_state(start, stack, false)
+    }
+
+    /// Create a new context that will resume execution by running proc(), or
+    /// return why the given stack can't be used for one. That includes
+    /// `StackBudgetExceeded` while the stacks in use take up more than the
+    /// budget set with `stack::set_stack_budget`.
+    pub fn try_new(start: proc(),
+                   stack: &mut StackSegment) -> Result<Context, ContextError> {
+        Context::try_new_with_fp_state(start, stack, false)
+    }
+
+    /// Same as `new`, but with control over the floating point state the task
+    /// starts with.
+    ///
+    /// The initial registers of a context may be a snapshot of the creating
+    /// thread's registers (see `baseline`), which on some architectures
+    /// includes floating point and SSE state. If `clean_fp_state` is true then
+    /// that portion of the snapshot is zeroed so the task doesn't inherit
+    /// whatever the creator was in the middle of.
+    pub fn new_with_fp_state(start: proc(), stack: &mut StackSegment,
+                             clean_fp_state: bool) -> Context {
+        let start = TaskStart::new(Plain(start));
+        match Context::with_task_start(start, stack, clean_fp_state) {
+            Ok(ctx) => ctx,
+            Err(e) => fail!("cannot create a context: {}", e),
+        }
+    }
+
+    /// Like `new_with_fp_state`, but returns why the stack can't be used
+    /// instead of failing, as `try_new` does.
+    pub fn try_new_with_fp_state(start: proc(), stack: &mut StackSegment,
+                                 clean_fp_state: bool)
+        -> Result<Context, ContextError>
+    {
+        if over_stack_budget() { return Err(StackBudgetExceeded) }
+        Context::with_task_start(TaskStart::new(Plain(start)), stack,
+                                 clean_fp_state)
+    }
+
+    /// Create a new context which will run `start` and keep what it returns,
+    /// for `take_result`.
+    ///
+    /// Once `start` returns the context switches back to whichever context
+    /// last swapped into it. The same happens if `start` fails, leaving no
+    /// result behind but its failure for `take_failure`.
+    ///
+    /// This fails if the stack can't be used for a context, see
+    /// `try_new_with_result`.
+    pub fn new_with_result<T: Send>(start: proc() -> T,
+                                    stack: &mut StackSegment) -> Context {
+        let start = TaskStart::new(Returning(returning(start),
+                                             call_returning::<T>));
+        match Context::with_task_start(start, stack, false) {
+            Ok(ctx) => ctx,
+            Err(e) => fail!("cannot create a context: {}", e),
+        }
+    }
+
+    /// Like `new_with_result`, but returns why the stack can't be used instead
+    /// of failing, as `try_new` does, and with control over the floating
+    /// point state the task starts with, as `new_with_fp_state` has.
+    pub fn try_new_with_result<T: Send>(start: proc() -> T,
+                                        stack: &mut StackSegment,
+                                        clean_fp_state: bool)
+        -> Result<Context, ContextError>
+    {
+        if over_stack_budget() { return Err(StackBudgetExceeded) }
+        let start = TaskStart::new(Returning(returning(start),
+                                             call_returning::<T>));
+        Context::with_task_start(start, stack, clean_fp_state)
+    }
+
+    // Checks the stack once and sets up a context on it which enters `start`
+    fn with_task_start(start: TaskStart, stack: &mut StackSegment,
+                       clean_fp_state: bool) -> Result<Context, ContextError> {
+        match validate_stack(stack) {
+            Ok(()) => {}
+            Err(e) => return Err(e),
+        }
+        Ok(Context::with_frame(task_start_wrapper as *c_void, 0 as *c_void, 0,
+                               Some(start), stack, clean_fp_state))
+    }
+
+    /// Create a new context which will run `start` with `arg`.
+    ///
+    /// `arg` is kept in the context rather than in the procedure's
+    /// environment, so a procedure which only needs to be handed one large
+    /// value can capture nothing at all. If the context is dropped without
+    /// ever having run, `arg` is dropped with it.
+    pub fn new_with_arg<T: Send>(start: proc(T), arg: T,
+                                 stack: &mut StackSegment) -> Context {
+        match validate_stack(stack) {
+            Ok(()) => {}
+            Err(e) => fail!("cannot create a context: {}", e),
+        }
+        // Procedures are dropped the same way whatever they take
+        let main: proc() = unsafe { transmute(start) };
+        let arg = ~arg as ~Any;
+        let start = TaskStart::new(WithArg(main, Some(arg), call_with_arg::<T>));
+        Context::with_frame(task_start_wrapper as *c_void, 0 as *c_void, 0,
+                            Some(start), stack, false)
+    }
+
+    /// Create a new context which will call `f(data)`, for a task which is a
+    /// plain function of some state rather than a procedure.
+    ///
+    /// A procedure's environment is boxed as soon as the procedure is made,
+    /// whereas `data` is kept in the context's frame if it takes up no more
+    /// than `INLINE_ENTRY_WORDS` words (and is aligned to no more than a
+    /// word), so that creating a context for a small task allocates just
+    /// once. Larger state is boxed. If the context is dropped without ever
+    /// having run, `data` is dropped with it.
+    pub fn new_inline<T: Send>(data: T, f: fn(T),
+                               stack: &mut StackSegment) -> Context {
+        match validate_stack(stack) {
+            Ok(()) => {}
+            Err(e) => fail!("cannot create a context: {}", e),
+        }
+        let mut main = InlineMain {
+            buf: [0, ..INLINE_ENTRY_WORDS],
+            f: f as uint,
+            call: call_inline::<T>,
+            live: true,
+        };
+        unsafe {
+            if fits_inline::<T>() {
+                ptr::copy_nonoverlapping_memory(&mut main.buf[0] as *mut uint
+                                                                 as *mut T,
+                                                &data as *T, 1);
+                forget(data);
+            } else {
+                main.buf[0] = transmute(~data);
+            }
+        }
+        Context::with_frame(task_start_wrapper as *c_void, 0 as *c_void, 0,
+                            Some(TaskStart::new(Inline(main))), stack, false)
+    }
+
+    /// Takes what the entry procedure given to `new_with_result` returned.
+    /// This is `None` until the procedure has returned, if it failed, and
+    /// once the result has been taken.
+    ///
+    /// This fails if the result isn't a `T`.
+    pub fn take_result<T: 'static>(&mut self) -> Option<T> {
+        let result = match self.frame.start {
+            Some(ref mut start) => start.result.take(),
+            None => None,
+        };
+        result.map(|result| {
+            match result.move::<T>() {
+                Ok(result) => *result,
+                Err(..) => fail!("task result has the wrong type"),
             }
+        })
+    }
+
+    /// Takes what the entry procedure failed with, or `None` if it hasn't
+    /// failed (or the failure has been taken already).
+    ///
+    /// A context whose procedure fails switches back to whichever context last
+    /// swapped into it, just as if the procedure had returned. The failure is
+    /// caught at the bottom of the context's stack, after everything above it
+    /// has been unwound, and it's no longer the failure of the task which was
+    /// running on the thread. See `fail_with` for failing that task with it
+    /// instead.
+    pub fn take_failure(&mut self) -> Option<~Any> {
+        match self.frame.start {
+            Some(ref mut start) => start.failure.take(),
+            None => None,
+        }
+    }
+
+    /// Has `on_finish` run once this context's entry procedure has returned,
+    /// failed or been forcibly unwound, before the context switches away for
+    /// the last time. It runs on the context's own stack, on whichever thread
+    /// the task finishes on, so it's where per-task resources which belong to
+    /// that thread are released. A later call replaces an earlier one's
+    /// procedure.
+    ///
+    /// `on_finish` must not switch contexts: the task is finished as far as
+    /// its scheduler is concerned, and nothing would resume it. If it fails,
+    /// its failure is kept for `take_failure` unless the entry procedure's
+    /// already is.
+    ///
+    /// This fails for contexts without an entry procedure, such as those from
+    /// `new_raw` and `empty`.
+    pub fn set_on_finish(&mut self, on_finish: proc()) {
+        match self.frame.start {
+            Some(ref mut start) => start.on_finish = Some(on_finish),
+            None => fail!("cannot run a procedure once a context without an \
+                           entry procedure finishes"),
+        }
+    }
+
+    /// Fails the current task with what `take_failure` returned, keeping the
+    /// original message if it was a string.
+    pub fn fail_with(failure: ~Any) -> ! {
+        let failure = match failure.move::<&'static str>() {
+            Ok(msg) => unwind::begin_unwind(*msg, file!(), line!()),
+            Err(failure) => failure,
+        };
+        match failure.move::<~str>() {
+            Ok(msg) => unwind::begin_unwind(*msg, file!(), line!()),
+            Err(failure) => unwind::begin_unwind(failure, file!(), line!()),
+        }
+    }
+
+    /// Reuses this context for a new task which will run `start`, on the same
+    /// stack and without allocating, as a worker pool would for each job.
+    ///
+    /// Everything this context had recorded about the previous task (its
+    /// timings, stack usage, last thread and so on) is reset, as though it had
+    /// just been made by `new` on the stack it was originally created on.
+    ///
+    /// This is unsafe because the previous task must have finished, that is
+    /// made its last switch away, so that nothing it left on the stack will be
+    /// used again. Its frames are overwritten without any of their destructors
+    /// being run.
+    pub unsafe fn rearm(&mut self, start: proc()) {
+        let (lo, hi) = match self.segments.head_opt() {
+            Some(&bounds) => bounds,
+            None => rtabort!("cannot rearm a context without a stack"),
+        };
+        if self.is_running() {
+            rtabort!("rearming context {}, which is still running", self.id);
+        }
+        self.reinit(start, lo, hi);
+    }
+
+    /// Like `rearm`, but moves this context onto a different stack as well,
+    /// so that contexts can be pooled separately from the stacks they run on.
+    /// The registers are zeroed before the new task's frame is set up, and the
+    /// previous task's entry procedure is dropped.
+    ///
+    /// This fails if the stack can't be used for a context, see `try_new`, and
+    /// is unsafe for the same reason as `rearm`. The stack the previous task
+    /// ran on must not be the one that's running this.
+    pub unsafe fn reset(&mut self, start: proc(), stack: &mut StackSegment) {
+        match validate_stack(stack) {
+            Ok(()) => {}
+            Err(e) => fail!("cannot create a context: {}", e),
+        }
+        rtassert!(stack.guard_page().map_or(true, |g| g < stack.start() as uint));
+        if self.is_running() {
+            rtabort!("resetting context {}, which is still running", self.id);
+        }
+
+        let (lo, hi) = (stack.start() as uint, stack.end() as uint);
+        if cfg!(stack_registry) && self.id != 0 {
+            stack_registry::unregister(self.id);
+            stack_registry::register(lo, hi, self.id);
+        }
+        self.frame.regs = new_regs();
+        self.segments.truncate(0);
+        self.segments.push((lo, hi));
+        self.reinit(start, lo, hi);
+    }
+
+    // Whether this context's task is still running: either the last swap
+    // was into it, or the caller is on one of its stack segments, since the
+    // task may have moved onto any of them
+    fn is_running(&self) -> bool {
+        let here = 0u;
+        let sp = &here as *uint as uint;
+        self.running.get() ||
+            self.segments.iter().any(|&(lo, hi)| sp >= lo && sp < hi)
+    }
+
+    // Forgets everything about the tasks this context has run before it goes
+    // back on a `ContextPool`'s free list, so that it's taken out again just
+    // as `new` would have made it. Only the allocations are kept.
+    fn retire(&mut self) {
+        if cfg!(stack_registry) && self.id != 0 {
+            stack_registry::unregister(self.id);
+        }
+        if cfg!(context_registry) && self.id != 0 {
+            context_registry::unregister(self.id);
+        }
+        self.frame.start = None;
+        self.stack_bounds = None;
+        self.segments.truncate(0);
+        self.canary = 0 as *uint;
+        self.stack_guard = None;
+        self.segment_bases = None;
+        self.local = 0 as *mut c_void;
+        self.unwind_on_drop = false;
+        self.overflow_policy = None;
+        self.signal_stack = None;
+        self.prev_signal_stack.set(None);
+        self.span = None;
+        self.name = None;
+        self.native_bounds = None;
+        self.spawn_backtrace.truncate(0);
+    }
+
+    // Whether this context has neither a task to start nor registers saved by
+    // a swap. (`saved_ip` isn't 0 for zeroed registers everywhere, but
+    // `saved_sp` always is.)
+    fn is_uninitialized(&self) -> bool {
+        let regs = &self.frame.regs;
+        self.frame.start.is_none() && (saved_ip(regs) == 0 || saved_sp(regs) == 0)
+    }
+
+    // Brings what the `context_registry` lists for this context up to date,
+    // listing it again if it was taken off when it was pooled
+    fn note_in_registry(&self) {
+        if cfg!(context_registry) && self.id != 0 {
+            context_registry::register(context_registry::Entry {
+                id: self.id,
+                frame: &*self.frame as *Frame as uint,
+                name: self.name.clone(),
+                stack_bounds: self.stack_bounds,
+            });
+        }
+    }
+
+    // The name to use for this context in debug output, which falls back on
+    // its id
+    fn debug_name(&self) -> ~str {
+        match self.name {
+            Some(ref name) => name.clone(),
+            None => format!("context {}", self.id),
+        }
+    }
+
+    // Rebuilds the initial frame of a context which hasn't started yet so that
+    // it begins below `top` instead of the top of its stack
+    fn move_initial_frame(&mut self, top: uint, clean_fp_state: bool) {
+        rtassert!(!self.started.get());
+        let arg: *c_void = match self.frame.start {
+            Some(ref start) => unsafe { transmute(start) },
+            None => rtabort!("only a task's initial frame can be moved"),
+        };
+        let lo = match self.stack_bounds {
+            Some((lo, _)) => lo,
+            None => top,
+        };
+        self.canary = RegisterFile::new(&mut self.frame.regs)
+            .baseline()
+            .clear_fp_state(clean_fp_state)
+            .init_call_frame(task_start_wrapper as *c_void, arg, 0, lo,
+                             top as *mut uint);
+        self.frame.set_canary(self.canary);
+    }
+
+    // Sets this context up to run `start` on its first segment, `[lo, hi)`,
+    // forgetting everything about whatever ran on it before
+    unsafe fn reinit(&mut self, start: proc(), lo: uint, hi: uint) {
+        self.frame.start = Some(TaskStart::new(Plain(start)));
+        let arg: *c_void = match self.frame.start {
+            Some(ref start) => transmute(start),
+            None => unreachable!(),
+        };
+        self.canary = RegisterFile::new(&mut self.frame.regs)
+            .baseline()
+            .clear_fp_state(false)
+            .init_call_frame(task_start_wrapper as *c_void, arg, 0, lo,
+                             hi as *mut uint);
+        self.frame.set_canary(self.canary);
+
+        self.stack_bounds = Some((lo, hi));
+        self.segments.truncate(1);
+        self.timing = SwitchTiming::new();
+        self.started.set(false);
+        self.running.set(false);
+        self.last_thread.set(0);
+        self.min_sp_seen = uint::max_value;
+        self.low_stack_reported = false;
+        self.fp_dirty.set(false);
+        self.fp_saved.set(false);
+        self.last_switch_reason = None;
+        self.asan_fake_stack = 0 as *c_void;
+        self.generation = next_generation();
+        capture_spawn_backtrace(&mut self.spawn_backtrace);
+        self.note_in_registry();
+    }
+
+    /// Create a new context that will call `entry(data)` when it's resumed.
+    ///
+    /// This is intended for FFI, where the task body is a plain C function and
+    /// an opaque pointer rather than a rust procedure. No allocation is made
+    /// for the entry point, and `entry` is jumped to directly.
+    ///
+    /// This is unsafe because nothing about `data` is checked: the caller must
+    /// keep whatever it points at alive until the task has finished with it,
+    /// and is responsible for freeing it. Like any task entry point, `entry`
+    /// must never return; it should switch away for the last time instead.
+    pub unsafe fn new_raw(entry: extern "C" fn(*mut c_void), data: *mut c_void,
+                          stack: &mut StackSegment) -> Context {
+        match validate_stack(stack) {
+            Ok(()) => {}
+            Err(e) => fail!("cannot create a context: {}", e),
+        }
+        Context::with_frame(entry as *c_void, data as *c_void, 0, None, stack,
+                            false)
+    }
+
+    /// Like `new_raw`, but for an entry point which takes `data` as its
+    /// `arg_pos`th argument (counting from 0) instead of its first, such as a
+    /// C function of the form `void entry(void *unused, void *data)`.
+    ///
+    /// Only the first `ARG_POSITIONS` arguments can be used, and the other
+    /// arguments have unspecified values.
+    pub unsafe fn new_raw_with_arg_pos(entry: *c_void, data: *mut c_void,
+                                       arg_pos: uint,
+                                       stack: &mut StackSegment) -> Context {
+        match validate_stack(stack) {
+            Ok(()) => {}
+            Err(e) => fail!("cannot create a context: {}", e),
         }
+        rtassert!(arg_pos < ARG_POSITIONS);
+        Context::with_frame(entry, data as *c_void, arg_pos, None, stack, false)
+    }
 
+    // Builds a context which will call `fptr` with `arg` as argument number
+    // `arg_pos` on the given stack, or with `&start` if there's an entry
+    // procedure to keep in the frame.
+    fn with_frame(fptr: *c_void, arg: *c_void, arg_pos: uint,
+                  start: Option<TaskStart>,
+                  stack: &mut StackSegment, clean_fp_state: bool) -> Context {
         let sp: *uint = stack.end();
         let sp: *mut uint = unsafe { transmute_mut_unsafe(sp) };
-        // Save and then immediately load the current context,
-        // which we will then modify to call the given function when restored
-        let mut regs = new_regs();
-        unsafe {
-            rust_swap_registers(transmute_mut_region(&mut *regs),
-                                transmute_region(&*regs));
+        let mut frame = Frame::new(start);
+        let arg: *c_void = match frame.start {
+            Some(ref start) => unsafe { transmute(start) },
+            None => arg,
         };
 
-        // FIXME #7767: Putting main into a ~ so it's a thin pointer and can
-        // be passed to the spawn function.  Another unfortunate
-        // allocation
-        let start = ~start;
-        initialize_call_frame(&mut *regs,
-                              task_start_wrapper as *c_void,
-                              unsafe { transmute(&*start) },
-                              sp);
+        let canary = RegisterFile::new(&mut frame.regs)
+            .baseline()
+            .clear_fp_state(clean_fp_state)
+            .init_call_frame(fptr, arg, arg_pos, stack.start() as uint, sp);
+        frame.set_canary(canary);
 
         // Scheduler tasks don't have a stack in the "we allocated it" sense,
         // but rather they run on pthreads stacks. We have complete control over
         // them in terms of the code running on them (and hopefully they don't
         // overflow). Additionally, their coroutine stacks are listed as being
         // zero-length, so that's how we detect what's what here.
+        //
+        // A stack's guard page lies below its start, so the recorded bounds
+        // only ever cover the part of it that's usable.
         let stack_base: *uint = stack.start();
+        rtassert!(stack.guard_page().map_or(true, |g| g < stack_base as uint));
         let bounds = if sp as uint == stack_base as uint {
             None
         } else {
             Some((stack_base as uint, sp as uint))
         };
+        let native_tib = match (bounds, stack.reservation()) {
+            (Some((_, hi)), Some((base, committed))) => Some((hi, committed, base)),
+            _ => None,
+        };
+
+        static mut NEXT_ID: AtomicUint = INIT_ATOMIC_UINT;
+        let id = unsafe { NEXT_ID.fetch_add(1, SeqCst) + 1 };
+        if cfg!(stack_registry) {
+            match bounds {
+                Some((lo, hi)) => stack_registry::register(lo, hi, id),
+                None => {}
+            }
+        }
+        if cfg!(context_registry) {
+            context_registry::register(context_registry::Entry {
+                id: id,
+                frame: &*frame as *Frame as uint,
+                name: None,
+                stack_bounds: bounds,
+            });
+        }
+        let mut spawn_backtrace = ~[];
+        capture_spawn_backtrace(&mut spawn_backtrace);
         return Context {
-            start: Some(start),
-            regs: regs,
+            frame: frame,
             stack_bounds: bounds,
+            segments: match bounds { Some(b) => ~[b], None => ~[] },
+            canary: canary,
+            stack_guard: None,
+            segment_bases: None,
+            local: 0 as *mut c_void,
+            timing: SwitchTiming::new(),
+            started: Cell::new(false),
+            unwind_on_drop: false,
+            last_thread: Cell::new(0),
+            overflow_policy: None,
+            min_sp_seen: uint::max_value,
+            low_stack_reported: false,
+            signal_stack: None,
+            prev_signal_stack: Cell::new(None),
+            id: id,
+            generation: next_generation(),
+            running: Cell::new(false),
+            span: None,
+            fp_dirty: Cell::new(false),
+            fp_saved: Cell::new(false),
+            last_switch_reason: None,
+            asan_fake_stack: 0 as *c_void,
+            asan_stack: Cell::new((0, 0)),
+            asan_from: Cell::new(0 as *Context),
+            native_tib: Cell::new(native_tib),
+            bounds_slots: Cell::new(None),
+            native_bounds: None,
+            name: None,
+            spawn_backtrace: spawn_backtrace,
         }
     }
 
-    /* Switch contexts
-
-    Suspend the current execution context and resume another by
-    saving the registers values of the executing thread to a Context
-    then loading the registers from a previously saved Context.
-    */
-    pub fn swap(out_context: &mut Context, in_context: &Context) {
-        rtdebug!("swapping contexts");
-        let out_regs: &mut Registers = match out_context {
-            &Context { regs: ~ref mut r, .. } => r
-        };
-        let in_regs: &Registers = match in_context {
-            &Context { regs: ~ref r, .. } => r
-        };
-
-        rtdebug!("noting the stack limit and doing raw swap");
+    /// Create a new context which only uses the top `size` bytes of `stack`,
+    /// returning it along with a segment covering the rest of `stack`.
+    ///
+    /// This lets a scheduler pack several small tasks into one large stack:
+    /// the returned tail is an ordinary `StackSegment` which can be handed to
+    /// `new_in` (or `new`) again. The usual red zone is reserved at the bottom
+    /// of the region handed to this context, so an overflowing task fails
+    /// before it reaches the tail.
+    ///
+    /// This is unsafe for the same reason as `StackSegment::split_top`: the
+    /// original `stack` must outlive both the context and the tail.
+    pub unsafe fn new_in(start: proc(), stack: &mut StackSegment,
+                         size: uint) -> (Context, StackSegment) {
+        let (mut head, tail) = stack.split_top(size);
+        (Context::new(start, &mut head), tail)
+    }
 
-        unsafe {
-            // Right before we switch to the new context, set the new context's
-            // stack limit in the OS-specified TLS slot. This also  means that
-            // we cannot call any more rust functions after record_stack_bounds
-            // returns because they would all likely fail due to the limit being
-            // invalid for the current task. Lucky for us `rust_swap_registers`
-            // is a C function so we don't have to worry about that!
-            match in_context.stack_bounds {
-                Some((lo, hi)) => stack::record_stack_bounds(lo, hi),
-                // If we're going back to one of the original contexts or
-                // something that's possibly not a "normal task", then reset
-                // the stack limit to 0 to make morestack never fail
-                None => stack::record_stack_bounds(0, uint::max_value),
+    /// Create a new context on one of `arena`'s free stacks, returning it
+    /// along with the stack, which should be given back to the arena once the
+    /// context is gone. Returns `None` if every stack is in use.
+    ///
+    /// The stack is already protected by the arena's guard pages, so unlike
+    /// stacks which set up guard pages of their own this doesn't make any
+    /// system calls.
+    ///
+    /// This is unsafe for the same reason as `StackArena::take`: the arena
+    /// must outlive both the context and the stack.
+    pub unsafe fn new_in_arena(start: proc(), arena: &mut StackArena)
+                               -> Option<(Context, StackSegment)> {
+        match arena.take() {
+            Some(mut stack) => {
+                let ctx = Context::new(start, &mut stack);
+                Some((ctx, stack))
             }
-            rust_swap_registers(out_regs, in_regs)
+            None => None,
         }
     }
-}
 
-#[link(name = "rustrt", kind = "static")]
-extern {
-    fn rust_swap_registers(out_regs: *mut Registers, in_regs: *Registers);
-}
+    /// Registers another stack segment that this context may run on (for
+    /// example an overflow segment), returning the index of the segment.
+    ///
+    /// The segment does not become active until `set_active_segment` is
+    /// called with the returned index.
+    pub fn push_stack_segment(&mut self, lo: uint, hi: uint) -> uint {
+        self.segments.push((lo, hi));
+        self.segments.len() - 1
+    }
 
-// Register contexts used in various architectures
-//
-// These structures all represent a context of one task throughout its
-// execution. Each struct is a representation of the architecture's register
-// set. When swapping between tasks, these register sets are used to save off
-// the current registers into one struct, and load them all from another.
-//
-// Note that this is only used for context switching, which means that some of
-// the registers may go unused. For example, for architectures with
-// callee/caller saved registers, the context will only reflect the callee-saved
-// registers. This is because the caller saved registers are already stored
-// elsewhere on the stack (if it was necessary anyway).
-//
-// Additionally, there may be fields on various architectures which are unused
-// entirely because they only reflect what is theoretically possible for a
-// "complete register set" to show, but user-space cannot alter these registers.
-// An example of this would be the segment selectors for x86.
-//
-// These structures/functions are roughly in-sync with the source files inside
-// of src/rt/arch/$arch. The only currently used function from those folders is
-// the `rust_swap_registers` function, but that's only because for now segmented
-// stacks are disabled.
+    /// Marks the segment at `idx` as the one this context is running on, so
+    /// the next swap into this context records that segment's bounds.
+    ///
+    /// Note that this only affects future swaps. A task which moves onto a
+    /// different segment while it is running must also record the new bounds
+    /// itself before calling any more rust functions.
+    pub fn set_active_segment(&mut self, idx: uint) {
+        rtassert!(idx < self.segments.len());
+        self.stack_bounds = Some(self.segments[idx]);
+        self.note_in_registry();
+    }
 
-#[cfg(target_arch = "x86")]
-struct Registers {
-    eax: u32, ebx: u32, ecx: u32, edx: u32,
-    ebp: u32, esi: u32, edi: u32, esp: u32,
-    cs: u16, ds: u16, ss: u16, es: u16, fs: u16, gs: u16,
-    eflags: u32, eip: u32
-}
+    /// Gives this context its own stack protector cookie, which is installed
+    /// into the thread's TLS whenever the context is swapped in.
+    ///
+    /// This only has an effect when libgreen is built with
+    /// `--cfg stack_protector` on a platform where the cookie's location is
+    /// known (currently x86 and x86_64 linux). Otherwise swaps never touch the
+    /// cookie and this is a no-op.
+    pub fn set_stack_guard(&mut self, cookie: uint) {
+        self.stack_guard = Some(cookie);
+    }
 
-#[cfg(target_arch = "x86")]
-fn new_regs() -> ~Registers {
-    ~Registers {
-        eax: 0, ebx: 0, ecx: 0, edx: 0,
-        ebp: 0, esi: 0, edi: 0, esp: 0,
-        cs: 0, ds: 0, ss: 0, es: 0, fs: 0, gs: 0,
-        eflags: 0, eip: 0
+    /// Gives this context its own FS and GS base addresses, which are
+    /// installed whenever the context is swapped in. This is for embedders
+    /// which relocate the TLS block per task: normally every context on a
+    /// thread shares the thread's bases, and swaps never touch them.
+    ///
+    /// The bases are only swapped when libgreen is built with
+    /// `--cfg segment_bases` on x86_64 linux, and otherwise this is a no-op.
+    /// They're read and written with the `arch_prctl` system call, or with
+    /// `rdfsbase` and friends when also built with `--cfg fsgsbase` (which
+    /// needs a CPU and kernel which allow them in user mode). The bases that
+    /// a context was swapped out with are kept and reinstated when it's
+    /// resumed, as with `set_stack_guard`.
+    ///
+    /// The new FS base must point at a TLS block laid out like the thread's
+    /// own, since the stack limit, the stack protector cookie and every
+    /// thread local (including the runtime's) are found through it.
+    pub fn set_segment_bases(&mut self, fs: uint, gs: uint) {
+        self.segment_bases = Some((fs, gs));
     }
-}
 
-#[cfg(target_arch = "x86")]
-fn initialize_call_frame(regs: &mut Registers, fptr: *c_void, arg: *c_void,
-                         sp: *mut uint) {
+    /// Gives this context a pointer to task-local data, which the task can
+    /// read back with `current_task_local` while it's running. Unlike thread
+    /// locals this follows the task from one thread to another, since every
+    /// swap into the context publishes it for the thread it's resumed on.
+    ///
+    /// libgreen never dereferences the pointer or frees what it points to.
+    pub fn set_local(&mut self, local: *mut c_void) {
+        self.local = local;
+    }
 
-    let sp = align_down(sp);
-    let sp = mut_offset(sp, -4);
+    /// The pointer given to `set_local`, null if there wasn't one
+    pub fn local(&self) -> *mut c_void {
+        self.local
+    }
 
-    unsafe { *sp = arg as uint };
-    let sp = mut_offset(sp, -1);
-    unsafe { *sp = 0 }; // The final return address
+    /// Makes dropping this context while its task is suspended unwind the
+    /// task's stack first, so that the destructors of everything live on it
+    /// are run. Otherwise the stack is simply freed along with whatever was
+    /// on it.
+    ///
+    /// The unwind is performed by resuming the task as though the swap it is
+    /// suspended in had failed, and catching the failure at the bottom of the
+    /// task's stack before switching back to the code doing the drop. This
+    /// doesn't always work, and the drop silently falls back to leaking when:
+    ///
+    /// * the task has never run, or the context wasn't made by `new` (such as
+    ///   a raw or restored context), as there is nothing to catch the unwind;
+    /// * the architecture isn't x86 or x86_64, where a failing call can't yet
+    ///   be faked on the suspended stack.
+    ///
+    /// It's also up to the caller to avoid the cases where it's unsafe:
+    ///
+    /// * the drop must happen on the thread the task last ran on, as the
+    ///   destructors see that thread's local data;
+    /// * the task must not catch failure itself (a libgreen task does, in
+    ///   `Task::run`): the unwind stops there and the task carries on running
+    ///   its own exit path rather than coming back;
+    /// * destructors on the task's stack must not switch contexts or fail;
+    /// * a task which finished by switching away for the last time is still
+    ///   considered suspended, so the mode should be turned off again before
+    ///   that final switch.
+    pub fn set_unwind_on_drop(&mut self, enabled: bool) {
+        self.unwind_on_drop = enabled;
+    }
 
-    regs.esp = sp as u32;
-    regs.eip = fptr as u32;
+    /// Unwinds this context's suspended task straight away, running the
+    /// destructors of everything live on its stack, and returns once the
+    /// unwind has reached the bottom of the stack. This is the unwind that
+    /// `set_unwind_on_drop` makes dropping the context do, for cancelling a
+    /// task without having to drop its context as well.
+    ///
+    /// Returns whether the task was unwound. It's left alone in the cases
+    /// where `set_unwind_on_drop` falls back to leaking, and the caller must
+    /// avoid the ones it lists as unsafe. Once it's been unwound the task has
+    /// finished: its entry procedure is dropped, and the context has nothing
+    /// left to resume.
+    ///
+    /// This fails if the context isn't suspended, that is if it's running or
+    /// its task finished by switching away from some other context.
+    pub fn force_unwind(&mut self) -> bool {
+        if self.running.get() {
+            fail!("attempted to unwind context {}, which isn't suspended",
+                  self.id);
+        }
+        self.unwind()
+    }
 
-    // Last base pointer on the stack is 0
-    regs.ebp = 0;
-}
+    // Resumes the task in `unwind_trampoline`, as described for
+    // `set_unwind_on_drop`, if it can be
+    fn unwind(&mut self) -> bool {
+        if !self.started.get() || self.frame.start.is_none() { return false }
+        if !redirect_call(&mut self.frame.regs, unwind_trampoline as *c_void) {
+            return false
+        }
+        let mut here = Context::empty();
+        match self.frame.start {
+            Some(ref mut start) => start.unwind_to = &here as *Context,
+            None => unreachable!(),
+        }
 
-// windows requires saving more registers (both general and XMM), so the windows
-// register context must be larger.
-#[cfg(windows, target_arch = "x86_64")]
-type Registers = [uint, ..34];
-#[cfg(not(windows), target_arch = "x86_64")]
-type Registers = [uint, ..22];
+        // Just like `run_on_stack`, coming back to `here` leaves no stack
+        // limit behind, so it's put back by hand.
+        let limit = unsafe { stack::get_sp_limit() };
+        Context::swap(&mut here, &*self);
+        unsafe { stack::record_sp_limit(limit) };
 
-#[cfg(windows, target_arch = "x86_64")]
-fn new_regs() -> ~Registers { ~([0, .. 34]) }
-#[cfg(not(windows), target_arch = "x86_64")]
-fn new_regs() -> ~Registers { ~([0, .. 22]) }
+        // The task switched away for the last time from a context of its own,
+        // so this one is left looking like it's still running
+        self.frame.start = None;
+        self.frame.regs = new_regs();
+        self.running.set(false);
+        true
+    }
 
-#[cfg(target_arch = "x86_64")]
-fn initialize_call_frame(regs: &mut Registers, fptr: *c_void, arg: *c_void,
-                         sp: *mut uint) {
+    /// The deepest this context's stack has been seen to reach, in bytes from
+    /// the top of its active segment.
+    ///
+    /// This is sampled every time the context is swapped out, so it's a cheap
+    /// lower bound on the stack's real peak usage: anything deeper which
+    /// returned before the next switch is missed. Sampling only happens when
+    /// libgreen is built with `--cfg stack_sampling`. Without that, or for a
+    /// context without stack bounds, this is 0.
+    pub fn observed_peak_depth(&self) -> uint {
+        match self.stack_bounds {
+            Some((_, hi)) if self.min_sp_seen < hi => hi - self.min_sp_seen,
+            _ => 0,
+        }
+    }
 
-    // Redefinitions from rt/arch/x86_64/regs.h
-    static RUSTRT_ARG0: uint = 3;
-    static RUSTRT_RSP: uint = 1;
-    static RUSTRT_IP: uint = 8;
-    static RUSTRT_RBP: uint = 2;
+    /// Gives this context its own alternate signal stack of `size` bytes.
+    ///
+    /// By default, every task on a thread shares the thread's alternate
+    /// signal stack. A context with its own has it installed with
+    /// `sigaltstack` whenever it's swapped in, and puts the previous one back
+    /// when it's swapped out. This only has an effect when libgreen is built
+    /// with `--cfg task_sigaltstack`, and never on windows.
+    ///
+    /// Note that the previous stack is only put back when the context itself
+    /// is swapped out. A task which finishes by switching away on a throwaway
+    /// context (like `Context::empty()`) leaves its stack installed.
+    ///
+    /// This fails if `size` is less than the OS's MINSIGSTKSZ
+    /// (`MIN_SIGNAL_STACK`), which `sigaltstack` would refuse.
+    /// If `sigaltstack` refuses the stack anyway when the context is swapped
+    /// in, that's reported and the task runs with the thread's stack instead.
+    pub fn set_signal_stack(&mut self, size: uint) {
+        if size < MIN_SIGNAL_STACK {
+            fail!("a signal stack of {} bytes is too small, it must be at \
+                   least {}", size, MIN_SIGNAL_STACK);
+        }
+        self.signal_stack = Some(SignalStack::new(size));
+    }
 
-    let sp = align_down(sp);
-    let sp = mut_offset(sp, -1);
+    /// A number identifying this context, unique within the process, which is
+    /// what `stack_registry` lookups return. Contexts from `empty` all have
+    /// the id 0.
+    pub fn id(&self) -> uint {
+        self.id
+    }
 
-    // The final return address. 0 indicates the bottom of the stack
-    unsafe { *sp = 0; }
+    /// A number which changes whenever this context is set up to run a new
+    /// task, by `rearm` and `reset` (and so when it's reused by a
+    /// `ContextPool`). Generations only ever increase, and no two tasks in the
+    /// process are given the same one. Contexts from `empty` are generation 0.
+    ///
+    /// A scheduler which refers to parked contexts indirectly, such as by an
+    /// index into a table, can keep the generation alongside (see
+    /// `ContextHandle`) and resume through `swap_checked`, so that a stale
+    /// reference to a context which has since been reused fails cleanly
+    /// instead of resuming the wrong task.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
 
-    rtdebug!("creating call frame");
-    rtdebug!("fptr {}", fptr);
-    rtdebug!("arg {}", arg);
-    rtdebug!("sp {}", sp);
+    /// Names this context in libgreen's debug output (with `--cfg rtdebug`),
+    /// or removes its name. Contexts have no name to begin with, and keep
+    /// theirs when they're reused by `rearm` or `reset`.
+    pub fn set_name(&mut self, name: Option<~str>) {
+        self.name = name;
+        self.note_in_registry();
+    }
 
-    regs[RUSTRT_ARG0] = arg as uint;
-    regs[RUSTRT_RSP] = sp as uint;
-    regs[RUSTRT_IP] = fptr as uint;
+    /// This context's name, see `set_name`
+    pub fn name<'a>(&'a self) -> Option<&'a str> {
+        self.name.as_ref().map(|name| name.as_slice())
+    }
 
-    // Last base pointer on the stack should be 0
-    regs[RUSTRT_RBP] = 0;
-}
+    /// The return addresses of the code which created this context's task, as
+    /// many as `set_spawn_backtraces` asked for, innermost first. The first
+    /// few are within libgreen itself, in whichever constructor (or `rearm`
+    /// or `reset`) set the task up.
+    ///
+    /// A higher layer can resolve these and append them to a backtrace taken
+    /// within the task, which otherwise ends at the bottom of the task's
+    /// stack. This is empty if capturing was off when the task was created,
+    /// for contexts from `empty`, and where capturing isn't supported (only
+    /// linux and macos have it).
+    pub fn spawn_backtrace<'a>(&'a self) -> &'a [*c_void] {
+        self.spawn_backtrace.as_slice()
+    }
 
-#[cfg(target_arch = "arm")]
-type Registers = [uint, ..32];
+    /// The bounds of the stack segment this context is running on, or `None`
+    /// for contexts (like schedulers) which run on the thread's own stack
+    pub fn stack_bounds(&self) -> Option<(uint, uint)> {
+        self.stack_bounds
+    }
 
-#[cfg(target_arch = "arm")]
-fn new_regs() -> ~Registers { ~([0, .. 32]) }
+    /// Whether this is a scheduler's context, running on its thread's own
+    /// stack rather than a stack of its own, which is exactly when
+    /// `stack_bounds` is `None`. Contexts from `empty` are, as are those which
+    /// a `ContextPool` has taken back.
+    #[inline]
+    pub fn is_scheduler(&self) -> bool {
+        self.stack_bounds.is_none()
+    }
 
-#[cfg(target_arch = "arm")]
-fn initialize_call_frame(regs: &mut Registers, fptr: *c_void, arg: *c_void,
-                         sp: *mut uint) {
-    let sp = align_down(sp);
-    // sp of arm eabi is 8-byte aligned
-    let sp = mut_offset(sp, -2);
+    /// Gives a context without stack bounds of its own (normally a scheduler
+    /// on its thread's stack) the bounds of the stack it really runs on, as
+    /// found by `thread_stack_bounds`. Swapping into it then records these for
+    /// morestack, instead of removing the stack limit altogether, so an
+    /// overflow of the scheduler's stack is caught like a task's would be.
+    ///
+    /// This is unsafe because morestack reports an overflow as soon as the
+    /// context runs within `stack::RED_ZONE` of `lo`, whether or not that's
+    /// really anywhere near the end of its stack.
+    pub unsafe fn set_native_bounds(&mut self, lo: uint, hi: uint) {
+        rtassert!(self.is_scheduler());
+        rtassert!(lo < hi);
+        self.native_bounds = Some((lo, hi));
+    }
 
-    // The final return address. 0 indicates the bottom of the stack
-    unsafe { *sp = 0; }
+    /// Takes back the bounds given by `set_native_bounds`, so that swapping
+    /// into this context removes the stack limit again.
+    pub fn clear_native_bounds(&mut self) {
+        self.native_bounds = None;
+    }
 
-    regs[0] = arg as uint;   // r0
-    regs[13] = sp as uint;   // #53 sp, r13
-    regs[14] = fptr as uint; // #60 pc, r15 --> lr
+    /// Checks that the canary written above this context's initial frame is
+    /// intact, so a scheduler can catch a task which has written past either
+    /// end of its own frames when it yields or finishes. The canary sits at
+    /// the top of the stack, where nothing which stayed within the stack (or
+    /// within its own frames) ever writes, so finding it overwritten means the
+    /// task most likely overflowed a stack below this one or underflowed its
+    /// own.
+    ///
+    /// Every swap checks the canary of the context it's about to resume, and
+    /// carries out its overflow policy if it's been overwritten.
+    ///
+    /// Contexts without a stack of their own, such as schedulers, have no
+    /// canary and always pass.
+    pub fn check_canary(&self) -> Result<(), ContextError> {
+        if self.canary.is_null() { return Ok(()) }
+        if unsafe { *self.canary } == STACK_CANARY {
+            Ok(())
+        } else {
+            Err(CanaryOverwritten)
+        }
+    }
+
+    /// Where this context's initial frame starts, the highest address its
+    /// task's frames can reach, or `None` for contexts without a stack of
+    /// their own.
+    ///
+    /// The same address is kept on the stack itself, in the word directly
+    /// below `STACK_CANARY`, so that tools without access to the `Context`
+    /// (such as a core dump analyzer) can find where each task's stack begins
+    /// by looking for the canary followed by a pointer to just below itself.
+    pub fn initial_sp(&self) -> Option<uint> {
+        if self.canary.is_null() { return None }
+        Some(self.canary as uint + mem::size_of::<uint>() - CANARY_BYTES)
+    }
+
+    /// Overrides the global `set_overflow_policy` for this context.
+    ///
+    /// Running out of stack under morestack only follows the policy once
+    /// `handle_stack_exhaustion` has been called, which a `SchedPool` does.
+    pub fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+        self.overflow_policy = Some(policy);
+    }
+
+    /// Associates this context with a tracing span, which is what gets handed
+    /// to the `set_span_hook` hook whenever it's swapped in or out.
+    pub fn set_span(&mut self, span: Option<SpanToken>) {
+        self.span = span;
+    }
+
+    /// Why this context was last swapped out, if it was swapped out with
+    /// `swap_with_reason`. A plain `swap` clears it.
+    pub fn last_switch_reason(&self) -> Option<SwitchReason> {
+        self.last_switch_reason
+    }
+
+    /// The tracing span last given to `set_span`, if any
+    pub fn span(&self) -> Option<SpanToken> {
+        self.span
+    }
+
+    /// Notes that this context has used the floating point registers, so
+    /// they must be saved the next time that it's swapped out.
+    ///
+    /// This only matters when libgreen is built with `--cfg lazy_fp`. A swap
+    /// then only saves and restores floating point state (which only x86_64
+    /// has, see `swap_gp_only`) if the outgoing context is dirty or the
+    /// incoming one has state saved from an earlier swap. Otherwise the
+    /// floating point registers are left as they are, like `swap_gp_only`.
+    ///
+    /// There's no trap on first use to set this automatically, so a task must
+    /// call it itself after using floating point and before it next swaps
+    /// out, or whatever values it had live in those registers may be lost.
+    /// That includes floating point code the compiler generated on its behalf.
+    /// New contexts start out clean. Contexts from `empty` start out dirty,
+    /// since nothing is known about the code they stand for.
+    pub fn mark_fp_dirty(&self) {
+        self.fp_dirty.set(true);
+    }
+
+    /// Forgets that this context has used the floating point registers, and
+    /// any state that was saved for it, so that they're left alone on its
+    /// swaps until it's marked dirty again. See `mark_fp_dirty`.
+    ///
+    /// This is unsafe because the context's code must really have no live
+    /// floating point values, now and until it's marked dirty.
+    pub unsafe fn mark_fp_clean(&self) {
+        self.fp_dirty.set(false);
+        self.fp_saved.set(false);
+    }
+
+    /// Releases the memory backing the part of this context's stack which
+    /// lies below its saved stack pointer.
+    ///
+    /// A task which briefly recursed very deeply leaves all of those pages
+    /// resident even after it has returned. Calling this while the task is
+    /// suspended gives every whole page between the bottom of the stack and
+    /// the page containing the saved stack pointer back to the OS (the
+    /// addresses stay mapped, and are faulted back in as zeroes if the task
+    /// grows down into them again). Nothing is touched at or above the saved
+    /// stack pointer, or in the red zone below it where the architecture has one.
+    ///
+    /// This does nothing for scheduler contexts. It fails if the context is
+    /// running, or if the calling code is on this context's stack, since the
+    /// pages in use there are below the saved stack pointer.
+    pub fn trim_stack(&mut self) {
+        let lo = match self.stack_bounds {
+            Some((lo, _)) => lo,
+            None => return,
+        };
+        if self.is_running() {
+            fail!("cannot trim the stack of context {}, which is running",
+                  self.id);
+        }
+        let page = os::page_size();
+        let bottom = (lo + page - 1) & !(page - 1);
+        let saved = saved_sp(&self.frame.regs);
+        let red_zone = red_zone_size();
+        if saved < lo + red_zone { return }
+        let top = (saved - red_zone) & !(page - 1);
+        if top > bottom {
+            unsafe { ::stack::release_pages(bottom, top) }
+        }
+    }
+
+    /// The total time, in nanoseconds, that this context has spent swapped
+    /// out. Always 0 unless libgreen is built with `--cfg context_timing`.
+    pub fn suspended_time(&self) -> u64 {
+        self.timing.suspended.get()
+    }
+
+    /// The total time, in nanoseconds, that this context has spent running up
+    /// until it was last swapped out. Always 0 unless libgreen is built with
+    /// `--cfg context_timing`.
+    pub fn running_time(&self) -> u64 {
+        self.timing.running.get()
+    }
+
+    /// How many times per second this context has been swapped in, averaged
+    /// over roughly the last second.
+    ///
+    /// A scheduler can use this to notice a task which yields in a tight loop
+    /// and deprioritize it, how is up to the scheduler. Always 0 unless
+    /// libgreen is built with `--cfg context_timing`.
+    pub fn recent_switch_rate(&self) -> f64 {
+        if !cfg!(context_timing) { return 0.0 }
+        self.timing.rate(time::precise_time_ns())
+    }
+
+    /// The OS thread which this context was last swapped in on, if any.
+    ///
+    /// Work-stealing schedulers can use this to notice a task migrating
+    /// between threads. This is only recorded when libgreen is built with
+    /// `--cfg context_thread_tracking`, otherwise it is always `None`.
+    pub fn last_thread(&self) -> Option<ThreadId> {
+        match self.last_thread.get() {
+            0 => None,
+            id => Some(id),
+        }
+    }
+
+    /// Where this context will resume, for crash reports which record every
+    /// suspended context. Unlike `registers_snapshot` this is only a few
+    /// values with the same meaning on every architecture.
+    ///
+    /// These are the registers that were saved when this context last
+    /// switched away, so they're stale while it's running. A context that
+    /// hasn't started yet resumes at its entry point.
+    pub fn register_snapshot(&self) -> SavedRegisters {
+        SavedRegisters {
+            ip: saved_ip(&self.frame.regs),
+            sp: saved_sp(&self.frame.regs),
+            fp: saved_fp(&self.frame.regs),
+        }
+    }
+
+    /// The address this context will resume at when it's next swapped in.
+    /// Like `register_snapshot` it's stale while the context is running, and
+    /// is the entry point of a context that hasn't started yet.
+    pub fn instruction_pointer(&self) -> *c_void {
+        saved_ip(&self.frame.regs) as *c_void
+    }
+
+    /// Makes this context resume at `ip` instead, as though the swap that
+    /// suspended it had returned there. Its stack and callee-saved registers
+    /// are left as they were. On arm, the low bit of `ip` selects Thumb mode.
+    ///
+    /// This fails on architectures where the resume address can't be changed
+    /// (the ucontext fallback), and on a context that's running.
+    ///
+    /// This is unsafe because nothing checks that the code at `ip` can carry
+    /// on from the suspended state; it's meant for jumping within the
+    /// function the context was suspended in, or to a trampoline that never
+    /// returns.
+    pub unsafe fn set_instruction_pointer(&mut self, ip: *c_void) {
+        if self.running.get() {
+            fail!("attempted to move the instruction pointer of a running context");
+        }
+        if !set_saved_ip(&mut self.frame.regs, ip as uint) {
+            fail!("the instruction pointer can't be changed on this architecture");
+        }
+    }
+
+    /// Serializes this context's saved registers into a stable byte layout,
+    /// for checkpointing a suspended task. See `from_registers_snapshot`.
+    ///
+    /// The layout is a 16 byte header (the magic bytes `GRSN`, then the
+    /// layout version, an architecture tag, and the number of registers, each
+    /// as a little-endian u32) followed by every register as a little-endian
+    /// u64. Only registers are included; the stack must be saved separately
+    /// by the caller.
+    pub fn registers_snapshot(&self) -> ~[u8] {
+        let words = register_words();
+        let mut out = vec::with_capacity(16 + words * 8);
+        out.push_all(SNAPSHOT_MAGIC);
+        push_le(&mut out, SNAPSHOT_VERSION as u64, 4);
+        push_le(&mut out, SNAPSHOT_ARCH as u64, 4);
+        push_le(&mut out, words as u64, 4);
+        unsafe {
+            let regs: *uint = transmute(&self.frame.regs);
+            vec::raw::buf_as_slice(regs, words, |regs| {
+                for &r in regs.iter() {
+                    push_le(&mut out, r as u64, 8);
+                }
+            });
+        }
+        out
+    }
+
+    /// Rebuilds a suspended context from a snapshot made by
+    /// `registers_snapshot`, possibly in another process.
+    ///
+    /// Snapshots from a different architecture, register layout or snapshot
+    /// version are refused. The context has no entry procedure, and its stack
+    /// bounds are the ones given, which should describe where the embedder
+    /// has restored the task's stack.
+    ///
+    /// This is unsafe because resuming the context jumps to whatever the
+    /// snapshot says; the task's stack must be restored at exactly the
+    /// addresses it was saved from.
+    pub unsafe fn from_registers_snapshot(snapshot: &[u8],
+                                          stack_bounds: Option<(uint, uint)>)
+        -> Result<Context, SnapshotError>
+    {
+        let words = register_words();
+        if snapshot.len() < 16 || snapshot.slice_to(4) != SNAPSHOT_MAGIC {
+            return Err(InvalidSnapshot)
+        }
+        if read_le(snapshot.slice(4, 8)) != SNAPSHOT_VERSION as u64 {
+            return Err(UnsupportedVersion)
+        }
+        if read_le(snapshot.slice(8, 12)) != SNAPSHOT_ARCH as u64 ||
+           read_le(snapshot.slice(12, 16)) != words as u64 {
+            return Err(WrongArchitecture)
+        }
+        if snapshot.len() != 16 + words * 8 {
+            return Err(InvalidSnapshot)
+        }
+
+        let mut ctx = Context::empty();
+        ctx.running.set(false);
+        ctx.stack_bounds = stack_bounds;
+        ctx.segments = match stack_bounds { Some(b) => ~[b], None => ~[] };
+        let regs: *mut uint = transmute(&mut ctx.frame.regs);
+        vec::raw::mut_buf_as_slice(regs, words, |regs| {
+            for (i, r) in regs.mut_iter().enumerate() {
+                let at = 16 + i * 8;
+                *r = read_le(snapshot.slice(at, at + 8)) as uint;
+            }
+        });
+        Ok(ctx)
+    }
+
+    /* Switch contexts
+
+    Suspend the current execution context and resume another by
+    saving the registers values of the executing thread to a Context
+    then loading the registers from a previously saved Context.
+
+    With `--cfg asan` every swap is announced to AddressSanitizer as a
+    fiber switch, so that it knows which stack is in use. A context swapped
+    out with `Finished` is taken to never be resumed, and its fake stack is
+    thrown away.
+
+    Stacks are always registered with valgrind when they're allocated.
+    Building with `--cfg valgrind` additionally registers the views made
+    by `StackSegment::split_top` and friends, and marks the unused part
+    of a new context's stack as undefined when it first runs.
+    */
+    pub fn swap(out_context: &mut Context, in_context: &Context) {
+        Context::swap_impl(out_context, in_context, false, None)
+    }
+
+    /// Same as `swap`, but records why the outgoing context is being swapped
+    /// out, for `last_switch_reason`.
+    pub fn swap_with_reason(out_context: &mut Context, in_context: &Context,
+                            reason: SwitchReason) {
+        Context::swap_impl(out_context, in_context, false, Some(reason))
+    }
+
+    /// Same as `swap`, but first checks that `in_context` is still running the
+    /// task of the given `generation`, and fails if it isn't. Nothing is
+    /// touched before the check.
+    pub fn swap_checked(out_context: &mut Context, in_context: &Context,
+                        generation: u64) {
+        if in_context.generation != generation {
+            fail!("resuming {} for generation {}, but it's at generation {}",
+                  in_context.debug_name(), generation, in_context.generation);
+        }
+        Context::swap_impl(out_context, in_context, false, None)
+    }
+
+    /// Switch contexts, preserving only the general purpose registers.
+    ///
+    /// This is a faster variant of `swap` for hot scheduling loops. On
+    /// architectures where `swap` also saves floating point/SIMD registers
+    /// (currently x86_64) they are left untouched here, so whatever values
+    /// the outgoing context had in them are what the incoming context sees,
+    /// and vice versa when switching back.
+    ///
+    /// This is unsafe because the caller is responsible for proving that
+    /// neither side has live floating point state at the switch. If in doubt,
+    /// use `swap`.
+    pub unsafe fn swap_gp_only(out_context: &mut Context, in_context: &Context) {
+        Context::swap_impl(out_context, in_context, true, None)
+    }
+
+    /// Same as `swap`, for callers which hold their contexts behind raw
+    /// pointers.
+    ///
+    /// This is unsafe because both pointers must be valid for the whole swap,
+    /// and because it skips the checks which `swap` makes without
+    /// `--cfg ndebug`: that the contexts aren't one and the same, and that
+    /// `in_context` isn't a context from `empty` which has never been swapped
+    /// out of. Swapping into either jumps to garbage.
+    pub unsafe fn swap_raw(out_context: *mut Context, in_context: *Context) {
+        Context::swap_unchecked(&mut *out_context, &*in_context, false, None)
+    }
+
+    fn swap_impl(out_context: &mut Context, in_context: &Context,
+                 gp_only: bool, reason: Option<SwitchReason>) {
+        if cfg!(not(ndebug)) {
+            // Swapping a context into itself (which only unsafe code can ask
+            // for) would save the registers right over the ones being loaded.
+            let out_regs: *Registers = &out_context.frame.regs;
+            let in_regs: *Registers = &in_context.frame.regs;
+            if out_regs == in_regs {
+                rtabort!("swapping context {} into itself", in_context.id);
+            }
+            // A context from `empty` which nothing has ever been swapped out
+            // of has registers of all zeroes, and swapping into it would jump
+            // to address 0. It's fine as the context being swapped out, which
+            // is what it's for.
+            if in_context.is_uninitialized() {
+                fail!("attempted to swap into an uninitialized context");
+            }
+        }
+        Context::swap_unchecked(out_context, in_context, gp_only, reason)
+    }
+
+    fn swap_unchecked(out_context: &mut Context, in_context: &Context,
+                      gp_only: bool, reason: Option<SwitchReason>) {
+        if intercept_swap(&*out_context, in_context) { return }
+        rtdebug!("swapping from {} to {}", out_context.debug_name(),
+                 in_context.debug_name());
+        count_swap(in_context);
+        begin_swap();
+
+        // A context whose canary is gone was overflowed into from below, or
+        // underflowed itself (see `check_canary`). Nothing has been changed
+        // yet, so with a `Fail` policy the swapping task fails cleanly.
+        if in_context.check_canary().is_err() {
+            overflowed(in_context.overflow_policy,
+                       format!("resuming {}, the stack canary of which was \
+                                overwritten (stack {:?})",
+                               in_context.debug_name(), in_context.stack_bounds));
+        }
+        out_context.last_switch_reason = reason;
+
+        // Resuming a context which is already running would have two threads
+        // (or the same one, twice) on one stack. That's always a scheduler
+        // bug, and a cheap one to catch here rather than as stack corruption.
+        if in_context.running.get() {
+            rtabort!("resuming context {} (stack {:?}), which is already \
+                      running", in_context.id, in_context.stack_bounds);
+        }
+        out_context.running.set(false);
+        in_context.running.set(true);
+        match in_context.frame.start {
+            Some(ref start) => start.resumer.set(&*out_context as *Context),
+            None => {}
+        }
+        if cfg!(context_timing) {
+            let now = time::precise_time_ns();
+            out_context.timing.suspend(now);
+            in_context.timing.resume(now);
+        }
+        unsafe { swap_stack_guard(out_context, in_context) }
+        // Everything after this runs with the incoming context's TLS, which is
+        // where the stack bounds it's about to be given need to go
+        unsafe { swap_segment_bases(out_context, in_context) }
+        unsafe { record_task_local(in_context.local) }
+        unsafe { record_overflow_policy(in_context.overflow_policy) }
+        unsafe { record_on_green_stack(in_context.stack_bounds.is_some()) }
+        if cfg!(context_thread_tracking) {
+            in_context.last_thread.set(current_thread_id());
+        }
+        if cfg!(task_sigaltstack) {
+            unsafe { swap_signal_stacks(out_context, in_context) }
+        }
+        if cfg!(stack_sampling) {
+            let here = 0u;
+            let sp = &here as *uint as uint;
+            if sp < out_context.min_sp_seen {
+                out_context.min_sp_seen = sp;
+            }
+        }
+
+        // Under valgrind (`--cfg valgrind`), whatever is below a new context's
+        // initial frame is garbage, possibly left behind by an earlier task on
+        // the same stack, and memcheck should treat it as such.
+        if cfg!(valgrind) && !in_context.started.get() {
+            match in_context.stack_bounds {
+                Some((lo, _)) => {
+                    let sp = saved_sp(&in_context.frame.regs);
+                    unsafe {
+                        rust_valgrind_make_mem_undefined(lo as *c_void,
+                                                         (sp - lo) as size_t)
+                    }
+                }
+                None => {}
+            }
+        }
+
+        // A context which has never run starts off at its entry point rather
+        // than by returning from this function, so it won't be able to clear
+        // the in-progress flag itself. Clear it on its behalf now.
+        if !in_context.started.get() {
+            in_context.started.set(true);
+            end_swap();
+        }
+        let gp_only = gp_only ||
+                      (cfg!(lazy_fp) && fp_untouched(out_context, in_context));
+
+        // Every hook is installed through `note_swap_hook`, so a swap without
+        // any of them only pays for loading the one word
+        let hooks = unsafe { SWAP_HOOKS.load(Relaxed) };
+        if hooks != 0 {
+            run_swap_hooks(hooks, out_context, in_context);
+        }
+
+        // This must be the last thing before the stack limit changes, the
+        // sanitizer's hooks are C but the wrappers around them aren't.
+        if cfg!(asan) {
+            in_context.asan_from.set(&*out_context as *Context);
+            let region = match in_context.stack_bounds {
+                Some(bounds) => bounds,
+                None => in_context.asan_stack.get(),
+            };
+            unsafe {
+                if reason == Some(Finished) {
+                    asan::start_switch(None, region);
+                } else {
+                    asan::start_switch(Some(&mut out_context.asan_fake_stack),
+                                       region);
+                }
+            }
+        }
+        // ThreadSanitizer can't see that whichever thread resumes a context
+        // has synchronized with the one which suspended it (see the comment on
+        // the raw swap below), so with `--cfg tsan` it's told: each context's
+        // boxed frame stands in for the handover, released by the thread
+        // leaving a context and acquired by the one entering it. Both happen
+        // here on the swapping thread, which is the one that runs the
+        // incoming context's code from now on.
+        if cfg!(tsan) {
+            unsafe {
+                tsan::release(&*out_context.frame as *Frame as *c_void);
+                tsan::acquire(&*in_context.frame as *Frame as *c_void);
+            }
+        }
+        let out_regs: &mut Registers = &mut out_context.frame.regs;
+        let in_regs: &Registers = &in_context.frame.regs;
+
+        // When stacks are packed next to each other without guard pages, a task
+        // which overflowed may have left its stack pointer inside of whatever
+        // is below it. With `--cfg stack_clash_check` that's caught here,
+        // before the task gets to run and corrupt its neighbor any further.
+        if cfg!(stack_clash_check) {
+            match in_context.stack_bounds {
+                Some((lo, hi)) => {
+                    let sp = saved_sp(in_regs);
+                    if sp < lo || sp > hi {
+                        overflowed(in_context.overflow_policy,
+                                   format!("stack clash: {} resuming with \
+                                            its stack pointer {:#x} outside of \
+                                            its stack [{:#x}, {:#x}]",
+                                           in_context.debug_name(), sp, lo, hi));
+                    }
+                }
+                None => {}
+            }
+        }
+
+        // Otherwise a stack pointer outside of the bounds that are about to be
+        // recorded for morestack means that they're not this context's stack
+        // at all (a `reset` or hand-built context which didn't update them),
+        // and overflow checks against them are meaningless. Without `--cfg
+        // ndebug` that's caught here, since it can't be anything but a bug.
+        if cfg!(not(ndebug)) && !cfg!(stack_clash_check) {
+            match in_context.stack_bounds {
+                Some((lo, hi)) => {
+                    let sp = saved_sp(in_regs);
+                    if sp < lo || sp >= hi {
+                        rtabort!("resuming context {} with its stack pointer \
+                                  {:#x} outside of its recorded stack bounds \
+                                  [{:#x}, {:#x})", in_context.id, sp, lo, hi);
+                    }
+                }
+                None => {}
+            }
+        }
+
+        rtdebug!("noting the stack limit and doing raw swap");
+
+        unsafe {
+            // Right before we switch to the new context, set the new context's
+            // stack limit in the OS-specified TLS slot. This also  means that
+            // we cannot call any more rust functions after record_stack_bounds
+            // returns because they would all likely fail due to the limit being
+            // invalid for the current task. Lucky for us `rust_swap_registers`
+            // is a C function so we don't have to worry about that!
+            //
+            // There are no memory fences around the register swap, and none
+            // are needed for a context which migrates between threads. To the
+            // thread running it a swap is an ordinary call, so everything the
+            // outgoing code wrote is ordered before whatever the incoming code
+            // does next. A suspended context only gets to another thread by
+            // being handed over through something which synchronizes the two
+            // already (a lock, a channel, or the atomics in the work stealing
+            // deque), after it's been swapped out on one and before it's
+            // swapped in on the other, so that handover carries all of its
+            // writes with it. Handing contexts over through anything which
+            // doesn't synchronize is a data race a fence here couldn't fix.
+            //
+            // Stacks grow down on every architecture we support, so `lo` is
+            // the value that morestack actually compares the stack pointer
+            // against (plus the red zone) and `hi` is only used to describe
+            // the stack to the OS (e.g. the TIB on windows). Bounds with
+            // `lo > hi` would put the limit above the stack pointer and the
+            // overflow check would never fire, so refuse to record them.
+            //
+            // Finding where the bounds go means reading the thread pointer,
+            // which traps to the kernel on some arm and mips cores. So only
+            // the first swap on a thread looks them up, and each swap hands
+            // them on to the context it switches to, which then runs on the
+            // same thread. With `--cfg segment_bases` the thread pointer
+            // itself changes with the context, so they're looked up every time.
+            let slots = match out_context.bounds_slots.get() {
+                Some(slots) if !cfg!(segment_bases) => slots,
+                _ => bounds_slots(),
+            };
+            out_context.bounds_slots.set(None);
+            in_context.bounds_slots.set(Some(slots));
+            if out_context.is_scheduler() || out_context.native_tib.get().is_some() {
+                save_native_tib(out_context);
+            }
+            match in_context.stack_bounds {
+                Some((lo, hi)) => {
+                    rtassert!(lo <= hi);
+                    if !bounds_recorded(slots, lo) {
+                        record_bounds_at(slots, lo, hi)
+                    }
+                    // The TIB's limit is where the stack is committed down to
+                    // for a stack that's committed as it grows, rather than
+                    // `lo`, which the stack limit for morestack stays at
+                    restore_native_tib(in_context);
+                }
+                // If we're going back to one of the original contexts or
+                // something that's possibly not a "normal task", then reset
+                // the stack limit to 0 to make morestack never fail, unless
+                // the bounds of the thread's own stack were given to it. On
+                // windows the TIB gets back the bounds it had when the context
+                // was left, which are the thread's own for a scheduler.
+                None => {
+                    match in_context.native_bounds {
+                        Some((lo, hi)) => {
+                            if !bounds_recorded(slots, lo) {
+                                record_bounds_at(slots, lo, hi)
+                            }
+                        }
+                        None => {
+                            if !bounds_recorded(slots, 0) {
+                                record_bounds_at(slots, 0, uint::max_value)
+                            }
+                        }
+                    }
+                    restore_native_tib(in_context);
+                }
+            }
+            if gp_only {
+                swap_gp_registers(out_regs, in_regs);
+            } else {
+                rust_swap_registers(out_regs, in_regs);
+            }
+        }
+        if cfg!(asan) {
+            let region = unsafe { asan::finish_switch(out_context.asan_fake_stack) };
+            note_asan_stack(out_context.asan_from.get(), region);
+        }
+        end_swap();
+    }
 }
 
-#[cfg(target_arch = "mips")]
-type Registers = [uint, ..32];
+impl Drop for Context {
+    fn drop(&mut self) {
+        if cfg!(stack_registry) && self.id != 0 {
+            stack_registry::unregister(self.id);
+        }
+        if cfg!(context_registry) && self.id != 0 {
+            context_registry::unregister(self.id);
+        }
+        if self.unwind_on_drop { self.unwind(); }
+    }
+}
 
-#[cfg(target_arch = "mips")]
-fn new_regs() -> ~Registers { ~([0, .. 32]) }
+// The C-ABI function that is the task entry point
+//
+// Note that this function is a little sketchy. We're taking a
+// procedure, transmuting it to a stack-closure, and then calling to
+// closure. This leverages the fact that the representation of these two
+// types is the same.
+//
+// The reason that we're doing this is that this procedure is expected
+// to never return. The codegen which frees the environment of the
+// procedure occurs *after* the procedure has completed, and this means
+// that we'll never actually free the procedure.
+//
+// To solve this, we use this transmute (to not trigger the procedure
+// deallocation here), and then store the procedure in the frame of
+// the `Context` structure returned. When the `Context` is deallocated,
+// then the procedure will be deallocated along with its frame.
+//
+// The procedure is run underneath a catch so that a forced unwind (see
+// `set_unwind_on_drop`) has somewhere to stop. Any other failure which
+// makes it this far had nothing else to catch it, and is kept for
+// `take_failure`. The `set_on_finish` procedure runs after it under a catch of
+// its own.
+//
+// Once the procedure is done with, and the stack is checked to be left as it
+// was found (see `check_stack_balance`), the task goes back to whichever
+// context resumed it last.
+extern fn task_start_wrapper(start: &mut TaskStart) {
+    let entry_depth = stack_depth();
+    // This is the other half of the swap into a new context, which never
+    // returns from `swap_impl` to tell the sanitizer it arrived. There's no
+    // fake stack to restore yet.
+    if cfg!(asan) {
+        let region = unsafe { asan::finish_switch(0 as *c_void) };
+        note_asan_stack(start.resumer.get(), region);
+    }
+    let mut finished = false;
+    unsafe {
+        let mut unwinder = Unwinder::new();
+        match start.main {
+            Plain(ref main) => {
+                let f: &|| = transmute(main);
+                unwinder.try(|| { (*f)(); finished = true; });
+            }
+            Returning(ref main, call) => {
+                let result = &mut start.result;
+                unwinder.try(|| { *result = Some(call(main)); finished = true; });
+            }
+            WithArg(ref main, ref mut arg, call) => {
+                unwinder.try(|| { call(main, arg.take().unwrap()); finished = true; });
+            }
+            Inline(ref mut main) => {
+                let call = main.call;
+                unwinder.try(|| { call(main, true); finished = true; });
+            }
+        }
+        if !finished && start.unwind_to.is_null() {
+            start.failure = Some(caught_failure(&mut unwinder));
+        }
 
-#[cfg(target_arch = "mips")]
-fn initialize_call_frame(regs: &mut Registers, fptr: *c_void, arg: *c_void,
-                         sp: *mut uint) {
-    let sp = align_down(sp);
-    // sp of mips o32 is 8-byte aligned
-    let sp = mut_offset(sp, -2);
+        match start.on_finish.take() {
+            Some(on_finish) => {
+                let mut unwinder = Unwinder::new();
+                let mut done = false;
+                let f: &|| = transmute(&on_finish);
+                unwinder.try(|| { (*f)(); done = true; });
+                if !done {
+                    let failure = caught_failure(&mut unwinder);
+                    if start.failure.is_none() && start.unwind_to.is_null() {
+                        start.failure = Some(failure);
+                    }
+                }
+            }
+            None => {}
+        }
+    }
+    if cfg!(not(ndebug)) {
+        check_stack_balance(start.canary, entry_depth);
+    }
+    let to = if !start.unwind_to.is_null() {
+        start.unwind_to
+    } else {
+        start.resumer.get()
+    };
+    let mut dead = Context::empty();
+    Context::swap_with_reason(&mut dead, unsafe { &*to }, Finished);
+}
 
-    // The final return address. 0 indicates the bottom of the stack
-    unsafe { *sp = 0; }
+// An address in the frame of whatever calls this, which moves exactly as the
+// caller's stack pointer does. Never inlined, so that two calls made at the
+// same depth of the same function give the same address.
+#[inline(never)]
+fn stack_depth() -> uint {
+    let here = 0u;
+    &here as *uint as uint
+}
+
+// Checks that `task_start_wrapper`, done with the task's procedures, has the
+// stack as it found it: back at the depth it started at, which is below the
+// initial frame, with the canary and the initial stack pointer above that frame
+// still intact. Both depths are taken from the same call in the same frame, so
+// they're equal whatever the alignment and red zone made of the initial frame.
+// Anything else means some callee returned with the stack pointer moved (asm
+// or a foreign function with the wrong calling convention, most likely) or
+// wrote over the top of the stack, which is better reported here than as a
+// crash some time after switching away.
+fn check_stack_balance(canary: *uint, entry_depth: uint) {
+    let depth = stack_depth();
+    if depth != entry_depth {
+        rtabort!("task finished with its stack imbalanced: it started at {:#x}, \
+                  but it's now at {:#x}", entry_depth, depth);
+    }
+    if canary.is_null() { return }
+    let initial_sp = canary as uint + mem::size_of::<uint>() - CANARY_BYTES;
+    if depth >= initial_sp {
+        rtabort!("task finished at {:#x}, above its initial frame at {:#x}",
+                 depth, initial_sp);
+    }
+    unsafe {
+        if *canary != STACK_CANARY || *mut_offset(canary as *mut uint, -1) != initial_sp {
+            // The task is done with, so there's nothing to fail
+            let msg = format!("task finished with the top of its stack at \
+                               {:#x} overwritten", initial_sp);
+            match effective_policy(running_overflow_policy()) {
+                Callback(f) => f(msg),
+                Abort | Fail => {}
+            }
+            rtabort!("{}", msg);
+        }
+    }
+}
+
+// What a procedure run under `unwinder` failed with. A `fail!` records its
+// cause with the thread's current task rather than the unwinder which caught
+// it, and that task would otherwise go on to fail as well once it's done, so
+// it's taken back from there.
+unsafe fn caught_failure(unwinder: &mut Unwinder) -> ~Any {
+    let failure = match unwinder.take_failure() {
+        Some(failure) => Some(failure),
+        None => {
+            let task: Option<*mut Task> = Local::try_unsafe_borrow();
+            task.and_then(|task| (*task).unwinder.take_failure())
+        }
+    };
+    match failure {
+        Some(failure) => failure,
+        None => ~"task failed" as ~Any,
+    }
+}
 
-    regs[4] = arg as uint;
-    regs[29] = sp as uint;
-    regs[25] = fptr as uint;
-    regs[31] = fptr as uint;
+// Where a context being forcibly unwound is resumed, as though it had been
+// called by the swap that the context is suspended in. The failure is raised
+// through a private unwinder so that the thread's current task isn't marked as
+// failing.
+extern fn unwind_trampoline() -> ! {
+    // This is the end of the swap which resumed the context, standing in for
+    // the one it was suspended in, which won't return to finish it. The fake
+    // stack that one saved is abandoned along with the rest of the stack.
+    end_swap();
+    if cfg!(asan) {
+        unsafe { asan::finish_switch(0 as *c_void); }
+    }
+    let mut unwinder = Unwinder::new();
+    unwinder.begin_unwind(~"context forcibly unwound" as ~Any)
 }
 
-fn align_down(sp: *mut uint) -> *mut uint {
+/// What to do when a context is found to have overflowed its stack
+///
+/// Overflows are found when a context whose stack canary has been overwritten
+/// is resumed, or with `--cfg stack_clash_check` whose stack pointer is out of
+/// its bounds; when a task finishes with its canary overwritten; and when a
+/// task runs out of stack, by morestack or (see `PoolConfig::overflow_handler`)
+/// by running into its guard page. The last three leave no task which can be
+/// failed, see `stack_exhausted`.
+pub enum OverflowPolicy {
+    /// Print a message and abort the process. This is the default.
+    Abort,
+    /// Fail the task which was running when the overflow was found. This is
+    /// only safe if that task has enough stack left to unwind.
+    Fail,
+    /// Call the given function with a description of the overflow. The
+    /// process is aborted if it returns.
+    Callback(fn(&str)),
+}
+
+static mut OVERFLOW_POLICY: OverflowPolicy = Abort;
+
+/// Sets what happens when an overflow is found in any context which doesn't
+/// have a policy of its own (see `Context::set_overflow_policy`).
+///
+/// This is unsafe because it isn't synchronized with other threads, so it
+/// should only be called before any contexts are running.
+pub unsafe fn set_overflow_policy(policy: OverflowPolicy) {
+    OVERFLOW_POLICY = policy;
+    handle_stack_exhaustion();
+}
+
+/// Has morestack carry out the running context's overflow policy once it has
+/// reported an overflow (see `stack_exhausted`), rather than just aborting.
+/// This is process-wide, for every thread, so it's done once when a
+/// `SchedPool` is created and by `set_overflow_policy`, not by each context.
+pub fn handle_stack_exhaustion() {
+    stack::set_stack_exhausted_hook(Some(morestack_exhausted));
+}
+
+// A context's own overflow policy, or the global one if it has none
+fn effective_policy(policy: Option<OverflowPolicy>) -> OverflowPolicy {
+    match policy {
+        Some(policy) => policy,
+        None => unsafe { OVERFLOW_POLICY },
+    }
+}
+
+// Carries out the overflow policy for a context, falling back to the global
+// one. This may be called in the middle of a swap which is now never going to
+// finish.
+fn overflowed(policy: Option<OverflowPolicy>, msg: ~str) -> ! {
+    end_swap();
+    match effective_policy(policy) {
+        Abort => rtabort!("{}", msg),
+        Fail => fail!("{}", msg),
+        Callback(f) => {
+            f(msg);
+            rtabort!("{}", msg)
+        }
+    }
+}
+
+/// Carries out the overflow policy of the context running on this thread for
+/// an overflow which has already been reported, found once its task has run
+/// out of stack: by morestack or by the guard page handler. There's no stack
+/// left to unwind, so a `Fail` policy aborts the process just as `Abort` does,
+/// and a `Callback` is called with `msg` before it's aborted.
+///
+/// The running context's policy is only known where compiled thread-local
+/// support is (not on windows or android), elsewhere the global policy is
+/// used.
+pub fn stack_exhausted(msg: &str) -> ! {
+    match effective_policy(running_overflow_policy()) {
+        Callback(f) => f(msg),
+        Abort | Fail => {}
+    }
+    unsafe { libc::abort() }
+}
+
+// Handed to libstd's `rust_stack_exhausted`, which has already reported the
+// overflow, once an overflow policy has been chosen
+fn morestack_exhausted(task: &str) {
+    stack_exhausted(format!("task '{}' has overflowed its stack", task))
+}
+
+// Which of the hooks run by `Context::swap` are installed, as a set of the
+// bits below. Each is also kept in its own word, this is only so that a swap
+// without any of them has a single load to make.
+static mut SWAP_HOOKS: AtomicUint = INIT_ATOMIC_UINT;
+static SPAN_HOOK_BIT: uint = 1 << 0;
+static SWITCH_HOOK_BIT: uint = 1 << 1;
+static EVENT_SINK_BIT: uint = 1 << 2;
+static LOW_STACK_HOOK_BIT: uint = 1 << 3;
+
+// Adds `bit` to `SWAP_HOOKS`, or takes it out. A hook is stored before its
+// bit is set and after it's cleared, and `run_swap_hooks` checks the hook
+// itself again, so a swap racing with either sees an empty slot at worst.
+fn note_swap_hook(bit: uint, installed: bool) {
     unsafe {
-        let sp: uint = transmute(sp);
-        let sp = sp & !(16 - 1);
-        transmute::<uint, *mut uint>(sp)
+        let mut old = SWAP_HOOKS.load(SeqCst);
+        loop {
+            let new = if installed { old | bit } else { old & !bit };
+            let prev = SWAP_HOOKS.compare_and_swap(old, new, SeqCst);
+            if prev == old { break }
+            old = prev;
+        }
+    }
+}
+
+// Runs whichever of the hooks in `hooks` (a set of `SWAP_HOOKS` bits) are
+// still installed. Kept out of line so that swaps carry none of its code.
+#[inline(never)]
+fn run_swap_hooks(hooks: uint, out_context: &mut Context,
+                  in_context: &Context) {
+    if hooks & LOW_STACK_HOOK_BIT != 0 {
+        match low_stack_hook() {
+            Some((hook, threshold)) => note_low_stack(out_context, hook,
+                                                      threshold),
+            None => {}
+        }
+    }
+    if cfg!(task_tracing) && hooks & SPAN_HOOK_BIT != 0 {
+        match span_hook() {
+            Some(hook) => hook(out_context.span, in_context.span),
+            None => {}
+        }
+    }
+    if hooks & SWITCH_HOOK_BIT != 0 {
+        match switch_hook() {
+            Some(hook) => hook(&*out_context, in_context),
+            None => {}
+        }
+    }
+    if hooks & EVENT_SINK_BIT != 0 {
+        match event_sink() {
+            Some(sink) => sink(SwapEvent {
+                from_id: out_context.id,
+                to_id: in_context.id,
+                timestamp_ns: time::precise_time_ns(),
+                from_is_scheduler: out_context.is_scheduler(),
+                to_is_scheduler: in_context.is_scheduler(),
+            }),
+            None => {}
+        }
+    }
+}
+
+/// Identifies a tracing span, as chosen by whichever tracing library is in use
+pub type SpanToken = u64;
+
+/// Called by `Context::swap` with the spans of the outgoing and incoming
+/// contexts, see `set_span_hook`
+pub type SpanHook = fn(Option<SpanToken>, Option<SpanToken>);
+
+// The installed `SpanHook` as an address, 0 when there's none
+static mut SPAN_HOOK: AtomicUint = INIT_ATOMIC_UINT;
+
+/// Makes every `Context::swap` call `hook` with the outgoing and incoming
+/// contexts' spans (see `Context::set_span`) just before switching, so that a
+/// tracing subscriber can exit the one and enter the other. Passing `None`
+/// removes the hook, which is the default. This only has an effect when
+/// libgreen is built with `--cfg task_tracing`, and may be changed while
+/// contexts are running on other threads.
+///
+/// The hook runs on the outgoing context's stack in the middle of the swap,
+/// so it must not swap contexts itself.
+pub fn set_span_hook(hook: Option<SpanHook>) {
+    match hook {
+        Some(hook) => unsafe {
+            SPAN_HOOK.store(hook as uint, SeqCst);
+            note_swap_hook(SPAN_HOOK_BIT, true);
+        },
+        None => unsafe {
+            note_swap_hook(SPAN_HOOK_BIT, false);
+            SPAN_HOOK.store(0, SeqCst);
+        },
     }
 }
 
-// ptr::mut_offset is positive ints only
 #[inline]
-pub fn mut_offset<T>(ptr: *mut T, count: int) -> *mut T {
-    use std::mem::size_of;
-    (ptr as int + count * (size_of::<T>() as int)) as *mut T
+fn span_hook() -> Option<SpanHook> {
+    match unsafe { SPAN_HOOK.load(Relaxed) } {
+        0 => None,
+        addr => Some(unsafe { transmute(addr) }),
+    }
+}
+
+/// Called by `Context::swap` with the outgoing and incoming contexts, see
+/// `set_switch_hook`
+pub type SwitchHook = fn(out_context: &Context, in_context: &Context);
+
+// The installed `SwitchHook` as an address, 0 when there's none
+static mut SWITCH_HOOK: AtomicUint = INIT_ATOMIC_UINT;
+
+/// Makes every `Context::swap` call `hook` with the outgoing and incoming
+/// contexts right before the registers are switched, for example to log the
+/// stack bounds of both with a timestamp. Passing `None` removes the hook,
+/// which is the default. Unlike `set_span_hook`, this takes effect in every
+/// build. It may be changed while contexts are running on other threads.
+///
+/// The hook runs on the outgoing context's stack in the middle of the swap,
+/// so it must not swap contexts, yield or block itself.
+pub fn set_switch_hook(hook: Option<SwitchHook>) {
+    match hook {
+        Some(hook) => unsafe {
+            SWITCH_HOOK.store(hook as uint, SeqCst);
+            note_swap_hook(SWITCH_HOOK_BIT, true);
+        },
+        None => unsafe {
+            note_swap_hook(SWITCH_HOOK_BIT, false);
+            SWITCH_HOOK.store(0, SeqCst);
+        },
+    }
+}
+
+#[inline]
+fn switch_hook() -> Option<SwitchHook> {
+    match unsafe { SWITCH_HOOK.load(Relaxed) } {
+        0 => None,
+        addr => Some(unsafe { transmute(addr) }),
+    }
+}
+
+/// A swap between two contexts, as handed to the sink installed with
+/// `set_event_sink`
+#[deriving(Clone, Eq)]
+pub struct SwapEvent {
+    /// The `Context::id` of the context being swapped out
+    from_id: uint,
+    /// The `Context::id` of the context being swapped in
+    to_id: uint,
+    /// When the swap happened, in nanoseconds from an arbitrary point which is
+    /// the same for every thread
+    timestamp_ns: u64,
+    /// Whether the context being swapped out is a scheduler's, see
+    /// `Context::is_scheduler`
+    from_is_scheduler: bool,
+    /// Whether the context being swapped in is a scheduler's
+    to_is_scheduler: bool,
+}
+
+/// Called by `Context::swap` with every swap, see `set_event_sink`
+pub type EventSink = fn(event: SwapEvent);
+
+// The installed `EventSink` as an address, 0 when there's none
+static mut EVENT_SINK: AtomicUint = INIT_ATOMIC_UINT;
+
+/// Makes every `Context::swap` hand a `SwapEvent` to `sink` right before the
+/// registers are switched, for tools which turn a run into a trace (such as a
+/// Chrome trace or perfetto converter). Passing `None` removes the sink,
+/// which is the default, and leaves swaps paying for nothing more than
+/// checking for it. The sink may be changed while contexts are running on
+/// other threads.
+///
+/// The sink runs on the outgoing context's stack in the middle of every swap,
+/// so it must be quick and must not block: pushing the event onto a
+/// lock-free ring buffer which something else drains is the expected use. Like
+/// the `set_switch_hook` hook, it must not swap contexts itself.
+pub fn set_event_sink(sink: Option<EventSink>) {
+    match sink {
+        Some(sink) => unsafe {
+            EVENT_SINK.store(sink as uint, SeqCst);
+            note_swap_hook(EVENT_SINK_BIT, true);
+        },
+        None => unsafe {
+            note_swap_hook(EVENT_SINK_BIT, false);
+            EVENT_SINK.store(0, SeqCst);
+        },
+    }
+}
+
+#[inline]
+fn event_sink() -> Option<EventSink> {
+    match unsafe { EVENT_SINK.load(Relaxed) } {
+        0 => None,
+        addr => Some(unsafe { transmute(addr) }),
+    }
+}
+
+/// Called by `Context::swap` with a context being swapped out and the bytes of
+/// stack it had left, see `set_low_stack_hook`
+pub type LowStackHook = fn(context: &Context, remaining: uint);
+
+// The installed `LowStackHook` as an address, 0 when there's none, and the
+// threshold it was installed with
+static mut LOW_STACK_HOOK: AtomicUint = INIT_ATOMIC_UINT;
+static mut LOW_STACK_THRESHOLD: AtomicUint = INIT_ATOMIC_UINT;
+
+/// Makes `Context::swap` call `hook` the first time a task is swapped out
+/// with fewer than `threshold` bytes of stack left below its stack pointer
+/// (as `stack_remaining` counts them), for example to log that it's running
+/// low. The hook is called at most once for each task a context runs, and
+/// never for contexts without stack bounds. Passing `None` removes the hook,
+/// which is the default.
+///
+/// The stack is only sampled at swaps, so a task which goes deeper and comes
+/// back between two swaps isn't noticed. The hook runs on the outgoing
+/// context's stack in the middle of the swap (which may be the little that's
+/// left of it), so it must not swap contexts, yield or block itself.
+pub fn set_low_stack_hook(threshold: uint, hook: Option<LowStackHook>) {
+    unsafe {
+        match hook {
+            Some(hook) => {
+                LOW_STACK_THRESHOLD.store(threshold, SeqCst);
+                LOW_STACK_HOOK.store(hook as uint, SeqCst);
+                note_swap_hook(LOW_STACK_HOOK_BIT, true);
+            }
+            None => {
+                note_swap_hook(LOW_STACK_HOOK_BIT, false);
+                LOW_STACK_HOOK.store(0, SeqCst);
+            }
+        }
+    }
+}
+
+#[inline]
+fn low_stack_hook() -> Option<(LowStackHook, uint)> {
+    match unsafe { LOW_STACK_HOOK.load(Relaxed) } {
+        0 => None,
+        addr => unsafe {
+            Some((transmute(addr), LOW_STACK_THRESHOLD.load(Relaxed)))
+        }
+    }
+}
+
+// Calls `hook` if the outgoing context is below the threshold for the first
+// time in its current task
+fn note_low_stack(out_context: &mut Context, hook: LowStackHook,
+                  threshold: uint) {
+    if out_context.low_stack_reported { return }
+    match out_context.stack_bounds {
+        Some((lo, _)) => {
+            let here = 0u;
+            let sp = &here as *uint as uint;
+            let remaining = if sp > lo { sp - lo } else { 0 };
+            if remaining < threshold {
+                out_context.low_stack_reported = true;
+                hook(&*out_context, remaining);
+            }
+        }
+        None => {}
+    }
+}
+
+// How many frames `capture_spawn_backtrace` records, 0 when it's off
+static mut SPAWN_BACKTRACE_FRAMES: AtomicUint = INIT_ATOMIC_UINT;
+
+/// The most frames `set_spawn_backtraces` will have a context keep
+pub static MAX_SPAWN_BACKTRACE_FRAMES: uint = 32;
+
+/// Makes every context which is set up to run a task from now on record the
+/// innermost `frames` return addresses of the code doing it, for
+/// `Context::spawn_backtrace`. This is off (0) by default, since it walks the
+/// creator's stack every time. `frames` is capped at
+/// `MAX_SPAWN_BACKTRACE_FRAMES`, and may be changed while contexts are being
+/// created on other threads.
+pub fn set_spawn_backtraces(frames: uint) {
+    let frames = cmp::min(frames, MAX_SPAWN_BACKTRACE_FRAMES);
+    unsafe { SPAWN_BACKTRACE_FRAMES.store(frames, SeqCst) }
+}
+
+// Replaces `out` with the return addresses of whoever called this, if
+// `set_spawn_backtraces` turned capturing on. Never inlined, so that its own
+// frame is always the one dropped.
+#[inline(never)]
+fn capture_spawn_backtrace(out: &mut ~[*c_void]) {
+    out.truncate(0);
+    let frames = unsafe { SPAWN_BACKTRACE_FRAMES.load(Relaxed) };
+    if frames == 0 { return }
+    let mut buf = [0 as *c_void, ..MAX_SPAWN_BACKTRACE_FRAMES + 1];
+    let n = unsafe {
+        execinfo_backtrace(buf.as_mut_ptr(), (frames + 1) as c_int)
+    };
+    for &ip in buf.slice(1, cmp::max(n as uint, 1)).iter() {
+        out.push(ip);
+    }
+}
+
+// From the C library's execinfo
+#[cfg(target_os = "linux")]
+#[cfg(target_os = "macos")]
+unsafe fn execinfo_backtrace(buf: *mut *c_void, size: c_int) -> c_int {
+    extern { fn backtrace(buf: *mut *c_void, size: c_int) -> c_int; }
+    backtrace(buf, size)
+}
+
+#[cfg(not(target_os = "linux"), not(target_os = "macos"))]
+unsafe fn execinfo_backtrace(_buf: *mut *c_void, _size: c_int) -> c_int { 0 }
+
+/// A reference to a parked context by its index in some table of the
+/// scheduler's, along with the generation it's expected to be at, see
+/// `Context::generation`.
+///
+/// ```ignore
+/// let handle = ContextHandle::new(idx, &contexts[idx]);
+/// // ... later, after `contexts[idx]` may have been reused
+/// Context::swap_checked(&mut sched, &contexts[handle.idx], handle.generation);
+/// ```
+#[deriving(Eq, Clone)]
+pub struct ContextHandle {
+    idx: uint,
+    generation: u64,
+}
+
+impl ContextHandle {
+    pub fn new(idx: uint, ctx: &Context) -> ContextHandle {
+        ContextHandle { idx: idx, generation: ctx.generation }
+    }
+
+    /// Whether `ctx` is still running the task this handle was made for
+    pub fn is_current(&self, ctx: &Context) -> bool {
+        ctx.generation == self.generation
+    }
+}
+
+// The generation handed to the next task set up in any context. This is only
+// a uint, so on 32 bit targets it wraps after 2^32 tasks.
+static mut NEXT_GENERATION: AtomicUint = INIT_ATOMIC_UINT;
+
+fn next_generation() -> u64 {
+    unsafe { NEXT_GENERATION.fetch_add(1, Relaxed) as u64 + 1 }
+}
+
+/// How many context switches have happened in this process so far. They're
+/// only counted when libgreen is built with `--cfg context_stats`, otherwise
+/// both counts stay 0.
+#[deriving(Eq)]
+pub struct ContextStats {
+    /// Every switch, whichever kind of context it went to
+    swaps: uint,
+    /// Switches into contexts without stack bounds, which are schedulers (or
+    /// whatever else was running before a context was created) rather than
+    /// tasks
+    scheduler_swaps: uint,
+}
+
+impl ContextStats {
+    /// Switches into tasks, that is contexts with stack bounds
+    pub fn task_swaps(&self) -> uint {
+        self.swaps - self.scheduler_swaps
+    }
+}
+
+// These are only ever incremented with relaxed ordering, the counts aren't
+// used to synchronize anything. Every scheduler thread shares them, which
+// makes them a point of contention on the swap path, hence the cfg.
+static mut SWAPS: AtomicUint = INIT_ATOMIC_UINT;
+static mut SCHEDULER_SWAPS: AtomicUint = INIT_ATOMIC_UINT;
+
+/// The number of times `Context::swap` (or one of its variants) has switched
+/// contexts, on any thread. Always 0 unless libgreen is built with
+/// `--cfg context_stats`.
+pub fn swap_count() -> uint {
+    unsafe { SWAPS.load(Relaxed) }
+}
+
+/// The number of context switches so far, split by the kind of context
+/// switched into. The two counts are read separately, so a switch happening
+/// on another thread meanwhile may only be reflected in one of them.
+pub fn context_stats() -> ContextStats {
+    unsafe {
+        let scheduler_swaps = SCHEDULER_SWAPS.load(Relaxed);
+        ContextStats {
+            swaps: SWAPS.load(Relaxed).max(&scheduler_swaps),
+            scheduler_swaps: scheduler_swaps,
+        }
+    }
+}
+
+/// What `live_contexts` reports about a context
+#[deriving(Clone)]
+pub struct ContextSummary {
+    /// See `Context::id`
+    id: uint,
+    /// See `Context::name`
+    name: Option<~str>,
+    /// See `Context::is_scheduler`
+    is_scheduler: bool,
+    /// See `Context::stack_bounds`
+    stack_bounds: Option<(uint, uint)>,
+    /// See `Context::instruction_pointer`. It's stale for a context which is
+    /// running.
+    instruction_pointer: *c_void,
+}
+
+/// Every live context other than those from `empty`, in the order they were
+/// created, for diagnosing a hang. There are only any when libgreen is built
+/// with `--cfg context_registry`, see the `context_registry` module.
+pub fn live_contexts() -> ~[ContextSummary] {
+    let mut out = ~[];
+    context_registry::each(|e| {
+        let frame: &Frame = unsafe { transmute(e.frame) };
+        out.push(ContextSummary {
+            id: e.id,
+            name: e.name.clone(),
+            is_scheduler: e.stack_bounds.is_none(),
+            stack_bounds: e.stack_bounds,
+            instruction_pointer: saved_ip(&frame.regs) as *c_void,
+        });
+    });
+    out.sort_by(|a, b| a.id.cmp(&b.id));
+    out
+}
+
+/// Writes a line about every context from `live_contexts` to stderr,
+/// without going through the running task (which may be the one that's
+/// stuck).
+pub fn dump_all_contexts() {
+    let contexts = live_contexts();
+    rterrln!("{} live contexts", contexts.len());
+    for c in contexts.iter() {
+        let name = match c.name {
+            Some(ref name) => name.as_slice(),
+            None => "<unnamed>",
+        };
+        let kind = if c.is_scheduler { "scheduler" } else { "task" };
+        match c.stack_bounds {
+            Some((lo, hi)) => {
+                rterrln!("context {} ({}, {}): stack [{:#x}, {:#x}), ip {}",
+                         c.id, name, kind, lo, hi, c.instruction_pointer)
+            }
+            None => {
+                rterrln!("context {} ({}, {}): no stack, ip {}",
+                         c.id, name, kind, c.instruction_pointer)
+            }
+        }
+    }
+}
+
+#[inline]
+fn count_swap(in_context: &Context) {
+    if !cfg!(context_stats) { return }
+    unsafe {
+        SWAPS.fetch_add(1, Relaxed);
+        if in_context.is_scheduler() {
+            SCHEDULER_SWAPS.fetch_add(1, Relaxed);
+        }
+    }
+}
+
+// Whether a lazy floating point swap can leave the floating point registers
+// alone: the outgoing context hasn't changed them since they were saved and
+// the incoming one has nothing saved. If not, the full swap about to happen
+// saves the outgoing context's registers.
+fn fp_untouched(out_context: &Context, in_context: &Context) -> bool {
+    if !out_context.fp_dirty.get() && !in_context.fp_saved.get() {
+        return true
+    }
+    out_context.fp_dirty.set(false);
+    out_context.fp_saved.set(true);
+    false
+}
+
+// The outgoing context gives back the signal stack it replaced before the
+// incoming one installs its own, so that switching straight between two tasks
+// with their own stacks still remembers the thread's.
+//
+// Failing to put the replaced stack back would leave signals to be handled on
+// memory the task may be about to free, so that aborts. A task whose own stack
+// can't be installed just carries on with the one it would have replaced.
+unsafe fn swap_signal_stacks(out_context: &Context, in_context: &Context) {
+    match out_context.prev_signal_stack.get() {
+        Some(prev) => {
+            match prev.install() {
+                Ok(..) => {}
+                Err(e) => rtabort!("cannot put back the signal stack that \
+                                    {} replaced: {}",
+                                   out_context.debug_name(), e),
+            }
+            out_context.prev_signal_stack.set(None);
+        }
+        None => {}
+    }
+    match in_context.signal_stack {
+        Some(ref ss) => match ss.install() {
+            Ok(prev) => in_context.prev_signal_stack.set(Some(prev)),
+            Err(e) => rterrln!("cannot install the signal stack of {}: {}",
+                               in_context.debug_name(), e),
+        },
+        None => {}
+    }
+}
+
+// Set while this thread is in the middle of `Context::swap`. A swap which
+// starts while another is still in flight (for example from a signal handler
+// which preempted the first one) would corrupt the registers being saved, so
+// it's caught here instead.
+//
+// This uses the same compiled thread-local support as the runtime's local
+// pointer, and is likewise missing on platforms where that doesn't work.
+#[cfg(not(windows), not(target_os = "android"))]
+#[thread_local]
+static mut SWAP_IN_PROGRESS: bool = false;
+
+#[cfg(not(windows), not(target_os = "android"))]
+#[inline]
+fn begin_swap() {
+    unsafe {
+        if SWAP_IN_PROGRESS {
+            rtabort!("context swap started while another swap was in progress");
+        }
+        SWAP_IN_PROGRESS = true;
+    }
+}
+
+#[cfg(not(windows), not(target_os = "android"))]
+#[inline]
+fn end_swap() {
+    unsafe { SWAP_IN_PROGRESS = false; }
+}
+
+#[cfg(windows)]
+#[cfg(target_os = "android")]
+#[inline]
+fn begin_swap() {}
+
+#[cfg(windows)]
+#[cfg(target_os = "android")]
+#[inline]
+fn end_swap() {}
+
+// Sets up the registers of a new context. That has to happen in a fixed
+// order: the registers are filled with a snapshot of the current thread's,
+// which is then partly overwritten to describe the task's first call. Doing
+// those the other way around would throw away the call frame, so each step
+// consumes the state left by the previous one and they can only be chained
+// together in the right order.
+//
+// This works on borrowed registers rather than owning them because the
+// snapshot must be taken in place, where they're suitably aligned.
+struct RegisterFile<'a> {
+    priv regs: &'a mut Registers,
+}
+
+// The registers once they hold a snapshot of the current thread's
+struct RegisterSnapshot<'a> {
+    priv regs: &'a mut Registers,
+}
+
+impl<'a> RegisterFile<'a> {
+    // Without `--cfg ndebug` the registers start out as `REGISTER_POISON`
+    // rather than zero, so that one which `initialize_call_frame` forgot to
+    // set (and which no snapshot overwrote) faults at an obviously bad address
+    // instead of looking like the zero that ends a stack walk.
+    fn new(regs: &'a mut Registers) -> RegisterFile<'a> {
+        *regs = new_regs();
+        if cfg!(not(ndebug)) {
+            unsafe {
+                let words: *mut uint = transmute(&mut *regs);
+                vec::raw::mut_buf_as_slice(words, register_words(), |words| {
+                    for w in words.mut_iter() { *w = REGISTER_POISON }
+                });
+            }
+        }
+        RegisterFile { regs: regs }
+    }
+
+    // The registers a new context starts from. Where some register which
+    // `initialize_call_frame` doesn't set still has to hold something sensible
+    // (`SNAPSHOT_NEW_REGISTERS`) that's a snapshot of the current thread's,
+    // and elsewhere it's the zeroed registers, which saves a register swap on
+    // every spawn. Building with `--cfg snapshot_registers` always snapshots.
+    fn baseline(self) -> RegisterSnapshot<'a> {
+        if SNAPSHOT_NEW_REGISTERS || cfg!(snapshot_registers) {
+            self.snapshot()
+        } else {
+            RegisterSnapshot { regs: self.regs }
+        }
+    }
+
+    // Save and then immediately load the current context, which will then be
+    // modified to call the given function when restored
+    fn snapshot(self) -> RegisterSnapshot<'a> {
+        let regs = self.regs;
+        unsafe {
+            rust_swap_registers(transmute_mut_region(&mut *regs),
+                                transmute_region(&*regs));
+        }
+        RegisterSnapshot { regs: regs }
+    }
+}
+
+impl<'a> RegisterSnapshot<'a> {
+    fn clear_fp_state(self, clear: bool) -> RegisterSnapshot<'a> {
+        let regs = self.regs;
+        if clear {
+            clear_fp_regs(regs);
+        }
+        RegisterSnapshot { regs: regs }
+    }
+
+    // `lo` is the bottom of the stack which `sp` is the top of. Unless built
+    // with `--cfg ndebug`, the whole initial frame is checked to fit between
+    // the two before `initialize_call_frame` writes any of it, and the stack
+    // pointer it leaves is checked to be aligned as the entry point expects
+    // (see `ENTRY_SP_MISALIGNMENT`), which would otherwise only show up as a
+    // crash wherever the task first uses an aligned vector load. There's
+    // nothing to check for the zero-length stacks of scheduler contexts.
+    //
+    // The stack's canary goes in the highest word below `sp` once that's
+    // aligned, and the frame starts `CANARY_BYTES` below that so that it stays
+    // aligned. The word below the canary records where the frame starts, see
+    // `Context::initial_sp`. Where the canary was written is returned, or null
+    // if there's no stack to write it on.
+    fn init_call_frame(self, fptr: *c_void, arg: *c_void, arg_pos: uint,
+                       lo: uint, sp: *mut uint) -> *uint {
+        if lo == sp as uint {
+            initialize_call_frame(self.regs, fptr, arg, arg_pos, sp);
+            return 0 as *uint
+        }
+        if cfg!(not(ndebug)) {
+            let frame = (INITIAL_FRAME_BYTES + CANARY_BYTES +
+                         STACK_ALIGNMENT) as int;
+            if checked_mut_offset(sp as *mut u8, -frame, lo, sp as uint).is_none() {
+                rtabort!("the initial frame below {} doesn't fit on the stack \
+                          starting at {:#x}", sp, lo);
+            }
+        }
+        let top = align_down(sp);
+        let canary = mut_offset(top, -1);
+        unsafe { *canary = STACK_CANARY; }
+        let sp = mut_offset(top as *mut u8, -(CANARY_BYTES as int)) as *mut uint;
+        unsafe { *mut_offset(canary, -1) = sp as uint; }
+        initialize_call_frame(self.regs, fptr, arg, arg_pos, sp);
+        if cfg!(not(ndebug)) {
+            let sp = saved_sp(&*self.regs);
+            if (sp + ENTRY_SP_MISALIGNMENT) & (STACK_ALIGNMENT - 1) != 0 {
+                rtabort!("initialize_call_frame left the stack pointer at {:#x}, \
+                          which isn't {}-byte aligned", sp, STACK_ALIGNMENT);
+            }
+        }
+        canary as *uint
+    }
+}
+
+/// A stand-in for the register switch in `Context::swap`, see `set_swap_hook`
+#[cfg(test)]
+pub type SwapHook = fn(&Context, &Context);
+
+#[cfg(test)]
+local_data_key!(swap_hook: SwapHook)
+
+/// Makes every `Context::swap` on this task call `hook` with the outgoing and
+/// incoming contexts instead of switching to the incoming one, or go back to
+/// really switching if `hook` is `None`. Nothing else about the contexts is
+/// touched while a hook is installed.
+///
+/// This only exists in test builds, so that scheduler logic (who resumes whom,
+/// and in which order) can be tested without running anything on another
+/// stack.
+#[cfg(test)]
+pub fn set_swap_hook(hook: Option<SwapHook>) {
+    match hook {
+        Some(hook) => local_data::set(swap_hook, hook),
+        None => { local_data::pop(swap_hook); }
+    }
+}
+
+#[cfg(test)]
+fn intercept_swap(out_context: &Context, in_context: &Context) -> bool {
+    local_data::get(swap_hook, |hook| {
+        match hook {
+            Some(hook) => { (*hook)(out_context, in_context); true }
+            None => false,
+        }
+    })
+}
+
+#[cfg(not(test))]
+#[inline(always)]
+fn intercept_swap(_out_context: &Context, _in_context: &Context) -> bool {
+    false
+}
+
+/// Runs `f` to completion on the given stack, returning its result.
+///
+/// This is a synchronous convenience built on `Context::new` and
+/// `Context::swap` for code which just wants to run something (like a deeply
+/// recursive computation) on a larger stack than the current one. No scheduler
+/// is involved: the calling context is suspended until `f` returns, at which
+/// point the caller's stack limit is restored.
+///
+/// If `f` fails, its failure is caught at the bottom of the new stack and the
+/// calling task fails with it once it's back on its own stack (see
+/// `Context::fail_with`).
+pub fn run_on_stack<R: Send>(stack: &mut StackSegment, f: proc() -> R) -> R {
+    let mut ret: Option<R> = None;
+    let mut caller = Context::empty();
+
+    // The proc must be sendable, so the pointers back into this frame are
+    // smuggled across as integers. Both outlive the proc, because this
+    // function doesn't return until the proc has swapped back to us.
+    let ret_ptr: uint = unsafe { transmute(&mut ret) };
+    let caller_ptr: uint = unsafe { transmute(&caller) };
+    let mut task = Context::new(proc() {
+        unsafe {
+            let ret: &mut Option<R> = transmute(ret_ptr);
+            let caller: &Context = transmute(caller_ptr);
+            *ret = Some(f());
+
+            // This context is never resumed, so the registers saved here are
+            // simply discarded along with the stack.
+            let mut dead = Context::empty();
+            Context::swap(&mut dead, caller);
+        }
+        unreachable!();
+    }, stack);
+
+    let limit = unsafe { stack::get_sp_limit() };
+    Context::swap(&mut caller, &task);
+    unsafe { stack::record_sp_limit(limit) };
+
+    match task.take_failure() {
+        Some(failure) => Context::fail_with(failure),
+        None => ret.take_unwrap(),
+    }
+}
+
+/// Runs `f` with `[lo, hi)` recorded as the current stack's bounds, as though
+/// it were the stack of a context being swapped in, then puts the previous
+/// stack limit back (even if `f` fails).
+///
+/// This is for code which manages its own stack space and knows that there's
+/// more of it than the recorded bounds admit, such as a deep recursion on a
+/// stack it made itself. Only the stack limit is put back afterwards, not
+/// anything else `record_stack_bounds` touches (the TIB on windows).
+///
+/// This is unsafe because nothing checks that the bounds really describe the
+/// stack that `f` runs on. Being too generous turns a stack overflow into
+/// memory corruption.
+pub unsafe fn with_stack_bounds<R>(lo: uint, hi: uint, f: || -> R) -> R {
+    struct RestoreLimit { limit: uint }
+    impl Drop for RestoreLimit {
+        fn drop(&mut self) { unsafe { stack::record_sp_limit(self.limit) } }
+    }
+
+    let here = 0u;
+    let sp = &here as *uint as uint;
+    rtassert!(lo <= sp && sp <= hi);
+    let _restore = RestoreLimit { limit: stack::get_sp_limit() };
+    record_bounds(lo, hi);
+    f()
+}
+
+/// A configurable way of creating a context along with the stack it runs on.
+///
+/// Options are set by chaining calls, and then checked all together by
+/// `build`, for example:
+///
+/// ```ignore
+/// let (ctx, stack) = ContextBuilder::new().stack_size(64 * 1024)
+///                                         .clean_fp_state(true)
+///                                         .build(proc() { ... }).unwrap();
+/// ```
+///
+/// With no options set, `build` makes the same context as `Context::new`
+/// would on a stack from `StackSegment::try_new`.
+pub struct ContextBuilder {
+    priv stack_size: uint,
+    priv clean_fp_state: bool,
+    priv overflow_policy: Option<OverflowPolicy>,
+    priv signal_stack_size: Option<uint>,
+    priv unwind_on_drop: bool,
+    priv guard_page: bool,
+    priv alignment: uint,
+    priv name: Option<~str>,
+}
+
+impl ContextBuilder {
+    /// A builder for a context on a stack of the runtime's default minimum
+    /// size, with every other option off.
+    pub fn new() -> ContextBuilder {
+        ContextBuilder {
+            stack_size: env::min_stack(),
+            clean_fp_state: false,
+            overflow_policy: None,
+            signal_stack_size: None,
+            unwind_on_drop: false,
+            guard_page: false,
+            alignment: STACK_ALIGNMENT,
+            name: None,
+        }
+    }
+
+    /// The size in bytes of the stack to allocate
+    pub fn stack_size(self, size: uint) -> ContextBuilder {
+        ContextBuilder { stack_size: size, ..self }
+    }
+
+    /// See `Context::new_with_fp_state`
+    pub fn clean_fp_state(self, clean: bool) -> ContextBuilder {
+        ContextBuilder { clean_fp_state: clean, ..self }
+    }
+
+    /// See `Context::set_overflow_policy`
+    pub fn overflow_policy(self, policy: OverflowPolicy) -> ContextBuilder {
+        ContextBuilder { overflow_policy: Some(policy), ..self }
+    }
+
+    /// See `Context::set_signal_stack`
+    pub fn signal_stack(self, size: uint) -> ContextBuilder {
+        ContextBuilder { signal_stack_size: Some(size), ..self }
+    }
+
+    /// See `Context::set_unwind_on_drop`
+    pub fn unwind_on_drop(self, enabled: bool) -> ContextBuilder {
+        ContextBuilder { unwind_on_drop: enabled, ..self }
+    }
+
+    /// Whether the stack gets a guard page below it (see
+    /// `stack::set_guard_pages`). With this set, failing to protect the page
+    /// is an error rather than falling back to an unguarded stack.
+    pub fn guard_page(self, enabled: bool) -> ContextBuilder {
+        ContextBuilder { guard_page: enabled, ..self }
+    }
+
+    /// Aligns the task's initial stack pointer to `bytes`, which must be a
+    /// power of two and at least the ABI's `STACK_ALIGNMENT` (the default).
+    /// A stricter alignment, such as 32 bytes for tasks which keep AVX
+    /// vectors on their stack, saves the entry point from realigning it.
+    ///
+    /// This is the alignment the ABI describes, which on x86 and x86_64 is of
+    /// the stack pointer before the call that enters the task pushes its
+    /// return address.
+    pub fn alignment(self, bytes: uint) -> ContextBuilder {
+        ContextBuilder { alignment: bytes, ..self }
+    }
+
+    /// See `Context::set_name`
+    pub fn name(self, name: ~str) -> ContextBuilder {
+        ContextBuilder { name: Some(name), ..self }
+    }
+
+    /// Allocates the stack and creates a context on it which will run
+    /// `start`, or returns why the options given can't be used.
+    ///
+    /// The stack must be kept alive for as long as the context is.
+    pub fn build(self, start: proc())
+        -> Result<(Context, StackSegment), ContextError>
+    {
+        let mut stack = match self.make_stack() {
+            Ok(stack) => stack,
+            Err(e) => return Err(e),
+        };
+        let ctx = match Context::try_new_with_fp_state(start, &mut stack,
+                                                       self.clean_fp_state) {
+            Ok(ctx) => ctx,
+            Err(e) => return Err(e),
+        };
+        Ok(self.configure(ctx, stack))
+    }
+
+    /// Like `build`, but for a task whose result is kept for
+    /// `Context::take_result`, see `Context::new_with_result`.
+    pub fn build_with_result<T: Send>(self, start: proc() -> T)
+        -> Result<(Context, StackSegment), ContextError>
+    {
+        let mut stack = match self.make_stack() {
+            Ok(stack) => stack,
+            Err(e) => return Err(e),
+        };
+        let ctx = match Context::try_new_with_result(start, &mut stack,
+                                                     self.clean_fp_state) {
+            Ok(ctx) => ctx,
+            Err(e) => return Err(e),
+        };
+        Ok(self.configure(ctx, stack))
+    }
+
+    // Checks the options and allocates a stack which a context can be made on
+    fn make_stack(&self) -> Result<StackSegment, ContextError> {
+        // A scheduler context can't be built this way, it needs no stack
+        if self.stack_size == 0 { return Err(StackTooSmall) }
+        match self.signal_stack_size {
+            Some(size) if size < MIN_SIGNAL_STACK => return Err(StackTooSmall),
+            _ => {}
+        }
+        if self.alignment < STACK_ALIGNMENT ||
+           self.alignment & (self.alignment - 1) != 0 {
+            return Err(BadAlignment)
+        }
+
+        let stack = if self.guard_page {
+            match StackSegment::try_new_guarded(self.stack_size) {
+                Ok(stack) => stack,
+                Err(e) => return Err(e),
+            }
+        } else {
+            match StackSegment::try_new(self.stack_size) {
+                Ok(stack) => stack,
+                Err(e) => return Err(e),
+            }
+        };
+        match validate_stack(&stack) {
+            Ok(()) => {}
+            Err(e) => return Err(e),
+        }
+        // The stricter alignment mustn't leave too little stack either
+        let top = (stack.end() as uint & !(self.alignment - 1)) -
+                  (self.alignment - STACK_ALIGNMENT);
+        if !fits_new_context(stack.start() as uint, top) {
+            return Err(StackTooSmall)
+        }
+        Ok(stack)
+    }
+
+    // Applies the rest of the options to a context made on `stack`
+    fn configure(self, mut ctx: Context, stack: StackSegment)
+        -> (Context, StackSegment)
+    {
+        // The initial frame is laid out the same way below any top that's
+        // `STACK_ALIGNMENT` aligned, so it's built once to see how far off the
+        // stack pointer it leaves is, and then moved down by that much. That's
+        // always a multiple of `STACK_ALIGNMENT`, which the frame has already.
+        if self.alignment > STACK_ALIGNMENT {
+            let top = stack.end() as uint & !(self.alignment - 1);
+            ctx.move_initial_frame(top, self.clean_fp_state);
+            let sp = saved_sp(&ctx.frame.regs) + ENTRY_SP_MISALIGNMENT;
+            let off = sp & (self.alignment - 1);
+            if off != 0 {
+                ctx.move_initial_frame(top - off, self.clean_fp_state);
+            }
+        }
+        match self.overflow_policy {
+            Some(policy) => ctx.set_overflow_policy(policy),
+            None => {}
+        }
+        match self.signal_stack_size {
+            Some(size) => ctx.set_signal_stack(size),
+            None => {}
+        }
+        ctx.set_unwind_on_drop(self.unwind_on_drop);
+        ctx.set_name(self.name);
+        (ctx, stack)
+    }
+}
+
+/// A free list of contexts which have been finished with, so that tasks can be
+/// created and destroyed quickly without allocating a frame for each one.
+///
+/// Contexts are handed out by `take` inside a `PooledContext`, which puts the
+/// context back on the list when it's dropped, unless the list already holds
+/// `capacity` contexts. A pool isn't synchronized and is meant to belong to a
+/// single thread, usually a scheduler's: its contexts can be swapped into on
+/// any thread, but must be dropped on the pool's own.
+pub struct ContextPool {
+    priv free: RefCell<~[Context]>,
+    /// The most contexts which are kept for reuse
+    priv capacity: uint,
+}
+
+impl ContextPool {
+    pub fn new(capacity: uint) -> ContextPool {
+        ContextPool { free: RefCell::new(~[]), capacity: capacity }
+    }
+
+    /// A context that will run `start` on `stack`, just like one from
+    /// `Context::new`, but reusing a context from the free list if there is
+    /// one (see `Context::reset`).
+    ///
+    /// This fails if the stack can't be used for a context, see `try_new`.
+    pub fn take<'a>(&'a self, start: proc(), stack: &mut StackSegment)
+        -> PooledContext<'a>
+    {
+        let ctx = match self.free.with_mut(|free| free.pop_opt()) {
+            // A retired context has no stack left for `reset` to worry about
+            Some(mut ctx) => {
+                unsafe { ctx.reset(start, stack); }
+                ctx
+            }
+            None => Context::new(start, stack),
+        };
+        PooledContext { ctx: Some(ctx), pool: self }
+    }
+
+    /// How many contexts are waiting to be reused
+    pub fn len(&self) -> uint {
+        self.free.with(|free| free.len())
+    }
+
+    // Takes back a context whose owner is done with it
+    fn give(&self, mut ctx: Context) {
+        // A task which wants to be unwound is left to `Context`'s destructor
+        if ctx.unwind_on_drop && ctx.started.get() { return }
+        if self.len() >= self.capacity { return }
+        ctx.retire();
+        self.free.with_mut(|free| free.push(ctx));
+    }
+}
+
+/// A context taken from a `ContextPool`, which goes back to the pool when it's
+/// dropped. Just like dropping a `Context`, dropping this while its task is
+/// suspended doesn't run any of the destructors on the task's stack, unless
+/// the context is set to unwind on drop.
+pub struct PooledContext<'a> {
+    priv ctx: Option<Context>,
+    priv pool: &'a ContextPool,
+}
+
+impl<'a> PooledContext<'a> {
+    pub fn get<'b>(&'b self) -> &'b Context {
+        self.ctx.get_ref()
+    }
+
+    pub fn get_mut<'b>(&'b mut self) -> &'b mut Context {
+        self.ctx.get_mut_ref()
+    }
+}
+
+#[unsafe_destructor]
+impl<'a> Drop for PooledContext<'a> {
+    fn drop(&mut self) {
+        match self.ctx.take() {
+            Some(ctx) => self.pool.give(ctx),
+            None => {}
+        }
+    }
+}
+
+/// Where a new context's initial frame would be placed on a stack, as worked
+/// out by `plan_call_frame`. All values are in bytes.
+pub struct FramePlan {
+    /// The task's initial stack pointer, as an offset from the bottom of the
+    /// stack
+    initial_sp: uint,
+    /// The space at the bottom of the stack which is reserved for the red zone
+    red_zone: uint,
+    /// The ABI's red zone below the stack pointer, `red_zone_size`. It moves
+    /// with the task, but there must always be room for it below the stack
+    /// pointer, so it's left out of `usable` just as `red_zone` is.
+    abi_red_zone: uint,
+    /// The space between the red zones and the initial stack pointer, which
+    /// is what the task actually has to run in
+    usable: uint,
+    /// Whether `Context::new` would accept the stack, which needs `usable` to
+    /// be at least `MIN_STACK_SIZE`
+    fits: bool,
+}
+
+/// Works out where `Context::new` would put the initial frame on a stack of
+/// `stack_size` bytes, without needing the stack itself.
+///
+/// This assumes that the bottom of the stack is aligned to `STACK_ALIGNMENT`,
+/// as it is for a `StackSegment`. It's meant for checking configured stack
+/// sizes up front, such as reporting how much of each stack a task really
+/// gets to use.
+pub fn plan_call_frame(stack_size: uint) -> FramePlan {
+    let top = stack_size & !(STACK_ALIGNMENT - 1);
+    let initial_sp = if top > INITIAL_FRAME_BYTES + CANARY_BYTES {
+        top - INITIAL_FRAME_BYTES - CANARY_BYTES
+    } else {
+        0
+    };
+    let abi_red_zone = red_zone_size();
+    let usable = if initial_sp > stack::RED_ZONE + abi_red_zone {
+        initial_sp - stack::RED_ZONE - abi_red_zone
+    } else {
+        0
+    };
+    FramePlan {
+        initial_sp: initial_sp,
+        red_zone: stack::RED_ZONE,
+        abi_red_zone: abi_red_zone,
+        usable: usable,
+        fits: fits_new_context(0, top),
+    }
+}
+
+/// A small stack set aside for recovering from a stack overflow.
+///
+/// Once a task has overflowed there's no room left on its stack to do anything
+/// about it, and its frames can't be trusted anyway. An overflow handler (for
+/// example a `SIGSEGV` handler running on an alternate signal stack, once it
+/// has recognized a guard page fault) can instead call `rescue`, which
+/// abandons the faulting code and calls a routine on this stack. That routine
+/// can then log what happened, switch to a scheduler, or abort gracefully.
+pub struct RescueStack {
+    priv context: Context,
+    // Owns the memory that the context runs on, and so must outlive it
+    priv stack: StackSegment,
+}
+
+impl RescueStack {
+    /// Sets aside a stack of `size` bytes which `rescue` will call
+    /// `handler(data)` on.
+    ///
+    /// This is unsafe for the same reasons as `Context::new_raw`: `data` must
+    /// stay valid for as long as this rescue stack may be used, and `handler`
+    /// must never return.
+    pub unsafe fn new(size: uint, handler: extern "C" fn(*mut c_void),
+                      data: *mut c_void) -> RescueStack {
+        let mut stack = StackSegment::new(size);
+        let context = Context::new_raw(handler, data, &mut stack);
+        RescueStack { context: context, stack: stack }
+    }
+
+    /// Abandons whatever is currently running, and runs the handler on the
+    /// rescue stack instead. This never returns.
+    ///
+    /// Nothing on the abandoned stack is unwound, and the stack limit is
+    /// lifted before anything else is done, so this can be called from code
+    /// which isn't running on the stack it was last switched to. A rescue
+    /// stack can only be used once; if the handler overflows as well, the
+    /// process is aborted.
+    pub unsafe fn rescue(&self) -> ! {
+        set_unbounded_stack();
+        if self.context.started.get() {
+            rtabort!("overflowed the stack while handling a stack overflow");
+        }
+        // If the overflow happened in the middle of a swap, that swap is never
+        // going to finish.
+        end_swap();
+        let mut abandoned = Context::empty();
+        Context::swap(&mut abandoned, &self.context);
+        rtabort!("a stack overflow handler returned");
+    }
+}
+
+/// The registers of a suspended context which say where it will resume, see
+/// `Context::register_snapshot`
+#[deriving(Eq, Clone)]
+pub struct SavedRegisters {
+    /// The instruction pointer the context resumes at
+    ip: uint,
+    /// The context's stack pointer
+    sp: uint,
+    /// The frame pointer, for walking the context's stack. This is only
+    /// meaningful for code that keeps frame pointers.
+    fp: uint,
+}
+
+/// The reasons that a register snapshot can be refused
+#[deriving(Eq)]
+pub enum SnapshotError {
+    /// The snapshot is truncated or isn't a register snapshot at all
+    InvalidSnapshot,
+    /// The snapshot was made with a different version of the layout
+    UnsupportedVersion,
+    /// The snapshot was made on a different architecture (or OS, where that
+    /// changes which registers are saved)
+    WrongArchitecture,
+}
+
+static SNAPSHOT_MAGIC: &'static [u8] = bytes!("GRSN");
+static SNAPSHOT_VERSION: u32 = 1;
+
+// Tags for `Context::registers_snapshot`. These must never be reused for a
+// different layout; the register count in the header distinguishes the
+// windows and non-windows layouts on x86_64.
+#[cfg(target_arch = "x86")] static SNAPSHOT_ARCH: u32 = 1;
+#[cfg(target_arch = "x86_64")] static SNAPSHOT_ARCH: u32 = 2;
+#[cfg(target_arch = "arm")] static SNAPSHOT_ARCH: u32 = 3;
+#[cfg(target_arch = "mips")] static SNAPSHOT_ARCH: u32 = 4;
+#[cfg(target_arch = "aarch64")] static SNAPSHOT_ARCH: u32 = 5;
+#[cfg(target_arch = "riscv64")] static SNAPSHOT_ARCH: u32 = 6;
+#[cfg(target_arch = "mips64")] static SNAPSHOT_ARCH: u32 = 7;
+// 8 is the ucontext fallback, see `ucontext`
+#[cfg(target_arch = "sparc64")] static SNAPSHOT_ARCH: u32 = 9;
+#[cfg(target_arch = "powerpc64")] static SNAPSHOT_ARCH: u32 = 10;
+#[cfg(target_arch = "s390x")] static SNAPSHOT_ARCH: u32 = 11;
+
+// The number of machine words in `Registers`
+fn register_words() -> uint {
+    mem::size_of::<Registers>() / mem::size_of::<uint>()
+}
+
+fn push_le(out: &mut ~[u8], val: u64, bytes: uint) {
+    for i in range(0, bytes) {
+        out.push((val >> (8 * i)) as u8);
+    }
+}
+
+fn read_le(bytes: &[u8]) -> u64 {
+    bytes.iter().enumerate().fold(0, |val, (i, &b)| val | (b as u64 << (8 * i)))
+}
+
+/// The reasons that setting up a context can fail
+#[deriving(Eq)]
+pub enum ContextError {
+    /// The stack is too small to hold the initial frame and the red zone, with
+    /// `MIN_STACK_SIZE` to spare
+    StackTooSmall,
+    /// Memory for the stack couldn't be allocated
+    AllocFailed,
+    /// The guard page below the stack couldn't be protected
+    GuardPageFailed,
+    /// The stack isn't aligned well enough for the architecture
+    BadAlignment,
+    /// Allocating the stack would go over the budget set with
+    /// `stack::set_stack_budget`
+    StackBudgetExceeded,
+    /// The canary at the top of the context's stack was overwritten, see
+    /// `Context::check_canary`
+    CanaryOverwritten,
+}
+
+impl fmt::Default for ContextError {
+    fn fmt(err: &ContextError, f: &mut fmt::Formatter) {
+        let msg = match *err {
+            StackTooSmall => "stack is too small",
+            AllocFailed => "failed to allocate stack",
+            GuardPageFailed => "failed to protect the stack's guard page",
+            BadAlignment => "stack is misaligned",
+            StackBudgetExceeded => "stack budget exceeded",
+            CanaryOverwritten => "stack canary was overwritten, the stack \
+                                  probably overflowed or underflowed",
+        };
+        write!(f.buf, "{}", msg)
+    }
+}
+
+/// The least stack that a new context is given to run its entry function in,
+/// in bytes. A stack must have at least this much left between its bottom and
+/// its top, once that's aligned to `STACK_ALIGNMENT`, after taking out the red
+/// zone for the stack limit, the initial frame, the canary and the ABI's red
+/// zone. A guard page isn't part of the stack, so it doesn't count either way.
+///
+/// Anything smaller is rejected when the context is made (see
+/// `Context::try_new`) with `StackTooSmall`, rather than having the task
+/// scribble past the bottom of its stack the first time it's resumed.
+pub static MIN_STACK_SIZE: uint = 4 * 1024;
+
+// The bytes at either end of a stack which a new context can't run in
+fn validate_reserved() -> uint {
+    stack::RED_ZONE + INITIAL_FRAME_BYTES + CANARY_BYTES + red_zone_size()
+}
+
+/// The largest red zone which `set_red_zone_size` accepts
+pub static MAX_RED_ZONE_SIZE: uint = 1024;
+
+// The size given to `set_red_zone_size` plus one, 0 if it hasn't been called
+static mut RED_ZONE_OVERRIDE: AtomicUint = INIT_ATOMIC_UINT;
+
+/// Sets how many bytes below its stack pointer the code run by contexts
+/// created from now on may use without moving it, in place of the ABI's
+/// `RED_ZONE_SIZE` (128 bytes on System V x86_64, 0 on windows and most other
+/// architectures). This is for linking tasks against code built with a
+/// non-standard red zone, such as gcc's `-mno-red-zone`.
+///
+/// The red zone lies below the stack pointer, so the initial frame is placed
+/// the same way whatever its size. What it changes is how much stack below
+/// that frame a new context must have to spare (see `MIN_STACK_SIZE`), and
+/// what `plan_call_frame` reports.
+///
+/// This must match how the tasks' code was really compiled: a red zone larger
+/// than the one set here can run off the bottom of a stack which was only just
+/// big enough, silently corrupting whatever is below it. `bytes` must be a
+/// multiple of the word size no larger than `MAX_RED_ZONE_SIZE`, otherwise this
+/// fails.
+pub fn set_red_zone_size(bytes: uint) {
+    if bytes > MAX_RED_ZONE_SIZE || bytes % mem::size_of::<uint>() != 0 {
+        fail!("invalid red zone size {}, it must be a multiple of {} no \
+               larger than {}", bytes, mem::size_of::<uint>(),
+              MAX_RED_ZONE_SIZE);
+    }
+    unsafe { RED_ZONE_OVERRIDE.store(bytes + 1, SeqCst) }
+}
+
+/// The red zone new contexts are given, `RED_ZONE_SIZE` unless it's been
+/// changed with `set_red_zone_size`
+pub fn red_zone_size() -> uint {
+    match unsafe { RED_ZONE_OVERRIDE.load(Relaxed) } {
+        0 => RED_ZONE_SIZE,
+        n => n - 1,
+    }
+}
+
+// Whether a new context fits between `lo` and the already aligned `top`
+fn fits_new_context(lo: uint, top: uint) -> bool {
+    top >= lo && top - lo >= validate_reserved() + MIN_STACK_SIZE
+}
+
+// Checks that a stack is usable for a new context. Zero-length stacks are
+// always allowed through, they're how scheduler contexts are represented.
+//
+// Besides the red zone for the stack limit, the entry function needs room for
+// the ABI's red zone below its initial frame.
+fn validate_stack(stack: &StackSegment) -> Result<(), ContextError> {
+    let (lo, hi) = (stack.start() as uint, stack.end() as uint);
+    if lo == hi { return Ok(()) }
+    if !fits_new_context(lo, hi & !(STACK_ALIGNMENT - 1)) {
+        return Err(StackTooSmall)
+    }
+    if hi % mem::size_of::<uint>() != 0 { return Err(BadAlignment) }
+    Ok(())
+}
+
+impl fmt::Default for Context {
+    fn fmt(ctx: &Context, f: &mut fmt::Formatter) {
+        write!(f.buf, "Context \\{ scheduler: {}, stack_bounds: {:?}, \
+                       has_start: {}",
+               ctx.is_scheduler(), ctx.stack_bounds,
+               ctx.frame.start.is_some());
+        // Register values are mostly noise outside of runtime debugging
+        if cfg!(rtdebug) {
+            write!(f.buf, ", ip: {:#x}, sp: {:#x}",
+                   saved_ip(&ctx.frame.regs), saved_sp(&ctx.frame.regs));
+        }
+        write!(f.buf, " \\}");
+    }
+}
+
+// Bookkeeping for `Context::suspended_time`, `Context::running_time` and
+// `Context::recent_switch_rate`. The counters are cells because the incoming
+// context of a swap is only borrowed immutably.
+struct SwitchTiming {
+    /// When this context was last swapped in or out, 0 if never timed
+    last_switch: Cell<u64>,
+    suspended: Cell<u64>,
+    running: Cell<u64>,
+    /// When the current rate window started
+    window_start: Cell<u64>,
+    /// How many times this context was swapped in during the current window,
+    /// and during the one before it
+    window_resumes: Cell<uint>,
+    prev_window_resumes: Cell<uint>,
+}
+
+// The length of the window which switch rates are measured over
+static RATE_WINDOW_NS: u64 = 1000000000;
+
+impl SwitchTiming {
+    fn new() -> SwitchTiming {
+        let now = if cfg!(context_timing) {time::precise_time_ns()} else {0};
+        SwitchTiming {
+            last_switch: Cell::new(now),
+            suspended: Cell::new(0),
+            running: Cell::new(0),
+            window_start: Cell::new(now),
+            window_resumes: Cell::new(0),
+            prev_window_resumes: Cell::new(0),
+        }
+    }
+
+    fn suspend(&self, now: u64) {
+        let last = self.last_switch.get();
+        if last != 0 {
+            self.running.set(self.running.get() + (now - last));
+        }
+        self.last_switch.set(now);
+    }
+
+    fn resume(&self, now: u64) {
+        let last = self.last_switch.get();
+        if last != 0 {
+            self.suspended.set(self.suspended.get() + (now - last));
+        }
+        self.last_switch.set(now);
+        self.roll_window(now);
+        self.window_resumes.set(self.window_resumes.get() + 1);
+    }
+
+    // Starts a new window if the current one is over, possibly skipping some
+    // which had no resumes at all
+    fn roll_window(&self, now: u64) {
+        let start = self.window_start.get();
+        if now < start + RATE_WINDOW_NS { return }
+        let windows = (now - start) / RATE_WINDOW_NS;
+        let prev = if windows == 1 { self.window_resumes.get() } else { 0 };
+        self.prev_window_resumes.set(prev);
+        self.window_resumes.set(0);
+        self.window_start.set(start + windows * RATE_WINDOW_NS);
+    }
+
+    // Resumes per second over the last window's length. The part of the
+    // previous window which is still in range is assumed to have had its
+    // resumes spread evenly.
+    fn rate(&self, now: u64) -> f64 {
+        self.roll_window(now);
+        let into = (now - self.window_start.get()) as f64;
+        let window = RATE_WINDOW_NS as f64;
+        let prev = self.prev_window_resumes.get() as f64 * (window - into) / window;
+        (prev + self.window_resumes.get() as f64) * 1e9 / window
+    }
+}
+
+// When stack protection is in use, functions check a cookie stored in TLS on
+// return. Frames on a suspended context's stack were created with the cookie
+// that was live at the time, so the outgoing cookie is remembered in the
+// outgoing context (to be reinstated when it's resumed) before the incoming
+// context's cookie, if it has one, is installed.
+#[cfg(stack_protector, target_os = "linux", target_arch = "x86_64")]
+#[cfg(stack_protector, target_os = "linux", target_arch = "x86")]
+#[inline(always)]
+unsafe fn swap_stack_guard(out_context: &mut Context, in_context: &Context) {
+    out_context.stack_guard = Some(get_stack_guard());
+    match in_context.stack_guard {
+        Some(cookie) => set_stack_guard(cookie),
+        None => {}
+    }
+
+    #[cfg(target_arch = "x86_64")] #[inline(always)]
+    unsafe fn get_stack_guard() -> uint {
+        let cookie;
+        asm!("movq %fs:0x28, $0" : "=r"(cookie) ::: "volatile");
+        return cookie;
+    }
+    #[cfg(target_arch = "x86_64")] #[inline(always)]
+    unsafe fn set_stack_guard(cookie: uint) {
+        asm!("movq $0, %fs:0x28" :: "r"(cookie) :: "volatile")
+    }
+    #[cfg(target_arch = "x86")] #[inline(always)]
+    unsafe fn get_stack_guard() -> uint {
+        let cookie;
+        asm!("movl %gs:0x14, $0" : "=r"(cookie) ::: "volatile");
+        return cookie;
+    }
+    #[cfg(target_arch = "x86")] #[inline(always)]
+    unsafe fn set_stack_guard(cookie: uint) {
+        asm!("movl $0, %gs:0x14" :: "r"(cookie) :: "volatile")
+    }
+}
+
+#[cfg(not(stack_protector))]
+#[cfg(not(target_os = "linux"))]
+#[cfg(not(target_arch = "x86_64"), not(target_arch = "x86"))]
+#[inline(always)]
+unsafe fn swap_stack_guard(_out_context: &mut Context, _in_context: &Context) {}
+
+// The same as `swap_stack_guard`, for the bases from `set_segment_bases`
+#[cfg(segment_bases, target_os = "linux", target_arch = "x86_64")]
+#[inline(always)]
+unsafe fn swap_segment_bases(out_context: &mut Context, in_context: &Context) {
+    out_context.segment_bases = Some(segment_bases::get());
+    match in_context.segment_bases {
+        Some((fs, gs)) => segment_bases::set(fs, gs),
+        None => {}
+    }
+}
+
+#[cfg(not(segment_bases))]
+#[cfg(not(target_os = "linux"))]
+#[cfg(not(target_arch = "x86_64"))]
+#[inline(always)]
+unsafe fn swap_segment_bases(_out_context: &mut Context,
+                             _in_context: &Context) {}
+
+/// The FS and GS base addresses of the calling thread, or `None` if they
+/// aren't swapped (see `Context::set_segment_bases`).
+#[cfg(segment_bases, target_os = "linux", target_arch = "x86_64")]
+pub fn current_segment_bases() -> Option<(uint, uint)> {
+    unsafe { Some(segment_bases::get()) }
+}
+
+/// The FS and GS base addresses of the calling thread, or `None` if they
+/// aren't swapped (see `Context::set_segment_bases`).
+#[cfg(not(segment_bases))]
+#[cfg(not(target_os = "linux"))]
+#[cfg(not(target_arch = "x86_64"))]
+pub fn current_segment_bases() -> Option<(uint, uint)> { None }
+
+#[cfg(segment_bases, target_os = "linux", target_arch = "x86_64")]
+mod segment_bases {
+    use std::libc::c_long;
+
+    #[cfg(fsgsbase)] #[inline(always)]
+    pub unsafe fn get() -> (uint, uint) {
+        let fs;
+        let gs;
+        asm!("rdfsbase $0" : "=r"(fs) ::: "volatile");
+        asm!("rdgsbase $0" : "=r"(gs) ::: "volatile");
+        (fs, gs)
+    }
+
+    #[cfg(fsgsbase)] #[inline(always)]
+    pub unsafe fn set(fs: uint, gs: uint) {
+        asm!("wrfsbase $0" :: "r"(fs) :: "volatile");
+        asm!("wrgsbase $0" :: "r"(gs) :: "volatile");
+    }
+
+    static SYS_ARCH_PRCTL: c_long = 158;
+    static ARCH_SET_GS: c_long = 0x1001;
+    static ARCH_SET_FS: c_long = 0x1002;
+    static ARCH_GET_FS: c_long = 0x1003;
+    static ARCH_GET_GS: c_long = 0x1004;
+
+    #[cfg(not(fsgsbase))] #[inline(always)]
+    pub unsafe fn get() -> (uint, uint) {
+        let (mut fs, mut gs) = (0u, 0u);
+        syscall(SYS_ARCH_PRCTL, ARCH_GET_FS, &mut fs as *mut uint);
+        syscall(SYS_ARCH_PRCTL, ARCH_GET_GS, &mut gs as *mut uint);
+        (fs, gs)
+    }
+
+    #[cfg(not(fsgsbase))] #[inline(always)]
+    pub unsafe fn set(fs: uint, gs: uint) {
+        syscall(SYS_ARCH_PRCTL, ARCH_SET_FS, fs);
+        syscall(SYS_ARCH_PRCTL, ARCH_SET_GS, gs);
+    }
+
+    extern {
+        fn syscall(number: c_long, ...) -> c_long;
+    }
+}
+
+#[cfg(unix)]
+fn current_thread_id() -> ThreadId {
+    extern { fn pthread_self() -> ThreadId; }
+    unsafe { pthread_self() }
+}
+
+#[cfg(windows)]
+fn current_thread_id() -> ThreadId {
+    extern "system" { fn GetCurrentThreadId() -> u32; }
+    unsafe { GetCurrentThreadId() as ThreadId }
+}
+
+// Switching back and forth between two contexts on the same stack, or between
+// two without a stack, would otherwise record the same bounds on every swap.
+// Outside of windows the limit is all that's recorded, so there's nothing to
+// do if it's already right. Like `record_stack_bounds` these must be inlined.
+#[cfg(not(windows), not(bare_metal))] #[inline(always)]
+unsafe fn bounds_recorded(slots: (*mut uint, *mut uint), lo: uint) -> bool {
+    let (sp_limit, _) = slots;
+    stack::get_sp_limit_at(sp_limit) == lo + stack::RED_ZONE
+}
+#[cfg(windows, not(bare_metal))] #[inline(always)]
+unsafe fn bounds_recorded(_slots: (*mut uint, *mut uint), _lo: uint) -> bool {
+    false
+}
+#[cfg(bare_metal)] #[inline(always)]
+unsafe fn bounds_recorded(_slots: (*mut uint, *mut uint), _lo: uint) -> bool {
+    true
+}
+
+// Without an OS (`--cfg bare_metal`) there's no TLS slot for the stack limit,
+// and nothing which would read one, so stack bounds are never recorded, and
+// contexts can run on stacks the embedder allocated itself (see
+// `StackSegment::from_raw`). That's all `bare_metal` changes. libgreen still
+// links `std` (there's no libcore to build it on instead), and a `Context`
+// still boxes its frame and keeps thread-local state such as
+// `ON_GREEN_STACK`; see the `raw` module for switching without either.
+#[inline(always)]
+unsafe fn record_bounds(lo: uint, hi: uint) {
+    record_bounds_at((ptr::mut_null(), ptr::mut_null()), lo, hi)
+}
+
+// Like `record_bounds`, writing straight through the addresses from
+// `bounds_slots` unless they're null
+#[cfg(not(bare_metal))] #[inline(always)]
+unsafe fn record_bounds_at(slots: (*mut uint, *mut uint), lo: uint, hi: uint) {
+    let (sp_limit, stack_hi) = slots;
+    stack::record_stack_bounds_at(sp_limit, lo, hi);
+    if stack_hi.is_null() { record_stack_hi(hi) } else { *stack_hi = hi }
+}
+#[cfg(bare_metal)] #[inline(always)]
+unsafe fn record_bounds_at(_slots: (*mut uint, *mut uint), _lo: uint,
+                           _hi: uint) {}
+
+// Where the calling thread keeps the stack limit and `STACK_HI`, either of
+// which is null if it has to be recorded some other way
+#[cfg(not(bare_metal))]
+unsafe fn bounds_slots() -> (*mut uint, *mut uint) {
+    (stack::sp_limit_slot(), stack_hi_slot())
+}
+#[cfg(bare_metal)]
+unsafe fn bounds_slots() -> (*mut uint, *mut uint) {
+    (ptr::mut_null(), ptr::mut_null())
+}
+
+// SEH and parts of the CRT check the stack pointer against the TIB's stack
+// base and limit, which `record_bounds` points at a task's stack. A context
+// without bounds of its own (normally a scheduler on the thread's stack) keeps
+// whatever the TIB held when it was left and puts it back when it's resumed,
+// rather than being given the unbounded range that `set_unbounded_stack`
+// records. These must be inlined for the same reason as `record_bounds`.
+//
+// The TEB's deallocation stack, the bottom of the reservation which the OS
+// commits a stack from on a guard page fault, goes along with them. It's
+// undocumented, but it's where the OS itself (and every fiber switch) keeps
+// it in each version of windows.
+#[cfg(windows, target_arch = "x86_64")] #[inline(always)]
+unsafe fn save_native_tib(context: &Context) {
+    let base: uint;
+    let limit: uint;
+    let dealloc: uint;
+    asm!("movq %gs:0x08, $0" : "=r"(base) ::: "volatile");
+    asm!("movq %gs:0x10, $0" : "=r"(limit) ::: "volatile");
+    asm!("movq %gs:0x1478, $0" : "=r"(dealloc) ::: "volatile");
+    context.native_tib.set(Some((base, limit, dealloc)));
+}
+#[cfg(windows, target_arch = "x86_64")] #[inline(always)]
+unsafe fn restore_native_tib(context: &Context) {
+    match context.native_tib.get() {
+        Some((base, limit, dealloc)) => {
+            asm!("movq $0, %gs:0x08" :: "r"(base) :: "volatile");
+            asm!("movq $0, %gs:0x10" :: "r"(limit) :: "volatile");
+            asm!("movq $0, %gs:0x1478" :: "r"(dealloc) :: "volatile");
+        }
+        None => {}
+    }
+}
+#[cfg(windows, target_arch = "x86")] #[inline(always)]
+unsafe fn save_native_tib(context: &Context) {
+    let base: uint;
+    let limit: uint;
+    let dealloc: uint;
+    asm!("movl %fs:0x04, $0" : "=r"(base) ::: "volatile");
+    asm!("movl %fs:0x08, $0" : "=r"(limit) ::: "volatile");
+    asm!("movl %fs:0xe0c, $0" : "=r"(dealloc) ::: "volatile");
+    context.native_tib.set(Some((base, limit, dealloc)));
+}
+#[cfg(windows, target_arch = "x86")] #[inline(always)]
+unsafe fn restore_native_tib(context: &Context) {
+    match context.native_tib.get() {
+        Some((base, limit, dealloc)) => {
+            asm!("movl $0, %fs:0x04" :: "r"(base) :: "volatile");
+            asm!("movl $0, %fs:0x08" :: "r"(limit) :: "volatile");
+            asm!("movl $0, %fs:0xe0c" :: "r"(dealloc) :: "volatile");
+        }
+        None => {}
+    }
+}
+#[cfg(not(windows))] #[inline(always)]
+unsafe fn save_native_tib(_context: &Context) {}
+#[cfg(not(windows))] #[inline(always)]
+unsafe fn restore_native_tib(_context: &Context) {}
+
+// Only the low end of the stack goes in the OS's TLS slot, so the high end is
+// kept alongside it for `current_stack_bounds`. Windows has both in the TIB.
+#[cfg(not(windows), not(target_os = "android"))]
+#[thread_local]
+static mut STACK_HI: uint = 0;
+
+#[cfg(not(windows), not(target_os = "android"))] #[inline(always)]
+unsafe fn record_stack_hi(hi: uint) { STACK_HI = hi }
+#[cfg(not(windows), not(target_os = "android"))] #[inline(always)]
+unsafe fn recorded_stack_hi() -> uint { STACK_HI }
+#[cfg(not(windows), not(target_os = "android"))]
+unsafe fn stack_hi_slot() -> *mut uint { &mut STACK_HI as *mut uint }
+
+#[cfg(windows)]
+#[cfg(target_os = "android")] #[inline(always)]
+unsafe fn record_stack_hi(_hi: uint) {}
+#[cfg(target_os = "android")] #[inline(always)]
+unsafe fn recorded_stack_hi() -> uint { 0 }
+#[cfg(windows)]
+#[cfg(target_os = "android")]
+unsafe fn stack_hi_slot() -> *mut uint { ptr::mut_null() }
+#[cfg(windows, target_arch = "x86_64")] #[inline(always)]
+unsafe fn recorded_stack_hi() -> uint {
+    let hi;
+    asm!("movq %gs:0x08, $0" : "=r"(hi) ::: "volatile");
+    hi
+}
+#[cfg(windows, target_arch = "x86")] #[inline(always)]
+unsafe fn recorded_stack_hi() -> uint {
+    let hi;
+    asm!("movl %fs:0x04, $0" : "=r"(hi) ::: "volatile");
+    hi
+}
+
+// The running context's `local`, published by every swap alongside the stack
+// bounds
+#[cfg(not(windows), not(target_os = "android"))]
+#[thread_local]
+static mut TASK_LOCAL: *mut c_void = 0 as *mut c_void;
+
+#[cfg(not(windows), not(target_os = "android"))] #[inline(always)]
+unsafe fn record_task_local(local: *mut c_void) { TASK_LOCAL = local }
+
+/// The task-local pointer of the context running on this thread, see
+/// `Context::set_local`. This is null for contexts without one, and always
+/// null on windows and android, which lack the compiled thread-local support
+/// this needs.
+#[cfg(not(windows), not(target_os = "android"))]
+pub fn current_task_local() -> *mut c_void {
+    unsafe { TASK_LOCAL }
+}
+
+#[cfg(windows)]
+#[cfg(target_os = "android")] #[inline(always)]
+unsafe fn record_task_local(_local: *mut c_void) {}
+
+/// The task-local pointer of the context running on this thread, see
+/// `Context::set_local`. This is null for contexts without one, and always
+/// null on windows and android, which lack the compiled thread-local support
+/// this needs.
+#[cfg(windows)]
+#[cfg(target_os = "android")]
+pub fn current_task_local() -> *mut c_void { 0 as *mut c_void }
+
+// Whether the running context has a stack of its own, see `on_green_stack`
+#[cfg(not(windows), not(target_os = "android"))]
+#[thread_local]
+static mut ON_GREEN_STACK: bool = false;
+
+#[cfg(not(windows), not(target_os = "android"))] #[inline(always)]
+unsafe fn record_on_green_stack(green: bool) { ON_GREEN_STACK = green }
+#[cfg(windows)]
+#[cfg(target_os = "android")] #[inline(always)]
+unsafe fn record_on_green_stack(_green: bool) {}
+
+// The running context's `overflow_policy`, published along with its `local`
+// for the overflow checks which only have the thread to go on
+#[cfg(not(windows), not(target_os = "android"))]
+#[thread_local]
+static mut RUNNING_POLICY: Option<OverflowPolicy> = None;
+
+#[cfg(not(windows), not(target_os = "android"))] #[inline(always)]
+unsafe fn record_overflow_policy(policy: Option<OverflowPolicy>) {
+    RUNNING_POLICY = policy
+}
+#[cfg(not(windows), not(target_os = "android"))] #[inline(always)]
+fn running_overflow_policy() -> Option<OverflowPolicy> {
+    unsafe { RUNNING_POLICY }
+}
+
+#[cfg(windows)]
+#[cfg(target_os = "android")] #[inline(always)]
+unsafe fn record_overflow_policy(_policy: Option<OverflowPolicy>) {}
+#[cfg(windows)]
+#[cfg(target_os = "android")] #[inline(always)]
+fn running_overflow_policy() -> Option<OverflowPolicy> { None }
+
+/// The bounds of the calling thread's own stack, as the C library describes
+/// them, for `Context::set_native_bounds`. They cover the whole stack, so
+/// `lo` may be the start of a guard page rather than of usable memory. This
+/// is `None` where the thread's stack can't be looked up, which is anywhere
+/// but linux, android, macos and freebsd.
+#[cfg(target_os = "linux")]
+#[cfg(target_os = "android")]
+pub fn thread_stack_bounds() -> Option<(uint, uint)> {
+    extern {
+        fn pthread_self() -> ThreadId;
+        fn pthread_getattr_np(thread: ThreadId, attr: *mut c_void) -> c_int;
+        fn pthread_attr_getstack(attr: *c_void, addr: *mut *c_void,
+                                 size: *mut size_t) -> c_int;
+        fn pthread_attr_destroy(attr: *mut c_void) -> c_int;
+    }
+    // pthread_attr_t is opaque, and no larger than this anywhere
+    let mut attr = [0u64, ..16];
+    let attr = attr.as_mut_ptr() as *mut c_void;
+    let mut addr = 0 as *c_void;
+    let mut size = 0 as size_t;
+    unsafe {
+        if pthread_getattr_np(pthread_self(), attr) != 0 { return None }
+        let ret = pthread_attr_getstack(attr as *c_void, &mut addr, &mut size);
+        pthread_attr_destroy(attr);
+        if ret != 0 { return None }
+    }
+    Some((addr as uint, addr as uint + size as uint))
+}
+
+/// See the linux version
+#[cfg(target_os = "macos")]
+pub fn thread_stack_bounds() -> Option<(uint, uint)> {
+    extern {
+        fn pthread_self() -> ThreadId;
+        // The top of the stack, not its start
+        fn pthread_get_stackaddr_np(thread: ThreadId) -> *c_void;
+        fn pthread_get_stacksize_np(thread: ThreadId) -> size_t;
+    }
+    unsafe {
+        let hi = pthread_get_stackaddr_np(pthread_self()) as uint;
+        let size = pthread_get_stacksize_np(pthread_self()) as uint;
+        Some((hi - size, hi))
+    }
+}
+
+/// See the linux version
+#[cfg(target_os = "freebsd")]
+pub fn thread_stack_bounds() -> Option<(uint, uint)> {
+    extern {
+        fn pthread_self() -> ThreadId;
+        fn pthread_attr_init(attr: *mut c_void) -> c_int;
+        // Unlike pthread_getattr_np, this fills in an attribute object which
+        // must already have been initialized
+        fn pthread_attr_get_np(thread: ThreadId, attr: *mut c_void) -> c_int;
+        fn pthread_attr_getstack(attr: *c_void, addr: *mut *c_void,
+                                 size: *mut size_t) -> c_int;
+        fn pthread_attr_destroy(attr: *mut c_void) -> c_int;
+    }
+    // A pthread_attr_t is just a pointer here, to what pthread_attr_init
+    // allocates
+    let mut attr = 0 as *c_void;
+    let attr = &mut attr as *mut *c_void as *mut c_void;
+    let mut addr = 0 as *c_void;
+    let mut size = 0 as size_t;
+    unsafe {
+        if pthread_attr_init(attr) != 0 { return None }
+        let mut ret = pthread_attr_get_np(pthread_self(), attr);
+        if ret == 0 {
+            ret = pthread_attr_getstack(attr as *c_void, &mut addr, &mut size);
+        }
+        pthread_attr_destroy(attr);
+        if ret != 0 { return None }
+    }
+    Some((addr as uint, addr as uint + size as uint))
+}
+
+/// See the linux version
+#[cfg(not(target_os = "linux"), not(target_os = "android"),
+      not(target_os = "macos"), not(target_os = "freebsd"))]
+pub fn thread_stack_bounds() -> Option<(uint, uint)> { None }
+
+/// Removes the stack limit for the running code, so that morestack never
+/// reports an overflow.
+///
+/// This is what a swap into a context without stack bounds (such as a
+/// scheduler running on its thread's native stack) does. It's unsafe for the
+/// same reason as `stack::record_stack_bounds`.
+#[inline(always)]
+pub unsafe fn set_unbounded_stack() {
+    record_bounds(0, uint::max_value)
+}
+
+/// The number of bytes of stack that the running code can still use before
+/// morestack reports an overflow, or `uint::max_value` if the stack limit has
+/// been removed with `set_unbounded_stack`.
+pub fn stack_headroom() -> uint {
+    let limit = unsafe { stack::get_sp_limit() };
+    // An unbounded stack records a lower bound of 0, which leaves nothing but
+    // the red zone in the limit
+    if limit <= stack::RED_ZONE { return uint::max_value }
+    let here = 0u;
+    let sp = &here as *uint as uint;
+    if sp > limit { sp - limit } else { 0 }
+}
+
+/// The number of bytes between the running code's stack pointer and the low
+/// end of its stack, as recorded in `current_stack_bounds`. This counts the
+/// red zone that morestack keeps for itself, so it's `stack::RED_ZONE` more
+/// than `stack_headroom`, and the running code can't actually use all of it.
+///
+/// Without recorded bounds (on a scheduler's stack, say) this is
+/// `uint::max_value`.
+pub fn stack_remaining() -> uint {
+    match current_stack_bounds() {
+        Some((lo, _)) => {
+            let here = 0u;
+            let sp = &here as *uint as uint;
+            if sp > lo { sp - lo } else { 0 }
+        }
+        None => uint::max_value,
+    }
+}
+
+/// The bounds of the stack the running code is on, as they were last recorded
+/// for morestack, or `None` if the stack limit has been removed (as it is
+/// while a scheduler runs, unless it was given `Context::set_native_bounds`).
+/// The remaining headroom is the current stack pointer minus `lo`, less
+/// `stack::RED_ZONE`, see also `stack_headroom`.
+///
+/// The high end is `uint::max_value` if it isn't known: on android, where it
+/// isn't kept anywhere, and for bounds that were recorded by something other
+/// than libgreen (such as the native runtime) outside of windows.
+///
+/// This only reads a couple of thread-local words, so it can be called from a
+/// signal handler to find the bounds of the stack a fault happened on (see
+/// `stack_bounds_for_limit` for one which has had to clear the limit).
+#[cfg(not(bare_metal))]
+pub fn current_stack_bounds() -> Option<(uint, uint)> {
+    stack_bounds_for_limit(unsafe { stack::get_sp_limit() })
+}
+
+/// The bounds that `current_stack_bounds` would give if `limit` were the
+/// recorded stack limit. A signal handler running on an alternate stack has to
+/// clear the limit before it can call any split-stack function, and this
+/// finds the bounds from the limit it saved.
+#[cfg(not(bare_metal))]
+pub fn stack_bounds_for_limit(limit: uint) -> Option<(uint, uint)> {
+    if limit <= stack::RED_ZONE { return None }
+    let lo = limit - stack::RED_ZONE;
+    let hi = match unsafe { recorded_stack_hi() } {
+        hi if hi > lo => hi,
+        _ => uint::max_value,
+    };
+    Some((lo, hi))
+}
+
+/// Without an OS no bounds are ever recorded, see `record_bounds`
+#[cfg(bare_metal)]
+pub fn current_stack_bounds() -> Option<(uint, uint)> {
+    None
+}
+
+/// Without an OS no bounds are ever recorded, see `record_bounds`
+#[cfg(bare_metal)]
+pub fn stack_bounds_for_limit(_limit: uint) -> Option<(uint, uint)> {
+    None
+}
+
+/// Whether the running code is a green task, that is whether the last swap on
+/// this thread was into a context with a stack of its own. This is false for a
+/// scheduler (even one given its thread's bounds with
+/// `Context::set_native_bounds`) and on any thread which has never swapped
+/// into a context, such as those of the native runtime.
+///
+/// Windows and android lack the compiled thread-local support this is kept
+/// in. There it falls back on whether the running code has finite stack
+/// bounds recorded, which is also true of native threads and of schedulers
+/// with native bounds.
+#[cfg(not(windows), not(target_os = "android"))]
+pub fn on_green_stack() -> bool {
+    unsafe { ON_GREEN_STACK }
+}
+
+/// Whether the running code is a green task, that is whether the last swap on
+/// this thread was into a context with a stack of its own. This is false for a
+/// scheduler (even one given its thread's bounds with
+/// `Context::set_native_bounds`) and on any thread which has never swapped
+/// into a context, such as those of the native runtime.
+///
+/// Windows and android lack the compiled thread-local support this is kept
+/// in. There it falls back on whether the running code has finite stack
+/// bounds recorded, which is also true of native threads and of schedulers
+/// with native bounds.
+#[cfg(windows)]
+#[cfg(target_os = "android")]
+pub fn on_green_stack() -> bool {
+    unsafe { stack::get_sp_limit() > stack::RED_ZONE }
+}
+
+#[link(name = "rustrt", kind = "static")]
+extern {
+    fn rust_swap_registers(out_regs: *mut Registers, in_regs: *Registers);
+    #[cfg(target_arch = "x86_64")]
+    fn rust_swap_registers_gp(out_regs: *mut Registers, in_regs: *Registers);
+    fn rust_valgrind_make_mem_undefined(start: *c_void, len: size_t);
+}
+
+// Records the stack which AddressSanitizer says `from` was running on, if it
+// doesn't have bounds anyone could have told the sanitizer about. This is how
+// scheduler contexts, which run on the thread's own stack, get a region to be
+// swapped back into: they're always swapped out of before they're resumed.
+fn note_asan_stack(from: *Context, region: (uint, uint)) {
+    if from.is_null() { return }
+    let from = unsafe { &*from };
+    if from.is_scheduler() {
+        from.asan_stack.set(region);
+    }
+}
+
+// The fiber switching interface from AddressSanitizer's
+// `common_interface_defs.h`. `start_switch` is called on the way out of a
+// context with the stack that's about to be used, and `finish_switch` once
+// the switch has landed, returning the stack that was left.
+//
+// Passing no place to save the fake stack to `start_switch` means the context
+// being left is gone for good, and passing a null fake stack to
+// `finish_switch` means there's none to restore (the context is brand new).
+#[cfg(asan)]
+mod asan {
+    use std::libc::{c_void, size_t};
+    use std::ptr;
+
+    extern {
+        fn __sanitizer_start_switch_fiber(fake_stack_save: *mut *c_void,
+                                          bottom: *c_void, size: size_t);
+        fn __sanitizer_finish_switch_fiber(fake_stack_save: *c_void,
+                                           bottom_old: *mut *c_void,
+                                           size_old: *mut size_t);
+    }
+
+    #[inline(always)]
+    pub unsafe fn start_switch(save: Option<&mut *c_void>,
+                               (lo, hi): (uint, uint)) {
+        let save = match save {
+            Some(save) => save as *mut *c_void,
+            None => ptr::mut_null(),
+        };
+        __sanitizer_start_switch_fiber(save, lo as *c_void,
+                                       (hi - lo) as size_t);
+    }
+
+    #[inline(always)]
+    pub unsafe fn finish_switch(fake_stack: *c_void) -> (uint, uint) {
+        let mut bottom = 0 as *c_void;
+        let mut size = 0 as size_t;
+        __sanitizer_finish_switch_fiber(fake_stack, &mut bottom, &mut size);
+        (bottom as uint, bottom as uint + size as uint)
+    }
+}
+
+#[cfg(not(asan))]
+mod asan {
+    use std::libc::c_void;
+
+    #[inline(always)]
+    pub unsafe fn start_switch(_save: Option<&mut *c_void>,
+                               _region: (uint, uint)) {}
+
+    #[inline(always)]
+    pub unsafe fn finish_switch(_fake_stack: *c_void) -> (uint, uint) {
+        (0, 0)
+    }
+}
+
+// ThreadSanitizer's annotations for synchronization it can't see, from
+// `sanitizer/tsan_interface.h`
+#[cfg(tsan)]
+mod tsan {
+    use std::libc::c_void;
+
+    extern {
+        fn __tsan_acquire(addr: *c_void);
+        fn __tsan_release(addr: *c_void);
+    }
+
+    #[inline(always)]
+    pub unsafe fn acquire(addr: *c_void) { __tsan_acquire(addr) }
+
+    #[inline(always)]
+    pub unsafe fn release(addr: *c_void) { __tsan_release(addr) }
+}
+
+#[cfg(not(tsan))]
+mod tsan {
+    use std::libc::c_void;
+
+    #[inline(always)]
+    pub unsafe fn acquire(_addr: *c_void) {}
+
+    #[inline(always)]
+    pub unsafe fn release(_addr: *c_void) {}
+}
+
+// Only x86_64 saves any floating point state in `rust_swap_registers`, so
+// everywhere else the general purpose swap is the full swap.
+#[cfg(target_arch = "x86_64")] #[inline(always)]
+unsafe fn swap_gp_registers(out_regs: *mut Registers, in_regs: *Registers) {
+    rust_swap_registers_gp(out_regs, in_regs)
+}
+#[cfg(not(target_arch = "x86_64"))] #[inline(always)]
+unsafe fn swap_gp_registers(out_regs: *mut Registers, in_regs: *Registers) {
+    rust_swap_registers(out_regs, in_regs)
+}
+
+// Register contexts used in various architectures
+//
+// These structures all represent a context of one task throughout its
+// execution. Each struct is a representation of the architecture's register
+// set. When swapping between tasks, these register sets are used to save off
+// the current registers into one struct, and load them all from another.
+//
+// Note that this is only used for context switching, which means that some of
+// the registers may go unused. For example, for architectures with
+// callee/caller saved registers, the context will only reflect the callee-saved
+// registers. This is because the caller saved registers are already stored
+// elsewhere on the stack (if it was necessary anyway).
+//
+// Additionally, there may be fields on various architectures which are unused
+// entirely because they only reflect what is theoretically possible for a
+// "complete register set" to show, but user-space cannot alter these registers.
+// An example of this would be the segment selectors for x86.
+//
+// These structures/functions are roughly in-sync with the source files inside
+// of src/rt/arch/$arch. The only currently used function from those folders is
+// the `rust_swap_registers` function, but that's only because for now segmented
+// stacks are disabled.
+//
+// Nothing but these definitions keeps `Registers` as large as the assembly
+// expects it to be, and an index out of step with it is silent corruption of
+// whatever follows the registers in a `Context`. So every architecture with an
+// array of registers names the slots of its assembly's layout which the code
+// here indexes with, works out from those how many slots the assembly
+// addresses (from the last register it saves) and the highest slot that
+// `initialize_call_frame` writes, and both are checked here at compile time
+// against the `SAVED_REGISTER_COUNT` that sizes the array. The x86 and x86_64
+// layouts are instead checked in the tests against the size which their
+// assembly exports as `rust_swap_registers_bytes`, since the constants here
+// would only be compared with copies of themselves.
+
+#[cfg(not(target_arch = "x86"), not(target_arch = "x86_64"))]
+#[static_assert]
+static REGISTERS_MATCH_THE_ASSEMBLY: bool =
+    SAVED_REGISTER_COUNT == ASM_REGISTER_SLOTS;
+#[cfg(not(target_arch = "x86"))]
+#[static_assert]
+static FRAME_SLOTS_IN_BOUNDS: bool = LAST_FRAME_SLOT < SAVED_REGISTER_COUNT;
+
+/// The number of registers that a context switch saves and restores
+#[cfg(not(windows), target_arch = "x86")]
+pub static SAVED_REGISTER_COUNT: uint = 16;
+#[cfg(windows, target_arch = "x86")]
+pub static SAVED_REGISTER_COUNT: uint = 17;
+/// The alignment in bytes which the ABI requires of the stack pointer at a call
+///
+/// The i386 System V ABI itself only promises 4, but GCC and LLVM have long
+/// assumed 16 (and use aligned SSE loads and stores accordingly), as does the
+/// argument layout in `initialize_call_frame`.
+#[cfg(target_arch = "x86")]
+pub static STACK_ALIGNMENT: uint = 16;
+/// How many argument positions a raw entry point can take its argument in,
+/// see `Context::new_raw_with_arg_pos`
+#[cfg(target_arch = "x86")]
+pub static ARG_POSITIONS: uint = 4;
+/// How many bytes below the stack pointer a function may use without moving
+/// it (the ABI's red zone). The stack below a new context's initial frame must
+/// have at least this much to spare, see `validate_stack`.
+#[cfg(target_arch = "x86")]
+pub static RED_ZONE_SIZE: uint = 0;
+
+// Whether new contexts start from a snapshot of the thread's registers rather
+// than zeroed ones, see `RegisterFile::baseline`. Here the swap loads eflags,
+// which are better inherited than made up.
+#[cfg(target_arch = "x86")]
+static SNAPSHOT_NEW_REGISTERS: bool = true;
+
+#[cfg(not(windows), target_arch = "x86")]
+struct Registers {
+    eax: u32, ebx: u32, ecx: u32, edx: u32,
+    ebp: u32, esi: u32, edi: u32, esp: u32,
+    cs: u16, ds: u16, ss: u16, es: u16, fs: u16, gs: u16,
+    eflags: u32, eip: u32
+}
+
+#[cfg(not(windows), target_arch = "x86")]
+fn new_regs() -> Registers {
+    Registers {
+        eax: 0, ebx: 0, ecx: 0, edx: 0,
+        ebp: 0, esi: 0, edi: 0, esp: 0,
+        cs: 0, ds: 0, ss: 0, es: 0, fs: 0, gs: 0,
+        eflags: 0, eip: 0
+    }
+}
+
+// windows additionally keeps the head of the structured exception handling
+// chain (%fs:0) per task. Unlike on x86_64, no XMM registers are callee-saved
+// on 32-bit windows, and the TIB's stack bounds are set by
+// `record_stack_bounds`, so nothing else is needed.
+#[cfg(windows, target_arch = "x86")]
+struct Registers {
+    eax: u32, ebx: u32, ecx: u32, edx: u32,
+    ebp: u32, esi: u32, edi: u32, esp: u32,
+    cs: u16, ds: u16, ss: u16, es: u16, fs: u16, gs: u16,
+    eflags: u32, eip: u32, seh_chain: u32
+}
+
+#[cfg(windows, target_arch = "x86")]
+fn new_regs() -> Registers {
+    Registers {
+        eax: 0, ebx: 0, ecx: 0, edx: 0,
+        ebp: 0, esi: 0, edi: 0, esp: 0,
+        cs: 0, ds: 0, ss: 0, es: 0, fs: 0, gs: 0,
+        eflags: 0, eip: 0, seh_chain: 0
+    }
+}
+
+#[cfg(target_arch = "x86")]
+fn saved_ip(regs: &Registers) -> uint { regs.eip as uint }
+#[cfg(target_arch = "x86")]
+fn set_saved_ip(regs: &mut Registers, ip: uint) -> bool {
+    regs.eip = ip as u32;
+    true
+}
+#[cfg(target_arch = "x86")]
+fn saved_sp(regs: &Registers) -> uint { regs.esp as uint }
+#[cfg(target_arch = "x86")]
+fn saved_fp(regs: &Registers) -> uint { regs.ebp as uint }
+
+// No floating point state is saved on x86
+#[cfg(target_arch = "x86")]
+fn clear_fp_regs(_regs: &mut Registers) {}
+
+// Makes the context resume by calling `fptr`, with a return address of where
+// it would otherwise have resumed. Returns whether that's possible.
+#[cfg(target_arch = "x86")]
+fn redirect_call(regs: &mut Registers, fptr: *c_void) -> bool {
+    let sp = mut_offset(regs.esp as uint as *mut u32, -1);
+    unsafe { *sp = regs.eip; }
+    regs.esp = sp as u32;
+    regs.eip = fptr as u32;
+    true
+}
+
+// What initialize_call_frame pushes below the aligned top of the stack
+#[cfg(target_arch = "x86")]
+static INITIAL_FRAME_BYTES: uint = 9 * 4;
+
+#[cfg(target_arch = "x86")]
+fn initialize_call_frame(regs: &mut Registers, fptr: *c_void, arg: *c_void,
+                         arg_pos: uint, sp: *mut uint) {
+
+    let top = align_down(sp);
+
+    // A terminating frame for frame pointer walkers, see the x86_64 version.
+    // It can't sit right above the return address like it does there, where
+    // the arguments go, so it's at the top with a word of padding below it
+    // to keep the arguments 16-byte aligned.
+    let fake_frame = mut_offset(top, -2);
+    unsafe {
+        *fake_frame = 0;
+        *mut_offset(fake_frame, 1) = 0;
+        *mut_offset(fake_frame, -1) = 0;
+        *mut_offset(fake_frame, -2) = 0;
+    }
+    let sp = mut_offset(fake_frame, -6);
+
+    // Arguments are passed in the slots reserved just above
+    unsafe {
+        for i in range(0, 4) { *mut_offset(sp, i) = 0 }
+        *mut_offset(sp, arg_pos as int) = arg as uint;
+    }
+    let sp = mut_offset(sp, -1);
+    unsafe { *sp = 0 }; // The final return address
+
+    regs.esp = sp as u32;
+    regs.eip = fptr as u32;
+    regs.ebp = fake_frame as u32;
+
+    init_seh_chain(regs);
+}
+
+// A new task starts with an empty exception handler chain, which windows marks
+// with a link of 0xffffffff
+#[cfg(windows, target_arch = "x86")]
+fn init_seh_chain(regs: &mut Registers) { regs.seh_chain = 0xffffffff; }
+#[cfg(not(windows), target_arch = "x86")]
+fn init_seh_chain(_regs: &mut Registers) {}
+
+// windows requires saving more registers (both general and XMM), so the windows
+// register context must be larger.
+//
+// The slots are u64 rather than uint because the registers are 64 bits wide
+// even when pointers aren't (the x32 ABI). Pointers stored into them must be
+// zero-extended, which the aarch64 port does the same way for its ILP32 ABI
+// (arm64_32).
+#[cfg(windows, target_arch = "x86_64")]
+pub static SAVED_REGISTER_COUNT: uint = 34;
+// Outside of windows no XMM register is callee-saved (the System V ABI makes
+// all of them caller-saved), so a cooperative switch, which is always a call,
+// never has live XMM state to keep. XMM0 - XMM5 are swapped anyway, as they
+// always have been. Building with `--cfg preserve_xmm` (which the runtime's
+// makefiles pass on to the assembly) swaps all sixteen, for code which
+// expects XMM values to survive a switch regardless.
+#[cfg(not(windows), not(preserve_xmm), target_arch = "x86_64")]
+pub static SAVED_REGISTER_COUNT: uint = 22;
+#[cfg(not(windows), preserve_xmm, target_arch = "x86_64")]
+pub static SAVED_REGISTER_COUNT: uint = 42;
+#[cfg(target_arch = "x86_64")]
+pub static STACK_ALIGNMENT: uint = 16;
+#[cfg(target_arch = "x86_64")]
+pub static ARG_POSITIONS: uint = 2;
+// Only the System V ABI has a red zone. Code built to run where interrupts
+// push onto the current stack (a kernel) is built without one, and libgreen
+// must then be built with `--cfg no_redzone` to match.
+#[cfg(target_arch = "x86_64", not(windows), not(no_redzone))]
+pub static RED_ZONE_SIZE: uint = 128;
+#[cfg(target_arch = "x86_64", windows)]
+#[cfg(target_arch = "x86_64", no_redzone)]
+pub static RED_ZONE_SIZE: uint = 0;
+
+// Nothing but general purpose and XMM registers is swapped, and whatever
+// callee-saved values a new context starts with are never used by anything,
+// as its entry point has no caller to return them to.
+#[cfg(target_arch = "x86_64")]
+static SNAPSHOT_NEW_REGISTERS: bool = false;
+
+#[cfg(target_arch = "x86_64")]
+type Registers = [u64, ..SAVED_REGISTER_COUNT];
+
+// Redefinitions from rt/arch/x86_64/regs.h
+#[cfg(target_arch = "x86_64")] static RUSTRT_ARG0: uint = 3;
+#[cfg(windows, target_arch = "x86_64")] static RUSTRT_ARG1: uint = 13;
+#[cfg(not(windows), target_arch = "x86_64")] static RUSTRT_ARG1: uint = 9;
+#[cfg(target_arch = "x86_64")] static RUSTRT_RSP: uint = 1;
+#[cfg(target_arch = "x86_64")] static RUSTRT_IP: uint = 8;
+#[cfg(target_arch = "x86_64")] static RUSTRT_RBP: uint = 2;
+// The first XMM slot, RUSTRT_XMM6 on windows and RUSTRT_XMM0 elsewhere. The
+// rest follow it up to the end of the registers.
+#[cfg(windows, target_arch = "x86_64")] static RUSTRT_XMM6: uint = 14;
+#[cfg(not(windows), target_arch = "x86_64")] static RUSTRT_XMM0: uint = 10;
+
+// RUSTRT_ARG1 is above every other slot which `initialize_call_frame` writes
+#[cfg(target_arch = "x86_64")]
+static LAST_FRAME_SLOT: uint = RUSTRT_ARG1;
+
+#[cfg(target_arch = "x86_64")]
+fn new_regs() -> Registers { [0, .. SAVED_REGISTER_COUNT] }
+
+#[cfg(target_arch = "x86_64")]
+fn saved_ip(regs: &Registers) -> uint { regs[RUSTRT_IP] as uint }
+#[cfg(target_arch = "x86_64")]
+fn set_saved_ip(regs: &mut Registers, ip: uint) -> bool {
+    regs[RUSTRT_IP] = ip as u64;
+    true
+}
+#[cfg(target_arch = "x86_64")]
+fn saved_sp(regs: &Registers) -> uint { regs[RUSTRT_RSP] as uint }
+#[cfg(target_arch = "x86_64")]
+fn saved_fp(regs: &Registers) -> uint { regs[RUSTRT_RBP] as uint }
+
+// The XMM slots, RUSTRT_XMM6 through RUSTRT_XMM15 on windows and RUSTRT_XMM0
+// through RUSTRT_XMM5 (or RUSTRT_XMM15) elsewhere
+#[cfg(windows, target_arch = "x86_64")]
+fn clear_fp_regs(regs: &mut Registers) {
+    for i in range(RUSTRT_XMM6, SAVED_REGISTER_COUNT) { regs[i] = 0; }
+}
+#[cfg(not(windows), target_arch = "x86_64")]
+fn clear_fp_regs(regs: &mut Registers) {
+    for i in range(RUSTRT_XMM0, SAVED_REGISTER_COUNT) { regs[i] = 0; }
+}
+
+// See the x86 version. The saved stack pointer is just above the return
+// address that `rust_swap_registers` popped, so pushing that address back
+// leaves the stack as though `fptr` had been called in its place.
+#[cfg(target_arch = "x86_64")]
+fn redirect_call(regs: &mut Registers, fptr: *c_void) -> bool {
+    let sp = mut_offset(regs[RUSTRT_RSP] as uint as *mut u64, -1);
+    unsafe { *sp = regs[RUSTRT_IP]; }
+    regs[RUSTRT_RSP] = sp as uint as u64;
+    regs[RUSTRT_IP] = fptr as uint as u64;
+    true
+}
+
+#[cfg(target_arch = "x86_64")]
+static INITIAL_FRAME_BYTES: uint = 3 * 8;
+
+#[cfg(target_arch = "x86_64")]
+fn initialize_call_frame(regs: &mut Registers, fptr: *c_void, arg: *c_void,
+                         arg_pos: uint, sp: *mut uint) {
+
+    // Stack slots are 8 bytes regardless of the pointer width, as `call` and
+    // `ret` always push and pop a 64-bit return address.
+    let top = align_down(sp) as *mut u64;
+
+    // A terminating frame for frame pointer walkers (like `perf record
+    // --call-graph fp`): a saved RBP and a return address, both 0. RBP points
+    // at it, so the entry function's prologue links its frame to this one.
+    let fake_frame = mut_offset(top, -2);
+    unsafe { *fake_frame = 0; *mut_offset(fake_frame, 1) = 0; }
+
+    // The final return address. 0 indicates the bottom of the stack, to
+    // debuggers too: with the CFI in rust_swap_registers a backtrace from the
+    // task ends at its entry point.
+    let sp = mut_offset(fake_frame, -1);
+    unsafe { *sp = 0; }
+
+    // The entry function may use `red_zone_size` bytes below this without
+    // moving the stack pointer, which validate_stack has left room for. The
+    // red zone is below the stack pointer, as it would be after a real call,
+    // so it never overlaps the return address or the frame above it, and
+    // nothing needs to be set aside for it here.
+
+    rtdebug!("creating call frame");
+    rtdebug!("fptr {}", fptr);
+    rtdebug!("arg {}", arg);
+    rtdebug!("sp {}", sp);
+
+    regs[if arg_pos == 0 {RUSTRT_ARG0} else {RUSTRT_ARG1}] = arg as uint as u64;
+    regs[RUSTRT_RSP] = sp as uint as u64;
+    regs[RUSTRT_IP] = fptr as uint as u64;
+    regs[RUSTRT_RBP] = fake_frame as uint as u64;
+}
+
+// r0 - r15 and the cpsr in the first 17 words, then d8 - d15 from word 24
+#[cfg(target_arch = "arm")]
+pub static SAVED_REGISTER_COUNT: uint = 40;
+#[cfg(target_arch = "arm")]
+pub static STACK_ALIGNMENT: uint = 8;
+#[cfg(target_arch = "arm")]
+pub static ARG_POSITIONS: uint = 4;
+#[cfg(target_arch = "arm")]
+pub static RED_ZONE_SIZE: uint = 0;
+
+// The swap loads the cpsr, which is better inherited than made up
+#[cfg(target_arch = "arm")]
+static SNAPSHOT_NEW_REGISTERS: bool = true;
+
+#[cfg(target_arch = "arm")]
+type Registers = [uint, ..SAVED_REGISTER_COUNT];
+
+// Redefinitions from rt/arch/arm/_context.S: r0 - r15 are in the slots of the
+// same number, and d8 - d15 take two slots each from 96 bytes in
+#[cfg(target_arch = "arm")] static R0: uint = 0;
+#[cfg(target_arch = "arm")] static SP: uint = 13;
+#[cfg(target_arch = "arm")] static LR: uint = 14;
+#[cfg(target_arch = "arm")] static D8: uint = 24;
+
+// d15 is the last register saved, and lr is above every other slot which
+// `initialize_call_frame` writes
+#[cfg(target_arch = "arm")]
+static ASM_REGISTER_SLOTS: uint = D8 + 8 * 2;
+#[cfg(target_arch = "arm")]
+static LAST_FRAME_SLOT: uint = LR;
+
+#[cfg(target_arch = "arm")]
+fn new_regs() -> Registers { [0, .. SAVED_REGISTER_COUNT] }
+
+// rust_swap_registers resumes at the saved lr (r14)
+#[cfg(target_arch = "arm")]
+fn saved_ip(regs: &Registers) -> uint { regs[LR] }
+#[cfg(target_arch = "arm")]
+fn set_saved_ip(regs: &mut Registers, ip: uint) -> bool {
+    regs[LR] = ip;
+    true
+}
+#[cfg(target_arch = "arm")]
+fn saved_sp(regs: &Registers) -> uint { regs[SP] }
+// r11, the frame pointer in ARM code (Thumb code uses r7)
+#[cfg(target_arch = "arm")]
+fn saved_fp(regs: &Registers) -> uint { regs[11] }
+
+// d8 - d15, which are only saved when built for hardware floating point
+#[cfg(target_arch = "arm")]
+fn clear_fp_regs(regs: &mut Registers) {
+    for i in range(D8, SAVED_REGISTER_COUNT) { regs[i] = 0; }
+}
+
+// The saved lr is both where the context resumes and, had `fptr` been called,
+// the return address it would need, so there's nowhere to put the latter.
+#[cfg(target_arch = "arm")]
+fn redirect_call(_regs: &mut Registers, _fptr: *c_void) -> bool { false }
+
+#[cfg(target_arch = "arm")]
+static INITIAL_FRAME_BYTES: uint = 2 * 4;
+
+// The swap resumes with `bx lr`, which switches to Thumb mode when the low bit
+// of the address is set. The address of a Thumb function normally has it set
+// already, but when libgreen itself is built as Thumb code (which has to be
+// passed on as `--cfg thumb`, as nothing else tells the code which instruction
+// set it's in) the entry point is made to land in Thumb mode regardless.
+#[cfg(target_arch = "arm", thumb)]
+static THUMB_BIT: uint = 1;
+#[cfg(target_arch = "arm", not(thumb))]
+static THUMB_BIT: uint = 0;
+
+#[cfg(target_arch = "arm")]
+fn initialize_call_frame(regs: &mut Registers, fptr: *c_void, arg: *c_void,
+                         arg_pos: uint, sp: *mut uint) {
+    // ARM instructions are 4-byte aligned. A set low bit instead selects Thumb
+    // mode, where clearing that bit always leaves a 2-byte aligned address.
+    rtassert!(fptr as uint & 1 == 1 || fptr as uint & 3 == 0);
+
+    let sp = align_down(sp);
+    // sp of arm eabi is 8-byte aligned
+    let sp = mut_offset(sp, -2);
+
+    // The final return address. 0 indicates the bottom of the stack
+    unsafe { *sp = 0; }
+
+    regs[R0 + arg_pos] = arg as uint;   // r0 - r3
+    regs[SP] = sp as uint;   // #53 sp, r13
+    regs[LR] = fptr as uint | THUMB_BIT; // #60 pc, r15 --> lr
+
+    // No floating point state is inherited by a new context
+    clear_fp_regs(regs);
+}
+
+#[cfg(target_arch = "mips")]
+pub static SAVED_REGISTER_COUNT: uint = 32;
+#[cfg(target_arch = "mips")]
+pub static STACK_ALIGNMENT: uint = 8;
+#[cfg(target_arch = "mips")]
+pub static ARG_POSITIONS: uint = 4;
+#[cfg(target_arch = "mips")]
+pub static RED_ZONE_SIZE: uint = 0;
+
+// The swap loads $gp, which position independent code relies on
+#[cfg(target_arch = "mips")]
+static SNAPSHOT_NEW_REGISTERS: bool = true;
+
+#[cfg(target_arch = "mips")]
+type Registers = [uint, ..SAVED_REGISTER_COUNT];
+
+// Redefinitions from rt/arch/mips/_context.S, which keeps every register in
+// the slot of the same number
+#[cfg(target_arch = "mips")] static A0: uint = 4;
+#[cfg(target_arch = "mips")] static T9: uint = 25;
+#[cfg(target_arch = "mips")] static SP: uint = 29;
+#[cfg(target_arch = "mips")] static RA: uint = 31;
+
+// $ra is both the last register saved and the highest slot which
+// `initialize_call_frame` writes
+#[cfg(target_arch = "mips")]
+static ASM_REGISTER_SLOTS: uint = RA + 1;
+#[cfg(target_arch = "mips")]
+static LAST_FRAME_SLOT: uint = RA;
+
+#[cfg(target_arch = "mips")]
+fn new_regs() -> Registers { [0, .. SAVED_REGISTER_COUNT] }
+
+// rust_swap_registers resumes at the saved $31 (ra)
+#[cfg(target_arch = "mips")]
+fn saved_ip(regs: &Registers) -> uint { regs[RA] }
+// $25 (t9) is caller-saved, so it can always be pointed at the new address
+// too, for position independent code to find its $gp from
+#[cfg(target_arch = "mips")]
+fn set_saved_ip(regs: &mut Registers, ip: uint) -> bool {
+    regs[T9] = ip;
+    regs[RA] = ip;
+    true
+}
+#[cfg(target_arch = "mips")]
+fn saved_sp(regs: &Registers) -> uint { regs[SP] }
+#[cfg(target_arch = "mips")]
+fn saved_fp(regs: &Registers) -> uint { regs[30] }
+
+// No floating point state is saved on mips
+#[cfg(target_arch = "mips")]
+fn clear_fp_regs(_regs: &mut Registers) {}
+
+// Same problem as on arm, with $31 (ra)
+#[cfg(target_arch = "mips")]
+fn redirect_call(_regs: &mut Registers, _fptr: *c_void) -> bool { false }
+
+#[cfg(target_arch = "mips")]
+static INITIAL_FRAME_BYTES: uint = 2 * 4;
+
+#[cfg(target_arch = "mips")]
+fn initialize_call_frame(regs: &mut Registers, fptr: *c_void, arg: *c_void,
+                         arg_pos: uint, sp: *mut uint) {
+    // Instructions are 4-byte aligned, so anything else is a bad pointer
+    rtassert!(fptr as uint & 3 == 0);
+
+    let sp = align_down(sp);
+    // sp of mips o32 is 8-byte aligned
+    let sp = mut_offset(sp, -2);
+
+    // The final return address. 0 indicates the bottom of the stack
+    unsafe { *sp = 0; }
+
+    regs[A0 + arg_pos] = arg as uint; // $4 - $7 (a0 - a3)
+    regs[SP] = sp as uint;
+    regs[T9] = fptr as uint;
+    regs[RA] = fptr as uint;
+}
+
+// The n64 ABI. rt/arch/mips64/_context.S keeps every register in the slot of
+// the same number like the o32 one does, only with doubleword slots, which are
+// stored whole and so don't depend on the byte order. There are eight argument
+// registers rather than four.
+//
+// This is deliberately not cfg'd on `target_endian`: big endian mips64 and
+// little endian mips64el use this same layout and the same assembly (see
+// `DEF_HOST_VAR` in mk/platform.mk). Nothing here packs values into parts of a
+// slot; the stack pointer, `fptr` and the argument are each widened to a whole
+// u64 before they're stored, so the value the assembly's `ld` loads back is
+// the same in either byte order.
+#[cfg(target_arch = "mips64")]
+pub static SAVED_REGISTER_COUNT: uint = 32;
+#[cfg(target_arch = "mips64")]
+pub static STACK_ALIGNMENT: uint = 16;
+#[cfg(target_arch = "mips64")]
+pub static ARG_POSITIONS: uint = 8;
+#[cfg(target_arch = "mips64")]
+pub static RED_ZONE_SIZE: uint = 0;
+
+// As on mips, the swap loads $gp
+#[cfg(target_arch = "mips64")]
+static SNAPSHOT_NEW_REGISTERS: bool = true;
+
+#[cfg(target_arch = "mips64")]
+type Registers = [u64, ..SAVED_REGISTER_COUNT];
+
+// Redefinitions from rt/arch/mips64/_context.S, the same as for mips
+#[cfg(target_arch = "mips64")] static A0: uint = 4;
+#[cfg(target_arch = "mips64")] static T9: uint = 25;
+#[cfg(target_arch = "mips64")] static SP: uint = 29;
+#[cfg(target_arch = "mips64")] static RA: uint = 31;
+
+// As on mips, $ra is both the last register saved and the highest slot which
+// `initialize_call_frame` writes
+#[cfg(target_arch = "mips64")]
+static ASM_REGISTER_SLOTS: uint = RA + 1;
+#[cfg(target_arch = "mips64")]
+static LAST_FRAME_SLOT: uint = RA;
+
+#[cfg(target_arch = "mips64")]
+fn new_regs() -> Registers { [0, .. SAVED_REGISTER_COUNT] }
+
+// rust_swap_registers resumes at the saved $31 (ra)
+#[cfg(target_arch = "mips64")]
+fn saved_ip(regs: &Registers) -> uint { regs[RA] as uint }
+// See the mips version
+#[cfg(target_arch = "mips64")]
+fn set_saved_ip(regs: &mut Registers, ip: uint) -> bool {
+    regs[T9] = ip as u64;
+    regs[RA] = ip as u64;
+    true
+}
+#[cfg(target_arch = "mips64")]
+fn saved_sp(regs: &Registers) -> uint { regs[SP] as uint }
+#[cfg(target_arch = "mips64")]
+fn saved_fp(regs: &Registers) -> uint { regs[30] as uint }
+
+// No floating point state is saved, just as on mips
+#[cfg(target_arch = "mips64")]
+fn clear_fp_regs(_regs: &mut Registers) {}
+
+// Same problem as on arm, with $31 (ra)
+#[cfg(target_arch = "mips64")]
+fn redirect_call(_regs: &mut Registers, _fptr: *c_void) -> bool { false }
+
+#[cfg(target_arch = "mips64")]
+static INITIAL_FRAME_BYTES: uint = 2 * 8;
+
+#[cfg(target_arch = "mips64")]
+fn initialize_call_frame(regs: &mut Registers, fptr: *c_void, arg: *c_void,
+                         arg_pos: uint, sp: *mut uint) {
+    // Instructions are 4-byte aligned, so anything else is a bad pointer
+    rtassert!(fptr as uint & 3 == 0);
+
+    // sp of mips n64 is 16-byte aligned, so a whole 16 bytes is pushed
+    let sp = align_down(sp) as *mut u64;
+    let sp = mut_offset(sp, -2);
+
+    // The final return address. 0 indicates the bottom of the stack
+    unsafe { *sp = 0; *mut_offset(sp, 1) = 0; }
+
+    regs[A0 + arg_pos] = arg as uint as u64; // $4 - $11 (a0 - a7)
+    regs[SP] = sp as uint as u64;
+    // Position independent code finds $gp from the address it was called at,
+    // which the n64 ABI passes in $25 (t9)
+    regs[T9] = fptr as uint as u64;
+    regs[RA] = fptr as uint as u64;
+}
+
+// The layout used by rt/arch/aarch64/_context.S: x19 - x30, sp, the resume
+// address, x0 - x3 and then d8 - d15. Of the argument registers only x0 is
+// saved by a swap, the others are only ever loaded for a new context's entry.
+//
+// The slots are 64 bits wide even with the ILP32 ABI (arm64_32, where
+// `target_word_size` is 32), since the assembly saves and loads whole x
+// registers. Every pointer stored into one goes through `as uint as u64`, so
+// that it's zero-extended and the top half of the register is clear.
+#[cfg(target_arch = "aarch64")]
+pub static SAVED_REGISTER_COUNT: uint = 26;
+#[cfg(target_arch = "aarch64")]
+pub static STACK_ALIGNMENT: uint = 16;
+#[cfg(target_arch = "aarch64")]
+pub static ARG_POSITIONS: uint = 4;
+#[cfg(target_arch = "aarch64")]
+pub static RED_ZONE_SIZE: uint = 0;
+
+// Only callee-saved registers are swapped (x18, the platform register, is
+// left alone), so as on x86_64 a new context doesn't need a snapshot
+#[cfg(target_arch = "aarch64")]
+static SNAPSHOT_NEW_REGISTERS: bool = false;
+
+#[cfg(target_arch = "aarch64")]
+type Registers = [u64, ..SAVED_REGISTER_COUNT];
+
+// Redefinitions from rt/arch/aarch64/_context.S
+#[cfg(target_arch = "aarch64")] static X29: uint = 10;
+#[cfg(target_arch = "aarch64")] static X30: uint = 11;
+#[cfg(target_arch = "aarch64")] static SP: uint = 12;
+#[cfg(target_arch = "aarch64")] static PC: uint = 13;
+#[cfg(target_arch = "aarch64")] static X0: uint = 14;
+#[cfg(target_arch = "aarch64")] static D8: uint = 18;
+
+// d15 is the last register saved, and the argument slot for the last position
+// is above every other slot which `initialize_call_frame` writes
+#[cfg(target_arch = "aarch64")]
+static ASM_REGISTER_SLOTS: uint = D8 + 8;
+#[cfg(target_arch = "aarch64")]
+static LAST_FRAME_SLOT: uint = X0 + ARG_POSITIONS - 1;
+
+#[cfg(target_arch = "aarch64")]
+fn new_regs() -> Registers { [0, .. SAVED_REGISTER_COUNT] }
+
+#[cfg(target_arch = "aarch64")]
+fn saved_ip(regs: &Registers) -> uint { regs[PC] as uint }
+#[cfg(target_arch = "aarch64")]
+fn set_saved_ip(regs: &mut Registers, ip: uint) -> bool {
+    regs[PC] = ip as u64;
+    true
+}
+#[cfg(target_arch = "aarch64")]
+fn saved_sp(regs: &Registers) -> uint { regs[SP] as uint }
+#[cfg(target_arch = "aarch64")]
+fn saved_fp(regs: &Registers) -> uint { regs[X29] as uint }
+
+// d8 - d15
+#[cfg(target_arch = "aarch64")]
+fn clear_fp_regs(regs: &mut Registers) {
+    for i in range(D8, SAVED_REGISTER_COUNT) { regs[i] = 0; }
+}
+
+// Unlike on arm, the address where the context resumes is kept apart from its
+// saved link register (which is still where the suspended swap returns to), so
+// the context can be made to resume in `fptr` as though called from there.
+#[cfg(target_arch = "aarch64")]
+fn redirect_call(regs: &mut Registers, fptr: *c_void) -> bool {
+    regs[PC] = fptr as uint as u64;
+    true
+}
+
+#[cfg(target_arch = "aarch64")]
+static INITIAL_FRAME_BYTES: uint = 2 * 8;
+
+#[cfg(target_arch = "aarch64")]
+fn initialize_call_frame(regs: &mut Registers, fptr: *c_void, arg: *c_void,
+                         arg_pos: uint, sp: *mut uint) {
+    // Instructions are 4-byte aligned, so anything else is a bad pointer
+    rtassert!(fptr as uint & 3 == 0);
+
+    // sp must stay 16-byte aligned (AAPCS64), so a whole 16 bytes is pushed
+    let sp = align_down(sp) as *mut u64;
+    let sp = mut_offset(sp, -2);
+
+    // The final return address. 0 indicates the bottom of the stack
+    unsafe { *sp = 0; *mut_offset(sp, 1) = 0; }
+
+    regs[X0 + arg_pos] = arg as uint as u64;
+    regs[SP] = sp as uint as u64;
+    regs[PC] = fptr as uint as u64;
+
+    // The entry returns through lr, and walks frames through x29, so both
+    // end here
+    regs[X30] = 0;
+    regs[X29] = 0;
+}
+
+// The layout used by rt/arch/riscv64/_context.S: ra, sp, s0 - s11, a0 - a3 and
+// then fs0 - fs11. Like x86_64's these slots are always 64 bits wide.
+#[cfg(target_arch = "riscv64")]
+pub static SAVED_REGISTER_COUNT: uint = 30;
+#[cfg(target_arch = "riscv64")]
+pub static STACK_ALIGNMENT: uint = 16;
+#[cfg(target_arch = "riscv64")]
+pub static ARG_POSITIONS: uint = 4;
+#[cfg(target_arch = "riscv64")]
+pub static RED_ZONE_SIZE: uint = 0;
+
+// gp and tp aren't swapped, so as on x86_64 a new context doesn't need a
+// snapshot
+#[cfg(target_arch = "riscv64")]
+static SNAPSHOT_NEW_REGISTERS: bool = false;
+
+#[cfg(target_arch = "riscv64")]
+type Registers = [u64, ..SAVED_REGISTER_COUNT];
+
+// Redefinitions from rt/arch/riscv64/_context.S
+#[cfg(target_arch = "riscv64")] static RA: uint = 0;
+#[cfg(target_arch = "riscv64")] static SP: uint = 1;
+#[cfg(target_arch = "riscv64")] static S0: uint = 2;
+#[cfg(target_arch = "riscv64")] static A0: uint = 14;
+#[cfg(target_arch = "riscv64")] static FS0: uint = 18;
+
+// fs11 is the last register saved, and the argument slot for the last
+// position is above every other slot which `initialize_call_frame` writes
+#[cfg(target_arch = "riscv64")]
+static ASM_REGISTER_SLOTS: uint = FS0 + 12;
+#[cfg(target_arch = "riscv64")]
+static LAST_FRAME_SLOT: uint = A0 + ARG_POSITIONS - 1;
+
+#[cfg(target_arch = "riscv64")]
+fn new_regs() -> Registers { [0, .. SAVED_REGISTER_COUNT] }
+
+// rust_swap_registers resumes at the saved ra
+#[cfg(target_arch = "riscv64")]
+fn saved_ip(regs: &Registers) -> uint { regs[RA] as uint }
+#[cfg(target_arch = "riscv64")]
+fn set_saved_ip(regs: &mut Registers, ip: uint) -> bool {
+    regs[RA] = ip as u64;
+    true
+}
+#[cfg(target_arch = "riscv64")]
+fn saved_sp(regs: &Registers) -> uint { regs[SP] as uint }
+#[cfg(target_arch = "riscv64")]
+fn saved_fp(regs: &Registers) -> uint { regs[S0] as uint }
+
+// fs0 - fs11
+#[cfg(target_arch = "riscv64")]
+fn clear_fp_regs(regs: &mut Registers) {
+    for i in range(FS0, SAVED_REGISTER_COUNT) { regs[i] = 0; }
+}
+
+// Same problem as on arm, with ra
+#[cfg(target_arch = "riscv64")]
+fn redirect_call(_regs: &mut Registers, _fptr: *c_void) -> bool { false }
+
+#[cfg(target_arch = "riscv64")]
+static INITIAL_FRAME_BYTES: uint = 2 * 8;
+
+#[cfg(target_arch = "riscv64")]
+fn initialize_call_frame(regs: &mut Registers, fptr: *c_void, arg: *c_void,
+                         arg_pos: uint, sp: *mut uint) {
+    // Instructions are at least 2-byte aligned (4 without the C extension)
+    rtassert!(fptr as uint & 1 == 0);
+
+    // sp must stay 16-byte aligned, so a whole 16 bytes is pushed
+    let sp = align_down(sp) as *mut u64;
+    let sp = mut_offset(sp, -2);
+
+    // The final return address. 0 indicates the bottom of the stack
+    unsafe { *sp = 0; *mut_offset(sp, 1) = 0; }
+
+    regs[A0 + arg_pos] = arg as uint as u64;
+    regs[SP] = sp as uint as u64;
+    regs[RA] = fptr as uint as u64;
+
+    // Last frame pointer (s0) on the stack is 0
+    regs[S0] = 0;
+}
+
+// The SPARC V9 (64 bit) layout used by rt/arch/sparc64/_context.S: the
+// caller's stack pointer, its return address and then %o0 - %o3. Everything
+// else the caller had in its register window is on its stack, where the swap
+// flushed it to.
+#[cfg(target_arch = "sparc64")]
+pub static SAVED_REGISTER_COUNT: uint = 6;
+#[cfg(target_arch = "sparc64")]
+pub static STACK_ALIGNMENT: uint = 16;
+#[cfg(target_arch = "sparc64")]
+pub static ARG_POSITIONS: uint = 4;
+#[cfg(target_arch = "sparc64")]
+pub static RED_ZONE_SIZE: uint = 0;
+
+// Nothing outside of the register windows is swapped
+#[cfg(target_arch = "sparc64")]
+static SNAPSHOT_NEW_REGISTERS: bool = false;
+
+#[cfg(target_arch = "sparc64")]
+type Registers = [u64, ..SAVED_REGISTER_COUNT];
+
+// Redefinitions from rt/arch/sparc64/_context.S
+#[cfg(target_arch = "sparc64")] static SP: uint = 0;
+#[cfg(target_arch = "sparc64")] static O7: uint = 1;
+#[cfg(target_arch = "sparc64")] static O0: uint = 2;
+
+// %o3 is both the last register saved and the highest slot which
+// `initialize_call_frame` writes
+#[cfg(target_arch = "sparc64")]
+static ASM_REGISTER_SLOTS: uint = O0 + 4;
+#[cfg(target_arch = "sparc64")]
+static LAST_FRAME_SLOT: uint = O0 + ARG_POSITIONS - 1;
+
+#[cfg(target_arch = "sparc64")]
+fn new_regs() -> Registers { [0, .. SAVED_REGISTER_COUNT] }
+
+// %sp and %fp always point 2047 bytes below the frame they belong to
+#[cfg(target_arch = "sparc64")]
+static STACK_BIAS: uint = 2047;
+
+// The words of a frame's register window save area, %l0 - %l7 and then
+// %i0 - %i7, which are followed by six words for the callee to spill its
+// arguments into
+#[cfg(target_arch = "sparc64")]
+static WINDOW_WORDS: uint = 16;
+#[cfg(target_arch = "sparc64")]
+static MIN_FRAME_WORDS: uint = WINDOW_WORDS + 6;
+
+// `ret` returns 8 bytes past the call (the call and its delay slot), so the
+// saved return address is 8 bytes short of where the context resumes
+#[cfg(target_arch = "sparc64")]
+fn saved_ip(regs: &Registers) -> uint { regs[O7] as uint + 8 }
+#[cfg(target_arch = "sparc64")]
+fn set_saved_ip(regs: &mut Registers, ip: uint) -> bool {
+    regs[O7] = (ip - 8) as u64;
+    true
+}
+#[cfg(target_arch = "sparc64")]
+fn saved_sp(regs: &Registers) -> uint {
+    if regs[SP] == 0 { 0 } else { regs[SP] as uint + STACK_BIAS }
+}
+// The caller's %fp is its saved %i6, in the window save area at its %sp
+#[cfg(target_arch = "sparc64")]
+fn saved_fp(regs: &Registers) -> uint {
+    match saved_sp(regs) {
+        0 => 0,
+        sp => unsafe {
+            let fp = *((sp + (WINDOW_WORDS - 2) * 8) as *u64) as uint;
+            if fp == 0 { 0 } else { fp + STACK_BIAS }
+        }
+    }
+}
+
+// No floating point register is callee-saved
+#[cfg(target_arch = "sparc64")]
+fn clear_fp_regs(_regs: &mut Registers) {}
+
+// The resumed caller's return address is its %o7, which the swap loads from
+// the same slot as where it resumes, so like arm there's no room for both
+#[cfg(target_arch = "sparc64")]
+fn redirect_call(_regs: &mut Registers, _fptr: *c_void) -> bool { false }
+
+#[cfg(target_arch = "sparc64")]
+static INITIAL_FRAME_BYTES: uint = MIN_FRAME_WORDS * 8;
+
+#[cfg(target_arch = "sparc64")]
+fn initialize_call_frame(regs: &mut Registers, fptr: *c_void, arg: *c_void,
+                         arg_pos: uint, sp: *mut uint) {
+    // Instructions are 4-byte aligned, so anything else is a bad pointer
+    rtassert!(fptr as uint & 3 == 0);
+
+    // The frame which the swap's restore fills a window in from. That window
+    // belongs to the entry's (nonexistent) caller, and a zeroed one gives it a
+    // frame pointer and return address of 0, the bottom of the stack.
+    let sp = align_down(sp) as *mut u64;
+    let sp = mut_offset(sp, -(MIN_FRAME_WORDS as int));
+    for i in range(0, MIN_FRAME_WORDS) {
+        unsafe { *mut_offset(sp, i as int) = 0; }
+    }
+
+    regs[SP] = (sp as uint - STACK_BIAS) as u64;
+    regs[O7] = (fptr as uint - 8) as u64;
+    regs[O0 + arg_pos] = arg as uint as u64; // %o0 - %o3
+}
+
+// The 64-bit PowerPC ELFv2 layout used by rt/arch/powerpc64/_context.S: r1,
+// the resume address, cr, r2 and r14 - r31, then r3 - r6 and r12 for a new
+// context's entry, f14 - f31, VRSAVE and v20 - v31. The vector registers take
+// two slots each, at a 16 byte aligned offset into the boxed frame.
+#[cfg(target_arch = "powerpc64")]
+pub static SAVED_REGISTER_COUNT: uint = 70;
+#[cfg(target_arch = "powerpc64")]
+pub static STACK_ALIGNMENT: uint = 16;
+#[cfg(target_arch = "powerpc64")]
+pub static ARG_POSITIONS: uint = 4;
+// The ELFv2 protected zone below the stack pointer
+#[cfg(target_arch = "powerpc64")]
+pub static RED_ZONE_SIZE: uint = 288;
+
+// Only callee-saved registers are swapped (r13, the thread pointer, is left
+// alone), so a new context doesn't need a snapshot
+#[cfg(target_arch = "powerpc64")]
+static SNAPSHOT_NEW_REGISTERS: bool = false;
+
+#[cfg(target_arch = "powerpc64")]
+type Registers = [u64, ..SAVED_REGISTER_COUNT];
+
+// Redefinitions from rt/arch/powerpc64/_context.S
+#[cfg(target_arch = "powerpc64")] static R1: uint = 0;
+#[cfg(target_arch = "powerpc64")] static RESUME: uint = 1;
+#[cfg(target_arch = "powerpc64")] static R3: uint = 22;
+#[cfg(target_arch = "powerpc64")] static R12: uint = 26;
+#[cfg(target_arch = "powerpc64")] static F14: uint = 27;
+#[cfg(target_arch = "powerpc64")] static V20: uint = 46;
+
+// v31 is the last register saved, two slots long like every vector register,
+// and r12 is above every other slot which `initialize_call_frame` writes
+#[cfg(target_arch = "powerpc64")]
+static ASM_REGISTER_SLOTS: uint = V20 + 12 * 2;
+#[cfg(target_arch = "powerpc64")]
+static LAST_FRAME_SLOT: uint = R12;
+
+#[cfg(target_arch = "powerpc64")]
+fn new_regs() -> Registers { [0, .. SAVED_REGISTER_COUNT] }
+
+#[cfg(target_arch = "powerpc64")]
+fn saved_ip(regs: &Registers) -> uint { regs[RESUME] as uint }
+// r12 is caller-saved, and ELFv2 global entry points compute their TOC
+// pointer from it, so it's pointed at the new address too
+#[cfg(target_arch = "powerpc64")]
+fn set_saved_ip(regs: &mut Registers, ip: uint) -> bool {
+    regs[RESUME] = ip as u64;
+    regs[R12] = ip as u64;
+    true
+}
+#[cfg(target_arch = "powerpc64")]
+fn saved_sp(regs: &Registers) -> uint { regs[R1] as uint }
+// There's no frame pointer, frames are walked through the back chain word
+// which every frame keeps at its stack pointer
+#[cfg(target_arch = "powerpc64")]
+fn saved_fp(regs: &Registers) -> uint { regs[R1] as uint }
+
+// f14 - f31, VRSAVE and v20 - v31
+#[cfg(target_arch = "powerpc64")]
+fn clear_fp_regs(regs: &mut Registers) {
+    for i in range(F14, SAVED_REGISTER_COUNT) { regs[i] = 0; }
+}
+
+// The resume address is kept apart from the link register, but the swap
+// always resumes with a link register of 0, so like on arm a redirected call
+// would have nowhere to return to
+#[cfg(target_arch = "powerpc64")]
+fn redirect_call(_regs: &mut Registers, _fptr: *c_void) -> bool { false }
+
+// The back chain, CR save, LR save and TOC save words of the ELFv2 minimal
+// frame header, which the entry's prologue saves its caller's state into
+#[cfg(target_arch = "powerpc64")]
+static INITIAL_FRAME_BYTES: uint = 4 * 8;
+
+#[cfg(target_arch = "powerpc64")]
+fn initialize_call_frame(regs: &mut Registers, fptr: *c_void, arg: *c_void,
+                         arg_pos: uint, sp: *mut uint) {
+    // Instructions are 4-byte aligned, so anything else is a bad pointer
+    rtassert!(fptr as uint & 3 == 0);
+
+    // r1 must stay 16-byte aligned, and point at the frame header the entry
+    // saves into. A back chain of 0 is the bottom of the stack, and so is the
+    // return address of 0 the entry saves from the link register.
+    let sp = align_down(sp) as *mut u64;
+    let sp = mut_offset(sp, -4);
+    for i in range(0, 4) {
+        unsafe { *mut_offset(sp, i) = 0; }
+    }
+
+    regs[R3 + arg_pos] = arg as uint as u64;
+    regs[R1] = sp as uint as u64;
+    regs[RESUME] = fptr as uint as u64;
+    // ELFv2 global entry points compute their TOC pointer from r12
+    regs[R12] = fptr as uint as u64;
+}
+
+// The z/Architecture (s390x) layout used by rt/arch/s390x/_context.S: r6 -
+// r13, r14, r15, the resume address, r2 - r5 for a new context's entry and
+// then f8 - f15.
+#[cfg(target_arch = "s390x")]
+pub static SAVED_REGISTER_COUNT: uint = 23;
+#[cfg(target_arch = "s390x")]
+pub static STACK_ALIGNMENT: uint = 8;
+#[cfg(target_arch = "s390x")]
+pub static ARG_POSITIONS: uint = 4;
+// Callees save into their caller's frame, never below the stack pointer
+#[cfg(target_arch = "s390x")]
+pub static RED_ZONE_SIZE: uint = 0;
+
+// Only callee-saved registers are swapped (the access registers, which hold
+// the thread pointer, are left alone), so a new context doesn't need a
+// snapshot
+#[cfg(target_arch = "s390x")]
+static SNAPSHOT_NEW_REGISTERS: bool = false;
+
+#[cfg(target_arch = "s390x")]
+type Registers = [u64, ..SAVED_REGISTER_COUNT];
+
+// Redefinitions from rt/arch/s390x/_context.S
+#[cfg(target_arch = "s390x")] static R11: uint = 5;
+#[cfg(target_arch = "s390x")] static R14: uint = 8;
+#[cfg(target_arch = "s390x")] static R15: uint = 9;
+#[cfg(target_arch = "s390x")] static RESUME: uint = 10;
+#[cfg(target_arch = "s390x")] static R2: uint = 11;
+#[cfg(target_arch = "s390x")] static F8: uint = 15;
+
+// f15 is the last register saved, and the argument slot for the last position
+// is above every other slot which `initialize_call_frame` writes
+#[cfg(target_arch = "s390x")]
+static ASM_REGISTER_SLOTS: uint = F8 + 8;
+#[cfg(target_arch = "s390x")]
+static LAST_FRAME_SLOT: uint = R2 + ARG_POSITIONS - 1;
+
+#[cfg(target_arch = "s390x")]
+fn new_regs() -> Registers { [0, .. SAVED_REGISTER_COUNT] }
+
+#[cfg(target_arch = "s390x")]
+fn saved_ip(regs: &Registers) -> uint { regs[RESUME] as uint }
+#[cfg(target_arch = "s390x")]
+fn set_saved_ip(regs: &mut Registers, ip: uint) -> bool {
+    regs[RESUME] = ip as u64;
+    true
+}
+#[cfg(target_arch = "s390x")]
+fn saved_sp(regs: &Registers) -> uint { regs[R15] as uint }
+// r11, when code keeps a frame pointer at all
+#[cfg(target_arch = "s390x")]
+fn saved_fp(regs: &Registers) -> uint { regs[R11] as uint }
+
+// f8 - f15
+#[cfg(target_arch = "s390x")]
+fn clear_fp_regs(regs: &mut Registers) {
+    for i in range(F8, SAVED_REGISTER_COUNT) { regs[i] = 0; }
+}
+
+// As on aarch64 the address where the context resumes is kept apart from its
+// saved r14 (which is still where the suspended swap returns to), so the
+// context can be made to resume in `fptr` as though called from there.
+#[cfg(target_arch = "s390x")]
+fn redirect_call(regs: &mut Registers, fptr: *c_void) -> bool {
+    regs[RESUME] = fptr as uint as u64;
+    true
+}
+
+// The register save area which every caller provides at the top of its frame,
+// for its callee to save r6 - r15 (and the back chain) into
+#[cfg(target_arch = "s390x")]
+static INITIAL_FRAME_BYTES: uint = 160;
+
+#[cfg(target_arch = "s390x")]
+fn initialize_call_frame(regs: &mut Registers, fptr: *c_void, arg: *c_void,
+                         arg_pos: uint, sp: *mut uint) {
+    // Instructions are 2-byte aligned, so anything else is a bad pointer
+    rtassert!(fptr as uint & 1 == 0);
+
+    // The entry saves its caller's registers into the save area at r15, which
+    // as the entry's (nonexistent) caller this frame provides. Zeroing it
+    // gives a back chain of 0, and the entry's own return address is the r14
+    // of 0 it starts with, so both end the stack.
+    let sp = align_down(sp) as *mut u64;
+    let sp = mut_offset(sp, -((INITIAL_FRAME_BYTES / 8) as int));
+    for i in range(0, INITIAL_FRAME_BYTES / 8) {
+        unsafe { *mut_offset(sp, i as int) = 0; }
+    }
+
+    regs[R2 + arg_pos] = arg as uint as u64;
+    regs[R15] = sp as uint as u64;
+    regs[RESUME] = fptr as uint as u64;
+    regs[R14] = 0;
+}
+
+// Every other architecture falls back to switching contexts with ucontext
+// (getcontext, makecontext and swapcontext), through the `rust_swap_registers`
+// in rt/rust_ucontext.c. Which architectures those are is only decided in
+// mk/platform.mk, as the ones with no rt/arch of their own: it builds libgreen
+// for them with `--cfg green_ucontext`, and the runtime with
+// RUST_UCONTEXT_FALLBACK defined. That's far slower than an assembly port,
+// since every swap also swaps the signal mask with a system call, and it both
+// makes the C compiler warn and uses a deprecated item here so that nobody
+// ships it by accident. It works wherever the C library has those functions,
+// but beyond that:
+//
+// * The saved ucontext is opaque, so the saved stack and frame pointers are
+//   those of the `rust_swap_registers` call rather than what swapcontext
+//   stored, and nothing can be redirected (see `redirect_call`).
+// * Some C libraries point into the ucontext from within it, so `Registers`
+//   must never be moved once they've been set up. They're always kept in a
+//   context's boxed frame, but restoring a `registers_snapshot` here gives
+//   back something which can't be resumed.
+// * There are no split stack prologues, so nothing reads the stack limit, and
+//   only the guard page (see `stack::set_guard_pages`) catches an overflow.
+#[cfg(green_ucontext)]
+mod ucontext {
+    use std::libc::{c_void, size_t};
+
+    use super::align_down;
+
+    // The ip, sp and fp words followed by a new context's entry and the
+    // ucontext, in as many bytes as FALLBACK_REGISTER_BYTES says
+    pub static SAVED_REGISTER_COUNT: uint = 1024;
+    pub static STACK_ALIGNMENT: uint = 16;
+    pub static ARG_POSITIONS: uint = 4;
+    // Unknown, so assume the largest of any supported architecture, the 288
+    // bytes of powerpc64
+    pub static RED_ZONE_SIZE: uint = 288;
+
+    // getcontext takes the snapshot itself
+    pub static SNAPSHOT_NEW_REGISTERS: bool = false;
+
+    pub static SNAPSHOT_ARCH: u32 = 8;
+
+    pub type Registers = [u64, ..SAVED_REGISTER_COUNT];
+
+    // Redefinition of FALLBACK_REGISTER_BYTES from rt/rust_ucontext.c, which
+    // checks that its `fallback_regs_t` fits in that many bytes
+    pub static FALLBACK_REGISTER_BYTES: uint = 8192;
+
+    // The u64 slots of FALLBACK_REGISTER_BYTES, and the last of the words
+    // `rust_ucontext_init` fills in ahead of the ucontext
+    pub static ASM_REGISTER_SLOTS: uint = FALLBACK_REGISTER_BYTES / 8;
+    pub static LAST_FRAME_SLOT: uint = 5;
+
+    pub fn new_regs() -> Registers { [0, .. SAVED_REGISTER_COUNT] }
+
+    pub fn saved_ip(regs: &Registers) -> uint { regs[0] as uint }
+    pub fn saved_sp(regs: &Registers) -> uint { regs[1] as uint }
+    pub fn saved_fp(regs: &Registers) -> uint { regs[2] as uint }
+
+    // Only informational, swapcontext resumes wherever the ucontext says
+    pub fn set_saved_ip(_regs: &mut Registers, _ip: uint) -> bool { false }
+
+    // swapcontext always swaps the floating point registers as well
+    pub fn clear_fp_regs(_regs: &mut Registers) {}
+
+    pub fn redirect_call(_regs: &mut Registers, _fptr: *c_void) -> bool {
+        false
+    }
+
+    // makecontext builds the frame itself, and its size isn't known. This is
+    // a generous guess.
+    pub static INITIAL_FRAME_BYTES: uint = 32 * 8;
+
+    pub fn initialize_call_frame(regs: &mut Registers, fptr: *c_void,
+                                 arg: *c_void, arg_pos: uint, sp: *mut uint) {
+        ucontext_fallback_in_use();
+        rtassert!(arg_pos < ARG_POSITIONS);
+        let sp = align_down(sp);
+        unsafe {
+            rust_ucontext_init(regs, fptr, arg, arg_pos as size_t,
+                               sp as *c_void);
+        }
+    }
+
+    // Only here for the warning that using it gives
+    #[deprecated = "libgreen is switching contexts with the slow ucontext \
+                    fallback, as there's no assembly port for this \
+                    architecture"]
+    fn ucontext_fallback_in_use() {}
+
+    #[link(name = "rustrt", kind = "static")]
+    extern {
+        fn rust_ucontext_init(regs: *mut Registers, fptr: *c_void,
+                              arg: *c_void, arg_pos: size_t, sp: *c_void);
+    }
+}
+
+/// The word written at the top of every new context's stack, see
+/// `Context::check_canary`
+pub static STACK_CANARY: uint = 0x0badc0de;
+
+/// What every register of a new context holds until it's set, unless
+/// libgreen is built with `--cfg ndebug`
+pub static REGISTER_POISON: uint = 0xbaadf00d;
+
+// How far below a `STACK_ALIGNMENT` boundary the stack pointer is on entry to
+// a function. On x86 and x86_64 that's the return address which `call` pushed
+// onto an aligned stack, everywhere else the return address is in a register.
+#[cfg(target_arch = "x86")]
+static ENTRY_SP_MISALIGNMENT: uint = 4;
+#[cfg(target_arch = "x86_64")]
+static ENTRY_SP_MISALIGNMENT: uint = 8;
+#[cfg(not(target_arch = "x86"), not(target_arch = "x86_64"))]
+static ENTRY_SP_MISALIGNMENT: uint = 0;
+
+// Room for the canary and the initial stack pointer below it, which keeps the
+// stack aligned below them
+static CANARY_BYTES: uint = STACK_ALIGNMENT;
+#[static_assert]
+static CANARY_HOLDS_THE_INITIAL_SP: bool = CANARY_BYTES >= 2 * uint::bits / 8;
+
+fn align_down(sp: *mut uint) -> *mut uint {
+    unsafe {
+        let sp: uint = transmute(sp);
+        let sp = sp & !(STACK_ALIGNMENT - 1);
+        transmute::<uint, *mut uint>(sp)
+    }
+}
+
+// ptr::mut_offset is positive ints only
+//
+// Without `--cfg ndebug` this aborts if the address wraps around, which can
+// only be the result of bad stack arithmetic. The arithmetic is all unsigned,
+// so it's right for stacks anywhere in the address space: with signed
+// arithmetic, an address in the upper half is negative, and offsetting it
+// would overflow `int` at the midpoint.
+#[inline]
+pub fn mut_offset<T>(ptr: *mut T, count: int) -> *mut T {
+    use std::mem::size_of;
+    if cfg!(not(ndebug)) {
+        match checked_mut_offset(ptr, count, 0, uint::max_value) {
+            Some(ptr) => return ptr,
+            None => rtabort!("offsetting {} by {} elements overflowed", ptr,
+                             count),
+        }
+    }
+    // A negative count wraps around to the same offset in two's complement
+    let bytes = (count as uint) * size_of::<T>();
+    ((ptr as uint) + bytes) as *mut T
+}
+
+/// Like `mut_offset`, but `None` if the address would wrap around or land
+/// outside of `[lo, hi)`, such as below the stack being set up.
+pub fn checked_mut_offset<T>(ptr: *mut T, count: int, lo: uint, hi: uint)
+    -> Option<*mut T>
+{
+    use std::mem::size_of;
+    let size = size_of::<T>();
+    let magnitude = if count < 0 { -(count + 1) as uint + 1 } else { count as uint };
+    let bytes = match magnitude.checked_mul(&size) {
+        Some(bytes) => bytes,
+        None => return None,
+    };
+    let addr = if count < 0 {
+        (ptr as uint).checked_sub(&bytes)
+    } else {
+        (ptr as uint).checked_add(&bytes)
+    };
+    match addr {
+        Some(addr) if lo <= addr && addr < hi => Some(addr as *mut T),
+        _ => None,
+    }
+}
+
+/// Context switching with nothing but the registers, for executors which have
+/// no OS (or no allocator) to build on, such as those in kernels and embedded
+/// firmware.
+///
+/// None of what `Context` does on top of switching the registers happens
+/// here: nothing boxes them along with an entry procedure, records the stack
+/// bounds in the OS's TLS slot for morestack, or runs the debug checks and
+/// the hooks. A `RawContext` is only a pointer to registers which the caller
+/// provides the memory for: a new context keeps them at the top of its own
+/// stack, and the context that's already running keeps them wherever it's
+/// given room for them. Nothing is allocated, and no thread-local state is
+/// read or written.
+///
+/// This is not a `no_std` layer. There's no libcore to build one on, so this
+/// module is still part of a crate which links `std`, and it uses
+/// `std::libc` for its types and `rtassert!` (which can print and abort) for
+/// its checks. What it avoids is allocating, or calling into the OS anywhere
+/// but on the way to aborting when one of those checks fails.
+///
+/// The running code's stack limit is left as it is by `swap`, so the code on
+/// either side must not be relying on morestack (build with `--cfg
+/// bare_metal`, or set the limit yourself, see `std::unstable::stack`).
+pub mod raw {
+    use std::libc::c_void;
+    use std::mem;
+
+    use super::{Registers, RegisterFile, fits_new_context, rust_swap_registers};
+    use super::{REGISTER_ALIGNMENT, ContextError, StackTooSmall};
+
+    /// The registers of a context, which are kept in memory owned by whoever
+    /// created it. See the module documentation.
+    pub struct RawContext {
+        priv regs: *mut Registers,
+    }
+
+    /// The bytes of memory `RawContext::in_storage` needs for a context's
+    /// registers, however that memory is aligned
+    pub fn storage_bytes() -> uint {
+        mem::size_of::<Registers>() + REGISTER_ALIGNMENT
+    }
+
+    fn align_up(addr: uint) -> uint {
+        (addr + REGISTER_ALIGNMENT - 1) & !(REGISTER_ALIGNMENT - 1)
+    }
+
+    impl RawContext {
+        /// A context for the code that's already running, which keeps its
+        /// registers in the `len` bytes at `storage` while it's switched
+        /// away. It can be resumed once it's been swapped out of.
+        ///
+        /// This is unsafe because the memory must stay valid, and not be
+        /// used for anything else, for as long as the context is. It fails
+        /// if `len` is less than `storage_bytes()`.
+        pub unsafe fn in_storage(storage: *mut u8, len: uint) -> RawContext {
+            rtassert!(len >= storage_bytes());
+            let regs = align_up(storage as uint) as *mut Registers;
+            RegisterFile::new(&mut *regs);
+            RawContext { regs: regs }
+        }
+
+        /// A context which calls `entry(data)` on the stack between `lo` and
+        /// `hi` when it's first swapped into. Its registers are kept at the
+        /// top of the stack, and the entry's frame starts below them.
+        ///
+        /// `entry` must never return, it has nowhere to return to. This is
+        /// unsafe because the stack must stay valid for as long as the
+        /// context is, and must not be in use by anything else. The stack is
+        /// left untouched, and `StackTooSmall` returned, if it can't hold the
+        /// registers as well as a new context's frame.
+        pub unsafe fn new(entry: extern "C" fn(*mut c_void), data: *mut c_void,
+                          lo: uint, hi: uint)
+                          -> Result<RawContext, ContextError> {
+            if lo > hi || hi - lo < storage_bytes() {
+                return Err(StackTooSmall)
+            }
+            let regs = (hi - mem::size_of::<Registers>()) &
+                       !(REGISTER_ALIGNMENT - 1);
+            if !fits_new_context(lo, regs) { return Err(StackTooSmall) }
+            let regs = regs as *mut Registers;
+            RegisterFile::new(&mut *regs)
+                .baseline()
+                .clear_fp_state(true)
+                .init_call_frame(entry as *c_void, data as *c_void, 0, lo,
+                                 regs as *mut uint);
+            Ok(RawContext { regs: regs })
+        }
+    }
+
+    /// Saves the running code's registers into `out_context` and switches to
+    /// `in_context`, returning once something switches back to `out_context`.
+    ///
+    /// Nothing but the registers is switched. This is unsafe because
+    /// `in_context` must be suspended (not running on this or any other
+    /// thread), and the memory of both must still be valid.
+    #[inline]
+    pub unsafe fn swap(out_context: &mut RawContext, in_context: &RawContext) {
+        rust_swap_registers(out_context.regs, in_context.regs as *Registers)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::unstable::stack;
+
+    use stack::StackSegment;
+    use super::{run_on_stack, Context, WrongArchitecture, InvalidSnapshot};
+    use super::{set_unbounded_stack, stack_headroom, set_swap_hook};
+    use super::{plan_call_frame, saved_sp, on_green_stack};
+    use super::{ContextBuilder, StackTooSmall, with_stack_bounds};
+    use super::{current_stack_bounds, ContextPool, ContextHandle};
+    use std::local_data;
+    use std::cast::transmute;
+    use std::libc::c_void;
+    use extra::test::BenchHarness;
+
+    // What a raw context run by `run_raw_probe` reports back to the test: its
+    // argument (or whatever else its entry records), and the address of one
+    // of the entry's locals
+    struct RawProbe { caller: Context, seen: uint, local: uint }
+
+    // Records the argument of a raw context's entry and the address of a local
+    // in the `RawProbe` which it is, then switches back to the probe's caller
+    // for good
+    fn report_probe(data: *mut c_void) {
+        unsafe {
+            let probe: &mut RawProbe = transmute(data);
+            probe.seen = data as uint;
+            let marker = 0u;
+            probe.local = &marker as *uint as uint;
+            let mut dead = Context::empty();
+            Context::swap(&mut dead, &probe.caller);
+        }
+    }
+
+    extern "C" fn probe_entry(data: *mut c_void) { report_probe(data) }
+
+    // Makes a raw context on `stack` entering `probe_entry`, lets `check` look
+    // at it along with the argument it's given, then runs it (see
+    // `run_raw_probe_with`) and returns what it reported
+    fn run_raw_probe(stack: &mut StackSegment,
+                     check: |&mut Context, *mut c_void|) -> RawProbe {
+        run_raw_probe_with(stack, |data, stack| unsafe {
+            Context::new_raw(probe_entry, data, stack)
+        }, check)
+    }
+
+    // Like `run_raw_probe`, for a context made by `new` with the argument it's
+    // handed, the address of a `RawProbe`. The context must end by switching
+    // back to the probe's caller, as `report_probe` does. The stack limit is
+    // put back afterwards.
+    fn run_raw_probe_with(stack: &mut StackSegment,
+                          new: |*mut c_void, &mut StackSegment| -> Context,
+                          check: |&mut Context, *mut c_void|) -> RawProbe {
+        let mut probe = RawProbe { caller: Context::empty(), seen: 0, local: 0 };
+        let data: *mut c_void = unsafe { transmute(&mut probe) };
+        let mut task = new(data, stack);
+        check(&mut task, data);
+        let limit = unsafe { stack::get_sp_limit() };
+        unsafe {
+            let caller: &mut Context = transmute(&mut probe.caller);
+            Context::swap(caller, &task);
+        }
+        unsafe { stack::record_sp_limit(limit) };
+        probe
+    }
+
+    #[test]
+    fn smoke_run_on_stack() {
+        let mut stack = StackSegment::new(1024 * 1024);
+        let ret = run_on_stack(&mut stack, proc() 1 + 2);
+        assert_eq!(ret, 3);
+    }
+
+    #[test]
+    fn run_on_stack_deep_recursion() {
+        fn recurse(n: uint) -> uint { if n == 0 { 0 } else { 1 + recurse(n - 1) } }
+        let mut stack = StackSegment::new(4 * 1024 * 1024);
+        let ret = run_on_stack(&mut stack, proc() recurse(10000));
+        assert_eq!(ret, 10000);
+    }
+
+    #[test]
+    fn run_on_stack_passes_failures_on() {
+        use std::any::{Any, AnyOwnExt};
+        use std::task;
+
+        let ret = task::try(proc() {
+            let mut stack = StackSegment::new(1024 * 1024);
+            run_on_stack(&mut stack, proc() -> uint { fail!("failed on {}", 1) })
+        });
+        match ret {
+            Err(failure) => {
+                assert_eq!(*failure.move::<~str>().unwrap(), ~"failed on 1")
+            }
+            Ok(..) => fail!("run_on_stack returned from a failing procedure"),
+        }
+    }
+
+    // This doubles as a self-check of the platform's TLS slot for the stack
+    // limit (e.g. on the BSDs): the limit that morestack sees while running on
+    // a green stack must fall inside of that stack, below the current frame.
+    #[test]
+    fn recorded_limit_is_within_stack() {
+        let mut stack = StackSegment::new(1024 * 1024);
+        let (lo, hi) = (stack.start() as uint, stack.end() as uint);
+        let (limit, sp) = run_on_stack(&mut stack, proc() {
+            let local = 0u;
+            (unsafe { stack::get_sp_limit() }, &local as *uint as uint)
+        });
+        assert!(lo < limit && limit < sp && sp < hi);
+    }
+
+    #[test]
+    fn registers_snapshot_round_trip() {
+        let mut stack = StackSegment::new(1024 * 1024);
+        let ctx = Context::new(proc() {}, &mut stack);
+        let snapshot = ctx.registers_snapshot();
+        let bounds = Some((stack.start() as uint, stack.end() as uint));
+        let restored = unsafe {
+            Context::from_registers_snapshot(snapshot, bounds).unwrap()
+        };
+        assert_eq!(restored.registers_snapshot(), snapshot);
+    }
+
+    #[test]
+    fn registers_snapshot_rejects_other_layouts() {
+        let snapshot = Context::empty().registers_snapshot();
+
+        let mut other_arch = snapshot.clone();
+        other_arch[8] += 1;
+        let ret = unsafe { Context::from_registers_snapshot(other_arch, None) };
+        assert!(ret.is_err() && ret.unwrap_err() == WrongArchitecture);
+
+        let truncated = snapshot.slice_to(snapshot.len() - 1);
+        let ret = unsafe { Context::from_registers_snapshot(truncated, None) };
+        assert!(ret.is_err() && ret.unwrap_err() == InvalidSnapshot);
+    }
+
+    // Runs `f` with the stack limit lifted, then puts back everything that
+    // lifting it recorded over: the limit, the high end of the stack kept for
+    // `current_stack_bounds`, and on windows the TIB's stack base, limit and
+    // deallocation stack, which SEH checks against
+    fn with_unbounded_stack<T>(f: || -> T) -> T {
+        use super::{save_native_tib, restore_native_tib, recorded_stack_hi};
+        use super::record_stack_hi;
+
+        let saved = Context::empty();
+        unsafe {
+            let limit = stack::get_sp_limit();
+            let hi = recorded_stack_hi();
+            save_native_tib(&saved);
+            set_unbounded_stack();
+            let ret = f();
+            record_stack_hi(hi);
+            restore_native_tib(&saved);
+            stack::record_sp_limit(limit);
+            ret
+        }
+    }
+
+    #[test]
+    fn unbounded_stack_has_max_headroom() {
+        fn recurse(n: uint) -> uint { if n == 0 { 0 } else { 1 + recurse(n - 1) } }
+        let (headroom, depth, on_green) = with_unbounded_stack(|| {
+            // Scheduler code running unbounded must never trip morestack
+            (stack_headroom(), recurse(1000), on_green_stack())
+        });
+        assert!(!on_green);
+        assert_eq!(headroom, ::std::uint::max_value);
+        assert_eq!(depth, 1000);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn lifting_the_stack_limit_leaves_the_tib_as_it_was() {
+        use super::recorded_stack_hi;
+
+        let before = unsafe { recorded_stack_hi() };
+        let during = with_unbounded_stack(|| unsafe { recorded_stack_hi() });
+        assert_eq!(during, ::std::uint::max_value);
+        assert_eq!(unsafe { recorded_stack_hi() }, before);
+    }
+
+    #[test]
+    fn task_headroom_is_bounded() {
+        let mut stack = StackSegment::new(1024 * 1024);
+        let (headroom, on_green) = run_on_stack(&mut stack, proc() {
+            (stack_headroom(), on_green_stack())
+        });
+        assert!(0 < headroom && headroom < 1024 * 1024);
+        assert!(on_green);
+    }
+
+    // Checks that the raw entry argument arrives bit-for-bit intact, which is
+    // where pointer width mismatches between the register slots and pointers
+    // (such as on x32) would show up.
+    #[test]
+    fn raw_entry_receives_its_argument() {
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut arg = 0u;
+        let probe = run_raw_probe(&mut stack, |_, data| arg = data as uint);
+        assert_eq!(probe.seen, arg);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86")]
+    #[cfg(target_arch = "x86_64")]
+    fn unwind_on_drop_runs_destructors() {
+        struct Flag { dropped: *mut bool }
+        impl Drop for Flag {
+            fn drop(&mut self) { unsafe { *self.dropped = true } }
+        }
+
+        let mut dropped = false;
+        let mut caller = Context::empty();
+        let dropped_ptr: uint = unsafe { transmute(&mut dropped) };
+        let caller_ptr: uint = unsafe { transmute(&caller) };
+        let mut task_at = 0u;
+        let task_at_ptr: uint = unsafe { transmute(&mut task_at) };
+        let mut stack = StackSegment::new(1024 * 1024);
+        {
+            let mut task = Context::new(proc() {
+                let _flag = Flag { dropped: unsafe { transmute(dropped_ptr) } };
+                unsafe {
+                    let task: &mut Context = transmute(*(task_at_ptr as *uint));
+                    Context::swap(task, transmute(caller_ptr));
+                }
+                unreachable!();
+            }, &mut stack);
+            task_at = &mut task as *mut Context as uint;
+            task.set_unwind_on_drop(true);
+
+            let limit = unsafe { stack::get_sp_limit() };
+            Context::swap(&mut caller, &task);
+            unsafe { stack::record_sp_limit(limit) };
+            assert!(!dropped);
+        }
+        assert!(dropped);
+
+        // The task's last swap, back to the drop, finished its swap as well,
+        // so another one can still start
+        assert_eq!(run_on_stack(&mut stack, proc() 1 + 2), 3);
+    }
+
+    #[test]
+    fn swap_hook_records_instead_of_switching() {
+        local_data_key!(swaps: ~[(uint, uint)])
+        fn record(out_context: &Context, in_context: &Context) {
+            let pair = (out_context as *Context as uint,
+                        in_context as *Context as uint);
+            local_data::modify(swaps, |v| {
+                let mut v = v.unwrap_or(~[]);
+                v.push(pair);
+                Some(v)
+            });
+        }
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let task = Context::new(proc() fail!(), &mut stack);
+        let mut sched = Context::empty();
+        set_swap_hook(Some(record));
+        Context::swap(&mut sched, &task);
+        Context::swap(&mut sched, &task);
+        set_swap_hook(None);
+
+        let pair = (&sched as *Context as uint, &task as *Context as uint);
+        local_data::get(swaps, |v| assert_eq!(v.unwrap(), &~[pair, pair]));
+    }
+
+    #[test]
+    fn plan_call_frame_matches_new() {
+        let size = 64 * 1024;
+        let plan = plan_call_frame(size);
+        assert_eq!(plan.red_zone + plan.abi_red_zone + plan.usable,
+                   plan.initial_sp);
+        assert_eq!(plan.abi_red_zone, super::red_zone_size());
+        assert!(plan.abi_red_zone < plan.usable);
+        assert!(plan.fits);
+
+        // The stack's top may not be aligned, which the plan doesn't know
+        let mut stack = StackSegment::new(size);
+        let ctx = Context::new(proc() {}, &mut stack);
+        let below_top = stack.end() as uint - saved_sp(&ctx.frame.regs);
+        assert!(size - plan.initial_sp <= below_top);
+        assert!(below_top < size - plan.initial_sp + 16);
+    }
+
+    #[test]
+    fn plan_call_frame_agrees_with_try_new() {
+        use super::{MIN_STACK_SIZE, STACK_ALIGNMENT};
+
+        // The smallest aligned stack which fits, and the largest which doesn't
+        let least = (super::validate_reserved() + MIN_STACK_SIZE +
+                     STACK_ALIGNMENT - 1) & !(STACK_ALIGNMENT - 1);
+        let plan = plan_call_frame(least);
+        assert!(plan.fits && plan.usable >= MIN_STACK_SIZE);
+        let mut stack = StackSegment::new(least);
+        assert!(Context::try_new(proc() {}, &mut stack).is_ok());
+
+        let plan = plan_call_frame(least - 1);
+        assert!(!plan.fits && plan.usable < MIN_STACK_SIZE);
+        let mut stack = StackSegment::new(least - 1);
+        let ret = Context::try_new(proc() {}, &mut stack);
+        assert!(ret.is_err() && ret.unwrap_err() == StackTooSmall);
+    }
+
+    #[test]
+    fn raw_entry_takes_later_argument() {
+        extern "C" fn entry(_unused: *mut c_void, data: *mut c_void) {
+            report_probe(data)
+        }
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut arg = 0u;
+        let probe = run_raw_probe_with(&mut stack, |data, stack| unsafe {
+            Context::new_raw_with_arg_pos(entry as *c_void, data, 1, stack)
+        }, |_, data| arg = data as uint);
+        assert_eq!(probe.seen, arg);
+    }
+
+    #[test]
+    #[cfg(task_sigaltstack, unix)]
+    fn signal_stack_is_swapped() {
+        use signal_stack::AltStack;
+
+        let before = AltStack::current();
+        let mut seen = 0u;
+        let mut caller = Context::empty();
+        let seen_ptr: uint = unsafe { transmute(&mut seen) };
+        let caller_ptr: uint = unsafe { transmute(&caller) };
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut task = Context::new(proc() {
+            unsafe { *transmute::<uint, &mut uint>(seen_ptr) = AltStack::current().start() };
+            let mut dead = Context::empty();
+            Context::swap(&mut dead, unsafe { transmute(caller_ptr) });
+        }, &mut stack);
+        task.set_signal_stack(64 * 1024);
+        let expected = match task.signal_stack {
+            Some(ref ss) => ss.start(),
+            None => fail!(),
+        };
+
+        let limit = unsafe { stack::get_sp_limit() };
+        Context::swap(&mut caller, &task);
+        unsafe { stack::record_sp_limit(limit) };
+        // The task never swapped out as itself, so it couldn't put the
+        // thread's stack back
+        unsafe { before.install().unwrap(); }
+        assert_eq!(seen, expected);
+    }
+
+    #[test]
+    fn builder_options_combine() {
+        use super::BadAlignment;
+
+        let ret = ContextBuilder::new().alignment(48).build(proc() {});
+        assert!(ret.is_err() && ret.unwrap_err() == BadAlignment);
+        let ret = ContextBuilder::new().alignment(super::STACK_ALIGNMENT / 2)
+                                       .build(proc() {});
+        assert!(ret.is_err() && ret.unwrap_err() == BadAlignment);
+
+        let (mut ctx, stack) = ContextBuilder::new().stack_size(64 * 1024)
+                                                    .guard_page(true)
+                                                    .alignment(256)
+                                                    .build_with_result(proc() 6 * 7)
+                                                    .unwrap();
+        let lo = stack.start() as uint;
+        assert!(stack.guard_page().unwrap() < lo);
+        let sp = saved_sp(&ctx.frame.regs);
+        let top = stack.end() as uint & !255;
+        assert!(sp < top &&
+                top - sp <= super::INITIAL_FRAME_BYTES + super::CANARY_BYTES + 256);
+        assert_eq!((sp + super::ENTRY_SP_MISALIGNMENT) & 255, 0);
+
+        let mut caller = Context::empty();
+        let limit = unsafe { stack::get_sp_limit() };
+        Context::swap(&mut caller, &ctx);
+        unsafe { stack::record_sp_limit(limit) };
+        assert_eq!(ctx.take_result::<int>(), Some(42));
+    }
+
+    #[test]
+    fn names_survive_reuse() {
+        let (mut ctx, _stack) = ContextBuilder::new().name(~"worker")
+                                                     .build(proc() {}).unwrap();
+        assert_eq!(ctx.name(), Some("worker"));
+        unsafe { ctx.rearm(proc() {}); }
+        assert_eq!(ctx.name(), Some("worker"));
+        ctx.set_name(None);
+        assert_eq!(ctx.name(), None);
+        assert_eq!(Context::empty().name(), None);
+    }
+
+    #[test]
+    fn builder_checks_options() {
+        let ret = ContextBuilder::new().stack_size(0).build(proc() {});
+        assert!(ret.is_err() && ret.unwrap_err() == StackTooSmall);
+        let ret = ContextBuilder::new().signal_stack(16).build(proc() {});
+        assert!(ret.is_err() && ret.unwrap_err() == StackTooSmall);
+        let ret = ContextBuilder::new().stack_size(0).build_with_result(proc() 1u);
+        assert!(ret.is_err() && ret.unwrap_err() == StackTooSmall);
+
+        let (ctx, stack) = ContextBuilder::new().stack_size(64 * 1024)
+                                                .build(proc() {}).unwrap();
+        let (lo, hi) = (stack.start() as uint, stack.end() as uint);
+        assert_eq!(hi - lo, 64 * 1024);
+        assert_eq!(ctx.stack_bounds, Some((lo, hi)));
+    }
+
+    // The argument must arrive with exactly the bits it was given, whatever
+    // the byte order and however the slot it travels in is sized. A pattern
+    // with a different value in every byte shows up any mixing.
+    #[test]
+    fn raw_entry_argument_is_bit_exact() {
+        static mut SEEN: uint = 0;
+        static mut CALLER: *Context = 0 as *Context;
+
+        extern "C" fn entry(data: *mut c_void) {
+            unsafe {
+                SEEN = data as uint;
+                let mut dead = Context::empty();
+                Context::swap(&mut dead, &*CALLER);
+            }
+        }
+
+        #[cfg(target_word_size = "64")] static PATTERN: uint = 0x0123456789abcdef;
+        #[cfg(target_word_size = "32")] static PATTERN: uint = 0x01234567;
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut caller = Context::empty();
+        let task = unsafe {
+            Context::new_raw(entry, PATTERN as *mut c_void, &mut stack)
+        };
+        unsafe {
+            CALLER = &caller as *Context;
+            let limit = stack::get_sp_limit();
+            Context::swap(&mut caller, &task);
+            stack::record_sp_limit(limit);
+            assert_eq!(SEEN, PATTERN);
+        }
+    }
+
+    #[test]
+    #[cfg(task_tracing)]
+    fn span_hook_sees_spans() {
+        use super::{set_span_hook, SpanToken};
+
+        local_data_key!(switches: ~[(Option<SpanToken>, Option<SpanToken>)])
+        fn record(out_span: Option<SpanToken>, in_span: Option<SpanToken>) {
+            local_data::modify(switches, |v| {
+                let mut v = v.unwrap_or(~[]);
+                v.push((out_span, in_span));
+                Some(v)
+            });
+        }
+
+        let mut caller = Context::empty();
+        caller.set_span(Some(1));
+        let caller_ptr: uint = unsafe { transmute(&caller) };
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut task = Context::new(proc() {
+            let mut dead = Context::empty();
+            Context::swap(&mut dead, unsafe { transmute(caller_ptr) });
+        }, &mut stack);
+        task.set_span(Some(2));
+
+        let limit = unsafe { stack::get_sp_limit() };
+        set_span_hook(Some(record));
+        Context::swap(&mut caller, &task);
+        set_span_hook(None);
+        unsafe { stack::record_sp_limit(limit) };
+
+        local_data::get(switches, |v| {
+            assert_eq!(v.unwrap(), &~[(Some(1), Some(2)), (None, Some(1))])
+        });
+    }
+
+    #[test]
+    #[cfg(lazy_fp)]
+    fn lazy_fp_tracks_dirty_contexts() {
+        use super::fp_untouched;
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let task = Context::new(proc() {}, &mut stack);
+        let sched = Context::empty();
+        // Nothing is known about the scheduler's code, so it's saved
+        assert!(!fp_untouched(&sched, &task));
+        // and then has to be restored
+        assert!(!fp_untouched(&task, &sched));
+        unsafe { sched.mark_fp_clean(); }
+        assert!(fp_untouched(&task, &sched));
+        task.mark_fp_dirty();
+        assert!(!fp_untouched(&task, &sched));
+        assert!(task.fp_saved.get() && !task.fp_dirty.get());
+    }
+
+    #[test]
+    fn with_stack_bounds_restores_the_limit() {
+        use std::any::Any;
+        use std::rt::unwind::Unwinder;
+
+        let limit = unsafe { stack::get_sp_limit() };
+        let here = 0u;
+        let sp = &here as *uint as uint;
+        let (lo, hi) = (sp - 32 * 1024, sp + 1024);
+        let seen = unsafe { with_stack_bounds(lo, hi, || stack::get_sp_limit()) };
+        if !cfg!(windows) && !cfg!(bare_metal) {
+            assert_eq!(seen, lo + stack::RED_ZONE);
+        }
+        assert_eq!(unsafe { stack::get_sp_limit() }, limit);
+
+        // A private unwinder, so that this task isn't marked as failing
+        let mut unwinder = Unwinder::new();
+        unwinder.try(|| unsafe {
+            with_stack_bounds(lo, hi, || {
+                let mut inner = Unwinder::new();
+                inner.begin_unwind(~"failure" as ~Any);
+            })
+        });
+        assert!(unwinder.result().is_err());
+        assert_eq!(unsafe { stack::get_sp_limit() }, limit);
+    }
+
+    #[test]
+    fn switch_rate_window() {
+        use super::{SwitchTiming, RATE_WINDOW_NS};
+
+        let timing = SwitchTiming::new();
+        let start = timing.window_start.get();
+        for i in range(0u64, 10) {
+            timing.resume(start + i * RATE_WINDOW_NS / 10);
+        }
+        assert_eq!(timing.rate(start + RATE_WINDOW_NS - 1).round(), 10.0);
+        // Half of the previous window still counts
+        assert_eq!(timing.rate(start + RATE_WINDOW_NS * 3 / 2), 5.0);
+        assert_eq!(timing.rate(start + RATE_WINDOW_NS * 3), 0.0);
+    }
+
+    #[test]
+    fn rearm_runs_a_new_task_on_the_same_stack() {
+        let mut runs = ~[];
+        let mut caller = Context::empty();
+        let runs_ptr: uint = unsafe { transmute(&mut runs) };
+        let caller_ptr: uint = unsafe { transmute(&caller) };
+        fn job(n: uint, runs_ptr: uint, caller_ptr: uint) -> proc() {
+            proc() {
+                let here = 0u;
+                let runs: &mut ~[(uint, uint)] = unsafe { transmute(runs_ptr) };
+                runs.push((n, &here as *uint as uint));
+                let mut dead = Context::empty();
+                Context::swap(&mut dead, unsafe { transmute(caller_ptr) });
+            }
+        }
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut task = Context::new(job(1, runs_ptr, caller_ptr), &mut stack);
+        let limit = unsafe { stack::get_sp_limit() };
+        Context::swap(&mut caller, &task);
+        unsafe { task.rearm(job(2, runs_ptr, caller_ptr)); }
+        assert!(!task.started.get());
+        Context::swap(&mut caller, &task);
+        unsafe { stack::record_sp_limit(limit) };
+
+        let (lo, hi) = (stack.start() as uint, stack.end() as uint);
+        assert_eq!(runs.len(), 2);
+        assert_eq!((runs[0].val0(), runs[1].val0()), (1, 2));
+        // The second task started afresh from the top of the stack
+        let (sp1, sp2) = (runs[0].val1(), runs[1].val1());
+        assert!(lo <= sp1 && sp1 < hi);
+        assert_eq!(sp1, sp2);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86")]
+    #[cfg(target_arch = "x86_64")]
+    fn new_contexts_end_the_frame_pointer_chain() {
+        use super::saved_fp;
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let task = Context::new(proc() {}, &mut stack);
+        let fp = saved_fp(&task.frame.regs);
+        assert!(stack.start() as uint < fp && fp < stack.end() as uint);
+        assert!(fp > saved_sp(&task.frame.regs));
+        unsafe {
+            assert_eq!(*(fp as *uint), 0);
+            assert_eq!(*(fp as *uint).offset(1), 0);
+        }
+    }
+
+    // The same scenario for every architecture's backend: the argument
+    // arrives, the initial frame is terminated by a zero return address, the
+    // stack pointer stays within the recorded bounds, and locals survive every
+    // yield back to the caller and resume.
+    #[test]
+    fn every_architecture_meets_the_same_contract() {
+        static YIELDS: uint = 5;
+        static SENTINEL: uint = 0x5a5a5a5a;
+        struct Scenario {
+            caller: Context,
+            task: *mut Context,
+            arg: uint,
+            sps: ~[uint],
+            intact: bool,
+            finished: bool,
+        }
+
+        extern "C" fn entry(data: *mut c_void) {
+            unsafe {
+                let s: &mut Scenario = transmute(data);
+                s.arg = data as uint;
+                for i in range(0, YIELDS) {
+                    let local = SENTINEL ^ i;
+                    s.sps.push(&local as *uint as uint);
+                    Context::swap(&mut *s.task, &s.caller);
+                    s.intact = s.intact && local == SENTINEL ^ i;
+                }
+                s.finished = true;
+                let mut dead = Context::empty();
+                Context::swap(&mut dead, &s.caller);
+            }
+        }
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let (lo, hi) = (stack.start() as uint, stack.end() as uint);
+        let mut s = Scenario {
+            caller: Context::empty(), task: 0 as *mut Context, arg: 0,
+            sps: ~[], intact: true, finished: false,
+        };
+        let data: *mut c_void = unsafe { transmute(&mut s) };
+        let mut task = unsafe { Context::new_raw(entry, data, &mut stack) };
+        s.task = &mut task as *mut Context;
+
+        assert_eq!(task.stack_bounds, Some((lo, hi)));
+        let sp = saved_sp(&task.frame.regs);
+        assert!(lo < sp && sp < hi &&
+                hi - sp <= super::INITIAL_FRAME_BYTES + super::CANARY_BYTES + 16);
+        assert_eq!(unsafe { *(sp as *uint) }, 0);
+
+        let limit = unsafe { stack::get_sp_limit() };
+        for i in range(0, YIELDS + 1) {
+            unsafe {
+                let caller: &mut Context = transmute(&mut s.caller);
+                Context::swap(caller, &*s.task);
+            }
+            assert_eq!(s.sps.len(), if i < YIELDS { i + 1 } else { YIELDS });
+        }
+        unsafe { stack::record_sp_limit(limit) };
+
+        assert_eq!(s.arg, data as uint);
+        assert!(s.intact && s.finished);
+        for &sp in s.sps.iter() {
+            assert!(lo + stack::RED_ZONE < sp && sp < hi);
+        }
+    }
+
+    #[test]
+    fn switch_reason_is_recorded() {
+        use super::{Yielded, Finished};
+
+        let mut caller = Context::empty();
+        let caller_ptr: uint = unsafe { transmute(&caller) };
+        let mut stack = StackSegment::new(1024 * 1024);
+        let task = Context::new(proc() {
+            let mut dead = Context::empty();
+            Context::swap_with_reason(&mut dead, unsafe { transmute(caller_ptr) },
+                                      Finished);
+        }, &mut stack);
+
+        assert_eq!(caller.last_switch_reason(), None);
+        let limit = unsafe { stack::get_sp_limit() };
+        Context::swap_with_reason(&mut caller, &task, Yielded);
+        unsafe { stack::record_sp_limit(limit) };
+        assert_eq!(caller.last_switch_reason(), Some(Yielded));
+    }
+
+    #[test]
+    #[cfg(target_arch = "riscv64")]
+    fn riscv64_register_indices() {
+        use super::{RA, SP, A0};
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut arg = 0u;
+        let probe = run_raw_probe(&mut stack, |task, data| {
+            let regs = &task.frame.regs;
+            assert_eq!(regs[RA], probe_entry as uint as u64);
+            assert_eq!(regs[SP] % 16, 0);
+            assert_eq!(regs[A0], data as uint as u64);
+            arg = data as uint;
+        });
+        assert_eq!(probe.seen, arg);
+    }
+
+    #[test]
+    #[cfg(target_arch = "mips64")]
+    fn mips64_register_indices() {
+        use super::{T9, RA, SP, A0};
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut arg = 0u;
+        let probe = run_raw_probe(&mut stack, |task, data| {
+            let regs = &task.frame.regs;
+            assert_eq!(regs[T9], probe_entry as uint as u64);
+            assert_eq!(regs[RA], probe_entry as uint as u64);
+            assert_eq!(regs[SP] % 16, 0);
+            assert_eq!(regs[A0], data as uint as u64);
+            arg = data as uint;
+        });
+        assert_eq!(probe.seen, arg);
+    }
+
+    #[test]
+    fn task_result_is_taken_once() {
+        use std::any::Any;
+        use std::rt::unwind::Unwinder;
+
+        let mut caller = Context::empty();
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut task = Context::new_with_result(proc() 42u, &mut stack);
+        assert_eq!(task.take_result::<uint>(), None);
+        let limit = unsafe { stack::get_sp_limit() };
+        Context::swap(&mut caller, &task);
+        unsafe { stack::record_sp_limit(limit) };
+        assert_eq!(task.take_result::<uint>(), Some(42));
+        assert_eq!(task.take_result::<uint>(), None);
+
+        // A private unwinder, so that this task isn't marked as failing
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut task = Context::new_with_result(proc() -> uint {
+            let mut unwinder = Unwinder::new();
+            unwinder.begin_unwind(~"failure" as ~Any)
+        }, &mut stack);
+        Context::swap(&mut caller, &task);
+        unsafe { stack::record_sp_limit(limit) };
+        assert_eq!(task.take_result::<uint>(), None);
+    }
+
+    #[test]
+    #[cfg(context_stats)]
+    fn swaps_are_counted() {
+        use super::{swap_count, context_stats};
+
+        static YIELDS: uint = 100;
+        struct Pair { caller: Context, task: *mut Context }
+
+        extern "C" fn entry(data: *mut c_void) {
+            unsafe {
+                let p: &mut Pair = transmute(data);
+                loop { Context::swap(&mut *p.task, &p.caller); }
+            }
+        }
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut p = Pair { caller: Context::empty(), task: 0 as *mut Context };
+        let data: *mut c_void = unsafe { transmute(&mut p) };
+        let mut task = unsafe { Context::new_raw(entry, data, &mut stack) };
+        p.task = &mut task as *mut Context;
+
+        // Other tests may be switching contexts at the same time, so this
+        // can only check for at least the switches made here.
+        let before = context_stats();
+        let count_before = swap_count();
+        let limit = unsafe { stack::get_sp_limit() };
+        for _ in range(0, YIELDS) {
+            unsafe {
+                let caller: &mut Context = transmute(&mut p.caller);
+                Context::swap(caller, &*p.task);
+            }
+        }
+        unsafe { stack::record_sp_limit(limit) };
+        let after = context_stats();
+
+        assert!(swap_count() - count_before >= 2 * YIELDS);
+        assert!(after.task_swaps() - before.task_swaps() >= YIELDS);
+        assert!(after.scheduler_swaps - before.scheduler_swaps >= YIELDS);
+    }
+
+    #[test]
+    fn switch_hook_sees_both_contexts() {
+        use std::sync::atomics::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+        use super::set_switch_hook;
+
+        // The hook is process-wide, so it only takes note of switches into
+        // the context made here.
+        static mut WATCHED: AtomicUint = INIT_ATOMIC_UINT;
+        static mut SEEN: AtomicUint = INIT_ATOMIC_UINT;
+        fn hook(out_context: &Context, in_context: &Context) {
+            unsafe {
+                if in_context.id() == WATCHED.load(SeqCst) {
+                    assert!(out_context.stack_bounds().is_none());
+                    assert!(in_context.stack_bounds().is_some());
+                    SEEN.fetch_add(1, SeqCst);
+                }
+            }
+        }
+
+        let mut caller = Context::empty();
+        let caller_ptr: uint = unsafe { transmute(&caller) };
+        let mut stack = StackSegment::new(1024 * 1024);
+        let task = Context::new(proc() {
+            let mut dead = Context::empty();
+            Context::swap(&mut dead, unsafe { transmute(caller_ptr) });
+        }, &mut stack);
+
+        unsafe { WATCHED.store(task.id(), SeqCst) }
+        set_switch_hook(Some(hook));
+        let limit = unsafe { stack::get_sp_limit() };
+        Context::swap(&mut caller, &task);
+        unsafe { stack::record_sp_limit(limit) };
+        set_switch_hook(None);
+        assert_eq!(unsafe { SEEN.load(SeqCst) }, 1);
+    }
+
+    #[test]
+    #[cfg(not(target_os = "android"))]
+    fn current_stack_bounds_follow_swaps() {
+        let mut stack = StackSegment::new(1024 * 1024);
+        let (lo, hi) = (stack.start() as uint, stack.end() as uint);
+        let bounds = run_on_stack(&mut stack, proc() current_stack_bounds());
+        assert_eq!(bounds, Some((lo, hi)));
+
+        let unbounded = with_unbounded_stack(|| current_stack_bounds());
+        assert_eq!(unbounded, None);
+    }
+
+    #[test]
+    fn stack_bounds_can_be_found_from_a_saved_limit() {
+        use super::stack_bounds_for_limit;
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let (lo, hi) = (stack.start() as uint, stack.end() as uint);
+        let bounds = run_on_stack(&mut stack, proc() {
+            let limit = unsafe { stack::get_sp_limit() };
+            unsafe { stack::record_sp_limit(0) };
+            let bounds = stack_bounds_for_limit(limit);
+            unsafe { stack::record_sp_limit(limit) };
+            bounds
+        });
+        assert_eq!(bounds, Some((lo, hi)));
+        assert_eq!(stack_bounds_for_limit(0), None);
+    }
+
+    #[test]
+    fn reset_moves_a_context_to_another_stack() {
+        let mut caller = Context::empty();
+        let caller_ptr: uint = unsafe { transmute(&caller) };
+        let mut sps = ~[];
+        let sps_ptr: uint = unsafe { transmute(&mut sps) };
+        fn job(sps_ptr: uint, caller_ptr: uint) -> proc() {
+            proc() {
+                let here = 0u;
+                let sps: &mut ~[uint] = unsafe { transmute(sps_ptr) };
+                sps.push(&here as *uint as uint);
+                let mut dead = Context::empty();
+                Context::swap(&mut dead, unsafe { transmute(caller_ptr) });
+            }
+        }
+
+        let mut first = StackSegment::new(1024 * 1024);
+        let mut second = StackSegment::new(1024 * 1024);
+        let mut task = Context::new(job(sps_ptr, caller_ptr), &mut first);
+        let limit = unsafe { stack::get_sp_limit() };
+        Context::swap(&mut caller, &task);
+        unsafe { task.reset(job(sps_ptr, caller_ptr), &mut second); }
+        let bounds = (second.start() as uint, second.end() as uint);
+        assert_eq!(task.stack_bounds(), Some(bounds));
+        assert!(!task.started.get());
+        Context::swap(&mut caller, &task);
+        unsafe { stack::record_sp_limit(limit) };
+
+        assert_eq!(sps.len(), 2);
+        assert!(first.start() as uint <= sps[0] && sps[0] < first.end() as uint);
+        assert!(bounds.val0() <= sps[1] && sps[1] < bounds.val1());
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn scheduler_gets_its_tib_back() {
+        use super::recorded_stack_hi;
+
+        let mut caller = Context::empty();
+        let caller_ptr: uint = unsafe { transmute(&caller) };
+        let mut stack = StackSegment::new(1024 * 1024);
+        let hi = stack.end() as uint;
+        let mut seen = 0u;
+        let seen_ptr: uint = unsafe { transmute(&mut seen) };
+        let task = Context::new(proc() {
+            unsafe { *(seen_ptr as *mut uint) = recorded_stack_hi() }
+            let mut dead = Context::empty();
+            Context::swap(&mut dead, unsafe { transmute(caller_ptr) });
+        }, &mut stack);
+
+        let before = unsafe { recorded_stack_hi() };
+        let limit = unsafe { stack::get_sp_limit() };
+        Context::swap(&mut caller, &task);
+        unsafe { stack::record_sp_limit(limit) };
+        assert_eq!(seen, hi);
+        assert_eq!(unsafe { recorded_stack_hi() }, before);
+    }
+
+    #[test]
+    fn floating_point_survives_yields() {
+        struct Pair { caller: Context, task: *mut Context, result: f64 }
+
+        // Keeps values live in the callee-saved floating point registers
+        // across each switch back to the caller.
+        extern "C" fn entry(data: *mut c_void) {
+            unsafe {
+                let p: &mut Pair = transmute(data);
+                let (mut x, mut y) = (1.5f64, 0.25f64);
+                for _ in range(0, 4) {
+                    x = x * 2.0 + y;
+                    Context::swap(&mut *p.task, &p.caller);
+                    y = y * 3.0 - x / 8.0;
+                }
+                p.result = x + y;
+                let mut dead = Context::empty();
+                Context::swap(&mut dead, &p.caller);
+            }
+        }
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut p = Pair { caller: Context::empty(), task: 0 as *mut Context,
+                           result: 0.0 };
+        let data: *mut c_void = unsafe { transmute(&mut p) };
+        let mut task = unsafe { Context::new_raw(entry, data, &mut stack) };
+        p.task = &mut task as *mut Context;
+
+        let limit = unsafe { stack::get_sp_limit() };
+        for i in range(0, 5) {
+            // Clobber the caller's registers in between
+            let z = (i as f64) * 7.75 - 1.0 / 3.0;
+            unsafe {
+                let caller: &mut Context = transmute(&mut p.caller);
+                Context::swap(caller, &*p.task);
+            }
+            assert!(z == (i as f64) * 7.75 - 1.0 / 3.0);
+        }
+        unsafe { stack::record_sp_limit(limit) };
+
+        let (mut x, mut y) = (1.5f64, 0.25f64);
+        for _ in range(0, 4) {
+            x = x * 2.0 + y;
+            y = y * 3.0 - x / 8.0;
+        }
+        assert_eq!(p.result, x + y);
+    }
+
+    #[test]
+    fn align_down_uses_the_architecture_alignment() {
+        use super::{align_down, STACK_ALIGNMENT};
+
+        assert_eq!(STACK_ALIGNMENT & (STACK_ALIGNMENT - 1), 0);
+        let base = 0x10000u;
+        for off in range(0, 2 * STACK_ALIGNMENT) {
+            let sp = align_down((base + off) as *mut uint) as uint;
+            assert_eq!(sp % STACK_ALIGNMENT, 0);
+            assert!(sp <= base + off && base + off - sp < STACK_ALIGNMENT);
+        }
+    }
+
+    #[test]
+    fn zeroed_and_snapshotted_registers_run_alike() {
+        use super::RegisterFile;
+
+        struct Run { caller: Context, task: *mut Context, arg: uint,
+                     sum: uint, intact: bool }
+
+        extern "C" fn entry(data: *mut c_void) {
+            unsafe {
+                let r: &mut Run = transmute(data);
+                r.arg = data as uint;
+                let local = 0x1234u;
+                for i in range(0u, 3) {
+                    r.sum += i * local;
+                    Context::swap(&mut *r.task, &r.caller);
+                }
+                r.intact = local == 0x1234;
+                let mut dead = Context::empty();
+                Context::swap(&mut dead, &r.caller);
+            }
+        }
+
+        fn run(snapshot: bool) -> (uint, bool) {
+            let mut stack = StackSegment::new(1024 * 1024);
+            let mut r = Run { caller: Context::empty(), task: 0 as *mut Context,
+                              arg: 0, sum: 0, intact: false };
+            let data: *mut c_void = unsafe { transmute(&mut r) };
+            let mut task = unsafe { Context::new_raw(entry, data, &mut stack) };
+            if snapshot {
+                RegisterFile::new(&mut task.frame.regs)
+                    .snapshot()
+                    .init_call_frame(entry as *c_void, data as *c_void, 0,
+                                     stack.start() as uint,
+                                     stack.end() as *mut uint);
+            }
+            r.task = &mut task as *mut Context;
+
+            let limit = unsafe { stack::get_sp_limit() };
+            for _ in range(0, 4) {
+                unsafe {
+                    let caller: &mut Context = transmute(&mut r.caller);
+                    Context::swap(caller, &*r.task);
+                }
+            }
+            unsafe { stack::record_sp_limit(limit) };
+            assert_eq!(r.arg, data as uint);
+            (r.sum, r.intact)
+        }
+
+        assert_eq!(run(false), run(true));
+        assert_eq!(run(false), (3 * 0x1234, true));
+    }
+
+    #[test]
+    fn checked_mut_offset_stays_in_bounds() {
+        use std::{int, uint};
+        use super::checked_mut_offset;
+
+        let base = 0x1000 as *mut u64;
+        assert_eq!(checked_mut_offset(base, -2, 0xff0, 0x1010), Some(0xff0 as *mut u64));
+        assert_eq!(checked_mut_offset(base, 1, 0xff0, 0x1010), Some(0x1008 as *mut u64));
+        assert_eq!(checked_mut_offset(base, -3, 0xff0, 0x1010), None);
+        assert_eq!(checked_mut_offset(base, 2, 0xff0, 0x1010), None);
+        assert_eq!(checked_mut_offset(base, int::min_value, 0, uint::max_value), None);
+        assert_eq!(checked_mut_offset(base, int::max_value, 0, uint::max_value), None);
+    }
+
+    // Stacks mapped in the upper half of the address space, where addresses
+    // are negative as `int`s, and right at the top of it
+    #[test]
+    fn frame_arithmetic_works_at_high_addresses() {
+        use std::uint;
+        use super::{align_down, checked_mut_offset, mut_offset, STACK_ALIGNMENT};
+
+        let mid = uint::max_value / 2 + 1;
+        let top = uint::max_value & !(STACK_ALIGNMENT - 1);
+        for &sp in [mid, mid + 8, top].iter() {
+            let p = sp as *mut u64;
+            assert_eq!(mut_offset(p, -2) as uint, sp - 16);
+            assert_eq!(mut_offset(mut_offset(p, -3), 3) as uint, sp);
+            assert_eq!(checked_mut_offset(p, -2, sp - 16, sp + 1),
+                       Some((sp - 16) as *mut u64));
+            let aligned = align_down((sp + 3) as *mut uint) as uint;
+            assert_eq!(aligned % STACK_ALIGNMENT, 0);
+            assert!(aligned <= sp + 3 && sp + 3 - aligned < STACK_ALIGNMENT);
+        }
+        // Crossing the midpoint, which is where `int` arithmetic overflows
+        assert_eq!(mut_offset((mid - 8) as *mut u64, 2) as uint, mid + 8);
+        assert_eq!(mut_offset((mid + 8) as *mut u64, -2) as uint, mid - 8);
+        // Nothing fits above the very top
+        assert_eq!(checked_mut_offset(top as *mut u64, 2, 0, uint::max_value), None);
+    }
+
+    // A leaf function on the entry's stack may keep its locals in the ABI's
+    // red zone without moving the stack pointer. That mustn't reach the
+    // initial frame above the entry's stack pointer.
+    #[test]
+    fn red_zone_leaves_the_initial_frame_alone() {
+        struct Leaf { caller: Context, sum: uint }
+
+        #[inline(never)]
+        fn leaf(seed: uint) -> uint {
+            let mut buf = [0u8, ..96];
+            for i in range(0, buf.len()) { buf[i] = (seed + i) as u8; }
+            buf.iter().fold(0u, |a, &b| a + b as uint)
+        }
+
+        extern "C" fn entry(data: *mut c_void) {
+            unsafe {
+                let l: &mut Leaf = transmute(data);
+                l.sum = leaf(3);
+                let mut dead = Context::empty();
+                Context::swap(&mut dead, &l.caller);
+            }
+        }
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut l = Leaf { caller: Context::empty(), sum: 0 };
+        let data: *mut c_void = unsafe { transmute(&mut l) };
+        let task = unsafe { Context::new_raw(entry, data, &mut stack) };
+        let sp = saved_sp(&task.frame.regs);
+
+        let limit = unsafe { stack::get_sp_limit() };
+        unsafe {
+            let caller: &mut Context = transmute(&mut l.caller);
+            Context::swap(caller, &task);
+        }
+        unsafe { stack::record_sp_limit(limit) };
+
+        assert_eq!(l.sum, range(0u, 96).fold(0u, |a, i| a + (3 + i) % 256));
+        // The final return address is still there
+        assert_eq!(unsafe { *(sp as *uint) }, 0);
+    }
+
+    #[test]
+    fn pooled_contexts_are_reused() {
+        let pool = ContextPool::new(1);
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut caller = Context::empty();
+
+        let first_id = {
+            let mut ctx = pool.take(proc() {}, &mut stack);
+            ctx.get_mut().set_name(Some(~"first"));
+            let limit = unsafe { stack::get_sp_limit() };
+            Context::swap(&mut caller, ctx.get());
+            unsafe { stack::record_sp_limit(limit) };
+            ctx.get().id()
+        };
+        assert_eq!(pool.len(), 1);
+
+        // Only one of these fits back in the pool, the other is freed
+        let mut other = StackSegment::new(1024 * 1024);
+        {
+            let ctx = pool.take(proc() {}, &mut stack);
+            let _extra = pool.take(proc() {}, &mut other);
+            assert_eq!(ctx.get().id(), first_id);
+            assert_eq!(ctx.get().name(), None);
+            assert_eq!(ctx.get().stack_bounds(),
+                       Some((stack.start() as uint, stack.end() as uint)));
+            assert_eq!(pool.len(), 0);
+        }
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn register_snapshot_shows_where_a_context_resumes() {
+        use super::task_start_wrapper;
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let task = Context::new(proc() {}, &mut stack);
+        let regs = task.register_snapshot();
+        assert_eq!(regs.ip, task_start_wrapper as uint);
+        assert!(regs.sp > stack.start() as uint && regs.sp < stack.end() as uint);
+        if cfg!(target_arch = "x86_64") || cfg!(target_arch = "x86") {
+            // The fake frame which ends the frame pointer chain
+            assert!(regs.fp > regs.sp && regs.fp < stack.end() as uint);
+        }
+        assert!(regs == regs.clone());
+    }
+
+    #[test]
+    fn canary_catches_writes_above_the_initial_frame() {
+        use std::mem;
+        use super::{CanaryOverwritten, STACK_CANARY};
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let task = Context::new(proc() {}, &mut stack);
+        assert!(task.check_canary().is_ok());
+        assert!(Context::empty().check_canary().is_ok());
+
+        let top = stack.end() as uint & !(super::STACK_ALIGNMENT - 1);
+        let canary = (top - mem::size_of::<uint>()) as *mut uint;
+        assert!(saved_sp(&task.frame.regs) < canary as uint);
+        unsafe {
+            assert_eq!(*canary, STACK_CANARY);
+            *canary = 0;
+        }
+        assert!(task.check_canary() == Err(CanaryOverwritten));
+    }
+
+    #[test]
+    fn generations_change_on_reuse() {
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut task = Context::new(proc() {}, &mut stack);
+        let handle = ContextHandle::new(3, &task);
+        assert!(handle.is_current(&task));
+        let mut other = StackSegment::new(1024 * 1024);
+        assert!(Context::new(proc() {}, &mut other).generation() >
+                task.generation());
+
+        unsafe { task.rearm(proc() {}); }
+        assert!(task.generation() > handle.generation);
+        assert!(!handle.is_current(&task));
+        assert_eq!(Context::empty().generation(), 0);
+    }
+
+    #[test]
+    #[should_fail]
+    fn stale_generations_are_not_resumed() {
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut task = Context::new(proc() {}, &mut stack);
+        let stale = task.generation();
+        unsafe { task.rearm(proc() {}); }
+        let mut caller = Context::empty();
+        Context::swap_checked(&mut caller, &task, stale);
+    }
+
+    #[test]
+    fn stacks_below_the_minimum_are_rejected() {
+        use std::mem;
+        use super::{MIN_STACK_SIZE, STACK_ALIGNMENT};
+
+        let backing = StackSegment::new(1024 * 1024);
+        let lo = backing.start() as uint;
+        let least = super::validate_reserved() + MIN_STACK_SIZE;
+        let least = (least + STACK_ALIGNMENT - 1) & !(STACK_ALIGNMENT - 1);
+
+        let mut stack = unsafe { StackSegment::from_raw(lo, lo + least) };
+        assert!(Context::try_new(proc() {}, &mut stack).is_ok());
+
+        // A word short, and the top is then rounded down to the alignment
+        let short = lo + least - mem::size_of::<uint>();
+        let mut stack = unsafe { StackSegment::from_raw(lo, short) };
+        let ret = Context::try_new(proc() {}, &mut stack);
+        assert!(ret.is_err() && ret.unwrap_err() == StackTooSmall);
+    }
+
+    #[test]
+    fn results_of_any_type_are_kept() {
+        let mut caller = Context::empty();
+        let mut stack = StackSegment::new(1024 * 1024);
+        let prefix = ~"kept";
+        let mut task = Context::new_with_result(proc() {
+            (prefix + " result", 7u8)
+        }, &mut stack);
+        let limit = unsafe { stack::get_sp_limit() };
+        Context::swap(&mut caller, &task);
+        unsafe { stack::record_sp_limit(limit) };
+        assert_eq!(task.take_result::<(~str, u8)>(), Some((~"kept result", 7u8)));
+
+        // A procedure which never runs is still dropped, along with what it
+        // captured
+        struct Flag { dropped: uint }
+        impl Drop for Flag {
+            fn drop(&mut self) {
+                unsafe { *(self.dropped as *mut bool) = true }
+            }
+        }
+        let mut dropped = false;
+        let flag = Flag { dropped: unsafe { transmute(&mut dropped) } };
+        let mut stack = StackSegment::new(1024 * 1024);
+        let task = Context::new_with_result(proc() { let _f = flag; 1u },
+                                            &mut stack);
+        assert!(!dropped);
+        drop(task);
+        assert!(dropped);
+    }
+
+    #[test]
+    #[cfg(segment_bases, target_os = "linux", target_arch = "x86_64")]
+    fn contexts_keep_their_own_segment_bases() {
+        use super::current_segment_bases;
+
+        struct Pair { caller: Context, task: *mut Context, seen: ~[uint] }
+
+        extern "C" fn entry(data: *mut c_void) {
+            unsafe {
+                let p: &mut Pair = transmute(data);
+                loop {
+                    let (_, gs) = current_segment_bases().unwrap();
+                    p.seen.push(gs);
+                    Context::swap(&mut *p.task, &p.caller);
+                }
+            }
+        }
+
+        // Only GS can be given made up values, FS has to stay on the thread's
+        // TLS block for anything to work at all
+        let (fs, gs) = current_segment_bases().unwrap();
+        let mut p = Pair { caller: Context::empty(), task: 0 as *mut Context,
+                           seen: ~[] };
+        let data: *mut c_void = unsafe { transmute(&mut p) };
+        let mut stack_a = StackSegment::new(1024 * 1024);
+        let mut stack_b = StackSegment::new(1024 * 1024);
+        let mut a = unsafe { Context::new_raw(entry, data, &mut stack_a) };
+        let mut b = unsafe { Context::new_raw(entry, data, &mut stack_b) };
+        a.set_segment_bases(fs, 0x10000);
+        b.set_segment_bases(fs, 0x20000);
+
+        let limit = unsafe { stack::get_sp_limit() };
+        for _ in range(0, 3) {
+            for task in [&mut a as *mut Context, &mut b as *mut Context].iter() {
+                unsafe {
+                    p.task = *task;
+                    let caller: &mut Context = transmute(&mut p.caller);
+                    Context::swap(caller, &**task);
+                }
+                assert_eq!(current_segment_bases(), Some((fs, gs)));
+            }
+        }
+        unsafe { stack::record_sp_limit(limit) };
+        assert_eq!(p.seen, ~[0x10000, 0x20000, 0x10000, 0x20000,
+                             0x10000, 0x20000]);
+    }
+
+    #[test]
+    fn migrated_contexts_see_earlier_writes() {
+        use std::rt::thread::Thread;
+        use std::sync::atomics::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+
+        static ROUNDS: uint = 1000;
+        // Which of the two threads may resume the task next. This is all that
+        // synchronizes them.
+        static mut TURN: AtomicUint = INIT_ATOMIC_UINT;
+
+        struct Shared { task: Context, caller: *Context, writes: ~[uint] }
+
+        extern "C" fn entry(data: *mut c_void) {
+            unsafe {
+                let s: &mut Shared = transmute(data);
+                loop {
+                    // Written by this task, most recently on the other thread
+                    let n = s.writes.len();
+                    if n > 0 && s.writes[n - 1] != n - 1 {
+                        rtabort!("write {} was lost", n - 1);
+                    }
+                    s.writes.push(n);
+                    Context::swap(&mut s.task, &*s.caller);
+                }
+            }
+        }
+
+        fn resume_task(shared: uint, me: uint) {
+            unsafe {
+                let s: &mut Shared = transmute(shared);
+                let mut caller = Context::empty();
+                for _ in range(0, ROUNDS) {
+                    while TURN.load(SeqCst) != me {}
+                    s.caller = &caller;
+                    let limit = stack::get_sp_limit();
+                    Context::swap(&mut caller, &s.task);
+                    stack::record_sp_limit(limit);
+                    TURN.store(1 - me, SeqCst);
+                }
+            }
+        }
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut shared = ~Shared { task: Context::empty(),
+                                   caller: 0 as *Context, writes: ~[] };
+        let data: *mut c_void = unsafe { transmute(&mut *shared) };
+        shared.task = unsafe { Context::new_raw(entry, data, &mut stack) };
+        unsafe { TURN.store(0, SeqCst) };
+
+        let addr = data as uint;
+        let first = Thread::start(proc() resume_task(addr, 0));
+        let second = Thread::start(proc() resume_task(addr, 1));
+        first.join();
+        second.join();
+        assert_eq!(shared.writes.len(), 2 * ROUNDS);
+        assert!(shared.writes.iter().enumerate().all(|(i, &n)| i == n));
+    }
+
+    #[test]
+    #[cfg(target_arch = "arm")]
+    fn arm_entry_lands_in_the_right_instruction_set() {
+        use super::{LR, THUMB_BIT};
+
+        // Landing in the wrong instruction set would fault straight away
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut arg = 0u;
+        let probe = run_raw_probe(&mut stack, |task, data| {
+            // The entry's address with the Thumb bit, which is only ever added
+            let lr = task.frame.regs[LR];
+            assert_eq!(lr & !1, probe_entry as uint & !1);
+            assert_eq!(lr & 1, (probe_entry as uint | THUMB_BIT) & 1);
+            if cfg!(thumb) { assert_eq!(lr & 1, 1); }
+            arg = data as uint;
+        });
+        assert_eq!(probe.seen, arg);
+    }
+
+    #[test]
+    #[cfg(target_arch = "sparc64")]
+    fn sparc64_register_windows() {
+        use super::{SP, O7, O0, STACK_BIAS, INITIAL_FRAME_BYTES, saved_fp};
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let hi = stack.end() as uint;
+        // The swap out of the probe has to flush this test's windows too, and
+        // returning to it has to fill them back in from the stack
+        let (a, b) = (hi * 3, hi ^ 0x5a5a);
+        let mut arg = 0u;
+        let probe = run_raw_probe(&mut stack, |task, data| {
+            let regs = &task.frame.regs;
+            // The biased stack pointer, the return address `ret` adds 8 to
+            let sp = regs[SP] as uint + STACK_BIAS;
+            assert_eq!(sp % 16, 0);
+            assert!(hi - sp <= INITIAL_FRAME_BYTES + 32);
+            assert_eq!(regs[O7] as uint + 8, probe_entry as uint);
+            assert_eq!(regs[O0], data as uint as u64);
+            assert_eq!(saved_sp(regs), sp);
+            assert_eq!(saved_fp(regs), 0);
+            arg = data as uint;
+        });
+        assert_eq!(probe.seen, arg);
+        assert_eq!((a, b), (hi * 3, hi ^ 0x5a5a));
+    }
+
+    #[test]
+    #[cfg(target_arch = "powerpc64")]
+    fn powerpc64_back_chain_and_link_register() {
+        use super::{RESUME, R3, R12, INITIAL_FRAME_BYTES};
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let (lo, hi) = (stack.start() as uint, stack.end() as uint);
+        let (mut arg, mut sp) = (0u, 0u);
+        let probe = run_raw_probe(&mut stack, |task, data| {
+            let regs = &task.frame.regs;
+            sp = saved_sp(regs);
+            assert_eq!(sp % 16, 0);
+            assert!(hi - sp <= INITIAL_FRAME_BYTES + 32);
+            // The back chain and the LR save word both end the stack
+            unsafe {
+                assert_eq!(*(sp as *u64), 0);
+                assert_eq!(*(sp as *u64).offset(2), 0);
+            }
+            assert_eq!(regs[RESUME], probe_entry as uint as u64);
+            assert_eq!(regs[R12], probe_entry as uint as u64);
+            assert_eq!(regs[R3], data as uint as u64);
+            arg = data as uint;
+        });
+        assert_eq!(probe.seen, arg);
+        // The entry ran in a frame of its own below the initial one, and saved
+        // the link register of 0 it started with as its return address
+        assert!(lo < probe.local && probe.local < sp);
+        unsafe {
+            assert_eq!(*(sp as *u64), 0);
+            assert_eq!(*(sp as *u64).offset(2), 0);
+        }
+    }
+
+    #[test]
+    #[cfg(target_arch = "s390x")]
+    fn s390x_register_save_area() {
+        use super::{RESUME, R14, R2, INITIAL_FRAME_BYTES};
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let (lo, hi) = (stack.start() as uint, stack.end() as uint);
+        let (mut arg, mut sp) = (0u, 0u);
+        let probe = run_raw_probe(&mut stack, |task, data| {
+            let regs = &task.frame.regs;
+            sp = saved_sp(regs);
+            assert_eq!(sp % 8, 0);
+            assert!(hi - sp <= INITIAL_FRAME_BYTES + 32);
+            // The whole save area is zeroed, the back chain included
+            for i in range(0, INITIAL_FRAME_BYTES / 8) {
+                unsafe { assert_eq!(*(sp as *u64).offset(i as int), 0); }
+            }
+            assert_eq!(regs[RESUME], probe_entry as uint as u64);
+            assert_eq!(regs[R14], 0);
+            assert_eq!(regs[R2], data as uint as u64);
+            arg = data as uint;
+        });
+        assert_eq!(probe.seen, arg);
+        // The entry ran in a frame of its own below the save area, having
+        // saved its r14 of 0 and its r15 into their slots in it (at 112 and
+        // 120)
+        assert!(lo < probe.local && probe.local < sp);
+        unsafe {
+            assert_eq!(*(sp as *u64).offset(14), 0);
+            assert_eq!(*(sp as *u64).offset(15), sp as u64);
+        }
+    }
+
+    #[test]
+    #[cfg(not(target_arch = "x86"), not(target_arch = "x86_64"))]
+    fn registers_are_as_large_as_the_assembly_expects() {
+        use std::mem;
+
+        let regs = super::new_regs();
+        let expected = super::ASM_REGISTER_SLOTS * mem::size_of_val(&regs[0]);
+        assert_eq!(mem::size_of::<super::Registers>(), expected);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86")]
+    #[cfg(target_arch = "x86_64")]
+    fn registers_are_as_large_as_the_assembly_says() {
+        use std::mem;
+
+        // Worked out by `rt/arch/$arch/_context.S` from its own offsets
+        extern { static rust_swap_registers_bytes: uint; }
+        assert_eq!(mem::size_of::<super::Registers>(),
+                   unsafe { rust_swap_registers_bytes });
+    }
+
+    #[test]
+    fn proc_contexts_run_before_the_swap_returns() {
+        static SENTINEL: uint = 0xa5a5a5a5;
+        let mut cell = 0u;
+        let mut caller = Context::empty();
+        let cell_ptr: uint = unsafe { transmute(&mut cell) };
+        let caller_ptr: uint = unsafe { transmute(&caller) };
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let task = Context::new(proc() {
+            let cell: &mut uint = unsafe { transmute(cell_ptr) };
+            *cell = SENTINEL;
+            let mut dead = Context::empty();
+            Context::swap(&mut dead, unsafe { transmute(caller_ptr) });
+        }, &mut stack);
+        assert_eq!(cell, 0);
+        let limit = unsafe { stack::get_sp_limit() };
+        Context::swap(&mut caller, &task);
+        unsafe { stack::record_sp_limit(limit) };
+        assert_eq!(cell, SENTINEL);
+    }
+
+    #[test]
+    fn proc_contexts_ping_pong() {
+        static ROUNDS: uint = 10;
+        struct Shared { caller: Context, task: *mut Context, sps: ~[uint] }
+
+        let mut shared = Shared {
+            caller: Context::empty(), task: 0 as *mut Context, sps: ~[],
+        };
+        let shared_ptr: uint = unsafe { transmute(&mut shared) };
+        let mut stack = StackSegment::new(1024 * 1024);
+        let (lo, hi) = (stack.start() as uint, stack.end() as uint);
+        let mut task = Context::new(proc() {
+            let s: &mut Shared = unsafe { transmute(shared_ptr) };
+            for i in range(0, ROUNDS) {
+                let local = i;
+                s.sps.push(&local as *uint as uint);
+                unsafe { Context::swap(&mut *s.task, &s.caller); }
+                assert_eq!(local, i);
+            }
+            let mut dead = Context::empty();
+            Context::swap(&mut dead, &s.caller);
+        }, &mut stack);
+        shared.task = &mut task as *mut Context;
+
+        let limit = unsafe { stack::get_sp_limit() };
+        for i in range(0, ROUNDS + 1) {
+            unsafe {
+                let caller: &mut Context = transmute(&mut shared.caller);
+                Context::swap(caller, &*shared.task);
+            }
+            assert_eq!(shared.sps.len(), if i < ROUNDS { i + 1 } else { ROUNDS });
+        }
+        unsafe { stack::record_sp_limit(limit) };
+
+        // Every round ran in the same frame, on the task's own stack
+        for &sp in shared.sps.iter() {
+            assert!(lo < sp && sp < hi);
+            assert_eq!(sp, shared.sps[0]);
+        }
+    }
+
+    // `mips64_register_indices` checks the slots by value. This checks them as
+    // the assembly sees them, a doubleword at a time in the target's own byte
+    // order, so that mips64el can't end up reading a different layout.
+    #[test]
+    #[cfg(target_arch = "mips64")]
+    fn mips64_slots_are_whole_words_in_either_byte_order() {
+        use std::vec;
+
+        extern "C" fn entry(_data: *mut c_void) {}
+
+        #[cfg(target_endian = "little")]
+        fn word(b: &[u8]) -> u64 {
+            b.iter().rev().fold(0u64, |w, &b| (w << 8) | b as u64)
+        }
+        #[cfg(target_endian = "big")]
+        fn word(b: &[u8]) -> u64 {
+            b.iter().fold(0u64, |w, &b| (w << 8) | b as u64)
+        }
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let data = 0x12345678 as *mut c_void;
+        let task = unsafe { Context::new_raw(entry, data, &mut stack) };
+        let regs: *u8 = unsafe { transmute(&task.frame.regs) };
+        unsafe {
+            vec::raw::buf_as_slice(regs, 32 * 8, |bytes| {
+                let slot = |i: uint| word(bytes.slice(i * 8, i * 8 + 8));
+                assert_eq!(slot(4), data as uint as u64);
+                assert_eq!(slot(25), entry as uint as u64);
+                assert_eq!(slot(29), saved_sp(&task.frame.regs) as u64);
+                assert_eq!(slot(31), entry as uint as u64);
+            });
+        }
+    }
+
+    #[test]
+    fn failures_are_kept_for_whoever_resumed_the_context() {
+        use std::any::{Any, AnyOwnExt};
+        use std::rt::unwind::Unwinder;
+        use std::task;
+
+        let mut caller = Context::empty();
+        let limit = unsafe { stack::get_sp_limit() };
+
+        // A failure of the thread's task is taken back from it, so that this
+        // test doesn't fail along with the context
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut ctx = Context::new(proc() { fail!("original message") },
+                                   &mut stack);
+        assert!(ctx.take_failure().is_none());
+        Context::swap(&mut caller, &ctx);
+        unsafe { stack::record_sp_limit(limit) };
+        let failure = ctx.take_failure().unwrap();
+        assert_eq!(*failure.move::<&'static str>().unwrap(), "original message");
+        assert!(ctx.take_failure().is_none());
+
+        // As is one through an unwinder of the context's own, without a result
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut ctx = Context::new_with_result(proc() -> uint {
+            let mut unwinder = Unwinder::new();
+            unwinder.begin_unwind(~"private failure" as ~Any)
+        }, &mut stack);
+        Context::swap(&mut caller, &ctx);
+        unsafe { stack::record_sp_limit(limit) };
+        assert_eq!(ctx.take_result::<uint>(), None);
+        let failure = ctx.take_failure().unwrap();
+        assert_eq!(*failure.move::<~str>().unwrap(), ~"private failure");
+
+        // Failing with it again keeps the message
+        let ret = task::try(proc() {
+            Context::fail_with(~~"passed on" as ~Any)
+        });
+        match ret {
+            Err(failure) => {
+                assert_eq!(*failure.move::<~str>().unwrap(), ~"passed on");
+            }
+            Ok(()) => fail!("fail_with returned"),
+        }
+    }
+
+    #[test]
+    fn the_initial_sp_is_recorded_below_the_canary() {
+        use std::mem;
+        use super::STACK_CANARY;
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let task = Context::new(proc() {}, &mut stack);
+        assert_eq!(Context::empty().initial_sp(), None);
+
+        let base = task.initial_sp().unwrap();
+        let top = stack.end() as uint & !(super::STACK_ALIGNMENT - 1);
+        assert_eq!(base, top - super::CANARY_BYTES);
+        // The initial frame is all below it, and the marker above
+        assert!(saved_sp(&task.frame.regs) < base);
+        let word = mem::size_of::<uint>();
+        unsafe {
+            assert_eq!(*((top - word) as *uint), STACK_CANARY);
+            assert_eq!(*((top - 2 * word) as *uint), base);
+        }
+    }
+
+    #[test]
+    fn unset_registers_are_poisoned() {
+        use super::{REGISTER_POISON, SNAPSHOT_NEW_REGISTERS, register_words};
+        use std::vec;
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let task = Context::new(proc() {}, &mut stack);
+        let snapshot = task.register_snapshot();
+        assert!(snapshot.ip != REGISTER_POISON);
+        assert!(snapshot.sp != REGISTER_POISON);
+        assert!(snapshot.fp != REGISTER_POISON);
+
+        let poisoned = unsafe {
+            let words: *uint = transmute(&task.frame.regs);
+            vec::raw::buf_as_slice(words, register_words(), |words| {
+                words.iter().any(|&w| w == REGISTER_POISON)
+            })
+        };
+        // A snapshot overwrites whatever the swap saves, which may leave
+        // unused slots poisoned or not
+        let snapshotted = SNAPSHOT_NEW_REGISTERS || cfg!(snapshot_registers);
+        if !snapshotted {
+            assert_eq!(poisoned, cfg!(not(ndebug)));
+        }
+    }
+
+    // Raw context switching alone, on the benchmark's own thread and without a
+    // scheduler. Each iteration is a round trip, a swap into a context and the
+    // swap back out of it.
+    #[bench]
+    fn bench_swap_round_trip(bh: &mut BenchHarness) {
+        struct Pair { caller: Context, task: *mut Context }
+
+        extern "C" fn entry(data: *mut c_void) {
+            unsafe {
+                let p: &mut Pair = transmute(data);
+                loop { Context::swap(&mut *p.task, &p.caller); }
+            }
+        }
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut p = Pair { caller: Context::empty(), task: 0 as *mut Context };
+        let data: *mut c_void = unsafe { transmute(&mut p) };
+        let mut task = unsafe { Context::new_raw(entry, data, &mut stack) };
+        p.task = &mut task as *mut Context;
+
+        let limit = unsafe { stack::get_sp_limit() };
+        bh.iter(|| {
+            unsafe {
+                let caller: &mut Context = transmute(&mut p.caller);
+                Context::swap(caller, &*p.task);
+            }
+        });
+        unsafe { stack::record_sp_limit(limit) };
+    }
+
+    // Recording bounds as a swap does, looking up where they go each time, and
+    // through the addresses that swaps now hand to each other. The two only
+    // differ where finding the stack limit means a call (arm, mips, mips64,
+    // aarch64 and riscv64); `bench_swap_round_trip` is the yield loop that
+    // both happen in.
+    #[bench]
+    fn bench_record_bounds(bh: &mut BenchHarness) {
+        use std::uint;
+        use super::record_bounds;
+
+        let limit = unsafe { stack::get_sp_limit() };
+        bh.iter(|| {
+            unsafe { record_bounds(0, uint::max_value) }
+        });
+        unsafe { stack::record_sp_limit(limit) };
+    }
+
+    #[bench]
+    fn bench_record_bounds_at_slots(bh: &mut BenchHarness) {
+        use std::uint;
+        use super::{bounds_slots, record_bounds_at};
+
+        let limit = unsafe { stack::get_sp_limit() };
+        let slots = unsafe { bounds_slots() };
+        bh.iter(|| {
+            unsafe { record_bounds_at(slots, 0, uint::max_value) }
+        });
+        unsafe { stack::record_sp_limit(limit) };
+    }
+
+    // Creating (and dropping) a context for a procedure on an existing stack,
+    // including the allocations for its frame and the procedure
+    #[bench]
+    fn bench_new_context(bh: &mut BenchHarness) {
+        let mut stack = StackSegment::new(1024 * 1024);
+        bh.iter(|| {
+            Context::new(proc() {}, &mut stack);
+        });
+    }
+
+    // The same, along with the stack
+    #[bench]
+    fn bench_new_context_and_stack(bh: &mut BenchHarness) {
+        bh.iter(|| {
+            let mut stack = StackSegment::new(64 * 1024);
+            Context::new(proc() {}, &mut stack);
+        });
+    }
+
+    #[test]
+    #[cfg(not(windows), not(target_os = "android"))]
+    fn task_locals_follow_their_contexts() {
+        use super::current_task_local;
+
+        extern "C" fn entry(data: *mut c_void) {
+            unsafe {
+                let probe: &mut RawProbe = transmute(data);
+                probe.seen = current_task_local() as uint;
+                let mut dead = Context::empty();
+                Context::swap(&mut dead, &probe.caller);
+            }
+        }
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut local = 7u;
+        let local_ptr: *mut c_void = unsafe { transmute(&mut local) };
+        let probe = run_raw_probe_with(&mut stack, |data, stack| unsafe {
+            Context::new_raw(entry, data, stack)
+        }, |task, _| {
+            task.set_local(local_ptr);
+            assert_eq!(task.local(), local_ptr);
+        });
+        assert_eq!(probe.seen, local_ptr as uint);
+        // Back on the caller, which has none
+        assert!(current_task_local().is_null());
+    }
+
+    #[test]
+    fn on_finish_runs_once_on_the_task_stack() {
+        use std::any::AnyOwnExt;
+
+        struct Seen { runs: uint, sp: uint }
+        let mut seen = Seen { runs: 0, sp: 0 };
+        let seen_ptr: uint = unsafe { transmute(&mut seen) };
+        let mut caller = Context::empty();
+        let limit = unsafe { stack::get_sp_limit() };
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let (lo, hi) = (stack.start() as uint, stack.end() as uint);
+        let mut task = Context::new(proc() {}, &mut stack);
+        task.set_on_finish(proc() {
+            let here = 0u;
+            let seen: &mut Seen = unsafe { transmute(seen_ptr) };
+            seen.runs += 1;
+            seen.sp = &here as *uint as uint;
+        });
+        Context::swap(&mut caller, &task);
+        unsafe { stack::record_sp_limit(limit) };
+        assert_eq!(seen.runs, 1);
+        assert!(lo < seen.sp && seen.sp < hi);
+
+        // It runs after a failure too, and its own failure doesn't replace
+        // the entry procedure's
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut task = Context::new(proc() { fail!("entry failed") }, &mut stack);
+        task.set_on_finish(proc() {
+            let seen: &mut Seen = unsafe { transmute(seen_ptr) };
+            seen.runs += 1;
+            fail!("on_finish failed");
+        });
+        Context::swap(&mut caller, &task);
+        unsafe { stack::record_sp_limit(limit) };
+        assert_eq!(seen.runs, 2);
+        let failure = task.take_failure().unwrap();
+        assert_eq!(*failure.move::<&'static str>().unwrap(), "entry failed");
+    }
+
+    #[test]
+    #[should_fail]
+    fn on_finish_needs_an_entry_procedure() {
+        Context::empty().set_on_finish(proc() {});
+    }
+
+    #[test]
+    #[cfg(not(target_os = "android"))]
+    fn stack_remaining_counts_down_to_the_low_bound() {
+        use std::uint;
+        use super::stack_remaining;
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let (lo, hi) = (stack.start() as uint, stack.end() as uint);
+        let (remaining, headroom) = run_on_stack(&mut stack, proc() {
+            (stack_remaining(), stack_headroom())
+        });
+        assert!(0 < remaining && remaining < hi - lo);
+        assert!(remaining >= headroom + stack::RED_ZONE);
+
+        let unbounded = with_unbounded_stack(|| stack_remaining());
+        assert_eq!(unbounded, uint::max_value);
+    }
+
+    #[test]
+    fn low_stack_hook_fires_once_per_task() {
+        use std::sync::atomics::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+        use std::uint;
+        use super::set_low_stack_hook;
+
+        // The hook is process-wide, so it only takes note of the context
+        // made here. Every stack is below a threshold this size.
+        static mut WATCHED: AtomicUint = INIT_ATOMIC_UINT;
+        static mut SEEN: AtomicUint = INIT_ATOMIC_UINT;
+        fn hook(context: &Context, remaining: uint) {
+            unsafe {
+                if context.id() == WATCHED.load(SeqCst) {
+                    let (lo, hi) = context.stack_bounds().unwrap();
+                    assert!(remaining < hi - lo);
+                    SEEN.fetch_add(1, SeqCst);
+                }
+            }
+        }
+
+        struct Pair { caller: Context, task: *mut Context }
+        let mut pair = Pair { caller: Context::empty(),
+                              task: 0 as *mut Context };
+        let pair_ptr: uint = unsafe { transmute(&mut pair) };
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut task = Context::new(proc() {
+            let pair: &mut Pair = unsafe { transmute(pair_ptr) };
+            for _ in range(0, 3) {
+                Context::swap(unsafe { &mut *pair.task }, &pair.caller);
+            }
+            let mut dead = Context::empty();
+            Context::swap(&mut dead, &pair.caller);
+        }, &mut stack);
+        pair.task = &mut task as *mut Context;
+
+        unsafe { WATCHED.store(task.id(), SeqCst) }
+        set_low_stack_hook(uint::max_value, Some(hook));
+        let limit = unsafe { stack::get_sp_limit() };
+        for _ in range(0, 4) {
+            let caller: &mut Context = unsafe { transmute(&mut pair.caller) };
+            Context::swap(caller, &task);
+        }
+        unsafe { stack::record_sp_limit(limit) };
+        set_low_stack_hook(0, None);
+        assert_eq!(unsafe { SEEN.load(SeqCst) }, 1);
+    }
+
+    #[test]
+    fn spawn_backtraces_are_opt_in() {
+        use super::set_spawn_backtraces;
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let off = Context::new(proc() {}, &mut stack);
+        assert!(off.spawn_backtrace().is_empty());
+
+        set_spawn_backtraces(4);
+        let mut stack = StackSegment::new(1024 * 1024);
+        let on = Context::new(proc() {}, &mut stack);
+        set_spawn_backtraces(0);
+        let frames = on.spawn_backtrace();
+        assert!(frames.len() <= 4);
+        if cfg!(target_os = "linux") || cfg!(target_os = "macos") {
+            assert!(!frames.is_empty());
+            assert!(frames.iter().all(|ip| !ip.is_null()));
+        }
+        assert!(Context::empty().spawn_backtrace().is_empty());
+    }
+
+    #[test]
+    #[cfg(not(target_os = "android"))]
+    fn native_bounds_are_recorded_for_schedulers() {
+        use super::thread_stack_bounds;
+
+        let (lo, hi) = match thread_stack_bounds() {
+            Some(bounds) => bounds,
+            None => return,
+        };
+        let here = 0u;
+        let sp = &here as *uint as uint;
+        assert!(lo < sp && sp < hi);
+
+        let mut caller = Context::empty();
+        unsafe { caller.set_native_bounds(lo, hi) }
+        let caller_ptr: uint = unsafe { transmute(&caller) };
+        let mut stack = StackSegment::new(1024 * 1024);
+        let task = Context::new(proc() {
+            let mut dead = Context::empty();
+            Context::swap(&mut dead, unsafe { transmute(caller_ptr) });
+        }, &mut stack);
+
+        let limit = unsafe { stack::get_sp_limit() };
+        Context::swap(&mut caller, &task);
+        let bounds = current_stack_bounds();
+        unsafe { stack::record_sp_limit(limit) };
+        assert_eq!(bounds, Some((lo, hi)));
+    }
+
+    #[test]
+    fn schedulers_are_contexts_without_a_stack() {
+        assert!(Context::empty().is_scheduler());
+        let mut stack = StackSegment::new(1024 * 1024);
+        assert!(!Context::new(proc() {}, &mut stack).is_scheduler());
+    }
+
+    #[test]
+    fn raw_contexts_switch_without_a_context() {
+        use super::raw;
+        use super::raw::RawContext;
+
+        struct Shared { caller: RawContext, task: *mut RawContext, rounds: uint }
+
+        extern "C" fn entry(data: *mut c_void) {
+            unsafe {
+                let shared: &mut Shared = transmute(data);
+                loop {
+                    shared.rounds += 1;
+                    raw::swap(&mut *shared.task, &shared.caller);
+                }
+            }
+        }
+
+        let mut storage = ~[0u8, ..1024];
+        assert!(storage.len() >= raw::storage_bytes());
+        let mut stack = StackSegment::new(1024 * 1024);
+        let (lo, hi) = (stack.start() as uint, stack.end() as uint);
+        let mut shared = Shared {
+            caller: unsafe {
+                RawContext::in_storage(storage.as_mut_ptr(), storage.len())
+            },
+            task: 0 as *mut RawContext,
+            rounds: 0,
+        };
+        let data: *mut c_void = unsafe { transmute(&mut shared) };
+        let mut task = unsafe { RawContext::new(entry, data, lo, hi).unwrap() };
+        shared.task = &mut task as *mut RawContext;
+
+        let limit = unsafe { stack::get_sp_limit() };
+        for _ in range(0, 3) {
+            unsafe { raw::swap(&mut shared.caller, &task) }
+        }
+        unsafe { stack::record_sp_limit(limit) };
+        assert_eq!(shared.rounds, 3);
+    }
+
+    #[test]
+    fn finished_tasks_leave_their_stack_balanced() {
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut here = Context::empty();
+        let mut ran = false;
+        let ran_ptr: uint = unsafe { transmute(&mut ran) };
+        let task = Context::new(proc() {
+            unsafe { *(ran_ptr as *mut bool) = true; }
+        }, &mut stack);
+        // The wrapper checks the canary it was handed once the task is done
+        match task.frame.start {
+            Some(ref start) => assert_eq!(start.canary, task.canary),
+            None => fail!(),
+        }
+
+        let limit = unsafe { stack::get_sp_limit() };
+        Context::swap(&mut here, &task);
+        unsafe { stack::record_sp_limit(limit) };
+        assert!(ran);
+        assert!(task.check_canary().is_ok());
+    }
+
+    #[test]
+    fn tasks_are_handed_their_arg() {
+        let mut caller = Context::empty();
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut seen = 0u;
+        let seen_ptr: uint = unsafe { transmute(&mut seen) };
+        let task = Context::new_with_arg(proc(arg: (~[uint], uint)) {
+            let (v, n) = arg;
+            unsafe { *(seen_ptr as *mut uint) = v.len() + n; }
+        }, (~[1u, 2, 3], 4u), &mut stack);
+        let limit = unsafe { stack::get_sp_limit() };
+        Context::swap(&mut caller, &task);
+        unsafe { stack::record_sp_limit(limit) };
+        assert_eq!(seen, 7);
+
+        // An arg which is never handed over is dropped with the context
+        struct Flag { dropped: uint }
+        impl Drop for Flag {
+            fn drop(&mut self) {
+                unsafe { *(self.dropped as *mut bool) = true }
+            }
+        }
+        let mut dropped = false;
+        let flag = Flag { dropped: unsafe { transmute(&mut dropped) } };
+        let mut stack = StackSegment::new(1024 * 1024);
+        let task = Context::new_with_arg(proc(_f: Flag) {}, flag, &mut stack);
+        assert!(!dropped);
+        drop(task);
+        assert!(dropped);
+    }
+
+    #[test]
+    fn new_contexts_start_with_an_aligned_stack() {
+        use super::{ENTRY_SP_MISALIGNMENT, STACK_ALIGNMENT};
+
+        // An odd size, so that the top of the stack needs aligning
+        let mut stack = StackSegment::new(1024 * 1024 + 12);
+        let task = Context::new(proc() {}, &mut stack);
+        let sp = saved_sp(&task.frame.regs);
+        assert_eq!((sp + ENTRY_SP_MISALIGNMENT) & (STACK_ALIGNMENT - 1), 0);
+    }
+
+    #[test]
+    #[should_fail]
+    #[cfg(not(ndebug))]
+    fn empty_contexts_cannot_be_swapped_into() {
+        let mut here = Context::empty();
+        let never_run = Context::empty();
+        Context::swap(&mut here, &never_run);
+    }
+
+    #[test]
+    #[should_fail]
+    fn red_zones_are_bounded() {
+        use super::{set_red_zone_size, MAX_RED_ZONE_SIZE};
+
+        // Fails before changing anything, so other tests aren't affected
+        set_red_zone_size(MAX_RED_ZONE_SIZE + 8);
+    }
+
+    #[test]
+    fn raw_swaps_switch_like_swap() {
+        struct Pair { caller: Context, task: *mut Context, runs: uint }
+
+        extern "C" fn entry(data: *mut c_void) {
+            unsafe {
+                let pair: *mut Pair = transmute(data);
+                loop {
+                    (*pair).runs += 1;
+                    Context::swap_raw((*pair).task, &(*pair).caller);
+                }
+            }
+        }
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut pair = Pair { caller: Context::empty(), task: 0 as *mut Context,
+                              runs: 0 };
+        let data: *mut c_void = unsafe { transmute(&mut pair) };
+        let mut task = unsafe { Context::new_raw(entry, data, &mut stack) };
+        pair.task = &mut task;
+
+        let limit = unsafe { stack::get_sp_limit() };
+        for _ in range(0, 2) {
+            unsafe { Context::swap_raw(&mut pair.caller, &task) };
+            unsafe { stack::record_sp_limit(limit) };
+        }
+        assert_eq!(pair.runs, 2);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn reserved_stacks_are_committed_as_they_grow() {
+        #[inline(never)]
+        fn recurse(n: uint) -> uint {
+            let buf = [n as u8, ..1024];
+            if n == 0 { 0 } else { buf[0] as uint + recurse(n - 1) }
+        }
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let (base, committed) = stack.reservation().unwrap();
+        assert_eq!(base, stack.start() as uint);
+        assert!(committed > base && committed < stack.end() as uint);
+        // Goes far deeper than what was committed to begin with
+        let sum = run_on_stack(&mut stack, proc() recurse(256));
+        assert_eq!(sum, range(1u, 257).fold(0u, |a, n| a + n % 256));
+    }
+
+    #[test]
+    fn swaps_are_handed_to_the_event_sink() {
+        use super::{set_event_sink, SwapEvent};
+
+        // Other tests swap on other threads while the sink is installed, and
+        // their events go to their own tasks
+        local_data_key!(events: ~[SwapEvent])
+        fn sink(event: SwapEvent) {
+            local_data::modify(events, |v| {
+                let mut v = v.unwrap_or(~[]);
+                v.push(event);
+                Some(v)
+            });
+        }
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let task = Context::new(proc() {}, &mut stack);
+        let mut sched = Context::empty();
+        set_event_sink(Some(sink));
+        let limit = unsafe { stack::get_sp_limit() };
+        Context::swap(&mut sched, &task);
+        unsafe { stack::record_sp_limit(limit) };
+        set_event_sink(None);
+
+        local_data::get(events, |v| {
+            let v = v.unwrap();
+            assert_eq!(v.len(), 2);
+            assert_eq!((v[0].from_id, v[0].to_id), (sched.id(), task.id()));
+            assert!(v[0].from_is_scheduler && !v[0].to_is_scheduler);
+            // A finished task leaves from an empty context of its own
+            assert_eq!((v[1].from_id, v[1].to_id), (0, sched.id()));
+            assert!(v[1].to_is_scheduler);
+            assert!(v[0].timestamp_ns <= v[1].timestamp_ns);
+        });
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86")]
+    #[cfg(target_arch = "x86_64")]
+    fn instruction_pointers_can_be_moved() {
+        use std::libc::c_void;
+        use super::task_start_wrapper;
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut task = Context::new(proc() {}, &mut stack);
+        let entry = task_start_wrapper as *c_void;
+        assert_eq!(task.instruction_pointer(), entry);
+        let sp = task.register_snapshot().sp;
+        unsafe { task.set_instruction_pointer(0x1234 as *c_void) };
+        assert_eq!(task.instruction_pointer(), 0x1234 as *c_void);
+        assert_eq!(task.register_snapshot().sp, sp);
+        // Put back, so that dropping the context doesn't go anywhere strange
+        unsafe { task.set_instruction_pointer(entry) };
+    }
+
+    #[test]
+    #[cfg(not(target_os = "android"))]
+    fn bounds_slots_are_handed_along_with_each_swap() {
+        let mut stack = StackSegment::new(1024 * 1024);
+        let (lo, hi) = (stack.start() as uint, stack.end() as uint);
+        let mut caller = Context::empty();
+        let caller_ptr: uint = unsafe { transmute(&caller) };
+        let mut seen: ~[Option<(uint, uint)>] = ~[];
+        let seen_ptr: uint = unsafe { transmute(&mut seen) };
+        let mut task_at = 0u;
+        let task_at_ptr: uint = unsafe { transmute(&mut task_at) };
+
+        let mut task = Context::new(proc() {
+            let seen: &mut ~[Option<(uint, uint)>] = unsafe { transmute(seen_ptr) };
+            for _ in range(0, 2) {
+                seen.push(current_stack_bounds());
+                unsafe {
+                    let task: &mut Context = transmute(*(task_at_ptr as *uint));
+                    Context::swap(task, transmute(caller_ptr));
+                }
+            }
+        }, &mut stack);
+        task_at = &mut task as *mut Context as uint;
+        assert!(caller.bounds_slots.get().is_none());
+
+        let limit = unsafe { stack::get_sp_limit() };
+        for i in range(0, 3) {
+            Context::swap(&mut caller, &task);
+            assert!(caller.bounds_slots.get().is_some());
+            if i < 2 { assert!(task.bounds_slots.get().is_none()) }
+        }
+        unsafe { stack::record_sp_limit(limit) };
+        assert_eq!(seen, ~[Some((lo, hi)), Some((lo, hi))]);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    #[cfg(target_arch = "powerpc64")]
+    fn registers_are_always_aligned_for_vector_saves() {
+        use std::vec;
+        use super::{Registers, REGISTER_ALIGNMENT};
+
+        // Kept alive, so that the allocator can't hand back the same memory
+        let contexts = vec::from_fn(4096, |_| Context::empty());
+        for ctx in contexts.iter() {
+            let regs = &ctx.frame.regs as *Registers as uint;
+            assert_eq!(regs & (REGISTER_ALIGNMENT - 1), 0);
+        }
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86")]
+    #[cfg(target_arch = "x86_64")]
+    fn forced_unwinds_run_destructors_and_come_back() {
+        struct Guard { drops: *mut uint }
+        impl Drop for Guard {
+            fn drop(&mut self) { unsafe { *self.drops += 1 } }
+        }
+
+        let mut drops = 0u;
+        let mut caller = Context::empty();
+        let drops_ptr: uint = unsafe { transmute(&mut drops) };
+        let caller_ptr: uint = unsafe { transmute(&caller) };
+        let mut task_at = 0u;
+        let task_at_ptr: uint = unsafe { transmute(&mut task_at) };
+        let mut stack = StackSegment::new(1024 * 1024);
+
+        fn nested(drops_ptr: uint, task_at_ptr: uint, caller_ptr: uint) {
+            let _inner = Guard { drops: unsafe { transmute(drops_ptr) } };
+            unsafe {
+                let task: &mut Context = transmute(*(task_at_ptr as *uint));
+                Context::swap(task, transmute(caller_ptr));
+            }
+            unreachable!();
+        }
+        let mut task = Context::new(proc() {
+            let _outer = Guard { drops: unsafe { transmute(drops_ptr) } };
+            nested(drops_ptr, task_at_ptr, caller_ptr);
+        }, &mut stack);
+        task_at = &mut task as *mut Context as uint;
+
+        let limit = unsafe { stack::get_sp_limit() };
+        Context::swap(&mut caller, &task);
+        unsafe { stack::record_sp_limit(limit) };
+        assert_eq!(drops, 0);
+
+        assert!(task.force_unwind());
+        assert_eq!(drops, 2);
+        assert!(task.take_failure().is_none());
+        // There's nothing left to unwind
+        assert!(!task.force_unwind());
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86")]
+    #[cfg(target_arch = "x86_64")]
+    fn swaps_carry_on_after_a_forced_unwind() {
+        struct Guard { dropped: *mut bool }
+        impl Drop for Guard {
+            fn drop(&mut self) { unsafe { *self.dropped = true } }
+        }
+
+        let mut dropped = false;
+        let mut caller = Context::empty();
+        let dropped_ptr: uint = unsafe { transmute(&mut dropped) };
+        let caller_ptr: uint = unsafe { transmute(&caller) };
+        let mut task_at = 0u;
+        let task_at_ptr: uint = unsafe { transmute(&mut task_at) };
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut task = Context::new(proc() {
+            let _guard = Guard { dropped: unsafe { transmute(dropped_ptr) } };
+            unsafe {
+                let task: &mut Context = transmute(*(task_at_ptr as *uint));
+                Context::swap(task, transmute(caller_ptr));
+            }
+            unreachable!();
+        }, &mut stack);
+        task_at = &mut task as *mut Context as uint;
+
+        let limit = unsafe { stack::get_sp_limit() };
+        Context::swap(&mut caller, &task);
+        unsafe { stack::record_sp_limit(limit) };
+        assert!(task.force_unwind());
+        assert!(dropped);
+
+        // Neither the swap into the trampoline nor the task's last one out of
+        // it is left unfinished
+        let mut other = StackSegment::new(1024 * 1024);
+        assert_eq!(run_on_stack(&mut other, proc() 1 + 2), 3);
+    }
+
+    #[test]
+    fn contexts_which_never_ran_are_not_unwound() {
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut task = Context::new(proc() {}, &mut stack);
+        assert!(!task.force_unwind());
+    }
+
+    #[test]
+    #[cfg(context_registry)]
+    fn live_contexts_are_listed() {
+        use super::{live_contexts, task_start_wrapper};
+        use std::libc::c_void;
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let bounds = Some((stack.start() as uint, stack.end() as uint));
+        let find = |id| live_contexts().move_iter().find(|c| c.id == id);
+        let id = {
+            let mut task = Context::new(proc() {}, &mut stack);
+            task.set_name(Some(~"listed"));
+            let c = find(task.id()).unwrap();
+            assert_eq!(c.name, Some(~"listed"));
+            assert!(!c.is_scheduler);
+            assert_eq!(c.stack_bounds, bounds);
+            assert_eq!(c.instruction_pointer, task.instruction_pointer());
+            if cfg!(target_arch = "x86") || cfg!(target_arch = "x86_64") {
+                assert_eq!(c.instruction_pointer, task_start_wrapper as *c_void);
+            }
+            task.id()
+        };
+        assert!(find(id).is_none());
+    }
+
+    #[test]
+    fn overwritten_canaries_follow_the_overflow_policy() {
+        use std::any::{Any, AnyOwnExt};
+        use std::task;
+        use super::{OverflowPolicy, Abort, Fail, Callback, effective_policy};
+
+        fn reported(msg: &str) { fail!("reported: {}", msg) }
+
+        // Resumes a context with its canary overwritten, and returns what the
+        // swap failed with
+        fn resume_overwritten(policy: OverflowPolicy) -> ~str {
+            let ret = task::try(proc() {
+                let mut stack = StackSegment::new(1024 * 1024);
+                let mut task = Context::new(proc() {}, &mut stack);
+                task.set_overflow_policy(policy);
+                unsafe { *(task.canary as *mut uint) = 0 }
+                let mut caller = Context::empty();
+                Context::swap(&mut caller, &task);
+            });
+            match ret {
+                Err(failure) => *failure.move::<~str>().unwrap(),
+                Ok(()) => fail!("resumed a context with its canary overwritten"),
+            }
+        }
+
+        assert!(resume_overwritten(Fail).starts_with("resuming context "));
+        assert!(resume_overwritten(Callback(reported))
+                    .starts_with("reported: resuming context "));
+        // Aborting can't be tested without taking the tests down with it, but
+        // it's what a context without a policy of its own gets by default
+        match effective_policy(None) {
+            Abort => {}
+            _ => fail!("the default overflow policy isn't to abort"),
+        }
+    }
+
+    #[test]
+    #[cfg(not(windows), not(target_os = "android"))]
+    fn the_running_overflow_policy_follows_swaps() {
+        use super::{Fail, running_overflow_policy};
+
+        let mut caller = Context::empty();
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut task = Context::new_with_result(proc() {
+            running_overflow_policy().is_some()
+        }, &mut stack);
+        task.set_overflow_policy(Fail);
+        let limit = unsafe { stack::get_sp_limit() };
+        Context::swap(&mut caller, &task);
+        unsafe { stack::record_sp_limit(limit) };
+        assert_eq!(task.take_result::<bool>(), Some(true));
+        assert!(running_overflow_policy().is_none());
+    }
+
+    #[test]
+    #[cfg(not(windows), not(target_os = "android"))]
+    fn native_code_is_not_on_a_green_stack() {
+        use std::rt::thread::Thread;
+        use super::thread_stack_bounds;
+
+        // A native thread has its own stack's bounds recorded, but has never
+        // swapped into a context
+        assert!(!Thread::start(proc() on_green_stack()).join());
+
+        // Nor is a scheduler with native bounds, once a task swaps back to it
+        let mut sched = Context::empty();
+        match thread_stack_bounds() {
+            Some((lo, hi)) => unsafe { sched.set_native_bounds(lo, hi) },
+            None => {}
+        }
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut task = Context::new_with_result(proc() on_green_stack(),
+                                                &mut stack);
+        let limit = unsafe { stack::get_sp_limit() };
+        Context::swap(&mut sched, &task);
+        let on_green = on_green_stack();
+        unsafe { stack::record_sp_limit(limit) };
+        assert_eq!(task.take_result::<bool>(), Some(true));
+        assert!(!on_green);
+    }
+
+    #[test]
+    fn inline_entries_are_run_or_dropped() {
+        use super::{INLINE_ENTRY_WORDS, fits_inline};
+
+        struct Counted { counter: uint, pad: [uint, ..4] }
+        impl Drop for Counted {
+            fn drop(&mut self) { unsafe { *(self.counter as *mut uint) += 1 } }
+        }
+        struct Large { counted: Counted, pad: [uint, ..INLINE_ENTRY_WORDS] }
+
+        fn small(c: Counted) { assert_eq!(c.pad[3], 3) }
+        fn large(l: Large) { assert_eq!(l.counted.pad[3], 3) }
+
+        assert!(fits_inline::<Counted>());
+        assert!(!fits_inline::<Large>());
+        let mut drops = 0u;
+        let counter: uint = unsafe { transmute(&mut drops) };
+        let counted = || Counted { counter: counter, pad: [0, 1, 2, 3] };
+
+        let mut caller = Context::empty();
+        let limit = unsafe { stack::get_sp_limit() };
+        let mut stack = StackSegment::new(1024 * 1024);
+        {
+            let task = Context::new_inline(counted(), small, &mut stack);
+            Context::swap(&mut caller, &task);
+            unsafe { stack::record_sp_limit(limit) };
+            assert_eq!(drops, 1);
+        }
+        {
+            let task = Context::new_inline(Large { counted: counted(),
+                                                   pad: [0, ..INLINE_ENTRY_WORDS] },
+                                           large, &mut stack);
+            Context::swap(&mut caller, &task);
+            unsafe { stack::record_sp_limit(limit) };
+            assert_eq!(drops, 2);
+        }
+
+        // Neither has run, so both are dropped along with their contexts
+        Context::new_inline(counted(), small, &mut stack);
+        assert_eq!(drops, 3);
+        Context::new_inline(Large { counted: counted(),
+                                    pad: [0, ..INLINE_ENTRY_WORDS] },
+                            large, &mut stack);
+        assert_eq!(drops, 4);
+    }
+
+    #[test]
+    fn trimmed_stacks_keep_their_live_frames() {
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut caller = Context::empty();
+        let caller_ptr: uint = unsafe { transmute(&caller) };
+        let mut task_at = 0u;
+        let task_at_ptr: uint = unsafe { transmute(&mut task_at) };
+        let mut sum = 0u;
+        let sum_ptr: uint = unsafe { transmute(&mut sum) };
+
+        fn deep(n: uint) -> uint {
+            let buf = [n, ..64];
+            if n == 0 { buf[0] } else { deep(n - 1) + buf[63] }
+        }
+        let mut task = Context::new(proc() {
+            let live = [7u, ..512];
+            unsafe {
+                *(sum_ptr as *mut uint) = deep(200);
+                let task: &mut Context = transmute(*(task_at_ptr as *uint));
+                Context::swap(task, transmute(caller_ptr));
+                *(sum_ptr as *mut uint) = live.iter().fold(0, |a, &b| a + b);
+                let task: &mut Context = transmute(*(task_at_ptr as *uint));
+                Context::swap(task, transmute(caller_ptr));
+            }
+        }, &mut stack);
+        task_at = &mut task as *mut Context as uint;
+
+        let limit = unsafe { stack::get_sp_limit() };
+        Context::swap(&mut caller, &task);
+        assert_eq!(sum, 200 * 201 / 2);
+        task.trim_stack();
+        Context::swap(&mut caller, &task);
+        unsafe { stack::record_sp_limit(limit) };
+        assert_eq!(sum, 7 * 512);
+    }
+
+    #[test]
+    fn undersized_stacks_are_an_error() {
+        let mut stack = StackSegment::new(64);
+        let ret = Context::try_new_with_fp_state(proc() {}, &mut stack, true);
+        assert!(ret.is_err() && ret.unwrap_err() == StackTooSmall);
+        let ret = Context::try_new_with_result(proc() 1u, &mut stack, true);
+        assert!(ret.is_err() && ret.unwrap_err() == StackTooSmall);
+        let ret = StackSegment::try_new(0);
+        assert!(ret.is_err() && ret.unwrap_err() == StackTooSmall);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn failed_stack_mappings_are_an_error() {
+        use std::uint;
+        use super::AllocFailed;
+
+        // Larger than the whole user address space, so it can never be mapped
+        let ret = StackSegment::try_new_guarded(1 << 47);
+        assert!(ret.is_err() && ret.unwrap_err() == AllocFailed);
+        let ret = StackSegment::try_new_guarded(uint::max_value);
+        assert!(ret.is_err() && ret.unwrap_err() == AllocFailed);
+    }
+
+    #[test]
+    #[cfg(target_os = "freebsd")]
+    fn freebsd_threads_know_their_stack() {
+        use super::thread_stack_bounds;
+
+        let here = 0u;
+        let sp = &here as *uint as uint;
+        match thread_stack_bounds() {
+            Some((lo, hi)) => assert!(lo < sp && sp < hi),
+            None => fail!("pthread_attr_get_np found no stack"),
+        }
+    }
+
+    #[test]
+    #[should_fail]
+    #[cfg(unix)]
+    fn undersized_signal_stacks_are_refused() {
+        Context::empty().set_signal_stack(16);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn refused_signal_stacks_are_reported() {
+        use signal_stack::{AltStack, SignalStack};
+
+        let before = AltStack::current().start();
+        let ss = SignalStack::new(16);
+        assert!(unsafe { ss.install() }.is_err());
+        assert_eq!(AltStack::current().start(), before);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn dropped_signal_stacks_are_taken_off_the_thread() {
+        use signal_stack::{AltStack, SignalStack, MIN_SIGNAL_STACK};
+
+        let ss = SignalStack::new(2 * MIN_SIGNAL_STACK);
+        let prev = unsafe { ss.install() }.unwrap();
+        assert_eq!(AltStack::current().start(), ss.start());
+        drop(ss);
+        assert!(!AltStack::current().is_enabled());
+        unsafe { prev.install() }.unwrap();
+    }
+
+    #[test]
+    #[cfg(windows, target_arch = "x86")]
+    fn seh_exceptions_are_handled_on_green_stacks() {
+        use std::libc::{DWORD, c_void};
+
+        // An EXCEPTION_REGISTRATION_RECORD in the fs:0 chain, followed by
+        // what the handler reports back through
+        struct Registration { next: uint, handler: uint, handled: *mut bool }
+
+        extern "system" {
+            fn RaiseException(code: DWORD, flags: DWORD, nargs: DWORD,
+                              args: *uint);
+        }
+
+        // Any code with the customer bit set is ours
+        static TEST_CODE: DWORD = 0xe0672e65;
+
+        // Continues after an exception raised by this test (0 is
+        // ExceptionContinueExecution), and passes anything else on (1 is
+        // ExceptionContinueSearch). The record starts with its code.
+        extern "system" fn handler(record: *DWORD, frame: *mut Registration,
+                                   _context: *c_void,
+                                   _dispatcher: *c_void) -> DWORD {
+            unsafe {
+                if *record != TEST_CODE { return 1 }
+                *(*frame).handled = true;
+            }
+            0
+        }
+
+        let mut stack = StackSegment::new(1024 * 1024);
+        let (chain_end, handled) = run_on_stack(&mut stack, proc() {
+            let mut handled = false;
+            let mut reg = Registration {
+                next: 0,
+                handler: handler as *c_void as uint,
+                handled: &mut handled as *mut bool,
+            };
+            unsafe {
+                let next: uint;
+                asm!("movl %fs:0, $0" : "=r"(next) ::: "volatile");
+                reg.next = next;
+                // The OS only dispatches to registrations within the TIB's
+                // stack bounds, which the swap pointed at this stack
+                let addr = &mut reg as *mut Registration as uint;
+                asm!("movl $0, %fs:0" :: "r"(addr) :: "volatile");
+                RaiseException(TEST_CODE, 0, 0, 0 as *uint);
+                asm!("movl $0, %fs:0" :: "r"(next) :: "volatile");
+            }
+            (reg.next, handled)
+        });
+        // The task's chain started out empty rather than in the creator's
+        assert_eq!(chain_end, 0xffffffff);
+        assert!(handled);
+    }
+
+    #[test]
+    #[cfg(stack_clash_check)]
+    fn stack_clashes_name_the_context() {
+        use std::any::{Any, AnyOwnExt};
+        use std::task;
+        use super::Fail;
+
+        let ret = task::try(proc() {
+            let mut stack = StackSegment::new(1024 * 1024);
+            let mut task = Context::new(proc() {}, &mut stack);
+            task.set_overflow_policy(Fail);
+            task.set_name(Some(~"clasher"));
+            // No stack pointer is inside the first byte of the address space
+            task.stack_bounds = Some((0, 1));
+            let mut caller = Context::empty();
+            Context::swap(&mut caller, &task);
+        });
+        match ret {
+            Err(failure) => {
+                let msg = *failure.move::<~str>().unwrap();
+                assert!(msg.starts_with("stack clash: clasher resuming "));
+            }
+            Ok(()) => fail!("resumed a context outside of its stack"),
+        }
+    }
+
+    #[test]
+    #[cfg(target_arch = "aarch64")]
+    fn aarch64_slots_hold_zero_extended_pointers() {
+        use super::{X0, SP, PC, saved_ip, saved_sp};
+
+        extern "C" fn entry(_data: *mut c_void) {}
+
+        let mut cell = 0u;
+        let data: *mut c_void = unsafe { transmute(&mut cell) };
+        let mut stack = StackSegment::new(1024 * 1024);
+        let task = unsafe { Context::new_raw(entry, data, &mut stack) };
+        let regs = &task.frame.regs;
+        assert_eq!(regs[X0], data as uint as u64);
+        assert_eq!(saved_ip(regs), entry as uint);
+        let sp = saved_sp(regs);
+        assert!(stack.start() as uint < sp && sp < stack.end() as uint);
+        // With 32-bit pointers (arm64_32) nothing may be left in the top half
+        // of a slot, which the assembly loads as a whole register
+        if cfg!(target_word_size = "32") {
+            for &slot in [regs[X0], regs[SP], regs[PC]].iter() {
+                assert_eq!(slot >> 32, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn running_checks_look_at_every_segment() {
+        let mut stack = StackSegment::new(1024 * 1024);
+        let mut task = Context::new(proc() {}, &mut stack);
+        assert!(!task.is_running());
+        // A later segment, which this test happens to be running on
+        let here = 0u;
+        let sp = &here as *uint as uint;
+        task.push_stack_segment(sp - 4096, sp + 4096);
+        assert!(task.is_running());
+    }
+
+    #[test]
+    fn raw_contexts_need_room_for_their_registers() {
+        use super::raw::{RawContext, storage_bytes};
+
+        extern "C" fn entry(_data: *mut c_void) { fail!() }
+
+        let stack = StackSegment::new(1024 * 1024);
+        let (lo, hi) = (stack.start() as uint, stack.end() as uint);
+        let data = 0 as *mut c_void;
+        unsafe {
+            assert!(RawContext::new(entry, data, hi, lo).is_err());
+            // Room for the registers, but not for a frame below them
+            assert!(RawContext::new(entry, data, hi - storage_bytes(), hi)
+                        .is_err());
+            assert!(RawContext::new(entry, data, lo, hi).is_ok());
+        }
+    }
 }
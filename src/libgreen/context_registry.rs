@@ -0,0 +1,110 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A process-wide list of the live contexts, so that all of them can be
+//! dumped at once when a scheduler has hung (see
+//! `context::dump_all_contexts`). Contexts are only listed when libgreen is
+//! built with `--cfg context_registry`. That costs taking a lock whenever a
+//! context is created, renamed, moved to another stack, pooled or dropped;
+//! without it nothing is kept, and the dump is empty.
+//!
+//! Contexts are moved around by value, so nothing can keep a pointer to one.
+//! Each entry is a copy of what gets dumped instead, which the context keeps
+//! up to date, along with the address of its boxed registers, which never
+//! move.
+
+use std::cast;
+use std::unstable::mutex::{Mutex, MUTEX_INIT};
+
+/// What's listed for one live context
+pub struct Entry {
+    /// See `Context::id`
+    id: uint,
+    /// The address of the context's boxed frame, which is freed only after
+    /// the context has been unregistered
+    frame: uint,
+    /// See `Context::name`
+    name: Option<~str>,
+    /// See `Context::stack_bounds`
+    stack_bounds: Option<(uint, uint)>,
+}
+
+static mut LOCK: Mutex = MUTEX_INIT;
+// A leaked `~[Entry]`, null until the first context is registered
+static mut ENTRIES: *mut ~[Entry] = 0 as *mut ~[Entry];
+
+/// Lists a context, or replaces what's listed for it if it's listed already
+pub fn register(entry: Entry) {
+    let mut entry = Some(entry);
+    with_entries(|entries| {
+        let entry = entry.take_unwrap();
+        match entries.iter().position(|e| e.id == entry.id) {
+            Some(i) => entries[i] = entry,
+            None => entries.push(entry),
+        }
+    })
+}
+
+/// Takes the context `id` off the list, if it was on it
+pub fn unregister(id: uint) {
+    with_entries(|entries| {
+        match entries.iter().position(|e| e.id == id) {
+            Some(i) => { entries.swap_remove(i); }
+            None => {}
+        }
+    })
+}
+
+/// Calls `f` with every entry, in no particular order. No context can be
+/// dropped while this is running, so its frame can be read, but `f` must not
+/// create, change or drop any contexts itself.
+pub fn each(f: |&Entry|) {
+    with_entries(|entries| {
+        for e in entries.iter() { f(e) }
+    })
+}
+
+fn with_entries(f: |&mut ~[Entry]|) {
+    unsafe {
+        LOCK.lock();
+        if ENTRIES.is_null() {
+            let entries: ~~[Entry] = ~~[];
+            ENTRIES = cast::transmute(entries);
+        }
+        f(&mut *ENTRIES);
+        LOCK.unlock();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Entry, register, unregister, each};
+
+    fn find(id: uint) -> Option<(Option<~str>, Option<(uint, uint)>)> {
+        let mut found = None;
+        each(|e| if e.id == id { found = Some((e.name.clone(), e.stack_bounds)) });
+        found
+    }
+
+    #[test]
+    fn entries_follow_their_contexts() {
+        // Far above the ids of any real contexts
+        let id = -1u;
+        register(Entry { id: id, frame: 0, name: None, stack_bounds: None });
+        assert_eq!(find(id), Some((None, None)));
+
+        register(Entry { id: id, frame: 0, name: Some(~"worker"),
+                         stack_bounds: Some((0x1000, 0x2000)) });
+        assert_eq!(find(id), Some((Some(~"worker"), Some((0x1000, 0x2000)))));
+
+        unregister(id);
+        assert_eq!(find(id), None);
+    }
+}
@@ -11,13 +11,25 @@
 // Coroutines represent nothing more than a context and a stack
 // segment.
 
+use std::cast::transmute;
 use std::rt::env;
 
-use context::Context;
+use context;
+use context::{Context, ContextError};
 use stack::{StackPool, StackSegment};
 
 /// A coroutine is nothing more than a (register context, stack) pair.
+///
+/// Owning both is what keeps the stack alive for as long as the context can
+/// be resumed on it, so outside of a scheduler this is the safe way to run a
+/// procedure on a stack of its own (see `with_stack_size` and `resume`).
 pub struct Coroutine {
+    /// Always valid if the task is alive and not running.
+    ///
+    /// This comes first so that it's dropped before the stack, which a
+    /// context that unwinds on drop still runs on.
+    saved_context: Context,
+
     /// The segment of stack on which the task is currently running or
     /// if the task is blocked, on which the task will resume
     /// execution.
@@ -25,21 +37,47 @@ pub struct Coroutine {
     /// Servo needs this to be public in order to tell SpiderMonkey
     /// about the stack bounds.
     current_stack_segment: StackSegment,
-
-    /// Always valid if the task is alive and not running.
-    saved_context: Context
 }
 
 impl Coroutine {
+    /// Creates a coroutine on a stack from `stack_pool`, failing if the pool
+    /// can't give one (see `try_new`)
     pub fn new(stack_pool: &mut StackPool,
                stack_size: Option<uint>,
                start: proc())
                -> Coroutine {
+        match Coroutine::try_new(stack_pool, stack_size, start) {
+            Ok(coroutine) => coroutine,
+            Err(e) => fail!("cannot create a coroutine: {}", e),
+        }
+    }
+
+    /// Creates a coroutine on a stack from `stack_pool`, or returns why the
+    /// pool couldn't give one, such as the stack budget having run out (see
+    /// `stack::set_stack_budget`)
+    pub fn try_new(stack_pool: &mut StackPool,
+                   stack_size: Option<uint>,
+                   start: proc())
+                   -> Result<Coroutine, ContextError> {
         let stack_size = match stack_size {
             Some(size) => size,
             None => env::min_stack()
         };
-        let mut stack = stack_pool.take_segment(stack_size);
+        let mut stack = match stack_pool.take_segment(stack_size) {
+            Ok(stack) => stack,
+            Err(e) => return Err(e),
+        };
+        let initial_context = Context::new(start, &mut stack);
+        Ok(Coroutine {
+            current_stack_segment: stack,
+            saved_context: initial_context
+        })
+    }
+
+    /// Creates a coroutine which runs `start` on a new stack of `stack_size`
+    /// bytes, rather than one from a scheduler's pool.
+    pub fn with_stack_size(stack_size: uint, start: proc()) -> Coroutine {
+        let mut stack = StackSegment::new(stack_size);
         let initial_context = Context::new(start, &mut stack);
         Coroutine {
             current_stack_segment: stack,
@@ -47,6 +85,13 @@ impl Coroutine {
         }
     }
 
+    /// Switches from `sched` to this coroutine, returning once something
+    /// switches back to `sched`. When the coroutine's procedure returns it
+    /// switches back to whichever context resumed it last.
+    pub fn resume(&mut self, sched: &mut Context) {
+        Context::swap(sched, &self.saved_context);
+    }
+
     pub fn empty() -> Coroutine {
         Coroutine {
             current_stack_segment: StackSegment::new(0),
@@ -60,3 +105,228 @@ impl Coroutine {
         stack_pool.give_segment(current_stack_segment);
     }
 }
+
+/// What `Resumable::resume` came back to
+#[deriving(Eq)]
+pub enum Resumed {
+    /// The body called `yield_back`, and can be resumed again
+    Yielded,
+    /// The body has returned
+    Done,
+}
+
+/// A coroutine which holds on to the context that resumes it, so that its body
+/// can switch back without either side keeping a pair of contexts in sync.
+///
+/// Each `resume` runs the body until it calls `Yielder::yield_back` or
+/// returns. The bounds of the stack `resume` was called on are put back when
+/// it returns (or the stack limit is left unset, if none were recorded), so
+/// it can be called from a task as well as from a thread's own stack.
+///
+/// Dropping a `Resumable` whose body has yielded but not returned unwinds the
+/// body, running the destructors of everything live on its stack (see
+/// `Context::set_unwind_on_drop`). Where that isn't possible, anywhere but x86
+/// and x86_64, those destructors never run and whatever they would have freed
+/// is leaked. Either way the drop must happen on the thread which last
+/// resumed the body.
+pub struct Resumable {
+    priv shared: ~Shared,
+}
+
+/// Handed to a `Resumable`'s body, for switching back to whatever resumed it
+pub struct Yielder {
+    priv shared: *mut Shared,
+}
+
+// Boxed, so that the body can find it from its own stack however the
+// `Resumable` is moved around
+struct Shared {
+    coroutine: Option<Coroutine>,
+    caller: Context,
+    done: bool,
+}
+
+impl Resumable {
+    /// Creates a coroutine which will run `body` on a new stack of
+    /// `stack_size` bytes once it's first resumed
+    pub fn new(stack_size: uint, body: proc(Yielder)) -> Resumable {
+        let mut shared = ~Shared {
+            coroutine: None,
+            caller: Context::empty(),
+            done: false,
+        };
+        let shared_ptr: uint = unsafe { transmute(&mut *shared) };
+        let mut coroutine = Coroutine::with_stack_size(stack_size, proc() {
+            body(Yielder { shared: shared_ptr as *mut Shared });
+            let shared: &mut Shared = unsafe { transmute(shared_ptr) };
+            shared.done = true;
+            // There's nothing left to unwind once the body has returned
+            let coroutine = shared.coroutine.get_mut_ref();
+            coroutine.saved_context.set_unwind_on_drop(false);
+        });
+        coroutine.saved_context.set_unwind_on_drop(true);
+        shared.coroutine = Some(coroutine);
+        Resumable { shared: shared }
+    }
+
+    /// Runs the body until it next yields or returns. Once it's returned this
+    /// does nothing but return `Done`.
+    ///
+    /// If the body fails, the failure is carried on in the caller.
+    pub fn resume(&mut self) -> Resumed {
+        if self.shared.done { return Done }
+        let shared = &mut *self.shared;
+        // Bounds left from an earlier resume on some other stack would be
+        // recorded on the way back here otherwise
+        match context::current_stack_bounds() {
+            Some((lo, hi)) => unsafe { shared.caller.set_native_bounds(lo, hi) },
+            None => shared.caller.clear_native_bounds(),
+        }
+        let coroutine = shared.coroutine.get_mut_ref();
+        coroutine.resume(&mut shared.caller);
+        match coroutine.saved_context.take_failure() {
+            Some(failure) => {
+                shared.done = true;
+                coroutine.saved_context.set_unwind_on_drop(false);
+                Context::fail_with(failure)
+            }
+            None => {}
+        }
+        if shared.done { Done } else { Yielded }
+    }
+
+    /// Whether the body has returned (or failed)
+    pub fn is_done(&self) -> bool {
+        self.shared.done
+    }
+}
+
+impl Yielder {
+    /// Switches back to whatever resumed the body, returning once it's resumed
+    /// again
+    pub fn yield_back(&self) {
+        let shared: &mut Shared = unsafe { &mut *self.shared };
+        let coroutine = shared.coroutine.get_mut_ref();
+        Context::swap(&mut coroutine.saved_context, &shared.caller);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cast::transmute;
+    use std::unstable::stack;
+
+    use context;
+    use context::{Context, run_on_stack};
+    use stack::StackSegment;
+    use super::{Coroutine, Resumable, Yielded, Done};
+
+    #[test]
+    fn resume_runs_the_procedure_on_its_own_stack() {
+        let mut ran = 0u;
+        let ran_ptr: uint = unsafe { transmute(&mut ran) };
+        let mut co = Coroutine::with_stack_size(1024 * 1024, proc() {
+            let here = 0u;
+            let ran: &mut uint = unsafe { transmute(ran_ptr) };
+            *ran = &here as *uint as uint;
+        });
+        let (lo, hi) = (co.current_stack_segment.start() as uint,
+                        co.current_stack_segment.end() as uint);
+
+        let mut sched = Context::empty();
+        let limit = unsafe { stack::get_sp_limit() };
+        co.resume(&mut sched);
+        unsafe { stack::record_sp_limit(limit) };
+        assert!(lo < ran && ran < hi);
+    }
+
+    #[test]
+    fn resumable_yields_until_its_body_returns() {
+        let mut count = 0u;
+        let count_ptr: uint = unsafe { transmute(&mut count) };
+        let mut co = Resumable::new(1024 * 1024, proc(yielder) {
+            let count: &mut uint = unsafe { transmute(count_ptr) };
+            for _ in range(0, 3) {
+                *count += 1;
+                yielder.yield_back();
+            }
+        });
+
+        let limit = unsafe { stack::get_sp_limit() };
+        let mut results = ~[];
+        for _ in range(0, 5) {
+            results.push(co.resume());
+        }
+        unsafe { stack::record_sp_limit(limit) };
+        assert_eq!(results, ~[Yielded, Yielded, Yielded, Done, Done]);
+        assert_eq!(count, 3);
+        assert!(co.is_done());
+    }
+
+    #[test]
+    #[should_fail]
+    fn resumable_failures_carry_on_in_the_caller() {
+        let mut co = Resumable::new(1024 * 1024, proc(_yielder) {
+            fail!("failed in the body");
+        });
+        co.resume();
+    }
+
+    #[test]
+    fn resumables_put_back_the_callers_bounds() {
+        let mut co = Resumable::new(1024 * 1024, proc(yielder) {
+            loop { yielder.yield_back() }
+        });
+
+        let limit = unsafe { stack::get_sp_limit() };
+        unsafe { stack::record_sp_limit(0) };
+        assert_eq!(co.resume(), Yielded);
+        assert!(context::current_stack_bounds().is_none());
+
+        // Resumed from a green stack, and then from one with no bounds again
+        let co_ptr: uint = unsafe { transmute(&mut co) };
+        let mut stack = StackSegment::new(1024 * 1024);
+        let (lo, hi) = (stack.start() as uint, stack.end() as uint);
+        let bounds = run_on_stack(&mut stack, proc() {
+            let co: &mut Resumable = unsafe { transmute(co_ptr) };
+            co.resume();
+            context::current_stack_bounds()
+        });
+        assert!(bounds.map_or(false, |(l, h)| l >= lo && h <= hi));
+        unsafe { stack::record_sp_limit(0) };
+        assert_eq!(co.resume(), Yielded);
+        assert!(context::current_stack_bounds().is_none());
+        unsafe { stack::record_sp_limit(limit) };
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86")]
+    #[cfg(target_arch = "x86_64")]
+    fn dropping_a_suspended_resumable_unwinds_it() {
+        struct Flag { dropped: *mut bool }
+        impl Drop for Flag {
+            fn drop(&mut self) { unsafe { *self.dropped = true } }
+        }
+
+        let mut dropped = false;
+        let dropped_ptr: uint = unsafe { transmute(&mut dropped) };
+        let limit = unsafe { stack::get_sp_limit() };
+        {
+            let mut co = Resumable::new(1024 * 1024, proc(yielder) {
+                let _flag = Flag { dropped: unsafe { transmute(dropped_ptr) } };
+                yielder.yield_back();
+                unreachable!();
+            });
+            assert_eq!(co.resume(), Yielded);
+            assert!(!dropped);
+        }
+        unsafe { stack::record_sp_limit(limit) };
+        assert!(dropped);
+
+        // One which has returned has nothing left to unwind
+        let mut co = Resumable::new(1024 * 1024, proc(_yielder) {});
+        assert_eq!(co.resume(), Done);
+        drop(co);
+        unsafe { stack::record_sp_limit(limit) };
+    }
+}
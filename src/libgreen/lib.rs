@@ -27,7 +27,9 @@
       html_root_url = "http://static.rust-lang.org/doc/master")];
 
 // NB this does *not* include globs, please keep it that way.
-#[feature(macro_rules)];
+#[feature(macro_rules, thread_local, asm)];
+
+#[cfg(test)] extern mod extra;
 
 use std::os;
 use std::rt::crate_map;
@@ -43,20 +45,33 @@ use std::sync::arc::UnsafeArc;
 
 use sched::{Shutdown, Scheduler, SchedHandle, TaskFromFriend, NewNeighbor};
 use sleeper_list::SleeperList;
-use stack::StackPool;
+use stack::{StackAllocator, StackPool};
 use task::GreenTask;
 
 mod macros;
+mod overflow;
+mod signal_stack;
 mod simple;
+mod time;
 
 pub mod basic;
 pub mod context;
+pub mod context_registry;
 pub mod coroutine;
 pub mod sched;
 pub mod sleeper_list;
 pub mod stack;
+pub mod stack_registry;
 pub mod task;
 
+// Green threads are switched between by swapping stacks, and wasm32 has no
+// stack which can be swapped (its call stack isn't in linear memory at all),
+// so there's nothing to port. This stops the build with the reason as its
+// name, rather than leaving it to fail to link `rust_swap_registers`.
+#[cfg(target_arch = "wasm32")]
+#[static_assert]
+static GREEN_THREADING_UNSUPPORTED_ON_WASM32: bool = false;
+
 #[lang = "start"]
 #[cfg(not(test))]
 pub fn lang_start(main: *u8, argc: int, argv: **u8) -> int {
@@ -134,6 +149,17 @@ pub struct PoolConfig {
     /// A factory function used to create new event loops. If this is not
     /// specified then the default event loop factory is used.
     event_loop_factory: Option<fn() -> ~rtio::EventLoop>,
+    /// Whether each scheduler thread should get an alternate signal stack and
+    /// a fault handler that reports a task overflowing onto the guard page
+    /// below its stack, rather than crashing silently on it. This installs a
+    /// process-wide `SIGSEGV` (and `SIGBUS`) handler, so it's off by default
+    /// for embedders which handle those signals themselves. Faults not caused
+    /// by an overflow are left to the default disposition.
+    overflow_handler: bool,
+    /// A factory function used to create the allocator each scheduler (and
+    /// the pool itself) takes task stacks from. If this is not specified then
+    /// the allocator from `stack::set_stack_allocator` is used.
+    stack_allocator: Option<fn() -> ~StackAllocator:Send>,
 }
 
 impl PoolConfig {
@@ -143,6 +169,8 @@ impl PoolConfig {
         PoolConfig {
             threads: rt::default_sched_threads(),
             event_loop_factory: None,
+            overflow_handler: false,
+            stack_allocator: None,
         }
     }
 }
@@ -159,6 +187,8 @@ pub struct SchedPool {
     priv deque_pool: deque::BufferPool<~task::GreenTask>,
     priv sleepers: SleeperList,
     priv factory: fn() -> ~rtio::EventLoop,
+    priv overflow_handler: bool,
+    priv stack_allocator: Option<fn() -> ~StackAllocator:Send>,
     priv task_state: TaskState,
     priv tasks_done: Port<()>,
 }
@@ -182,10 +212,13 @@ impl SchedPool {
 
         let PoolConfig {
             threads: nscheds,
-            event_loop_factory: factory
+            event_loop_factory: factory,
+            overflow_handler: overflow_handler,
+            stack_allocator: stack_allocator
         } = config;
         let factory = factory.unwrap_or(default_event_loop_factory());
         assert!(nscheds > 0);
+        context::handle_stack_exhaustion();
 
         // The pool of schedulers that will be returned from this function
         let (p, state) = TaskState::new();
@@ -195,10 +228,12 @@ impl SchedPool {
             stealers: ~[],
             id: unsafe { POOL_ID.fetch_add(1, SeqCst) },
             sleepers: SleeperList::new(),
-            stack_pool: StackPool::new(),
+            stack_pool: new_stack_pool(stack_allocator),
             deque_pool: deque::BufferPool::new(),
             next_friend: 0,
             factory: factory,
+            overflow_handler: overflow_handler,
+            stack_allocator: stack_allocator,
             task_state: state,
             tasks_done: p,
         };
@@ -221,9 +256,13 @@ impl SchedPool {
                                             pool.stealers.clone(),
                                             pool.sleepers.clone(),
                                             pool.task_state.clone());
+            sched.stack_pool = new_stack_pool(pool.stack_allocator);
             pool.handles.push(sched.make_handle());
             let sched = sched;
-            pool.threads.push(do Thread::start { sched.bootstrap(); });
+            let overflow_handler = pool.overflow_handler;
+            pool.threads.push(do Thread::start {
+                bootstrap(sched, overflow_handler);
+            });
         }
 
         return pool;
@@ -282,10 +321,14 @@ impl SchedPool {
                                         self.stealers.clone(),
                                         self.sleepers.clone(),
                                         self.task_state.clone());
+        sched.stack_pool = new_stack_pool(self.stack_allocator);
         let ret = sched.make_handle();
         self.handles.push(sched.make_handle());
         let sched = sched;
-        self.threads.push(do Thread::start { sched.bootstrap() });
+        let overflow_handler = self.overflow_handler;
+        self.threads.push(do Thread::start {
+            bootstrap(sched, overflow_handler);
+        });
 
         return ret;
     }
@@ -354,6 +397,24 @@ impl Drop for SchedPool {
     }
 }
 
+// Runs a scheduler on the thread it was spawned for, with the overflow
+// handler if the pool asked for it (see `PoolConfig::overflow_handler`)
+fn bootstrap(sched: ~Scheduler, overflow_handler: bool) {
+    // The signal stack has to outlive the scheduler. It's taken off the thread
+    // again when it's dropped, before the rest of the thread is torn down.
+    let _signal_stack = if overflow_handler { overflow::install() } else { None };
+    sched.bootstrap();
+}
+
+// A stack pool for a scheduler (or the pool itself), with an allocator from
+// `PoolConfig::stack_allocator` if there is one
+fn new_stack_pool(factory: Option<fn() -> ~StackAllocator:Send>) -> StackPool {
+    match factory {
+        Some(factory) => StackPool::with_allocator(factory()),
+        None => StackPool::new(),
+    }
+}
+
 fn default_event_loop_factory() -> fn() -> ~rtio::EventLoop {
     match crate_map::get_crate_map() {
         None => {}
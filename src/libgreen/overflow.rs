@@ -0,0 +1,370 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! An opt-in fault handler for scheduler threads, which recognizes a task
+//! running into the guard page below its stack (see `stack::set_guard_pages`)
+//! and reports the overflow before aborting. See `PoolConfig::overflow_handler`.
+//!
+//! The task's own stack is exhausted by then, so the handler runs on an
+//! alternate signal stack belonging to the thread. That stack is nowhere near
+//! the task's stack limit, which is still recorded for morestack, so the
+//! handler's entry point is `#[no_split_stack]` and clears the limit before
+//! calling anything else. Everything it does is async-signal-safe: the stack
+//! bounds are worked out from the limit it cleared (see
+//! `context::stack_bounds_for_limit`), and the report is formatted into a fixed
+//! buffer and written straight to stderr.
+//!
+//! Once the overflow is reported, the running context's overflow policy is
+//! carried out (see `context::stack_exhausted`): there's no stack left to
+//! unwind, so only a `Callback` policy does anything beyond aborting.
+//!
+//! Any other fault is passed on to however the signal was handled before
+//! `install`: a handler of the embedder's is called, and the default action
+//! (or ignoring the signal) is put back, so that returning from the handler
+//! replays the fault and the process dies just as it would have without it.
+//!
+//! Only linux (other than on mips, mips64, sparc64 and s390x, whose
+//! `sigaction` is laid out differently), macos and freebsd are supported,
+//! elsewhere `install` does nothing.
+
+use std::cmp;
+use std::libc;
+use std::libc::{c_int, c_void};
+use std::os;
+use std::str;
+use std::sync::atomics::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+use std::unstable::stack;
+
+use context;
+use signal_stack::{SignalStack, MIN_SIGNAL_STACK};
+use stack_registry;
+
+// Plenty for the handler, which calls nothing deeper than `write`, though some
+// OSes insist on more (see `MIN_SIGNAL_STACK`)
+static SIGNAL_STACK_SIZE: uint = 16 * 1024;
+
+// The page size, looked up when the handler is installed since `sysconf`
+// isn't async-signal-safe
+static mut PAGE_SIZE: AtomicUint = INIT_ATOMIC_UINT;
+
+/// Installs the overflow handler for the whole process, and gives the calling
+/// thread an alternate signal stack for it to run on. The stack is returned,
+/// and must be kept alive for as long as the thread is running. Dropping it
+/// takes it off the thread again.
+///
+/// This returns `None`, having done nothing, where the handler isn't
+/// supported. It also does where `sigaltstack` refuses the signal stack, as
+/// the handler can't run on the stack which overflowed, and says why.
+pub fn install() -> Option<SignalStack> {
+    if !imp::SUPPORTED { return None }
+    unsafe {
+        PAGE_SIZE.store(os::page_size(), SeqCst);
+        let size = cmp::max(SIGNAL_STACK_SIZE, MIN_SIGNAL_STACK);
+        let stack = SignalStack::new(size);
+        match stack.install() {
+            Ok(..) => {}
+            Err(e) => {
+                rterrln!("not handling stack overflows: cannot install a \
+                          signal stack: {}", e);
+                return None
+            }
+        }
+        imp::set_handler(handler);
+        Some(stack)
+    }
+}
+
+// Anything this called with the task's stack limit still recorded would see
+// the signal stack's pointer below it, and call morestack before getting
+// anywhere, and that includes the handler which a fault other than an
+// overflow is passed on to. The limit is put back once that has returned.
+#[no_split_stack]
+extern "C" fn handler(signum: c_int, info: *imp::siginfo, uctx: *c_void) {
+    unsafe {
+        let limit = stack::get_sp_limit();
+        stack::record_sp_limit(0);
+        check_fault((*info).si_addr as uint, limit);
+        imp::chain(signum, info, uctx);
+        stack::record_sp_limit(limit);
+    }
+}
+
+fn check_fault(addr: uint, limit: uint) {
+    let page = unsafe { PAGE_SIZE.load(SeqCst) };
+    match context::stack_bounds_for_limit(limit) {
+        // The guard page is directly below the stack
+        Some((lo, hi)) if addr < lo && lo - addr <= page => unsafe {
+            let msg = report(lo, hi, addr, page);
+            context::stack_exhausted(str::from_utf8(msg.buf.slice_to(msg.len)));
+        },
+        _ => {}
+    }
+}
+
+// Writes out the report, and returns it for the overflow policy
+unsafe fn report(lo: uint, hi: uint, addr: uint, page: uint) -> Message {
+    let mut msg = Message { buf: [0u8, ..192], len: 0 };
+    msg.push_str("stack overflow in task");
+    if cfg!(stack_registry) {
+        match stack_registry::find(addr, page) {
+            Some(id) => {
+                msg.push_str(" (context ");
+                msg.push_num(id, 10);
+                msg.push_str(")");
+            }
+            None => {}
+        }
+    }
+    msg.push_str(": faulted at 0x");
+    msg.push_num(addr, 16);
+    msg.push_str(" below its stack [0x");
+    msg.push_num(lo, 16);
+    msg.push_str(", 0x");
+    msg.push_num(hi, 16);
+    msg.push_str(")\n");
+    libc::write(libc::STDERR_FILENO, msg.buf.as_ptr() as *c_void,
+                msg.len as libc::size_t);
+    msg
+}
+
+static DIGITS: &'static [u8] = bytes!("0123456789abcdef");
+
+// A message built up without allocating, which is cut short if it doesn't fit
+struct Message {
+    buf: [u8, ..192],
+    len: uint,
+}
+
+impl Message {
+    fn push(&mut self, b: u8) {
+        if self.len < self.buf.len() {
+            self.buf[self.len] = b;
+            self.len += 1;
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        for &b in s.as_bytes().iter() { self.push(b) }
+    }
+
+    fn push_num(&mut self, n: uint, radix: uint) {
+        let mut digits = [0u8, ..20];
+        let (mut n, mut i) = (n, 0);
+        loop {
+            digits[i] = DIGITS[n % radix];
+            i += 1;
+            n /= radix;
+            if n == 0 { break }
+        }
+        while i > 0 {
+            i -= 1;
+            self.push(digits[i]);
+        }
+    }
+}
+
+#[cfg(target_os = "linux", not(target_arch = "mips"), not(target_arch = "mips64"),
+      not(target_arch = "sparc64"), not(target_arch = "s390x"))]
+mod imp {
+    use std::cast::transmute;
+    use std::libc::{c_int, c_void};
+    use std::mem;
+    use std::ptr;
+
+    pub static SUPPORTED: bool = true;
+
+    static SIGBUS: c_int = 7;
+    static SIGSEGV: c_int = 11;
+    static SA_SIGINFO: c_int = 0x00000004;
+    static SA_ONSTACK: c_int = 0x08000000;
+    static SIG_IGN: uint = 1;
+
+    pub struct siginfo {
+        si_signo: c_int,
+        si_errno: c_int,
+        si_code: c_int,
+        si_addr: *c_void,
+    }
+
+    struct sigaction {
+        sa_sigaction: uint,
+        sa_mask: [u32, ..32],
+        sa_flags: c_int,
+        sa_restorer: *c_void,
+    }
+
+    // How SIGSEGV and SIGBUS were handled before `set_handler`, for `chain`
+    static mut PREV_SEGV: Option<sigaction> = None;
+    static mut PREV_BUS: Option<sigaction> = None;
+
+    pub unsafe fn set_handler(handler: extern "C" fn(c_int, *siginfo, *c_void)) {
+        let mut sa: sigaction = mem::init();
+        sa.sa_sigaction = handler as uint;
+        sa.sa_flags = SA_SIGINFO | SA_ONSTACK;
+        replace(SIGSEGV, &sa, &mut PREV_SEGV);
+        replace(SIGBUS, &sa, &mut PREV_BUS);
+    }
+
+    // Installs `sa` for `signum`, and keeps the action it replaces in `prev`
+    // unless it's `sa` already (installed from another scheduler thread)
+    unsafe fn replace(signum: c_int, sa: &sigaction,
+                      prev: &mut Option<sigaction>) {
+        let mut old: sigaction = mem::init();
+        if sigaction(signum, sa, &mut old) == 0 &&
+           old.sa_sigaction != sa.sa_sigaction {
+            *prev = Some(old);
+        }
+    }
+
+    // Passes a fault which isn't an overflow on to the action `set_handler`
+    // replaced. A handler is called; the default action, or ignoring the
+    // signal, is put back for the fault to be replayed once `handler` returns.
+    pub unsafe fn chain(signum: c_int, info: *siginfo, uctx: *c_void) {
+        let prev = if signum == SIGSEGV { &PREV_SEGV } else { &PREV_BUS };
+        match *prev {
+            Some(ref old) if old.sa_sigaction > SIG_IGN => {
+                if old.sa_flags & SA_SIGINFO != 0 {
+                    let f: extern "C" fn(c_int, *siginfo, *c_void) =
+                        transmute(old.sa_sigaction);
+                    f(signum, info, uctx)
+                } else {
+                    let f: extern "C" fn(c_int) = transmute(old.sa_sigaction);
+                    f(signum)
+                }
+            }
+            Some(ref old) => { sigaction(signum, old, ptr::mut_null()); }
+            None => {
+                let dfl: sigaction = mem::init();
+                sigaction(signum, &dfl, ptr::mut_null());
+            }
+        }
+    }
+
+    extern {
+        fn sigaction(signum: c_int, act: *sigaction,
+                     oldact: *mut sigaction) -> c_int;
+    }
+}
+
+#[cfg(target_os = "macos")]
+#[cfg(target_os = "freebsd")]
+mod imp {
+    use std::cast::transmute;
+    use std::libc::{c_int, c_uint, c_void};
+    use std::mem;
+    use std::ptr;
+
+    pub static SUPPORTED: bool = true;
+
+    // A guard page fault is a SIGBUS on macos
+    static SIGBUS: c_int = 10;
+    static SIGSEGV: c_int = 11;
+    static SA_ONSTACK: c_int = 0x0001;
+    static SIG_IGN: uint = 1;
+    static SA_SIGINFO: c_int = 0x0040;
+
+    pub struct siginfo {
+        si_signo: c_int,
+        si_errno: c_int,
+        si_code: c_int,
+        si_pid: c_int,
+        si_uid: c_uint,
+        si_status: c_int,
+        si_addr: *c_void,
+    }
+
+    #[cfg(target_os = "macos")]
+    struct sigaction {
+        sa_sigaction: uint,
+        sa_mask: u32,
+        sa_flags: c_int,
+    }
+
+    #[cfg(target_os = "freebsd")]
+    struct sigaction {
+        sa_sigaction: uint,
+        sa_flags: c_int,
+        sa_mask: [u32, ..4],
+    }
+
+    // How SIGSEGV and SIGBUS were handled before `set_handler`, for `chain`
+    static mut PREV_SEGV: Option<sigaction> = None;
+    static mut PREV_BUS: Option<sigaction> = None;
+
+    pub unsafe fn set_handler(handler: extern "C" fn(c_int, *siginfo, *c_void)) {
+        let mut sa: sigaction = mem::init();
+        sa.sa_sigaction = handler as uint;
+        sa.sa_flags = SA_SIGINFO | SA_ONSTACK;
+        replace(SIGSEGV, &sa, &mut PREV_SEGV);
+        replace(SIGBUS, &sa, &mut PREV_BUS);
+    }
+
+    // Installs `sa` for `signum`, and keeps the action it replaces in `prev`
+    // unless it's `sa` already (installed from another scheduler thread)
+    unsafe fn replace(signum: c_int, sa: &sigaction,
+                      prev: &mut Option<sigaction>) {
+        let mut old: sigaction = mem::init();
+        if sigaction(signum, sa, &mut old) == 0 &&
+           old.sa_sigaction != sa.sa_sigaction {
+            *prev = Some(old);
+        }
+    }
+
+    // Passes a fault which isn't an overflow on to the action `set_handler`
+    // replaced. A handler is called; the default action, or ignoring the
+    // signal, is put back for the fault to be replayed once `handler` returns.
+    pub unsafe fn chain(signum: c_int, info: *siginfo, uctx: *c_void) {
+        let prev = if signum == SIGSEGV { &PREV_SEGV } else { &PREV_BUS };
+        match *prev {
+            Some(ref old) if old.sa_sigaction > SIG_IGN => {
+                if old.sa_flags & SA_SIGINFO != 0 {
+                    let f: extern "C" fn(c_int, *siginfo, *c_void) =
+                        transmute(old.sa_sigaction);
+                    f(signum, info, uctx)
+                } else {
+                    let f: extern "C" fn(c_int) = transmute(old.sa_sigaction);
+                    f(signum)
+                }
+            }
+            Some(ref old) => { sigaction(signum, old, ptr::mut_null()); }
+            None => {
+                let dfl: sigaction = mem::init();
+                sigaction(signum, &dfl, ptr::mut_null());
+            }
+        }
+    }
+
+    extern {
+        fn sigaction(signum: c_int, act: *sigaction,
+                     oldact: *mut sigaction) -> c_int;
+    }
+}
+
+#[cfg(windows)]
+#[cfg(target_os = "android")]
+#[cfg(target_os = "linux", target_arch = "mips")]
+#[cfg(target_os = "linux", target_arch = "mips64")]
+#[cfg(target_os = "linux", target_arch = "sparc64")]
+#[cfg(target_os = "linux", target_arch = "s390x")]
+mod imp {
+    use std::libc::{c_int, c_void};
+
+    pub static SUPPORTED: bool = false;
+
+    pub struct siginfo {
+        si_addr: *c_void,
+    }
+
+    pub unsafe fn set_handler(_handler: extern "C" fn(c_int, *siginfo,
+                                                      *c_void)) {
+    }
+
+    pub unsafe fn chain(_signum: c_int, _info: *siginfo, _uctx: *c_void) {}
+}
@@ -20,6 +20,7 @@ use std::unstable::raw;
 use mpsc = std::sync::mpsc_queue;
 
 use TaskState;
+use context;
 use context::Context;
 use coroutine::Coroutine;
 use sleeper_list::SleeperList;
@@ -184,9 +185,17 @@ impl Scheduler {
         let cb = ~SchedRunner as ~Callback;
         self.idle_callback = Some(self.event_loop.pausable_idle_callback(cb));
 
-        // Create a task for the scheduler with an empty context.
-        let sched_task = GreenTask::new_typed(Some(Coroutine::empty()),
-                                              TypeSched);
+        // Create a task for the scheduler with an empty context. Where the
+        // thread's own stack can be found, the scheduler keeps morestack
+        // checking against it while it runs rather than turning the check off.
+        let mut coroutine = Coroutine::empty();
+        match context::thread_stack_bounds() {
+            Some((lo, hi)) => unsafe {
+                coroutine.saved_context.set_native_bounds(lo, hi)
+            },
+            None => {}
+        }
+        let sched_task = GreenTask::new_typed(Some(coroutine), TypeSched);
 
         // Before starting our first task, make sure the idle callback
         // is active. As we do not start in the sleep state this is
@@ -972,6 +981,8 @@ mod test {
         SchedPool::new(PoolConfig {
             threads: 1,
             event_loop_factory: Some(basic::event_loop),
+            overflow_handler: false,
+            stack_allocator: None,
         })
     }
 
@@ -1219,6 +1230,8 @@ mod test {
         let mut pool = SchedPool::new(PoolConfig {
             threads: 2,
             event_loop_factory: None,
+            overflow_handler: false,
+            stack_allocator: None,
         });
 
         // This is a regression test that when there are no schedulable tasks in
@@ -1369,6 +1382,8 @@ mod test {
         let mut pool = SchedPool::new(PoolConfig {
             threads: 2, // this must be > 1
             event_loop_factory: Some(basic::event_loop),
+            overflow_handler: false,
+            stack_allocator: None,
         });
         do pool.spawn(TaskOpts::new()) {
             let (port, chan) = Chan::new();
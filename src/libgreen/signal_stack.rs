@@ -0,0 +1,183 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Alternate signal stacks which belong to a single task rather than to the
+//! OS thread, see `Context::set_signal_stack`.
+//!
+//! Windows has no `sigaltstack`, so there these are only ever allocated and
+//! installing one does nothing.
+
+use std::libc::c_void;
+use std::os;
+use std::vec;
+
+/// The smallest alternate signal stack the OS accepts, its MINSIGSTKSZ
+pub static MIN_SIGNAL_STACK: uint = imp::MINSIGSTKSZ;
+
+/// An alternate signal stack and the memory behind it
+pub struct SignalStack {
+    priv buf: ~[u8],
+    priv desc: AltStack,
+}
+
+impl SignalStack {
+    pub fn new(size: uint) -> SignalStack {
+        let buf = vec::from_elem(size, 0u8);
+        let desc = AltStack::new(buf.as_ptr() as *c_void, size);
+        SignalStack { buf: buf, desc: desc }
+    }
+
+    /// The low end of this stack
+    pub fn start(&self) -> uint {
+        self.buf.as_ptr() as uint
+    }
+
+    /// Makes this the thread's alternate signal stack, returning the one that
+    /// it replaces, or why `sigaltstack` refused to (in which case the
+    /// thread's alternate signal stack is unchanged).
+    ///
+    /// This is unsafe because this stack must outlive its installation, and
+    /// the thread mustn't be running on the stack being replaced.
+    pub unsafe fn install(&self) -> Result<AltStack, ~str> {
+        self.desc.install()
+    }
+}
+
+impl Drop for SignalStack {
+    // The calling thread mustn't be left taking signals on memory which is
+    // about to be freed, so if this is still its alternate signal stack it's
+    // disabled. It's up to whoever installed it on any other thread to take
+    // it off that one first.
+    fn drop(&mut self) {
+        let cur = AltStack::current();
+        if cur.is_enabled() && cur.start() == self.start() {
+            // This only fails while a signal handler is running on the stack
+            let _ = unsafe { AltStack::disabled().install() };
+        }
+    }
+}
+
+/// An alternate signal stack as the OS describes it, which may be disabled
+pub struct AltStack {
+    priv ss: imp::stack_t,
+}
+
+impl AltStack {
+    fn new(sp: *c_void, size: uint) -> AltStack {
+        AltStack { ss: imp::stack_t::new(sp, size) }
+    }
+
+    /// No alternate signal stack, so that signals are taken on whichever stack
+    /// the thread is running on
+    pub fn disabled() -> AltStack {
+        let mut ss = imp::stack_t::new(0 as *c_void, 0);
+        ss.ss_flags = imp::SS_DISABLE;
+        AltStack { ss: ss }
+    }
+
+    /// Whether this is a stack, rather than the lack of one
+    pub fn is_enabled(&self) -> bool {
+        self.ss.ss_flags & imp::SS_DISABLE == 0
+    }
+
+    /// The thread's current alternate signal stack
+    pub fn current() -> AltStack {
+        let mut cur = AltStack::new(0 as *c_void, 0);
+        // Only a bad pointer makes this fail
+        let ret = unsafe { imp::sigaltstack(0 as *imp::stack_t, &mut cur.ss) };
+        rtassert!(ret == 0);
+        cur
+    }
+
+    /// The low end of this stack
+    pub fn start(&self) -> uint {
+        self.ss.ss_sp as uint
+    }
+
+    /// See `SignalStack::install`
+    pub unsafe fn install(&self) -> Result<AltStack, ~str> {
+        let mut old = AltStack::new(0 as *c_void, 0);
+        if imp::sigaltstack(&self.ss, &mut old.ss) != 0 {
+            return Err(os::last_os_error())
+        }
+        Ok(old)
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::libc::{c_int, c_void, size_t};
+
+    #[cfg(target_os = "linux")]
+    #[cfg(target_os = "android")]
+    pub struct stack_t {
+        ss_sp: *c_void,
+        ss_flags: c_int,
+        ss_size: size_t,
+    }
+
+    #[cfg(target_os = "macos")]
+    #[cfg(target_os = "freebsd")]
+    pub struct stack_t {
+        ss_sp: *c_void,
+        ss_size: size_t,
+        ss_flags: c_int,
+    }
+
+    impl stack_t {
+        pub fn new(sp: *c_void, size: uint) -> stack_t {
+            stack_t { ss_sp: sp, ss_flags: 0, ss_size: size as size_t }
+        }
+    }
+
+    #[cfg(target_os = "linux", target_arch = "aarch64")]
+    pub static MINSIGSTKSZ: uint = 5120;
+    #[cfg(target_os = "linux", not(target_arch = "aarch64"))]
+    #[cfg(target_os = "android")]
+    #[cfg(target_os = "freebsd")]
+    pub static MINSIGSTKSZ: uint = 2048;
+    #[cfg(target_os = "macos")]
+    pub static MINSIGSTKSZ: uint = 32768;
+
+    #[cfg(target_os = "linux")]
+    #[cfg(target_os = "android")]
+    pub static SS_DISABLE: c_int = 2;
+    #[cfg(target_os = "macos")]
+    #[cfg(target_os = "freebsd")]
+    pub static SS_DISABLE: c_int = 4;
+
+    extern {
+        pub fn sigaltstack(ss: *stack_t, old_ss: *mut stack_t) -> c_int;
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::libc::{c_int, c_void};
+
+    pub struct stack_t {
+        ss_sp: *c_void,
+        ss_flags: c_int,
+    }
+
+    impl stack_t {
+        pub fn new(sp: *c_void, _size: uint) -> stack_t {
+            stack_t { ss_sp: sp, ss_flags: 0 }
+        }
+    }
+
+    // Nothing is ever installed, so any size will do
+    pub static MINSIGSTKSZ: uint = 0;
+    pub static SS_DISABLE: c_int = 1;
+
+    pub unsafe fn sigaltstack(_ss: *stack_t, _old_ss: *mut stack_t) -> c_int {
+        0
+    }
+}
@@ -8,25 +8,158 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use std::cast::transmute;
+use std::fmt;
+use std::mem;
+use std::os::{MemoryMap, MapReadable, MapWritable};
+use std::os;
+use std::ptr;
+use std::uint;
 use std::vec;
 use std::libc::{c_uint, uintptr_t};
+use std::sync::atomics::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+
+use context::{ContextError, AllocFailed, GuardPageFailed, StackTooSmall};
+use context::StackBudgetExceeded;
 
 pub struct StackSegment {
-    priv buf: ~[u8],
+    /// The memory backing this stack, or `None` if this segment is a view
+    /// into memory owned by some other segment (see `split_top`) or is mapped
+    /// along with a guard page
+    priv buf: Option<~[u8]>,
+    /// The mapping holding this stack and its guard page, if it has one
+    priv map: Option<MemoryMap>,
+    /// The address space holding this stack if it's committed as it grows,
+    /// see `reservation`
+    priv reserved: Option<Reservation>,
+    priv start: *uint,
+    priv end: *uint,
+    /// Bounds of the inaccessible page protecting the low end of the stack,
+    /// if there is one
+    priv guard: Option<(uint, uint)>,
+    /// Whether the stack was filled with `HIGH_WATER_FILL` when it was
+    /// allocated, see `high_water_mark`
+    priv filled: bool,
     priv valgrind_id: c_uint
 }
 
 impl StackSegment {
     pub fn new(size: uint) -> StackSegment {
+        unsafe { STACK_BYTES.fetch_add(size, SeqCst); }
+        StackSegment::alloc(size)
+    }
+
+    /// Like `new`, but every byte of the stack is zero, so that a task which
+    /// reads stack memory it never wrote (an uninitialized local, say) sees
+    /// the same thing on every run rather than whatever the allocator left
+    /// behind. This is independent of any other fill: a zeroed stack is never
+    /// filled with `HIGH_WATER_FILL`, so it has no `high_water_mark`, and is
+    /// still poisoned once it's freed.
+    ///
+    /// The stack is zeroed before anything uses it, so a context made on it
+    /// afterwards writes its initial frame and canary over the zeroes as
+    /// usual, and everything below the frame starts out zero.
+    pub fn new_zeroed(size: uint) -> StackSegment {
+        let mut stk = StackSegment::new(size);
+        // A reserved stack is zero already, and its pages aren't committed
+        // for anything but the task itself to grow into
+        if stk.reserved.is_none() {
+            let len = stk.end as uint - stk.start as uint;
+            unsafe { ptr::set_memory(stk.start as *mut u8, 0, len) }
+        }
+        stk.filled = false;
+        stk
+    }
+
+    /// Allocates a new stack unless that would take the bytes used by all
+    /// stacks over the budget set with `set_stack_budget`.
+    ///
+    /// `new` always allocates, even over budget, but its stacks still count
+    /// towards it. This is what a `StackPool`'s default allocator uses.
+    ///
+    /// Unlike `new`, which settles for a plain heap allocation when it can't
+    /// map a stack with a guard page (or, on windows, reserve one), this
+    /// returns `AllocFailed` if the mapping can't be made and
+    /// `GuardPageFailed` if its guard page can't be protected. An empty stack
+    /// is `StackTooSmall`.
+    pub fn try_new(size: uint) -> Result<StackSegment, ContextError> {
+        StackSegment::try_new_within(size, stack_budget())
+    }
+
+    // `try_new`, keeping to `budget` rather than the process-wide budget
+    fn try_new_within(size: uint,
+                      budget: uint) -> Result<StackSegment, ContextError> {
+        if size == 0 { return Err(StackTooSmall) }
+        if !StackSegment::reserve(size, budget) {
+            return Err(StackBudgetExceeded)
+        }
+        let ret = StackSegment::try_alloc(size);
+        if ret.is_err() { unsafe { STACK_BYTES.fetch_sub(size, SeqCst); } }
+        ret
+    }
+
+    /// Like `try_new`, but the stack always has a guard page below it (see
+    /// `set_guard_pages`), whether or not they're otherwise enabled.
+    pub fn try_new_guarded(size: uint) -> Result<StackSegment, ContextError> {
+        if size == 0 { return Err(StackTooSmall) }
+        if !StackSegment::reserve(size, stack_budget()) {
+            return Err(StackBudgetExceeded)
+        }
+        let ret = StackSegment::alloc_guarded(size);
+        if ret.is_err() { unsafe { STACK_BYTES.fetch_sub(size, SeqCst); } }
+        ret
+    }
+
+    // Counts `size` more bytes of stack, unless that would go over `budget`
+    fn reserve(size: uint, budget: uint) -> bool {
+        unsafe {
+            let used = STACK_BYTES.fetch_add(size, SeqCst) + size;
+            if budget != 0 && used > budget {
+                STACK_BYTES.fetch_sub(size, SeqCst);
+                return false
+            }
+        }
+        true
+    }
+
+    fn alloc(size: uint) -> StackSegment {
+        match StackSegment::try_alloc(size) {
+            Ok(stk) => stk,
+            Err(..) => StackSegment::alloc_owned(size),
+        }
+    }
+
+    fn try_alloc(size: uint) -> Result<StackSegment, ContextError> {
+        if unsafe { GUARD_PAGES.load(SeqCst) } != 0 {
+            return StackSegment::alloc_guarded(size)
+        }
+        match StackSegment::alloc_reserved(size) {
+            Ok(Some(stk)) => return Ok(stk),
+            Ok(None) => {}
+            Err(e) => return Err(e),
+        }
+        Ok(StackSegment::alloc_owned(size))
+    }
+
+    fn alloc_owned(size: uint) -> StackSegment {
         unsafe {
             // Crate a block of uninitialized values
             let mut stack = vec::with_capacity(size);
             stack.set_len(size);
 
+            let start = stack.as_ptr() as *uint;
+            let end = stack.as_ptr().offset(size as int) as *uint;
             let mut stk = StackSegment {
-                buf: stack,
+                buf: Some(stack),
+                map: None,
+                reserved: None,
+                start: start,
+                end: end,
+                guard: None,
+                filled: false,
                 valgrind_id: 0
             };
+            stk.fill();
 
             // XXX: Using the FFI to call a C macro. Slow
             stk.valgrind_id = rust_valgrind_stack_register(stk.start(), stk.end());
@@ -34,38 +167,644 @@ impl StackSegment {
         }
     }
 
+    // Maps the stack with an inaccessible page below it, so that overflowing
+    // it faults rather than running into whatever is next to it
+    fn alloc_guarded(size: uint) -> Result<StackSegment, ContextError> {
+        let page = os::page_size();
+        let len = match size.checked_add(&page) {
+            Some(len) => len,
+            None => return Err(AllocFailed),
+        };
+        let map = match MemoryMap::new(len, [MapReadable, MapWritable]) {
+            Ok(map) => map,
+            Err(..) => return Err(AllocFailed),
+        };
+        let guard = map.data as uint;
+        if unsafe { !protect_page(guard, page) } { return Err(GuardPageFailed) }
+        let mut stk = StackSegment {
+            buf: None,
+            map: Some(map),
+            reserved: None,
+            start: (guard + page) as *uint,
+            end: (guard + page + size) as *uint,
+            guard: Some((guard, guard + page)),
+            filled: false,
+            valgrind_id: 0
+        };
+        stk.fill();
+        unsafe {
+            stk.valgrind_id = rust_valgrind_stack_register(stk.start(), stk.end());
+        }
+        Ok(stk)
+    }
+
+    // On windows, reserves the address space for the whole stack but commits
+    // only its top `INITIAL_COMMIT` bytes, with a `PAGE_GUARD` page below
+    // them. The task's first touch of the guard page has the OS commit it
+    // and move the guard down a page, just as it does for a thread's own
+    // stack, as long as the TIB describes the reservation (which
+    // `Context::swap` sees to, from `reservation`). Once the guard reaches the
+    // bottom of the reservation the OS raises a stack overflow, well after
+    // the stack limit at `start() + RED_ZONE` should have caught it.
+    //
+    // This gives `None` for a stack too small to be worth reserving, and
+    // `AllocFailed` if the reservation or the initial commit fails.
+    //
+    // Only the committed part is ever touched on the task's behalf, so these
+    // stacks are never filled with `HIGH_WATER_FILL` or poisoned. Freeing one
+    // releases the whole reservation, which faults anything still pointing
+    // into it instead.
+    #[cfg(windows)]
+    fn alloc_reserved(size: uint)
+        -> Result<Option<StackSegment>, ContextError>
+    {
+        use std::libc::{LPVOID, SIZE_T, VirtualAlloc, MEM_RESERVE, MEM_COMMIT};
+        use std::libc::{PAGE_READWRITE, PAGE_GUARD};
+
+        let page = os::page_size();
+        // Too small for committing it lazily to save anything
+        if size < INITIAL_COMMIT + 2 * page { return Ok(None) }
+        unsafe {
+            let base = VirtualAlloc(0 as LPVOID, size as SIZE_T, MEM_RESERVE,
+                                    PAGE_READWRITE) as uint;
+            if base == 0 { return Err(AllocFailed) }
+            let reserved = Reservation { base: base };
+            let hi = base + size;
+            let committed = initial_commit_lo(hi);
+            if VirtualAlloc(committed as LPVOID, (hi - committed) as SIZE_T,
+                            MEM_COMMIT, PAGE_READWRITE).is_null() ||
+               VirtualAlloc((committed - page) as LPVOID, page as SIZE_T,
+                            MEM_COMMIT, PAGE_READWRITE | PAGE_GUARD).is_null() {
+                return Err(AllocFailed)
+            }
+            let mut stk = StackSegment {
+                buf: None,
+                map: None,
+                reserved: Some(reserved),
+                start: base as *uint,
+                end: hi as *uint,
+                guard: None,
+                filled: false,
+                valgrind_id: 0
+            };
+            stk.valgrind_id = rust_valgrind_stack_register(stk.start(), stk.end());
+            Ok(Some(stk))
+        }
+    }
+
+    #[cfg(not(windows))]
+    fn alloc_reserved(_size: uint)
+        -> Result<Option<StackSegment>, ContextError>
+    {
+        Ok(None)
+    }
+
+    /// For a stack which is committed as it grows, the bottom of its
+    /// reservation (which is `start`) and the low end of the part that was
+    /// committed to begin with, or `None` for any other stack.
+    ///
+    /// Only `new` makes such stacks, and only on windows, unless guard pages
+    /// are enabled (see `set_guard_pages`) or the stack is too small for it
+    /// to make a difference.
+    pub fn reservation(&self) -> Option<(uint, uint)> {
+        self.reserved.as_ref().map(|_| {
+            (self.start as uint, initial_commit_lo(self.end as uint))
+        })
+    }
+
     /// Point to the low end of the allocated stack
     pub fn start(&self) -> *uint {
-        self.buf.as_ptr() as *uint
+        self.start
     }
 
     /// Point one word beyond the high end of the allocated stack
     pub fn end(&self) -> *uint {
-        unsafe {
-            self.buf.as_ptr().offset(self.buf.len() as int) as *uint
+        self.end
+    }
+
+    /// The `(lo, hi)` bounds of the guard page below this stack, or `None` if
+    /// the stack isn't protected by one. A fault inside of this region means
+    /// that a task running on this stack overflowed it.
+    pub fn guard_region(&self) -> Option<(uint, uint)> {
+        self.guard
+    }
+
+    /// The address of the guard page below this stack, if it has one (see
+    /// `set_guard_pages`). The stack itself starts right above it, so this is
+    /// never part of the bounds a context records for the stack.
+    pub fn guard_page(&self) -> Option<uint> {
+        self.guard.map(|(lo, _)| lo)
+    }
+
+    /// How many bytes of this stack, counting down from its high end, have
+    /// ever been written to, or `None` if that isn't known. This is meant for
+    /// sizing stacks after a representative run of the task that uses them.
+    ///
+    /// It's only known for stacks allocated by this segment when libgreen is
+    /// built with `--cfg stack_high_water`, which fills each new stack with
+    /// `HIGH_WATER_FILL`. The stack is scanned up from its low end for the
+    /// first word that has changed, so the guard page below it is never
+    /// touched, and a word which happens to be written with the fill pattern
+    /// can only make the mark a little low. The initial frame that a context
+    /// writes at the top of its stack is counted, so a stack whose task has
+    /// never run reports a few words rather than nothing.
+    pub fn high_water_mark(&self) -> Option<uint> {
+        if !self.filled { return None }
+        // Every byte of a word is the fill byte
+        let fill = uint::max_value / 0xff * HIGH_WATER_FILL as uint;
+        let mut p = self.start as uint;
+        let hi = self.end as uint;
+        while p < hi && unsafe { *(p as *uint) } == fill {
+            p += mem::size_of::<uint>();
+        }
+        Some(hi - p)
+    }
+
+    /// Gives the physical memory behind this stack back to the OS, so that a
+    /// pool can keep a stack it isn't using without it counting towards the
+    /// process's resident memory. The stack's addresses stay mapped, and the
+    /// guard page below it stays protected. Every whole page of the stack is
+    /// released, and reads as zeroes the next time it's touched.
+    ///
+    /// This does nothing on platforms without `madvise`.
+    ///
+    /// This is unsafe because whatever is on the stack is lost: no context on
+    /// it may be resumed afterwards, though a new one can be made on it.
+    pub unsafe fn release_pages(&mut self) {
+        let page = os::page_size();
+        let lo = (self.start as uint + page - 1) & !(page - 1);
+        let hi = self.end as uint & !(page - 1);
+        if lo < hi { release_pages(lo, hi) }
+        // What's left of the fill is gone from whatever was released
+        self.filled = false;
+    }
+
+    /// Splits this segment into two views: one covering the top `size` bytes
+    /// (rounded up to keep both halves 16-byte aligned) and one covering
+    /// whatever is left below it. Stacks grow down, so a task running in the
+    /// top half will overflow into its red zone before it reaches the bottom
+    /// half. Either view can itself be split again.
+    ///
+    /// This is unsafe because the returned views do not own their memory. The
+    /// caller must keep this segment alive (and not split it again) for as
+    /// long as either view, or any context created on one, is in use.
+    pub unsafe fn split_top(&self, size: uint) -> (StackSegment, StackSegment) {
+        let size = (size + 15) & !15;
+        let (lo, hi) = (self.start as uint, self.end as uint);
+        rtassert!(size <= hi - lo);
+        let mid = (hi - size) & !15;
+        (StackSegment::view(mid, hi), StackSegment::view(lo, mid))
+    }
+
+    /// A segment covering the memory `[lo, hi)`, for embedders which provide
+    /// their own stacks.
+    ///
+    /// This is unsafe because, like the views returned by `split_top`, the
+    /// segment doesn't own the memory. It must stay valid for as long as the
+    /// segment, or any context created on it, is in use.
+    pub unsafe fn from_raw(lo: uint, hi: uint) -> StackSegment {
+        rtassert!(lo <= hi);
+        StackSegment::view(lo, hi)
+    }
+
+    /// Checks that this segment still describes usable memory, so that a pool
+    /// can catch a corrupted segment before handing it to `Context::new`.
+    ///
+    /// The bounds must be ordered and word aligned, a segment which owns its
+    /// memory must still cover exactly that memory, a guard page must sit
+    /// directly below the stack, and every page of the stack must be mapped.
+    /// Whether the guard page is still inaccessible can't be checked without
+    /// faulting on it. Pages are only checked on platforms with `mincore`.
+    pub fn validate(&self) -> Result<(), StackError> {
+        let (lo, hi) = (self.start as uint, self.end as uint);
+        let word = mem::size_of::<uint>();
+        if lo > hi || lo % word != 0 || hi % word != 0 {
+            return Err(BadBounds)
+        }
+        match self.buf {
+            Some(ref buf) if buf.as_ptr() as uint != lo ||
+                             buf.len() != hi - lo => return Err(BadLength),
+            _ => {}
+        }
+        match self.map {
+            Some(ref map) if map.data as uint + os::page_size() != lo ||
+                             hi - map.data as uint > map.len as uint => {
+                return Err(BadLength)
+            }
+            _ => {}
+        }
+        match self.reserved {
+            Some(ref reserved) if reserved.base != lo => return Err(BadLength),
+            _ => {}
+        }
+        match self.guard {
+            Some((glo, ghi)) if glo >= ghi || ghi != lo => {
+                return Err(GuardMismatch)
+            }
+            _ => {}
+        }
+        if lo != hi && !pages_mapped(lo, hi) { return Err(Unmapped) }
+        Ok(())
+    }
+
+    fn view(lo: uint, hi: uint) -> StackSegment {
+        let mut stk = StackSegment {
+            buf: None,
+            map: None,
+            reserved: None,
+            start: lo as *uint,
+            end: hi as *uint,
+            guard: None,
+            filled: false,
+            valgrind_id: 0,
+        };
+        // Only the segment owning the memory is normally registered. Telling
+        // valgrind about every view too stops it mistaking switches between
+        // them for huge stack frames, see `--cfg valgrind` on `Context::swap`.
+        if cfg!(valgrind) {
+            unsafe {
+                stk.valgrind_id = rust_valgrind_stack_register(stk.start(),
+                                                               stk.end());
+            }
+        }
+        stk
+    }
+
+    // Fills a newly allocated stack with `HIGH_WATER_FILL` for
+    // `high_water_mark`, when built with `--cfg stack_high_water`
+    fn fill(&mut self) {
+        if !cfg!(stack_high_water) || self.reserved.is_some() { return }
+        let len = self.end as uint - self.start as uint;
+        unsafe { ptr::set_memory(self.start as *mut u8, HIGH_WATER_FILL, len) }
+        self.filled = true;
+    }
+
+    // Fills this stack with `POISON`, so that anything still pointing into it
+    // after it's been freed reads obviously bad values. This only happens in
+    // builds with debug assertions, i.e. without `--cfg ndebug`.
+    fn poison(&mut self) {
+        if cfg!(ndebug) || self.start == self.end || self.reserved.is_some() {
+            return
         }
+        let len = self.end as uint - self.start as uint;
+        unsafe { ptr::set_memory(self.start as *mut u8, POISON, len) }
     }
 }
 
+// How much of a stack which is committed as it grows is committed to begin
+// with, see `StackSegment::alloc_reserved`. This is plenty for the initial
+// frame and a few calls, and keeps a task which never goes deeper to a few
+// pages.
+static INITIAL_COMMIT: uint = 16 * 1024;
+
+// The page which the committed part of a reserved stack ending at `hi` starts
+// at, which leaves at least `INITIAL_COMMIT` bytes committed
+fn initial_commit_lo(hi: uint) -> uint {
+    (hi - INITIAL_COMMIT) & !(os::page_size() - 1)
+}
+
+// Address space reserved for a stack, which is released when it's dropped
+struct Reservation {
+    base: uint,
+}
+
+#[cfg(windows)]
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        use std::libc::{LPVOID, VirtualFree, MEM_RELEASE};
+        unsafe { VirtualFree(self.base as LPVOID, 0, MEM_RELEASE); }
+    }
+}
+
+/// The byte that a stack is filled with when it's freed or given back to a
+/// `StackArena`, unless built with `--cfg ndebug`
+pub static POISON: u8 = 0xde;
+
+/// The byte that a new stack is filled with when built with
+/// `--cfg stack_high_water`, see `StackSegment::high_water_mark`
+pub static HIGH_WATER_FILL: u8 = 0xa5;
+
 impl Drop for StackSegment {
     fn drop(&mut self) {
+        // Views were never registered or counted, the segment that owns the
+        // memory was
+        if self.buf.is_some() || self.map.is_some() {
+            self.poison();
+        }
+        let size = match (&self.buf, &self.map) {
+            (&Some(ref buf), _) => buf.len(),
+            (&None, &Some(..)) => self.end as uint - self.start as uint,
+            (&None, &None) if self.reserved.is_some() => {
+                self.end as uint - self.start as uint
+            }
+            (&None, &None) => {
+                if self.valgrind_id != 0 {
+                    unsafe { rust_valgrind_stack_deregister(self.valgrind_id) }
+                }
+                return
+            }
+        };
         unsafe {
+            STACK_BYTES.fetch_sub(size, SeqCst);
             // XXX: Using the FFI to call a C macro. Slow
             rust_valgrind_stack_deregister(self.valgrind_id);
         }
     }
 }
 
-pub struct StackPool(());
+/// Why `StackSegment::validate` rejected a segment
+#[deriving(Eq)]
+pub enum StackError {
+    /// The stack's bounds are reversed or misaligned
+    BadBounds,
+    /// The bounds don't match the memory which the segment owns
+    BadLength,
+    /// The guard page isn't directly below the stack
+    GuardMismatch,
+    /// Part of the stack isn't mapped
+    Unmapped,
+}
+
+impl fmt::Default for StackError {
+    fn fmt(err: &StackError, f: &mut fmt::Formatter) {
+        let msg = match *err {
+            BadBounds => "stack bounds are reversed or misaligned",
+            BadLength => "stack bounds don't match its memory",
+            GuardMismatch => "guard page isn't below the stack",
+            Unmapped => "stack memory isn't mapped",
+        };
+        write!(f.buf, "{}", msg)
+    }
+}
+
+// Whether every page overlapping `[lo, hi)` is mapped. mincore fails with
+// ENOMEM for any range containing an unmapped page.
+#[cfg(unix)]
+fn pages_mapped(lo: uint, hi: uint) -> bool {
+    use std::libc::{c_void, size_t, mincore};
+    let page = os::page_size();
+    let start = lo & !(page - 1);
+    let pages = (hi - start + page - 1) / page;
+    let residency = vec::from_elem(pages, 0u8);
+    unsafe {
+        mincore(start as *c_void, (pages * page) as size_t,
+                residency.as_ptr()) == 0
+    }
+}
+
+#[cfg(windows)]
+fn pages_mapped(_lo: uint, _hi: uint) -> bool { true }
+
+/// Hands the physical memory backing the pages in `[lo, hi)` back to the OS,
+/// keeping the addresses mapped. The range must be page aligned, and the next
+/// access to any of these pages will see zeroes.
+///
+/// This is a no-op on platforms without `madvise`.
+#[cfg(unix)]
+pub unsafe fn release_pages(lo: uint, hi: uint) {
+    use std::libc::{c_void, size_t, MADV_DONTNEED, madvise};
+    rtassert!(lo <= hi);
+    madvise(lo as *c_void, (hi - lo) as size_t, MADV_DONTNEED);
+}
+
+#[cfg(windows)]
+pub unsafe fn release_pages(_lo: uint, _hi: uint) {}
+
+static mut STACK_BYTES: AtomicUint = INIT_ATOMIC_UINT;
+// 1 if new stacks get guard pages
+static mut GUARD_PAGES: AtomicUint = INIT_ATOMIC_UINT;
+
+/// Sets whether stacks allocated from now on get an inaccessible guard page
+/// below them, so that a task overflowing its stack faults rather than
+/// silently corrupting whatever memory is below. This is off by default,
+/// each guard page costs a separate mapping and a system call to protect it.
+///
+/// A stack whose guard page can't be set up is allocated without one, see
+/// `StackSegment::guard_page`.
+pub fn set_guard_pages(enabled: bool) {
+    unsafe { GUARD_PAGES.store(enabled as uint, SeqCst) }
+}
+// 0 for no budget
+static mut STACK_BUDGET: AtomicUint = INIT_ATOMIC_UINT;
+
+/// Limits the total size of all stacks in the process to `bytes`. A budget of
+/// 0 means no limit, which is the default.
+///
+/// Every stack is counted, but only `StackSegment::try_new` refuses to go
+/// over budget, and so does everything allocating through it: a `StackPool`
+/// with the default allocator (and so spawning a green task, which fails the
+/// spawning task instead), and `ContextBuilder::build`. Stacks from
+/// `StackSegment::new` are allocated regardless, and once they've taken the
+/// total over budget `Context::try_new` refuses to make a context on any
+/// stack until enough of them are freed.
+pub fn set_stack_budget(bytes: uint) {
+    unsafe { STACK_BUDGET.store(bytes, SeqCst) }
+}
+
+/// Whether the stacks in use take up more than the budget set with
+/// `set_stack_budget`, which only stacks from `StackSegment::new` can do
+pub fn over_stack_budget() -> bool {
+    over_budget(stack_budget())
+}
+
+fn stack_budget() -> uint {
+    unsafe { STACK_BUDGET.load(SeqCst) }
+}
+
+// Whether the stacks in use take up more than `budget`
+fn over_budget(budget: uint) -> bool {
+    budget != 0 && unsafe { STACK_BYTES.load(SeqCst) } > budget
+}
+
+/// The total size of all stacks currently allocated, counting those made by
+/// `StackSegment::new` but not views onto other stacks.
+pub fn stack_bytes_in_use() -> uint {
+    unsafe { STACK_BYTES.load(SeqCst) }
+}
+
+/// A single mapping carved up into equally sized stacks, with one guard page
+/// below each stack which doubles as the guard page above the next one down.
+///
+/// All the guard pages are protected once, when the arena is made, so taking
+/// a stack from the arena (see `Context::new_in_arena`) costs no system calls.
+/// Stacks are handed back with `give` to be reused.
+pub struct StackArena {
+    priv map: MemoryMap,
+    /// The size of each stack, in whole pages
+    priv stack_size: uint,
+    priv count: uint,
+    /// Indices of the stacks which haven't been taken
+    priv free: ~[uint],
+    /// Whether stacks given back have their pages released, see
+    /// `release_on_give`
+    priv release: bool,
+}
+
+impl StackArena {
+    /// Maps an arena for `count` stacks of at least `stack_size` bytes each.
+    pub fn new(stack_size: uint, count: uint) -> Result<StackArena, ContextError> {
+        if stack_size == 0 || count == 0 { return Err(StackTooSmall) }
+        let page = os::page_size();
+        let stack_size = (stack_size + page - 1) & !(page - 1);
+        let len = count * (stack_size + page) + page;
+        let map = match MemoryMap::new(len, [MapReadable, MapWritable]) {
+            Ok(map) => map,
+            Err(..) => return Err(AllocFailed),
+        };
+        let arena = StackArena {
+            map: map,
+            stack_size: stack_size,
+            count: count,
+            free: range(0, count).invert().collect(),
+            release: false,
+        };
+        // The guard page below stack `i`, and the one above the last stack
+        for i in range(0, count + 1) {
+            let guard = arena.stack_lo(i) - page;
+            if unsafe { !protect_page(guard, page) } {
+                return Err(GuardPageFailed)
+            }
+        }
+        Ok(arena)
+    }
+
+    /// Takes one of the arena's free stacks, or returns `None` if they're all
+    /// in use.
+    ///
+    /// This is unsafe because the segment doesn't own its memory: the arena
+    /// must outlive it, and any context created on it.
+    pub unsafe fn take(&mut self) -> Option<StackSegment> {
+        self.free.pop_opt().map(|i| {
+            let lo = self.stack_lo(i);
+            let mut stack = StackSegment::view(lo, lo + self.stack_size);
+            stack.guard = Some((lo - os::page_size(), lo));
+            stack
+        })
+    }
+
+    /// Sets whether stacks given back to the arena have their memory handed
+    /// back to the OS (see `StackSegment::release_pages`), rather than being
+    /// poisoned. This is off by default: it costs a system call on every
+    /// `give`, and the pages are faulted back in by the next task to use the
+    /// stack, but it keeps an arena which is mostly idle from holding on to
+    /// memory.
+    pub fn release_on_give(&mut self, release: bool) {
+        self.release = release;
+    }
+
+    /// Returns a stack taken from this arena, so that it can be taken again
+    pub fn give(&mut self, mut stack: StackSegment) {
+        let base = self.stack_lo(0);
+        let lo = stack.start() as uint;
+        let stride = self.stack_size + os::page_size();
+        rtassert!(lo >= base && (lo - base) % stride == 0);
+        let i = (lo - base) / stride;
+        rtassert!(i < self.count && !self.free.contains(&i));
+        if self.release {
+            unsafe { stack.release_pages() }
+        } else {
+            stack.poison();
+        }
+        self.free.push(i);
+    }
+
+    // The low end of stack `i`, which may be `count` for the end of the arena
+    fn stack_lo(&self, i: uint) -> uint {
+        let page = os::page_size();
+        self.map.data as uint + page + i * (self.stack_size + page)
+    }
+}
+
+#[cfg(unix)]
+unsafe fn protect_page(addr: uint, len: uint) -> bool {
+    use std::libc::{c_void, size_t, mprotect, PROT_NONE};
+    mprotect(addr as *c_void, len as size_t, PROT_NONE) == 0
+}
+
+#[cfg(windows)]
+unsafe fn protect_page(addr: uint, len: uint) -> bool {
+    use std::libc::{LPVOID, SIZE_T, DWORD, PAGE_NOACCESS, VirtualProtect};
+    let mut old: DWORD = 0;
+    VirtualProtect(addr as LPVOID, len as SIZE_T, PAGE_NOACCESS, &mut old) != 0
+}
+
+/// Where a `StackPool` gets the stacks of the tasks spawned from it, and
+/// where those stacks go once the tasks are done with them.
+///
+/// The segments handed out must keep to the `start`/`end` contract that
+/// `Context::new` relies on, and can be views onto memory the allocator
+/// manages itself (a `StackArena`, say). A segment isn't always given back:
+/// one whose task is dropped without finishing is dropped along with it.
+pub trait StackAllocator {
+    /// Returns a stack of at least `size` bytes, or why there isn't one
+    fn allocate(&mut self, size: uint) -> Result<StackSegment, ContextError>;
+    /// Takes back a stack returned by `allocate`
+    fn deallocate(&mut self, stack: StackSegment);
+}
+
+/// Allocates every stack with `StackSegment::try_new`, keeping to the stack
+/// budget, and frees it as soon as it's given back. This is what a
+/// `StackPool` uses unless told otherwise.
+pub struct DefaultStackAllocator;
+
+impl StackAllocator for DefaultStackAllocator {
+    fn allocate(&mut self, size: uint) -> Result<StackSegment, ContextError> {
+        StackSegment::try_new(size)
+    }
+
+    fn deallocate(&mut self, _stack: StackSegment) {}
+}
+
+// The factory given to `set_stack_allocator`, 0 for none
+static mut STACK_ALLOCATOR: AtomicUint = INIT_ATOMIC_UINT;
+
+/// Makes every `StackPool` created from now on without an allocator of its
+/// own (including those of new schedulers, unless `PoolConfig` says
+/// otherwise) get one from `factory`, or a `DefaultStackAllocator` if it's
+/// `None`, which is the default.
+pub fn set_stack_allocator(factory: Option<fn() -> ~StackAllocator:Send>) {
+    unsafe {
+        match factory {
+            Some(factory) => STACK_ALLOCATOR.store(factory as uint, SeqCst),
+            None => STACK_ALLOCATOR.store(0, SeqCst),
+        }
+    }
+}
+
+pub struct StackPool {
+    priv allocator: ~StackAllocator:Send,
+}
 
 impl StackPool {
-    pub fn new() -> StackPool { StackPool(()) }
+    /// A pool using the allocator from `set_stack_allocator`
+    pub fn new() -> StackPool {
+        let allocator = match unsafe { STACK_ALLOCATOR.load(SeqCst) } {
+            0 => ~DefaultStackAllocator as ~StackAllocator:Send,
+            addr => {
+                let factory: fn() -> ~StackAllocator:Send = unsafe {
+                    transmute(addr)
+                };
+                factory()
+            }
+        };
+        StackPool::with_allocator(allocator)
+    }
 
-    pub fn take_segment(&self, min_size: uint) -> StackSegment {
-        StackSegment::new(min_size)
+    /// A pool which takes all of its stacks from `allocator`
+    pub fn with_allocator(allocator: ~StackAllocator:Send) -> StackPool {
+        StackPool { allocator: allocator }
     }
 
-    pub fn give_segment(&self, _stack: StackSegment) {
+    /// A stack of at least `min_size` bytes from the pool's allocator, or why
+    /// it couldn't give one (such as `StackBudgetExceeded`)
+    pub fn take_segment(&mut self, min_size: uint)
+        -> Result<StackSegment, ContextError>
+    {
+        self.allocator.allocate(min_size)
+    }
+
+    pub fn give_segment(&mut self, stack: StackSegment) {
+        self.allocator.deallocate(stack)
     }
 }
 
@@ -73,3 +812,206 @@ extern {
     fn rust_valgrind_stack_register(start: *uintptr_t, end: *uintptr_t) -> c_uint;
     fn rust_valgrind_stack_deregister(id: c_uint);
 }
+
+#[cfg(test)]
+mod test {
+    use std::mem;
+    use std::os;
+    use context::ContextError;
+    use super::{StackSegment, StackArena, Unmapped, set_guard_pages, POISON};
+    use super::{HIGH_WATER_FILL, StackAllocator, StackPool};
+
+    #[test]
+    fn validate_segments() {
+        let stack = StackSegment::new(64 * 1024);
+        assert!(stack.validate().is_ok());
+        let (top, rest) = unsafe { stack.split_top(16 * 1024) };
+        assert!(top.validate().is_ok());
+        assert!(rest.validate().is_ok());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn validate_finds_unmapped_memory() {
+        // The lowest pages of the address space are never mapped
+        let stack = unsafe { StackSegment::from_raw(0x1000, 0x3000) };
+        assert!(stack.validate() == Err(Unmapped));
+    }
+
+    #[test]
+    fn arena_shares_guard_pages() {
+        let page = os::page_size();
+        let mut arena = StackArena::new(4 * page, 2).unwrap();
+        let a = unsafe { arena.take().unwrap() };
+        let b = unsafe { arena.take().unwrap() };
+        assert!(unsafe { arena.take() }.is_none());
+
+        let (a, b) = if a.start() < b.start() { (a, b) } else { (b, a) };
+        assert_eq!(a.end() as uint - a.start() as uint, 4 * page);
+        // The page above the lower stack is the one below the higher one
+        assert_eq!(b.guard_region(), Some((a.end() as uint, b.start() as uint)));
+        assert!(a.validate().is_ok() && b.validate().is_ok());
+
+        let start = a.start();
+        arena.give(a);
+        let c = unsafe { arena.take().unwrap() };
+        assert_eq!(c.start(), start);
+    }
+
+    #[test]
+    fn pools_take_stacks_from_their_allocator() {
+        struct Arena { arena: StackArena }
+        impl StackAllocator for Arena {
+            fn allocate(&mut self, _size: uint)
+                -> Result<StackSegment, ContextError>
+            {
+                Ok(unsafe { self.arena.take().unwrap() })
+            }
+            fn deallocate(&mut self, stack: StackSegment) {
+                self.arena.give(stack);
+            }
+        }
+
+        let page = os::page_size();
+        let arena = StackArena::new(4 * page, 1).unwrap();
+        let mut pool = StackPool::with_allocator(~Arena { arena: arena }
+                                                 as ~StackAllocator:Send);
+        let a = pool.take_segment(page).unwrap();
+        assert!(a.guard_region().is_some());
+        let start = a.start();
+        pool.give_segment(a);
+        let b = pool.take_segment(page).unwrap();
+        assert_eq!(b.start(), start);
+        pool.give_segment(b);
+    }
+
+    #[test]
+    fn guard_page_below_stack() {
+        set_guard_pages(true);
+        let stack = StackSegment::new(64 * 1024);
+        set_guard_pages(false);
+
+        let page = os::page_size();
+        let lo = stack.start() as uint;
+        assert_eq!(stack.guard_page(), Some(lo - page));
+        assert_eq!(stack.guard_region(), Some((lo - page, lo)));
+        assert_eq!(stack.end() as uint - lo, 64 * 1024);
+        assert!(stack.validate().is_ok());
+
+        assert!(StackSegment::new(64 * 1024).guard_page().is_none());
+    }
+
+    #[test]
+    #[cfg(not(ndebug))]
+    fn given_back_stacks_are_poisoned() {
+        let page = os::page_size();
+        let mut arena = StackArena::new(page, 1).unwrap();
+        let stack = unsafe { arena.take().unwrap() };
+        let lo = stack.start() as *mut u8;
+        unsafe { *lo = 0; *lo.offset(page as int - 1) = 0; }
+        arena.give(stack);
+        unsafe {
+            assert_eq!(*lo, POISON);
+            assert_eq!(*lo.offset(page as int - 1), POISON);
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn released_stacks_read_as_zero() {
+        let page = os::page_size();
+        let mut arena = StackArena::new(2 * page, 1).unwrap();
+        arena.release_on_give(true);
+        let stack = unsafe { arena.take().unwrap() };
+        let lo = stack.start() as *mut u8;
+        unsafe { *lo = 1; *lo.offset(2 * page as int - 1) = 1; }
+        let guard = stack.guard_region();
+        arena.give(stack);
+
+        let stack = unsafe { arena.take().unwrap() };
+        assert_eq!(stack.guard_region(), guard);
+        unsafe {
+            assert_eq!(*lo, 0);
+            assert_eq!(*lo.offset(2 * page as int - 1), 0);
+        }
+    }
+
+    #[test]
+    fn high_water_mark_finds_the_deepest_write() {
+        let stack = StackSegment::new(64 * 1024);
+        let (top, _) = unsafe { stack.split_top(16 * 1024) };
+        assert_eq!(top.high_water_mark(), None);
+        if !cfg!(stack_high_water) {
+            assert_eq!(stack.high_water_mark(), None);
+            return
+        }
+
+        assert_eq!(stack.high_water_mark(), Some(0));
+        let hi = stack.end() as *mut u8;
+        unsafe {
+            assert_eq!(*hi.offset(-1), HIGH_WATER_FILL);
+            *hi.offset(-128) = 0;
+        }
+        assert_eq!(stack.high_water_mark(), Some(128));
+    }
+
+    #[test]
+    fn zeroed_stacks_are_zero_below_the_initial_frame() {
+        use context::Context;
+
+        let mut stack = StackSegment::new_zeroed(64 * 1024);
+        assert_eq!(stack.high_water_mark(), None);
+        let (lo, hi) = (stack.start() as uint, stack.end() as uint);
+        let zero = |from: uint, to: uint| {
+            let mut p = from;
+            while p < to && unsafe { *(p as *uint) } == 0 {
+                p += mem::size_of::<uint>();
+            }
+            p >= to
+        };
+        assert!(zero(lo, hi));
+
+        // The frame and canary are written at the top, and nothing else
+        let ctx = Context::new(proc() {}, &mut stack);
+        assert!(ctx.check_canary().is_ok());
+        assert!(zero(lo, hi - 1024));
+    }
+
+    #[test]
+    fn pools_keep_to_the_stack_budget() {
+        use context::StackBudgetExceeded;
+        use coroutine::Coroutine;
+        use super::over_budget;
+
+        // The process-wide budget would hold for every other test's tasks
+        // too, so this allocator is given its own (as `DefaultStackAllocator`
+        // is given the process-wide one). Whatever else is running, a budget
+        // of a byte is exceeded.
+        struct Budgeted { budget: uint }
+        impl StackAllocator for Budgeted {
+            fn allocate(&mut self, size: uint)
+                -> Result<StackSegment, ContextError>
+            {
+                StackSegment::try_new_within(size, self.budget)
+            }
+            fn deallocate(&mut self, _stack: StackSegment) {}
+        }
+
+        let mut pool = StackPool::with_allocator(~Budgeted { budget: 1 }
+                                                 as ~StackAllocator:Send);
+        assert_eq!(pool.take_segment(64 * 1024).err(),
+                   Some(StackBudgetExceeded));
+        assert_eq!(Coroutine::try_new(&mut pool, Some(64 * 1024),
+                                      proc() {}).err(),
+                   Some(StackBudgetExceeded));
+
+        // What `Context::try_new` checks
+        let _stack = StackSegment::new(64 * 1024);
+        assert!(over_budget(1));
+        assert!(!over_budget(0));
+
+        let mut pool = StackPool::with_allocator(~Budgeted { budget: 0 }
+                                                 as ~StackAllocator:Send);
+        assert!(pool.take_segment(64 * 1024).is_ok());
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A process-wide registry of the stacks that contexts run on.
+//!
+//! A handler for a fault on a stack's guard page only knows the faulting
+//! address. `find` maps that back to the context (see `Context::id`) whose
+//! stack it belongs to without allocating or taking any locks, so it can be
+//! used from a signal handler. Contexts are only registered when libgreen is
+//! built with `--cfg stack_registry`.
+//!
+//! The registry is a sorted array of fixed size. Updates are serialized by a
+//! spin lock, and bump a sequence number before and after changing anything
+//! so that `find` can notice that it raced with one and try again.
+
+use std::sync::atomics::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+
+/// The most stacks which can be registered at once. Any more are silently
+/// left out, and can't be found.
+pub static MAX_STACKS: uint = 4096;
+
+// How many times `find` retries after racing with an update before giving up.
+// It can't wait for the update to finish, the update may be what the signal
+// interrupted.
+static FIND_ATTEMPTS: uint = 8;
+
+// (lo, hi, id) for every registered stack, sorted by `lo`
+static mut ENTRIES: [(uint, uint, uint), ..MAX_STACKS] = [(0, 0, 0), ..MAX_STACKS];
+static mut LEN: uint = 0;
+// Odd while an update is in progress
+static mut SEQ: AtomicUint = INIT_ATOMIC_UINT;
+static mut LOCK: AtomicUint = INIT_ATOMIC_UINT;
+
+/// Records that the context `id` runs on the stack `[lo, hi)`
+pub fn register(lo: uint, hi: uint, id: uint) {
+    update(|| unsafe {
+        if LEN == MAX_STACKS { return }
+        let mut i = LEN;
+        while i > 0 && start_of(i - 1) > lo {
+            ENTRIES[i] = ENTRIES[i - 1];
+            i -= 1;
+        }
+        ENTRIES[i] = (lo, hi, id);
+        LEN += 1;
+    })
+}
+
+/// Forgets the stack of the context `id`, if it was registered
+pub fn unregister(id: uint) {
+    update(|| unsafe {
+        let mut i = 0;
+        while i < LEN && id_of(i) != id { i += 1; }
+        if i == LEN { return }
+        while i + 1 < LEN {
+            ENTRIES[i] = ENTRIES[i + 1];
+            i += 1;
+        }
+        LEN -= 1;
+    })
+}
+
+/// Finds the context whose stack contains `addr`, or lies no more than
+/// `slack` bytes above it (for looking up a fault on the guard page below a
+/// stack). This is safe to call from a signal handler.
+///
+/// This returns `None` if no stack matches, and also if the registry was
+/// being updated (possibly by the code that the signal interrupted) every
+/// time it was looked in.
+pub fn find(addr: uint, slack: uint) -> Option<uint> {
+    for _ in range(0, FIND_ATTEMPTS) {
+        unsafe {
+            let before = SEQ.load(SeqCst);
+            if before % 2 == 1 { continue }
+            let found = search(addr, slack);
+            if SEQ.load(SeqCst) == before { return found }
+        }
+    }
+    None
+}
+
+// Binary search for the last stack starting at or below `addr + slack`
+unsafe fn search(addr: uint, slack: uint) -> Option<uint> {
+    let key = addr + slack;
+    let (mut lo, mut hi) = (0u, LEN);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if start_of(mid) <= key { lo = mid + 1 } else { hi = mid }
+    }
+    if lo == 0 { return None }
+    let (start, end, id) = ENTRIES[lo - 1];
+    if start <= addr + slack && addr < end { Some(id) } else { None }
+}
+
+unsafe fn start_of(i: uint) -> uint {
+    let (start, _, _) = ENTRIES[i];
+    start
+}
+
+unsafe fn id_of(i: uint) -> uint {
+    let (_, _, id) = ENTRIES[i];
+    id
+}
+
+fn update(f: ||) {
+    unsafe {
+        while LOCK.compare_and_swap(0, 1, SeqCst) != 0 {}
+        SEQ.fetch_add(1, SeqCst);
+        f();
+        SEQ.fetch_add(1, SeqCst);
+        LOCK.store(0, SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::uint;
+    use super::{register, unregister, find};
+
+    #[test]
+    fn find_registered_stacks() {
+        // Well away from anything a real stack could be using
+        let base = uint::max_value - 0x10000;
+        register(base + 0x2000, base + 0x3000, 2);
+        register(base, base + 0x1000, 1);
+
+        assert_eq!(find(base + 0x10, 0), Some(1));
+        assert_eq!(find(base + 0x2fff, 0), Some(2));
+        assert_eq!(find(base + 0x1800, 0), None);
+        // A guard page below the second stack
+        assert_eq!(find(base + 0x1800, 0x1000), Some(2));
+
+        unregister(1);
+        unregister(2);
+        assert_eq!(find(base + 0x10, 0), None);
+    }
+}
@@ -168,6 +168,8 @@ mod test {
         let mut pool = SchedPool::new(PoolConfig {
             threads: 1,
             event_loop_factory: None,
+            overflow_handler: false,
+            stack_allocator: None,
         });
 
         do pool.spawn(TaskOpts::new()) {
@@ -189,6 +191,8 @@ mod test {
         let mut pool = SchedPool::new(PoolConfig {
             threads: 1,
             event_loop_factory: None,
+            overflow_handler: false,
+            stack_allocator: None,
         });
 
         do pool.spawn(TaskOpts::new()) {
@@ -235,6 +235,14 @@ impl Unwinder {
             Ok(())
         }
     }
+
+    /// Takes the cause of the unwind away from this unwinder, once something
+    /// other than the task itself has caught it. The unwinder is then no
+    /// longer considered to be unwinding.
+    pub fn take_failure(&mut self) -> Option<~Any> {
+        self.unwinding = false;
+        self.cause.take()
+    }
 }
 
 // Rust's exception class identifier.  This is used by personality routines to
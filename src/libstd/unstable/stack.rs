@@ -24,7 +24,80 @@
 //! detection is not guaranteed to continue in the future. Usage of this module
 //! is discouraged unless absolutely necessary.
 
-static RED_ZONE: uint = 20 * 1024;
+use sync::atomics::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+
+/// The number of bytes at the low end of a stack which are reserved for
+/// handling stack overflow. The limit recorded for morestack is this far above
+/// the real bottom of the stack.
+pub static RED_ZONE: uint = 20 * 1024;
+
+/// Whether the stack limit is kept in aarch64's platform register, x18, rather
+/// than in the thread control block, which saves a load of the thread pointer
+/// and then of the limit on every check of it. This is chosen when building,
+/// with `--cfg aarch64_x18_stack_limit`.
+///
+/// With it, nothing else in the process may use x18: all Rust code must be
+/// built with `-C target-feature=+reserve-x18` and all C code with
+/// `-ffixed-x18`. That rules it out wherever the platform itself owns x18
+/// (for the TEB on windows, on darwin, and for the shadow call stack on
+/// android). libgreen never saves x18 in a context switch, so the register
+/// always holds the limit `record_sp_limit` last wrote on the thread, and a
+/// new thread starts without one (as though its stack were unbounded) until
+/// its limit is first recorded.
+pub static RECORD_SP_LIMIT_IN_X18: bool = cfg!(target_arch = "aarch64") &&
+                                           cfg!(aarch64_x18_stack_limit);
+
+// Where the stack limit is kept on architectures that have no TLS slot for it
+// (sparc64, powerpc64, s390x, and anything without a port of its own)
+#[cfg(not(target_arch = "x86"), not(target_arch = "x86_64"),
+      not(target_arch = "arm"), not(target_arch = "mips"),
+      not(target_arch = "mips64"), not(target_arch = "aarch64"),
+      not(target_arch = "riscv64"))]
+#[thread_local]
+static mut SP_LIMIT: uint = 0;
+
+// Where in the TIB the stack limit is kept on windows, see
+// `set_stack_limit_tls_slot`. This starts out as the "arbitrary data slot",
+// which is what the split stack prologues read.
+#[cfg(windows, target_arch = "x86_64")]
+static mut SP_LIMIT_SLOT: uint = 0x28;
+#[cfg(windows, target_arch = "x86")]
+static mut SP_LIMIT_SLOT: uint = 0x14;
+
+// The offsets of the TIB's TlsSlots array, and how many slots it has
+// (TLS_MINIMUM_AVAILABLE). Indices past those are kept elsewhere.
+#[cfg(windows, target_arch = "x86_64")]
+static TLS_SLOTS_OFFSET: uint = 0x1480;
+#[cfg(windows, target_arch = "x86")]
+static TLS_SLOTS_OFFSET: uint = 0xe10;
+#[cfg(windows)]
+static TLS_SLOTS: uint = 64;
+
+/// Moves the stack limit out of the TIB's "arbitrary data slot", where it's
+/// kept by default, into the TLS slot with the index `idx` (as allocated by
+/// `TlsAlloc`). This is for embedding alongside another runtime which uses
+/// the arbitrary data slot itself, so that the two don't overwrite each
+/// other's values on every switch.
+///
+/// The split stack prologues which rustc emits always compare the stack
+/// pointer against the arbitrary data slot, so once the limit has been moved
+/// they check it against whatever the other runtime keeps there instead.
+/// Only `record_sp_limit` and `get_sp_limit` (and so libgreen's bookkeeping,
+/// such as `current_stack_bounds`) follow the move, and the guard pages are
+/// what's left to catch an overflow.
+///
+/// This fails if `idx` isn't one of the TIB's own TLS slots (it must be less
+/// than 64). It must be called once, before any thread records a stack
+/// limit, because nothing moves the limits already recorded to the new slot;
+/// changing it while tasks exist is undefined behavior.
+#[cfg(windows)]
+pub unsafe fn set_stack_limit_tls_slot(idx: uint) {
+    if idx >= TLS_SLOTS {
+        fail!("TLS index {} isn't kept in the TIB (it must be less than {})",
+              idx, TLS_SLOTS);
+    }
+    SP_LIMIT_SLOT = TLS_SLOTS_OFFSET + idx * ::mem::size_of::<uint>();
+}
 
 /// This function is invoked from rust's current __morestack function. Segmented
 /// stacks are currently not enabled as segmented stacks, but rather one giant
@@ -36,6 +109,7 @@ static RED_ZONE: uint = 20 * 1024;
                   //   irrelevant for documentation purposes.
 #[cfg(not(test))] // in testing, use the original libstd's version
 pub extern "C" fn rust_stack_exhausted() {
+    use cast;
     use option::None;
     use rt::local::Local;
     use rt::task::Task;
@@ -96,10 +170,38 @@ pub extern "C" fn rust_stack_exhausted() {
         // and the FFI call needs 2MB of stack when we just ran out.
         println!("task '{}' has overflowed its stack", n);
 
+        match EXHAUSTED_HOOK.load(SeqCst) {
+            0 => {}
+            addr => {
+                let hook: fn(&str) = cast::transmute(addr);
+                hook(n);
+            }
+        }
+
         intrinsics::abort();
     }
 }
 
+// The hook installed with `set_stack_exhausted_hook` as an address, 0 when
+// there's none
+static mut EXHAUSTED_HOOK: AtomicUint = INIT_ATOMIC_UINT;
+
+/// Makes `rust_stack_exhausted` call `hook` with the name of the task which
+/// overflowed once it's reported the overflow, for a runtime which handles
+/// overflows its own way (libgreen's overflow policies). The process is still
+/// aborted if the hook returns. Passing `None` removes it, which is the
+/// default.
+///
+/// The hook runs in what's left of the task's red zone, so it has very little
+/// stack to work with.
+pub fn set_stack_exhausted_hook(hook: Option<fn(&str)>) {
+    let addr = match hook {
+        Some(hook) => hook as uint,
+        None => 0,
+    };
+    unsafe { EXHAUSTED_HOOK.store(addr, SeqCst) }
+}
+
 #[inline(always)]
 pub unsafe fn record_stack_bounds(stack_lo: uint, stack_hi: uint) {
     // When the old runtime had segmented stacks, it used a calculation that was
@@ -114,7 +216,9 @@ pub unsafe fn record_stack_bounds(stack_lo: uint, stack_hi: uint) {
 
     return target_record_stack_bounds(stack_lo, stack_hi);
 
-    #[cfg(not(windows))] #[cfg(not(target_arch = "x86_64"))] #[inline(always)]
+    #[cfg(not(windows))]
+    #[cfg(windows, not(target_arch = "x86_64"), not(target_arch = "x86"))]
+    #[inline(always)]
     unsafe fn target_record_stack_bounds(_stack_lo: uint, _stack_hi: uint) {}
     #[cfg(windows, target_arch = "x86_64")] #[inline(always)]
     unsafe fn target_record_stack_bounds(stack_lo: uint, stack_hi: uint) {
@@ -128,6 +232,13 @@ pub unsafe fn record_stack_bounds(stack_lo: uint, stack_hi: uint) {
         asm!("mov $0, %gs:0x08" :: "r"(stack_hi) :: "volatile");
         asm!("mov $0, %gs:0x10" :: "r"(stack_lo) :: "volatile");
     }
+    #[cfg(windows, target_arch = "x86")] #[inline(always)]
+    unsafe fn target_record_stack_bounds(stack_lo: uint, stack_hi: uint) {
+        // Same as above, but the 32-bit TIB is at %fs:0x04 (top) and %fs:0x08
+        // (bottom)
+        asm!("mov $0, %fs:0x04" :: "r"(stack_hi) :: "volatile");
+        asm!("mov $0, %fs:0x08" :: "r"(stack_lo) :: "volatile");
+    }
 }
 
 /// Records the current limit of the stack as specified by `end`.
@@ -161,9 +272,10 @@ pub unsafe fn record_sp_limit(limit: uint) {
     #[cfg(target_arch = "x86_64", target_os = "win32")] #[inline(always)]
     unsafe fn target_record_sp_limit(limit: uint) {
         // see: http://en.wikipedia.org/wiki/Win32_Thread_Information_Block
-        // store this inside of the "arbitrary data slot", but double the size
-        // because this is 64 bit instead of 32 bit
-        asm!("movq $0, %gs:0x28" :: "r"(limit) :: "volatile")
+        // store this inside of the "arbitrary data slot" (at 0x28, double the
+        // size because this is 64 bit instead of 32 bit), unless it's been
+        // moved with `set_stack_limit_tls_slot`
+        asm!("movq $0, %gs:($1)" :: "r"(limit), "r"(SP_LIMIT_SLOT) :: "volatile")
     }
     #[cfg(target_arch = "x86_64", target_os = "freebsd")] #[inline(always)]
     unsafe fn target_record_sp_limit(limit: uint) {
@@ -184,14 +296,26 @@ pub unsafe fn record_sp_limit(limit: uint) {
     #[cfg(target_arch = "x86", target_os = "win32")] #[inline(always)]
     unsafe fn target_record_sp_limit(limit: uint) {
         // see: http://en.wikipedia.org/wiki/Win32_Thread_Information_Block
-        // store this inside of the "arbitrary data slot"
-        asm!("movl $0, %fs:0x14" :: "r"(limit) :: "volatile")
+        // store this inside of the "arbitrary data slot" (at 0x14), unless
+        // it's been moved with `set_stack_limit_tls_slot`
+        asm!("movl $0, %fs:($1)" :: "r"(limit), "r"(SP_LIMIT_SLOT) :: "volatile")
     }
 
-    // mips, arm - Some brave soul can port these to inline asm, but it's over
-    //             my head personally
+    // aarch64, when the limit is kept in the platform register, see
+    // `RECORD_SP_LIMIT_IN_X18`
+    #[cfg(target_arch = "aarch64", aarch64_x18_stack_limit)] #[inline(always)]
+    unsafe fn target_record_sp_limit(limit: uint) {
+        asm!("mov x18, $0" :: "r"(limit) :: "volatile")
+    }
+
+    // mips, mips64, arm, aarch64, riscv64 - Some brave soul can port these to
+    //                                       inline asm, but it's over my head
+    //                                       personally
     #[cfg(target_arch = "mips")]
-    #[cfg(target_arch = "arm")] #[inline(always)]
+    #[cfg(target_arch = "mips64")]
+    #[cfg(target_arch = "arm")]
+    #[cfg(target_arch = "aarch64", not(aarch64_x18_stack_limit))]
+    #[cfg(target_arch = "riscv64")] #[inline(always)]
     unsafe fn target_record_sp_limit(limit: uint) {
         use libc::c_void;
         return record_sp_limit(limit as *c_void);
@@ -199,6 +323,16 @@ pub unsafe fn record_sp_limit(limit: uint) {
             fn record_sp_limit(limit: *c_void);
         }
     }
+
+    // Anything else has no known TLS slot, and no split stack prologues to
+    // read one, so the limit is just kept for `get_sp_limit`
+    #[cfg(not(target_arch = "x86"), not(target_arch = "x86_64"),
+          not(target_arch = "arm"), not(target_arch = "mips"),
+          not(target_arch = "mips64"), not(target_arch = "aarch64"),
+          not(target_arch = "riscv64"))] #[inline(always)]
+    unsafe fn target_record_sp_limit(limit: uint) {
+        SP_LIMIT = limit;
+    }
 }
 
 /// The counterpart of the function above, this function will fetch the current
@@ -230,7 +364,7 @@ pub unsafe fn get_sp_limit() -> uint {
     #[cfg(target_arch = "x86_64", target_os = "win32")] #[inline(always)]
     unsafe fn target_get_sp_limit() -> uint {
         let limit;
-        asm!("movq %gs:0x28, $0" : "=r"(limit) ::: "volatile");
+        asm!("movq %gs:($1), $0" : "=r"(limit) : "r"(SP_LIMIT_SLOT) :: "volatile");
         return limit;
     }
     #[cfg(target_arch = "x86_64", target_os = "freebsd")] #[inline(always)]
@@ -258,14 +392,25 @@ pub unsafe fn get_sp_limit() -> uint {
     #[cfg(target_arch = "x86", target_os = "win32")] #[inline(always)]
     unsafe fn target_get_sp_limit() -> uint {
         let limit;
-        asm!("movl %fs:0x14, $0" : "=r"(limit) ::: "volatile");
+        asm!("movl %fs:($1), $0" : "=r"(limit) : "r"(SP_LIMIT_SLOT) :: "volatile");
         return limit;
     }
 
-    // mips, arm - Some brave soul can port these to inline asm, but it's over
-    //             my head personally
+    #[cfg(target_arch = "aarch64", aarch64_x18_stack_limit)] #[inline(always)]
+    unsafe fn target_get_sp_limit() -> uint {
+        let limit;
+        asm!("mov $0, x18" : "=r"(limit) ::: "volatile");
+        return limit;
+    }
+
+    // mips, mips64, arm, aarch64, riscv64 - Some brave soul can port these to
+    //                                       inline asm, but it's over my head
+    //                                       personally
     #[cfg(target_arch = "mips")]
-    #[cfg(target_arch = "arm")] #[inline(always)]
+    #[cfg(target_arch = "mips64")]
+    #[cfg(target_arch = "arm")]
+    #[cfg(target_arch = "aarch64", not(aarch64_x18_stack_limit))]
+    #[cfg(target_arch = "riscv64")] #[inline(always)]
     unsafe fn target_get_sp_limit() -> uint {
         use libc::c_void;
         return get_sp_limit() as uint;
@@ -273,4 +418,73 @@ pub unsafe fn get_sp_limit() -> uint {
             fn get_sp_limit() -> *c_void;
         }
     }
+
+    #[cfg(not(target_arch = "x86"), not(target_arch = "x86_64"),
+          not(target_arch = "arm"), not(target_arch = "mips"),
+          not(target_arch = "mips64"), not(target_arch = "aarch64"),
+          not(target_arch = "riscv64"))] #[inline(always)]
+    unsafe fn target_get_sp_limit() -> uint {
+        SP_LIMIT
+    }
+}
+
+/// The address of the word that `record_sp_limit` stores the calling thread's
+/// stack limit in, for callers which record it often enough to want to skip
+/// finding the thread's TLS each time (see `record_sp_limit_at`). The address
+/// is only ever valid on the thread which looked it up.
+///
+/// This is null where the limit isn't kept at an address of its own, or
+/// where recording it is already a single store: on x86 and x86_64 it's a
+/// fixed offset from a segment register, and with `aarch64_x18_stack_limit`
+/// it's kept in a register.
+pub unsafe fn sp_limit_slot() -> *mut uint {
+    return target_sp_limit_slot();
+
+    #[cfg(target_arch = "x86")]
+    #[cfg(target_arch = "x86_64")]
+    #[cfg(target_arch = "aarch64", aarch64_x18_stack_limit)]
+    unsafe fn target_sp_limit_slot() -> *mut uint { ::ptr::mut_null() }
+
+    // Reading the thread pointer traps to the kernel on arm cores without
+    // TPIDRURO and on mips cores older than r2, which is the cost this avoids
+    #[cfg(target_arch = "mips")]
+    #[cfg(target_arch = "mips64")]
+    #[cfg(target_arch = "arm")]
+    #[cfg(target_arch = "aarch64", not(aarch64_x18_stack_limit))]
+    #[cfg(target_arch = "riscv64")]
+    unsafe fn target_sp_limit_slot() -> *mut uint {
+        return get_sp_limit_slot();
+        extern {
+            fn get_sp_limit_slot() -> *mut uint;
+        }
+    }
+
+    #[cfg(not(target_arch = "x86"), not(target_arch = "x86_64"),
+          not(target_arch = "arm"), not(target_arch = "mips"),
+          not(target_arch = "mips64"), not(target_arch = "aarch64"),
+          not(target_arch = "riscv64"))]
+    unsafe fn target_sp_limit_slot() -> *mut uint { &mut SP_LIMIT as *mut uint }
+}
+
+/// Like `record_stack_bounds`, but the limit is stored straight through
+/// `slot`, which must either be what `sp_limit_slot` returned on the calling
+/// thread or null, in which case this is just `record_stack_bounds`.
+///
+/// The slot is only ever non-null outside of windows, where the limit is all
+/// that's recorded.
+#[inline(always)]
+pub unsafe fn record_stack_bounds_at(slot: *mut uint, stack_lo: uint,
+                                     stack_hi: uint) {
+    use ptr::RawPtr;
+    if slot.is_null() { return record_stack_bounds(stack_lo, stack_hi) }
+    *slot = stack_lo + RED_ZONE;
+}
+
+/// The counterpart of `record_stack_bounds_at`, which reads the limit back
+/// through `slot` unless it's null.
+#[inline(always)]
+pub unsafe fn get_sp_limit_at(slot: *mut uint) -> uint {
+    use ptr::RawPtr;
+    if slot.is_null() { return get_sp_limit() }
+    *slot
 }
@@ -25,6 +25,8 @@
     #define RUSTRT_RSI   10
     #define RUSTRT_ST1   11
     #define RUSTRT_ST2   12
+    // RDX, only loaded (see RUSTRT_ARG1 below)
+    #define RUSTRT_ARG1  13
     #define RUSTRT_XMM6  14
     #define RUSTRT_XMM7  16
     #define RUSTRT_XMM8  18
@@ -37,16 +39,33 @@
     #define RUSTRT_XMM15 32
     #define RUSTRT_MAX   34
 #else
-    // Not used, just padding
-    #define RUSTRT_XXX   9
+    // RSI, only loaded: a new context's entry point may take its argument
+    // here instead of in ARG0, and for any other context it's garbage in a
+    // register which is dead anyway
+    #define RUSTRT_ARG1  9
     #define RUSTRT_XMM0 10
     #define RUSTRT_XMM1 12
     #define RUSTRT_XMM2 14
     #define RUSTRT_XMM3 16
     #define RUSTRT_XMM4 18
     #define RUSTRT_XMM5 20
+#if defined(RUST_PRESERVE_XMM)
+    // The rest of the XMM registers, with libgreen's `--cfg preserve_xmm`
+    #define RUSTRT_XMM6  22
+    #define RUSTRT_XMM7  24
+    #define RUSTRT_XMM8  26
+    #define RUSTRT_XMM9  28
+    #define RUSTRT_XMM10 30
+    #define RUSTRT_XMM11 32
+    #define RUSTRT_XMM12 34
+    #define RUSTRT_XMM13 36
+    #define RUSTRT_XMM14 38
+    #define RUSTRT_XMM15 40
+    #define RUSTRT_MAX  42
+#else
     #define RUSTRT_MAX  22
 #endif
+#endif
 
 // ARG0 is the register in which the first argument goes.
 // Naturally this depends on your operating system.
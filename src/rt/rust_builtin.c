@@ -11,6 +11,7 @@
 /* Foreign builtins. */
 
 #include "vg/valgrind.h"
+#include "vg/memcheck.h"
 
 #include <stdint.h>
 #include <time.h>
@@ -358,6 +359,11 @@ rust_valgrind_stack_deregister(unsigned int id) {
   VALGRIND_STACK_DEREGISTER(id);
 }
 
+void
+rust_valgrind_make_mem_undefined(void *start, size_t len) {
+  VALGRIND_MAKE_MEM_UNDEFINED(start, len);
+}
+
 #if defined(__WIN32__)
 
 void
@@ -0,0 +1,109 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A portable context switch for architectures which have no
+// rt/arch/$arch/_context.S, built on getcontext/makecontext/swapcontext. It
+// backs the fallback register context in libgreen/context.rs, and must be
+// kept in sync with it.
+//
+// This is far slower than an assembly port: swapcontext saves every
+// register and makes a system call to swap the signal mask as well. It exists
+// so that libgreen can be tried out on a new architecture before anyone has
+// written that port, and warns when it's built so that it isn't shipped by
+// accident.
+
+// Not even a slow fallback is possible there, see libgreen/lib.rs
+#if defined(__wasm32__)
+#error "green threading unsupported on wasm32: it has no stacks to switch between"
+#endif
+
+// Defined by mk/rt.mk for the architectures which mk/platform.mk finds no
+// port for, the same ones libgreen is built for with --cfg green_ucontext
+#if defined(RUST_UCONTEXT_FALLBACK)
+
+#warning "libgreen is switching contexts with ucontext, which is much slower than an assembly port for this architecture"
+
+#include <stdint.h>
+#include <stdlib.h>
+#include <ucontext.h>
+
+// The layout of libgreen's fallback `Registers`. The first three words are
+// what `saved_ip`, `saved_sp` and `saved_fp` read, the next three describe a
+// new context's entry until it's first resumed.
+typedef struct {
+    uint64_t ip;
+    uint64_t sp;
+    uint64_t fp;
+    uint64_t fptr;
+    uint64_t arg;
+    uint64_t arg_pos;
+    ucontext_t uc;
+} fallback_regs_t;
+
+// The size of the fallback `Registers`, redefined as FALLBACK_REGISTER_BYTES
+// in the `ucontext` module of libgreen/context.rs, which checks at compile
+// time that SAVED_REGISTER_COUNT slots take up exactly this many bytes
+#define FALLBACK_REGISTER_BYTES 8192
+
+typedef char fallback_regs_fit[
+    sizeof(fallback_regs_t) <= FALLBACK_REGISTER_BYTES ? 1 : -1];
+
+typedef void (*entry_t)(void *, void *, void *, void *);
+
+// makecontext only passes int arguments, so the registers are passed as
+// two halves
+static void
+fallback_start(unsigned int hi, unsigned int lo) {
+    fallback_regs_t *regs =
+        (fallback_regs_t *)(((uintptr_t)hi << 16 << 16) | (uintptr_t)lo);
+    void *args[4] = { NULL, NULL, NULL, NULL };
+    args[regs->arg_pos] = (void *)(uintptr_t)regs->arg;
+    ((entry_t)(uintptr_t)regs->fptr)(args[0], args[1], args[2], args[3]);
+    // Task entry points never return
+    abort();
+}
+
+// Makes `regs` describe a call of `fptr` with `arg` as its argument number
+// `arg_pos`, on the stack whose top is `sp`.
+void
+rust_ucontext_init(fallback_regs_t *regs, void *fptr, void *arg,
+                   size_t arg_pos, void *sp) {
+    uintptr_t p = (uintptr_t)regs;
+
+    getcontext(&regs->uc);
+    regs->uc.uc_link = NULL;
+    // Only the top of the stack is known, and it's all that makecontext
+    // uses, so the stack is described as the page below it
+    regs->uc.uc_stack.ss_sp = (char *)sp - 4096;
+    regs->uc.uc_stack.ss_size = 4096;
+    makecontext(&regs->uc, (void (*)(void))fallback_start, 2,
+                (unsigned int)(p >> 16 >> 16), (unsigned int)p);
+
+    regs->ip = (uintptr_t)fptr;
+    regs->sp = (uintptr_t)sp;
+    regs->fp = 0;
+    regs->fptr = (uintptr_t)fptr;
+    regs->arg = (uintptr_t)arg;
+    regs->arg_pos = arg_pos;
+}
+
+// swap_registers(registers_t *oregs, registers_t *regs)
+//
+// There's no way to read the stack pointer that swapcontext saves, so the
+// frame of this call stands in for it.
+void
+rust_swap_registers(fallback_regs_t *oregs, fallback_regs_t *regs) {
+    oregs->ip = (uintptr_t)__builtin_return_address(0);
+    oregs->sp = (uintptr_t)__builtin_frame_address(0);
+    oregs->fp = oregs->sp;
+    swapcontext(&oregs->uc, &regs->uc);
+}
+
+#endif